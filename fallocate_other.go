@@ -0,0 +1,14 @@
+//go:build !linux && !windows
+
+package main
+
+import (
+	"errors"
+	"os"
+)
+
+// allocateFile has no native call to use here, so preallocate writes
+// zeros instead
+func allocateFile(file *os.File, size int64) error {
+	return errors.ErrUnsupported
+}
@@ -1,28 +1,55 @@
 package main
 
 import (
+	"archive/tar"
+	"archive/zip"
 	"bufio"
 	"bytes"
+	"compress/gzip"
 	"context"
+	"crypto"
+	"crypto/hmac"
 	"crypto/md5"
+	"crypto/rsa"
 	"crypto/sha1"
 	"crypto/sha256"
+	"crypto/sha512"
+	"crypto/subtle"
 	"crypto/tls"
+	"crypto/x509"
 	"database/sql"
+	"encoding/base64"
+	"encoding/binary"
 	"encoding/hex"
 	"encoding/json"
+	"encoding/pem"
+	"encoding/xml"
+	"errors"
 	"flag"
 	"fmt"
+	"hash"
 	"io"
 	"log"
+	"log/slog"
+	"math"
+	"math/rand"
+	"mime"
 	"net"
 	"net/http"
+	"net/http/cookiejar"
+	"net/http/httptrace"
+	"net/textproto"
 	"net/url"
 	"os"
+	"os/exec"
 	"os/signal"
 	"path"
 	"path/filepath"
+	"reflect"
+	"regexp"
 	"runtime"
+	"runtime/debug"
+	"slices"
 	"sort"
 	"strconv"
 	"strings"
@@ -30,10 +57,18 @@ import (
 	"sync/atomic"
 	"syscall"
 	"time"
+	"unicode/utf8"
+	"unsafe"
 
 	_ "github.com/mattn/go-sqlite3"
+	"golang.org/x/crypto/blake2b"
+	"golang.org/x/crypto/openpgp"
+	"golang.org/x/crypto/ssh"
+	"golang.org/x/crypto/ssh/agent"
+	"golang.org/x/crypto/ssh/knownhosts"
 	"golang.org/x/crypto/ssh/terminal"
 	"golang.org/x/net/http2"
+	"golang.org/x/net/websocket"
 	"golang.org/x/time/rate"
 )
 
@@ -42,9 +77,121 @@ const (
 	DefaultChunks  = 32
 	ChunkSize      = 4 * 1024 * 1024 // 4MB
 	BufferSize     = 32 * 1024       // 32KB
+	BufferAlign    = 4096            // pooled buffers start on a page, as O_DIRECT wants
 	MaxRetries     = 5
 	RetryDelay     = 2 * time.Second
+	MaxRetryDelay  = 60 * time.Second
+	MaxRetryAfter  = 5 * time.Minute // longer Retry-After waits fail instead
 	ProgressUpdate = 100 * time.Millisecond
+	DNSNegativeTTL = 5 * time.Second
+	MaxChunkCount  = 4096
+
+	// Auto-tune probe
+	AutoTuneMinSize    = 256 * 1024 * 1024
+	AutoTuneSampleSize = 1024 * 1024
+
+	// Low priority preset
+	LowPriorityConnections = 4
+	LowPriorityParallel    = 1
+	LowPriorityRate        = 1024 * 1024 // 1MB/s unless a rate is configured
+
+	// Mirror striping: consecutive failures before a source sits out
+	MirrorMaxFailures = 3
+
+	// Work stealing: a slow chunk is only split while each half keeps this much
+	StealMinSize = 1024 * 1024
+
+	// SFTP: READ requests kept in flight, each of BufferSize bytes
+	SFTPMaxRequests = 64
+
+	// Adaptive connections: start small and add workers while throughput improves
+	AdaptiveStart    = 2
+	AdaptiveInterval = 2 * time.Second
+	AdaptiveGain     = 1.1 // a step has to raise throughput 10% to be kept
+
+	// Free space kept on the target filesystem on top of each download
+	DiskSpaceMargin = 64 * 1024 * 1024
+
+	// Async writes: buffers a chunk may have queued for its writer
+	AsyncWriteDepth = 8
+
+	// io_uring: submission queue size, which also bounds writes in flight
+	URingEntries = 256
+
+	// URL globs expanding to more than this are refused as a likely typo
+	MaxGlobURLs = 100000
+
+	// Torrents nesting lists and dicts deeper than this are refused
+	// rather than recursed into until the stack runs out
+	MaxBencodeDepth = 64
+)
+
+// io_uring ABI from linux/io_uring.h; the syscalls themselves are in
+// uring_linux.go
+const (
+	iouringOpNop          = 0
+	iouringOpWrite        = 23
+	iouringEnterGetEvents = 1
+
+	iouringOffSQRing = 0
+	iouringOffCQRing = 0x8000000
+	iouringOffSQEs   = 0x10000000
+)
+
+var (
+	errRemoteChanged = errors.New("remote file changed since the download started")
+	errCircuitOpen   = errors.New("circuit breaker open")
+	errDNSTimeout    = errors.New("dns lookup timed out")
+	errPanic         = errors.New("panic")
+	errShortChunk    = errors.New("connection closed before the chunk was complete")
+	errStalled       = errors.New("download stalled below minimum speed")
+	errPaused        = errors.New("download paused")
+	errChecksum      = errors.New("checksum mismatch")
+	errInterrupted   = errors.New("interrupted")
+	errThrottled     = errors.New("rate limited by server")
+	errDiskSpace     = errors.New("not enough disk space")
+	errQuota         = errors.New("download quota exceeded")
+	errRetryBudget   = errors.New("retry budget exhausted")
+	errNotModified   = errors.New("not modified")
+	errTruncatedBody = errors.New("response body ended before Content-Length")
+
+	errRangeNotSupported = errors.New("server does not support range requests")
+	errPartial           = errors.New("some downloads failed")
+	errJobNotFound       = errors.New("job not found")
+)
+
+// ErrorKind is the category of a failed download, stable enough for
+// scripts and the job API to branch on instead of parsing messages
+type ErrorKind string
+
+const (
+	ErrorNetwork           ErrorKind = "network"
+	ErrorHTTP              ErrorKind = "http"
+	ErrorIO                ErrorKind = "io"
+	ErrorDisk              ErrorKind = "disk"
+	ErrorRangeNotSupported ErrorKind = "range_not_supported"
+	ErrorTruncatedBody     ErrorKind = "truncated_body"
+	ErrorChecksum          ErrorKind = "checksum"
+	ErrorThrottled         ErrorKind = "throttled"
+	ErrorCircuitOpen       ErrorKind = "circuit_open"
+	ErrorCancelled         ErrorKind = "cancelled"
+	ErrorTimeout           ErrorKind = "timeout"
+	ErrorConfig            ErrorKind = "config"
+	ErrorInternal          ErrorKind = "internal"
+	ErrorUnknown           ErrorKind = "unknown"
+)
+
+// Process exit codes, so wrappers can tell a retryable failure from one
+// that needs a fix before running again
+const (
+	ExitOK          = 0
+	ExitFailure     = 1 // anything not covered below
+	ExitConfig      = 2 // bad flags, config or usage
+	ExitNetwork     = 3 // connection, HTTP status, timeout
+	ExitIO          = 4 // disk full, quota, filesystem errors
+	ExitChecksum    = 5
+	ExitPartial     = 6 // some downloads of a batch failed
+	ExitInterrupted = 130
 )
 
 var (
@@ -77,7 +224,9 @@ type Config struct {
 	RateLimit        int64             `json:"rate_limit_bytes"`
 	ProxyURL         string            `json:"proxy_url"`
 	UserAgent        string            `json:"user_agent"`
-	Timeout          int               `json:"timeout_seconds"`
+	Timeout          int               `json:"timeout_seconds"` // wait for response headers, not the whole body
+	ConnectTimeout   int               `json:"connect_timeout_seconds"`
+	ReadTimeout      int               `json:"read_timeout_seconds"` // longest wait for the next bytes on a connection
 	ResumeEnabled    bool              `json:"resume_enabled"`
 	VerifyChecksum   bool              `json:"verify_checksum"`
 	UseMirrors       bool              `json:"use_mirrors"`
@@ -93,13 +242,90 @@ type Config struct {
 	TorrentPort      int               `json:"torrent_port"`
 	EnableTorrent    bool              `json:"enable_torrent"`
 	EnableFTP        bool              `json:"enable_ftp"`
-	LogFile          string            `json:"log_file"`
+	FTPActive        bool              `json:"ftp_active"` // server connects back instead of passive mode
+	SSHKeyFile       string            `json:"ssh_key_file"`
+	SSHKnownHosts    string            `json:"ssh_known_hosts"`
+	LogFile          string            `json:"log_file"` // stderr when empty
 	ConfigPath       string            `json:"config_path"`
+	Preconnect       bool              `json:"preconnect"`
+	DefaultIndexName string            `json:"default_index_name"`
+	FreshConnRetry   int               `json:"fresh_connection_after_retries"`
+	VerifyResumeTail int64             `json:"verify_resume_tail_bytes"`
+	ArchiveOutput    string            `json:"archive_output"`
+	DNSTimeout       int               `json:"dns_timeout_seconds"`
+	DNSCacheTTL      int               `json:"dns_cache_ttl_seconds"`
+	LowPriority      bool              `json:"low_priority"`
+	FailFast         bool              `json:"fail_fast"`
+	PlanOnly         bool              `json:"plan_only"`
+	JitterStrategy   string            `json:"jitter_strategy"` // none, full, equal, decorrelated
+	ChunkQueue       bool              `json:"chunk_queue"`
+	TeeDirs          []string          `json:"tee_dirs"`
+	TeeFailFatal     bool              `json:"tee_fail_fatal"`
+	AutoTune         bool              `json:"auto_tune"`
+	UseNetrc         bool              `json:"use_netrc"`
+	NetrcFile        string            `json:"netrc_file"` // read instead of ~/.netrc, implies use_netrc
+	CircuitThreshold int               `json:"circuit_breaker_threshold"`
+	CircuitCooldown  int               `json:"circuit_breaker_cooldown_seconds"`
+	RetryBudget      int               `json:"retry_budget_per_minute"` // retries one host may cause, shared by all its downloads
+	OTLPEndpoint     string            `json:"otlp_endpoint"`
+	Extract          bool              `json:"extract_archives"`
+	FilenameEncoding string            `json:"filename_encoding"` // keep, ascii, escape, reject
+	MaxConcurrentDNS int               `json:"max_concurrent_dns"`
+	Preallocate      string            `json:"preallocate"` // none, sparse, prealloc, full
+	Verbose          bool              `json:"verbose"`
+	PipelineDepth    int               `json:"pipeline_depth"`
+	UseMmap          bool              `json:"use_mmap"`
+	SharedOutput     bool              `json:"shared_output"`
+	IOUring          bool              `json:"io_uring"` // shared_output writes through io_uring on Linux
+	BufferSize       int               `json:"buffer_size"`
+	MinSpeed         int64             `json:"min_speed_bytes_per_sec"`
+	MinSpeedWindow   int               `json:"min_speed_window_seconds"`
+	StallTimeout     int               `json:"stall_timeout_seconds"` // abort an attempt that receives nothing this long
+	ProxyRules       []ProxyRule       `json:"proxy_rules"`
+	SaveCookies      bool              `json:"save_cookies"`
+	StripeMirrors    bool              `json:"stripe_mirrors"`
+	ProgressFormat   string            `json:"progress_format"` // text, ndjson, pretty
+	ProgressFD       int               `json:"progress_fd"`     // ndjson goes here instead of stdout
+	HostBackoff      bool              `json:"host_backoff"`    // a 429/503 holds every request to that host
+	MaxPerHost       int               `json:"max_connections_per_host"`
+	WorkStealing     bool              `json:"work_stealing"`
+	AdaptiveConns    bool              `json:"adaptive_connections"`
+	NoProxy          []string          `json:"no_proxy"`    // hosts that bypass every proxy, "*.domain" allowed
+	TempDir          string            `json:"temp_dir"`    // in-progress files, next to the output when empty
+	OnConflict       string            `json:"on_conflict"` // overwrite, skip, resume, rename
+	OutputTemplate   string            `json:"output_template"`
+	DiskMargin       int64             `json:"disk_space_margin_bytes"`
+	MaxTotalBytes    int64             `json:"max_total_bytes"` // budget for everything this process downloads
+	AsyncWrites      bool              `json:"async_writes"`    // a writer goroutine per chunk keeps disk stalls off the socket
+	GlobOff          bool              `json:"globoff"`         // take [] and {} in URLs literally
+	QueueOrder       string            `json:"queue_order"`     // batch entries of equal priority: fifo, lifo, smallest, largest
+	ControlSocket    string            `json:"control_socket"`
+	RPCSecret        string            `json:"rpc_secret"`    // aria2 clients send it as "token:<secret>"
+	APIToken         string            `json:"api_token"`     // bearer token the REST and /api endpoints require
+	LogLevel         string            `json:"log_level"`     // debug, info, warn, error; -v alone means debug
+	LogFormat        string            `json:"log_format"`    // pretty, json
+	LogMaxBytes      int64             `json:"log_max_bytes"` // log_file is rotated at this size
+	LogKeep          int               `json:"log_keep"`      // rotated log files kept
+	History          bool              `json:"history"`       // record every download in history_path
+	HistoryPath      string            `json:"history_path"`
+	SkipDownloaded   bool              `json:"skip_downloaded"` // skip URLs the history has a completed, intact download of
+	Conditional      bool              `json:"conditional"`     // send the history's ETag/Last-Modified, a 304 skips the download
+	Timestamping     bool              `json:"timestamping"`    // like wget -N: only fetch files newer than the local copy
+	MaxRedirects     int               `json:"max_redirects"`   // 0 doesn't follow redirects at all
+	User             string            `json:"user"`            // user:password for the hosts of the URLs downloaded
+	BearerToken      string            `json:"bearer_token"`    // sent to the same hosts instead of user
+	RefreshCommand   string            `json:"refresh_command"` // prints a fresh URL when a chunk's URL expires (403)
+	CACert           string            `json:"ca_cert"`         // PEM bundle of extra CAs trusted for HTTPS and FTPS
+	ClientCert       string            `json:"client_cert"`     // PEM client certificate for mutual TLS
+	ClientKey        string            `json:"client_key"`      // client_cert's key, when not in the same file
+	Insecure         bool              `json:"insecure"`        // skip certificate verification
+	MinTLSVersion    string            `json:"min_tls_version"` // 1.0, 1.1, 1.2 or 1.3
 }
 
 // DownloadManager handles all download operations
 type DownloadManager struct {
 	client       *http.Client
+	freshClient  *http.Client // bypasses the idle pool for stubborn retries
 	maxWorkers   int
 	downloadDir  string
 	verifyHashes bool
@@ -107,6 +333,391 @@ type DownloadManager struct {
 	rateLimiter  *RateLimiter
 	proxyManager *ProxyManager
 	config       *Config
+	validators   []ContentValidator
+	auth         *authenticator
+	breaker      *CircuitBreaker
+	retries      *retryBudget
+	throttle     *hostThrottle
+	hostSlots    *hostLimiter
+	tracer       *Tracer
+	history      *History
+	observers    []DownloadObserver
+	cookies      *cookieStore
+	pause        pauseGate
+	progressOut  io.Writer
+	progressMu   sync.Mutex
+	bars         *multiBar
+	totalBytes   int64 // claimed against max_total_bytes
+	buffers      *bufferPool
+	tlsConfig    *tls.Config // also used for FTPS control and data connections
+	refreshMu    sync.Mutex  // one refresh hook call at a time
+	refreshed    sync.Map    // expired URL -> the URL the refresh hook gave for it
+}
+
+// bufferPool hands out read buffers of one size, aligned to BufferAlign,
+// so chunk workers reuse them instead of allocating one per attempt
+type bufferPool struct {
+	size int
+	pool sync.Pool
+}
+
+// DownloadProgress is one line of the ndjson progress stream
+type DownloadProgress struct {
+	ID         string  `json:"id,omitempty"` // daemon job, on progress WebSockets
+	URL        string  `json:"url"`
+	File       string  `json:"file"`
+	Downloaded int64   `json:"downloaded"`
+	Total      int64   `json:"total"`
+	Percent    float64 `json:"percent"`
+	Speed      float64 `json:"speed"` // bytes/sec over the last interval
+	ETA        float64 `json:"eta_seconds"`
+	Active     int32   `json:"active_chunks"`
+	Status     string  `json:"status"` // downloading, paused, done
+}
+
+// pauseGate holds transfers while a download manager is paused
+type pauseGate struct {
+	paused  bool
+	resume  chan struct{}
+	pausing chan struct{} // closed by Pause to interrupt chunk requests
+	mu      sync.Mutex
+}
+
+// cookieStore is a cookie jar that remembers every cookie it was given, so
+// the session can be written back out as a Netscape cookies.txt
+type cookieStore struct {
+	jar     *cookiejar.Jar
+	path    string
+	entries map[string]cookieEntry
+	dirty   bool
+	mu      sync.Mutex
+}
+
+type cookieEntry struct {
+	host   string // domain without the leading dot
+	cookie *http.Cookie
+}
+
+// CircuitBreaker fast-fails downloads from hosts that keep failing. After
+// the cooldown a single probe is let through to close it again.
+type CircuitBreaker struct {
+	threshold int
+	cooldown  time.Duration
+	hosts     map[string]*hostCircuit
+	mu        sync.Mutex
+}
+
+type hostCircuit struct {
+	failures  int
+	openUntil time.Time
+	probing   bool
+	probes    int // half-open probes handed out, so a stale release is ignored
+}
+
+// retryBudget caps how many retries each host may cause per minute across
+// every download, so a dead host fails its queue fast instead of every
+// URL working through its own retries and backoff
+type retryBudget struct {
+	perMinute int
+	hosts     map[string]*rate.Limiter
+	mu        sync.Mutex
+}
+
+// hostThrottle holds requests to hosts that answered 429 or 503 until
+// their Retry-After has passed
+type hostThrottle struct {
+	until map[string]time.Time
+	mu    sync.Mutex
+}
+
+// hostLimiter caps concurrent connections to each host across every
+// download, so a batch from one server stays polite while other hosts run
+// at full parallelism
+type hostLimiter struct {
+	limit int
+	slots map[string]chan struct{}
+	mu    sync.Mutex
+}
+
+// DownloadError is what Download returns: the underlying error plus its
+// kind, and the response status for ErrorHTTP and ErrorThrottled
+type DownloadError struct {
+	Kind   ErrorKind
+	Status int
+	Err    error
+}
+
+// throttleError is a 429/503 response; wait is the server's Retry-After,
+// zero when it sent none
+type throttleError struct {
+	status int
+	wait   time.Duration
+}
+
+// Tracer exports download and chunk spans to an OTLP/HTTP collector using
+// the JSON encoding, so no SDK dependency is needed
+type Tracer struct {
+	endpoint string
+	client   *http.Client
+	pending  []otlpSpan
+	mu       sync.Mutex
+}
+
+// logger is where diagnostics go: warnings, retries and, at debug level,
+// what each download and chunk is doing. It writes to stderr or log_file,
+// so stdout carries only progress and results. setupLogging replaces it.
+var logger = slog.New(newPrettyHandler(os.Stderr, slog.LevelWarn, false))
+
+// prettyHandler formats log records for people: time, level, message and
+// key=value attributes, with the level coloured on a terminal
+type prettyHandler struct {
+	out   io.Writer
+	mu    *sync.Mutex
+	level slog.Leveler
+	color bool
+	attrs string // preformatted attributes from WithAttrs
+	group string // key prefix from WithGroup
+}
+
+// rotatingFile is a log file that moves to path.1 (path.1 to path.2, and
+// so on) once it reaches maxSize, keeping keep old files
+type rotatingFile struct {
+	path    string
+	maxSize int64
+	keep    int
+	file    *os.File
+	size    int64
+	mu      sync.Mutex
+}
+
+// History records every download in a SQLite database, as an audit trail
+// for "fastdl history" and so skip_downloaded can dedup against it
+type History struct {
+	NopObserver
+	db  *sql.DB
+	dir string // download directory task paths are relative to
+}
+
+// HistoryEntry is one download in the history
+type HistoryEntry struct {
+	ID           int64     `json:"id"`
+	URL          string    `json:"url"`
+	Path         string    `json:"path,omitempty"`
+	Size         int64     `json:"size"`
+	DurationMS   int64     `json:"duration_ms"`
+	Checksum     string    `json:"checksum,omitempty"` // algo:hex
+	Status       string    `json:"status"`             // completed, skipped, failed, interrupted
+	Error        string    `json:"error,omitempty"`
+	ETag         string    `json:"etag,omitempty"`
+	LastModified string    `json:"last_modified,omitempty"`
+	StartedAt    time.Time `json:"started_at"`
+	FinishedAt   time.Time `json:"finished_at"`
+}
+
+// Metrics counts what downloads do, for the daemon's Prometheus /metrics
+// endpoint. It is a DownloadObserver, so it sees every download the
+// manager runs.
+type Metrics struct {
+	NopObserver
+	bytes    int64
+	running  map[*DownloadTask]ProgressSnapshot // last progress of each running download
+	results  map[string]int64                   // finished downloads: completed, failed
+	chunks   map[string]int64                   // chunk requests by host
+	retries  map[string]int64                   // chunk retries by host
+	failures map[[2]string]int64                // failed downloads by host and error kind
+	speeds   []int64                            // average speed of finished downloads, per SpeedBuckets
+	speedSum float64
+	speedObs int64
+	mu       sync.Mutex
+}
+
+// SpeedBuckets are the upper bounds, in bytes/sec, of the download speed
+// histogram
+var SpeedBuckets = []float64{64 << 10, 256 << 10, 1 << 20, 4 << 20, 16 << 20, 64 << 20, 256 << 20, 1 << 30}
+
+// Span is an in-flight trace span; a nil *Span records nothing
+type Span struct {
+	tracer *Tracer
+	data   otlpSpan
+}
+
+// spanKey carries a request's *Span in its context
+type spanKey struct{}
+
+// cdnHeaders name the edge that served a response, so slow requests can be
+// traced back to a CDN point of presence
+var cdnHeaders = []string{"Via", "Server", "X-Served-By", "X-Cache", "CF-Ray", "X-Amz-Cf-Pop", "X-Amz-Cf-Id", "Age"}
+
+type otlpSpan struct {
+	TraceID           string          `json:"traceId"`
+	SpanID            string          `json:"spanId"`
+	ParentSpanID      string          `json:"parentSpanId,omitempty"`
+	Name              string          `json:"name"`
+	Kind              int             `json:"kind"`
+	StartTimeUnixNano string          `json:"startTimeUnixNano"`
+	EndTimeUnixNano   string          `json:"endTimeUnixNano"`
+	Attributes        []otlpAttribute `json:"attributes,omitempty"`
+	Status            otlpStatus      `json:"status"`
+}
+
+type otlpAttribute struct {
+	Key   string    `json:"key"`
+	Value otlpValue `json:"value"`
+}
+
+type otlpValue struct {
+	StringValue *string `json:"stringValue,omitempty"`
+	IntValue    *string `json:"intValue,omitempty"` // int64 is a string in OTLP/JSON
+}
+
+type otlpStatus struct {
+	Code    int    `json:"code"` // 1 ok, 2 error
+	Message string `json:"message,omitempty"`
+}
+
+// netrcEntry holds the credentials of one .netrc machine
+type netrcEntry struct {
+	login    string
+	password string
+}
+
+// authenticator decides what credentials each request carries: -user or
+// -bearer for the hosts the user asked to download from, else the netrc
+// entry for the request's host. It remembers which hosts answered with a
+// Digest challenge so later requests skip the extra round trip.
+type authenticator struct {
+	user     string
+	password string
+	bearer   string
+	netrc    map[string]netrcEntry
+	mu       sync.Mutex
+	hosts    map[string]bool
+	digests  map[string]*digestChallenge
+}
+
+// digestChallenge is a parsed WWW-Authenticate: Digest header
+type digestChallenge struct {
+	realm     string
+	nonce     string
+	opaque    string
+	algorithm string
+	qop       string // "auth" when the server offers it, else empty
+	count     int    // nonce count, sent as nc
+}
+
+// authTransport applies an authenticator to every request, including the
+// ones redirects lead to, and answers Digest challenges
+type authTransport struct {
+	base http.RoundTripper
+	auth *authenticator
+}
+
+// s3Client holds what every s3:// request shares: credentials from the
+// AWS chain, the default region and the regions buckets turned out to be in
+type s3Client struct {
+	creds    *awsCredentials
+	region   string
+	endpoint *url.URL // AWS_ENDPOINT_URL(_S3), addressed path-style
+	mu       sync.Mutex
+	regions  map[string]string
+}
+
+// s3Transport is registered for the s3 scheme on the download transports.
+// It sends each request to the bucket's HTTPS endpoint signed with SigV4,
+// so probes and ranged chunk GETs work unchanged and, unlike a presigned
+// URL, never expire mid-transfer.
+type s3Transport struct {
+	base http.RoundTripper
+	s3   *s3Client
+}
+
+// awsKey is one set of AWS credentials; Expires is zero for long-lived keys
+type awsKey struct {
+	ID      string
+	Secret  string
+	Token   string
+	Expires time.Time
+}
+
+// awsCredentials resolves the standard chain once and again shortly before
+// temporary credentials expire
+type awsCredentials struct {
+	mu     sync.Mutex
+	key    awsKey
+	loaded bool
+	client *http.Client // metadata endpoints, never through a proxy
+}
+
+// bearerToken caches an OAuth access token until shortly before it
+// expires. fetch returns "" when there are no credentials to use.
+type bearerToken struct {
+	mu      sync.Mutex
+	token   string
+	expires time.Time
+	fetched bool
+	fetch   func(ctx context.Context) (string, time.Time, error)
+}
+
+// gcsTransport serves gs://bucket/object through the Cloud Storage HTTP
+// API with a token from the ambient Google credentials
+type gcsTransport struct {
+	base     http.RoundTripper
+	token    *bearerToken
+	endpoint *url.URL // STORAGE_EMULATOR_HOST
+}
+
+// azureTransport serves az://account/container/blob from Blob Storage,
+// authorized by a SAS token, the account key or an Entra ID token
+type azureTransport struct {
+	base  http.RoundTripper
+	sas   string // AZURE_STORAGE_SAS_TOKEN, appended to every request
+	key   []byte // AZURE_STORAGE_KEY, for Shared Key signing
+	token *bearerToken
+}
+
+// ContentValidator checks a finished file beyond its checksums
+type ContentValidator interface {
+	Validate(path string) error
+}
+
+// ValidatorFunc adapts a plain function to ContentValidator
+type ValidatorFunc func(path string) error
+
+func (f ValidatorFunc) Validate(path string) error {
+	return f(path)
+}
+
+// DownloadObserver receives lifecycle events for every download. Chunk
+// events only fire for multi-connection downloads. Embed NopObserver to
+// implement just the events you need.
+type DownloadObserver interface {
+	OnStart(task *DownloadTask)
+	OnChunkStart(task *DownloadTask, chunk ChunkInfo, attempt int)
+	OnProgress(task *DownloadTask, stats ProgressSnapshot)
+	OnChunkComplete(task *DownloadTask, chunk ChunkInfo)
+	OnRetry(task *DownloadTask, chunk ChunkInfo, attempt int, err error)
+	OnComplete(task *DownloadTask, stats ProgressSnapshot)
+	OnError(task *DownloadTask, err error)
+}
+
+// NopObserver implements DownloadObserver with no-ops
+type NopObserver struct{}
+
+func (NopObserver) OnStart(*DownloadTask) {}
+func (NopObserver) OnChunkStart(*DownloadTask, ChunkInfo, int) {}
+func (NopObserver) OnProgress(*DownloadTask, ProgressSnapshot) {}
+func (NopObserver) OnChunkComplete(*DownloadTask, ChunkInfo) {}
+func (NopObserver) OnRetry(*DownloadTask, ChunkInfo, int, error) {}
+func (NopObserver) OnComplete(*DownloadTask, ProgressSnapshot) {}
+func (NopObserver) OnError(*DownloadTask, error) {}
+
+// ProgressSnapshot is a point-in-time copy of a download's progress
+type ProgressSnapshot struct {
+	Downloaded int64
+	Total      int64
+	Speed      float64 // bytes/sec since the previous snapshot
+	Active     int32
+	Elapsed    time.Duration
 }
 
 // Job represents a download job
@@ -129,11 +740,19 @@ type Job struct {
 	Speed       float64           `json:"speed"`
 	ETA         int               `json:"eta"`
 	Error       string            `json:"error"`
+	ErrorKind   ErrorKind         `json:"error_kind,omitempty"`
 	Metadata    map[string]string `json:"metadata"`
 	ChunkStates []ChunkState      `json:"chunk_states"`
 	Chunks      int               `json:"chunks"`
 }
 
+// JobEvent is what JobQueue subscribers receive: a copy of the job after
+// its status changed or its download made progress
+type JobEvent struct {
+	Type string `json:"type"` // status or progress
+	Job  Job    `json:"job"`
+}
+
 // ChunkState tracks individual chunk progress
 type ChunkState struct {
 	Index      int   `json:"index"`
@@ -144,6 +763,11 @@ type ChunkState struct {
 	Retries    int   `json:"retries"`
 }
 
+// URLRefresher returns a fresh URL for one that expired mid-download and
+// got a 403, e.g. a new pre-signed URL. Set on a task it overrides
+// refresh_command.
+type URLRefresher func(ctx context.Context, expired string) (string, error)
+
 // DownloadTask represents a single download operation
 type DownloadTask struct {
 	URL           string
@@ -151,6 +775,9 @@ type DownloadTask struct {
 	SHA256        string
 	SHA1          string
 	MD5           string
+	Checksum      string            // expected digest for ChecksumAlgo
+	ChecksumAlgo  string            // sha256, sha1, md5, sha512, blake2b
+	Digests       map[string]string // computed after download when verify_checksum is on
 	Size          int64
 	Downloaded    int64
 	Chunks        int
@@ -158,6 +785,249 @@ type DownloadTask struct {
 	StartTime     time.Time
 	Headers       map[string]string
 	Cookies       []*http.Cookie
+	SignatureURL  string // detached .asc/.sig, URL or local path
+	PublicKey     string // trusted public key file for SignatureURL
+	ETag          string
+	LastModified  string
+	IfRange       string   // validator sent with chunk requests when resuming
+	Connections   int      // per-download worker count, 0 uses MaxConnections
+	RateLimit     int64    // per-download bytes/sec, applied on top of rate_limit_bytes
+	Mirrors       []string // alternate URLs serving the same file, tried in order
+	Skipped       bool     // on_conflict kept the existing file instead of downloading
+	Priority      int      // batch entries with higher priority start first
+	FinalURL      string   // where the probe's redirects ended up
+	Redirects     []string // URLs redirected through before FinalURL, in order
+	RefreshURL    URLRefresher
+	span          *Span
+	line          int               // source line in a batch URL file
+	index         int               // position in the batch, from 1
+	contentType   string
+	sums          map[string]string // digests computed while downloading
+	progress      *ProgressInfo
+	output        *sharedOutput // set while a use_mmap or shared_output download is running
+	resumeState   *ResumeState
+	limiter       *RateLimiter
+	source        int32 // index into sources() that requests currently go to
+	validatorURL  string
+	scheduler     *mirrorScheduler
+	tuner         *connTuner
+	pieces        *pieceHashes
+	multiplexed   bool // the probe was answered over HTTP/2
+}
+
+// pieceHashes are the per-piece digests from a metalink or torrent. Chunks
+// are laid out on piece boundaries so each one is checked as soon as it
+// finishes. Pieces of a multi-file torrent run across the concatenation of
+// its files, so offset places this file in that stream.
+type pieceHashes struct {
+	algorithm string
+	length    int64
+	hashes    []string
+	offset    int64 // where the file starts in the hashed stream
+	total     int64 // length of the hashed stream, 0 for just this file
+}
+
+// metalink covers both Metalink 4 (RFC 5854, .meta4) and the older 3.0
+// format (.metalink), which nests files, hashes and URLs one level deeper
+type metalink struct {
+	Files   []metalinkFile `xml:"file"`
+	V3Files []metalinkFile `xml:"files>file"`
+}
+
+type metalinkFile struct {
+	Name     string           `xml:"name,attr"`
+	Size     int64            `xml:"size"`
+	Hashes   []metalinkHash   `xml:"hash"`
+	Pieces   []metalinkPieces `xml:"pieces"`
+	URLs     []metalinkURL    `xml:"url"`
+	V3Hashes []metalinkHash   `xml:"verification>hash"`
+	V3Pieces []metalinkPieces `xml:"verification>pieces"`
+	V3URLs   []metalinkURL    `xml:"resources>url"`
+}
+
+type metalinkHash struct {
+	Type  string `xml:"type,attr"`
+	Value string `xml:",chardata"`
+}
+
+type metalinkPieces struct {
+	Type   string   `xml:"type,attr"`
+	Length int64    `xml:"length,attr"`
+	Hashes []string `xml:"hash"`
+}
+
+type metalinkURL struct {
+	URL        string `xml:",chardata"`
+	Priority   int    `xml:"priority,attr"`   // 4.0: 1 is best
+	Preference int    `xml:"preference,attr"` // 3.0: 100 is best
+}
+
+// mirrorScheduler spreads a download's chunks over all of its healthy
+// sources at once. Each pick goes to the source expected to finish soonest
+// given its measured speed and chunks in flight, so assignment shifts
+// toward faster mirrors as their speeds diverge.
+type mirrorScheduler struct {
+	stats []MirrorStat
+	mu    sync.Mutex
+}
+
+// MirrorStat is the per-source throughput of a striped download
+type MirrorStat struct {
+	URL      string        `json:"url"`
+	Bytes    int64         `json:"bytes"`
+	Busy     time.Duration `json:"busy"`
+	Chunks   int           `json:"chunks"`
+	Failures int           `json:"failures"` // consecutive, reset by a success
+	inFlight int
+}
+
+// PlanEntry describes what a download would do without fetching the body
+type PlanEntry struct {
+	URL        string `json:"url"`
+	Filename   string `json:"filename"`
+	OutputPath string `json:"output_path"`
+	Size       int64  `json:"size"`
+	Strategy   string `json:"strategy"` // multi, single
+	Chunks     int    `json:"chunks"`
+	Action     string `json:"action"` // download, resume, overwrite
+	Collision  bool   `json:"collision,omitempty"`
+	Error      string `json:"error,omitempty"`
+}
+
+// sharedOutput is the preallocated output file opened once for the whole
+// download, so chunk workers write straight to their offsets instead of
+// into part files: through a memory mapping when data is set, otherwise
+// with positional writes on the one descriptor
+type sharedOutput struct {
+	file    *os.File
+	data    []byte
+	ring    *uring // writes go through io_uring when set
+	buffers *bufferPool
+}
+
+// uring is a minimal io_uring used only for positional file writes. Any
+// goroutine may submit; one reaper goroutine drains completions and calls
+// each write's done func.
+type uring struct {
+	fd      int
+	sqRing  []byte
+	cqRing  []byte
+	sqeMem  []byte
+	sqHead  *uint32
+	sqTail  *uint32
+	sqMask  *uint32
+	sqArray []uint32
+	sqes    []uringSQE
+	cqHead  *uint32
+	cqTail  *uint32
+	cqMask  *uint32
+	cqes    []uringCQE
+	slots   chan struct{}
+	pending map[uint64]*uringWrite
+	next    uint64
+	done    chan struct{}
+	mu      sync.Mutex
+}
+
+type uringWrite struct {
+	fd   int
+	off  int64
+	buf  []byte // referenced here until the kernel is done with it
+	done func(error)
+}
+
+// uringWriter writes one chunk through the ring; Close waits for its
+// writes still in flight
+type uringWriter struct {
+	ring    *uring
+	buffers *bufferPool
+	fd      int
+	off     int64
+	wg      sync.WaitGroup
+	err     error
+	mu      sync.Mutex
+}
+
+type uringSQOffsets struct {
+	head        uint32
+	tail        uint32
+	ringMask    uint32
+	ringEntries uint32
+	flags       uint32
+	dropped     uint32
+	array       uint32
+	resv1       uint32
+	userAddr    uint64
+}
+
+type uringCQOffsets struct {
+	head        uint32
+	tail        uint32
+	ringMask    uint32
+	ringEntries uint32
+	overflow    uint32
+	cqes        uint32
+	flags       uint32
+	resv1       uint32
+	userAddr    uint64
+}
+
+type uringParams struct {
+	sqEntries    uint32
+	cqEntries    uint32
+	flags        uint32
+	sqThreadCPU  uint32
+	sqThreadIdle uint32
+	features     uint32
+	wqFD         uint32
+	resv         [3]uint32
+	sqOff        uringSQOffsets
+	cqOff        uringCQOffsets
+}
+
+type uringSQE struct {
+	opcode   uint8
+	flags    uint8
+	ioprio   uint16
+	fd       int32
+	off      uint64
+	addr     uint64
+	len      uint32
+	rwFlags  uint32
+	userData uint64
+	pad      [3]uint64
+}
+
+type uringCQE struct {
+	userData uint64
+	res      int32
+	flags    uint32
+}
+
+// prefixHasher hashes part files in order as soon as every earlier chunk
+// has finished, so checksums are ready without re-reading the merged file
+type prefixHasher struct {
+	table  *chunkTable
+	hashes map[string]hash.Hash
+	writer io.Writer
+	output *sharedOutput // hash from the output file instead of part files
+	done   chan int
+	result chan error
+}
+
+// ResumeState is kept next to a multi-connection download so a later run
+// can tell whether existing parts still belong to the same remote file
+type ResumeState struct {
+	URL          string     `json:"url"`
+	Size         int64      `json:"size"`
+	Chunks       int        `json:"chunks"`
+	Ranges       [][2]int64 `json:"ranges"` // inclusive byte range of each chunk ID
+	ETag         string     `json:"etag,omitempty"`
+	LastModified string     `json:"last_modified,omitempty"`
+	Completed    []int      `json:"completed,omitempty"` // chunk IDs whose parts are whole
+
+	outputPath string
+	mu         sync.Mutex
 }
 
 // ChunkInfo represents a download chunk
@@ -166,6 +1036,37 @@ type ChunkInfo struct {
 	Start int64
 	End   int64
 	Path  string
+
+	live *chunkProgress // shared by every copy of the chunk
+}
+
+// chunkProgress is the mutable state of an in-flight chunk, so an idle
+// worker can take over the second half of a slow one
+type chunkProgress struct {
+	end     int64 // shrinks when the tail is stolen
+	written int64
+	started time.Time
+	mu      sync.Mutex
+}
+
+// connTuner grows the worker pool of one download while throughput keeps
+// improving and gives back a step that didn't help
+type connTuner struct {
+	max     int
+	active  int
+	retire  int // workers asked to stop after their current chunk
+	stopped bool
+	idle    chan struct{} // closed when the last worker exits
+	mu      sync.Mutex
+}
+
+// chunkTable holds every chunk of a download, including tails split off
+// slow chunks while it runs. IDs index the table, in creation order.
+type chunkTable struct {
+	chunks     []ChunkInfo
+	outputPath string
+	pieces     *pieceHashes // splits land on piece boundaries
+	mu         sync.Mutex
 }
 
 // ProgressInfo for real-time updates
@@ -176,6 +1077,28 @@ type ProgressInfo struct {
 	Percentage float64
 	Active     int32
 	ETA        time.Duration
+	Chunks     int32 // 0 for single-stream downloads
+	ChunksDone int32
+}
+
+// multiBar draws one progress line per active download plus an aggregate
+// line, redrawing in place so concurrent downloads never interleave
+type multiBar struct {
+	out      io.Writer
+	rows     []*barRow
+	lines    int // lines drawn by the last render
+	started  int
+	finished int
+	running  bool
+	mu       sync.Mutex
+}
+
+type barRow struct {
+	task           *DownloadTask
+	progress       *ProgressInfo
+	lastDownloaded int64
+	lastTime       time.Time
+	speed          float64
 }
 
 // RateLimiter implements bandwidth throttling
@@ -183,13 +1106,34 @@ type RateLimiter struct {
 	limiter  *rate.Limiter
 	enabled  bool
 	maxBytes int64
+	streams  map[*fairStream]struct{}
+	parent   *RateLimiter // also charged, so per-download caps stay under the global one
 	mu       sync.RWMutex
 }
 
+// fairStream holds one chunk's equal share of the global rate limit
+type fairStream struct {
+	parent  *RateLimiter
+	limiter *rate.Limiter
+}
+
 // ProxyManager handles proxy configuration
 type ProxyManager struct {
 	proxyURL *url.URL
-	enabled  bool
+	rules    []proxyRoute
+	noProxy  []string
+}
+
+// ProxyRule routes hosts matching Host through Proxy. Host is an exact name
+// or "*.example.com" for subdomains; Proxy "direct" bypasses proxy_url.
+type ProxyRule struct {
+	Host  string `json:"host"`
+	Proxy string `json:"proxy"`
+}
+
+type proxyRoute struct {
+	pattern string
+	proxy   *url.URL // nil goes direct
 }
 
 // MirrorManager handles multiple mirrors
@@ -213,6 +1157,16 @@ type JobQueue struct {
 	stopCh     chan struct{}
 	wg         sync.WaitGroup
 	manager    *DownloadManager
+	cancels    map[string]context.CancelFunc // running jobs, for pause and remove
+	tasks      map[*DownloadTask]*Job        // running downloads, for their progress
+	watchers   map[chan JobEvent]struct{}    // see Subscribe
+	watchMu    sync.Mutex
+}
+
+// jobObserver copies download progress onto the daemon's jobs
+type jobObserver struct {
+	NopObserver
+	jq *JobQueue
 }
 
 // DaemonServer provides HTTP API
@@ -221,30 +1175,170 @@ type DaemonServer struct {
 	config      *Config
 	server      *http.Server
 	rateLimiter *RateLimiter
+	control     net.Listener // the unix socket "fastdl ctl" connects to
+	metrics     *Metrics
+}
+
+// controlRequest is one line sent to the daemon's control socket
+type controlRequest struct {
+	Command  string `json:"command"` // add, remove, pause, resume, retry, status, rate
+	ID       string `json:"id,omitempty"`
+	URL      string `json:"url,omitempty"`
+	Output   string `json:"output,omitempty"`
+	Priority int    `json:"priority,omitempty"`
+	SHA256   string `json:"sha256,omitempty"`
+	Rate     int64  `json:"rate,omitempty"` // bytes/sec for every download, 0 = unlimited
+}
+
+// controlResponse answers one controlRequest
+type controlResponse struct {
+	OK     bool        `json:"ok"`
+	Error  string      `json:"error,omitempty"`
+	Result interface{} `json:"result,omitempty"`
+}
+
+// ftpConn is a small FTP client covering what a download needs: login,
+// SIZE/MDTM, REST to resume and RETR over passive or active data
+// connections. ftps:// is implicit TLS, ftpes:// upgrades with AUTH TLS.
+type ftpConn struct {
+	conn   net.Conn
+	text   *textproto.Conn
+	tls    *tls.Config // set for FTPS, data connections are protected too
+	active bool
+	stop   func() bool
+}
+
+// sftpClient speaks SFTP version 3 over an SSH session, just the requests
+// a download needs. Replies are matched to requests by id, so many READs
+// can be in flight at once.
+type sftpClient struct {
+	conn    *ssh.Client
+	session *ssh.Session
+	w       io.WriteCloser
+	r       io.Reader
+	nextID  uint32
+	pending map[uint32]chan sftpReply
+	err     error // why the reply loop stopped
+	mu      sync.Mutex
+	closers []io.Closer
+}
+
+type sftpReply struct {
+	typ  byte
+	data []byte // after the request id
+}
+
+// sftpReader reads a remote file in order while keeping up to
+// SFTPMaxRequests READs outstanding, which hides the round trip per block
+type sftpReader struct {
+	c      *sftpClient
+	handle []byte
+	offset int64 // next offset to request
+	size   int64
+	queue  []sftpRead
+	buf    []byte
+	err    error
+}
+
+type sftpRead struct {
+	offset int64
+	length uint32
+	reply  <-chan sftpReply
+}
+
+// ftpData is a RETR stream; closing it collects the transfer reply
+type ftpData struct {
+	net.Conn
+	c *ftpConn
+}
+
+// teeWriter mirrors the output file into extra directories. A failing copy
+// is dropped with a warning unless fatal is set.
+type teeWriter struct {
+	primary *os.File
+	copies  []*teeCopy
+	fatal   bool
+}
+
+type teeCopy struct {
+	path string
+	file *os.File
+}
+
+// batchQueue holds the batch entries not yet started, highest priority
+// first and then in queue_order. Workers take from it as they free up, so
+// an entry pushed later with a higher priority still goes ahead of the
+// ones waiting.
+type batchQueue struct {
+	order   string // fifo, lifo, smallest, largest
+	pending []*DownloadTask
+	seq     map[*DownloadTask]int
+	next    int
+	mu      sync.Mutex
+}
+
+// zipArchive collects finished batch downloads into a single zip file
+type zipArchive struct {
+	file   *os.File
+	writer *zip.Writer
+	mu     sync.Mutex
+}
+
+// dnsCache memoizes host lookups across downloads, failures included
+type dnsCache struct {
+	resolver *net.Resolver
+	dialer   *net.Dialer
+	timeout  time.Duration
+	ttl      time.Duration
+	entries  map[string]dnsEntry
+	slots    chan struct{}
+	mu       sync.Mutex
+}
+
+type dnsEntry struct {
+	addrs   []string
+	err     error
+	expires time.Time
 }
 
 // Initialize default configuration
 func DefaultConfig() *Config {
 	homeDir, _ := os.UserHomeDir()
 	return &Config{
-		MaxConnections: DefaultChunks,
-		ChunkSize:      ChunkSize,
-		MaxRetries:     MaxRetries,
-		RetryDelay:     2,
-		DownloadDir:    "./downloads",
-		RateLimit:      0,
-		UserAgent:      fmt.Sprintf("FastDL/%s", Version),
-		Timeout:        30,
-		ResumeEnabled:  true,
-		VerifyChecksum: true,
-		DaemonPort:     8080,
-		DatabasePath:   filepath.Join(homeDir, ".config", "fastdl", "fastdl.db"),
-		EnableHTTP2:    true,
-		MaxParallel:    4,
-		TorrentPort:    6881,
-		LogFile:        filepath.Join(homeDir, ".config", "fastdl", "fastdl.log"),
-		ConfigPath:     filepath.Join(homeDir, ".config", "fastdl", "config.json"),
-		Headers:        make(map[string]string),
+		MaxConnections:   DefaultChunks,
+		ChunkSize:        ChunkSize,
+		MaxRetries:       MaxRetries,
+		RetryDelay:       2,
+		DownloadDir:      "./downloads",
+		RateLimit:        0,
+		UserAgent:        fmt.Sprintf("FastDL/%s", Version),
+		Timeout:          30,
+		ResumeEnabled:    true,
+		VerifyChecksum:   true,
+		DaemonPort:       8080,
+		DatabasePath:     filepath.Join(homeDir, ".config", "fastdl", "fastdl.db"),
+		EnableHTTP2:      true,
+		MaxParallel:      4,
+		TorrentPort:      6881,
+		ConfigPath:       filepath.Join(homeDir, ".config", "fastdl", "config.json"),
+		Headers:          make(map[string]string),
+		DefaultIndexName: "index.html",
+		CircuitCooldown:  60,
+		Preallocate:      "sparse",
+		MinSpeedWindow:   30,
+		EnableFTP:        true,
+		OnConflict:       "overwrite",
+		QueueOrder:       "fifo",
+		ControlSocket:    filepath.Join(homeDir, ".config", "fastdl", "fastdl.sock"),
+		LogFormat:        "pretty",
+		LogMaxBytes:      10 * 1024 * 1024,
+		LogKeep:          3,
+		History:          true,
+		MaxRedirects:     10,
+		HistoryPath:      filepath.Join(homeDir, ".config", "fastdl", "history.db"),
+		DiskMargin:       DiskSpaceMargin,
+		BufferSize:       BufferSize,
+		ProgressFormat:   "text",
 	}
 }
 
@@ -261,851 +1355,10034 @@ func NewRateLimiter(bytesPerSecond int64) *RateLimiter {
 }
 
 func (rl *RateLimiter) Wait(ctx context.Context, bytes int) error {
-	if !rl.enabled {
-		return nil
-	}
 	rl.mu.RLock()
-	defer rl.mu.RUnlock()
-	return rl.limiter.WaitN(ctx, bytes)
+	enabled, limiter := rl.enabled, rl.limiter
+	rl.mu.RUnlock()
+	if enabled {
+		if err := waitN(ctx, limiter, bytes); err != nil {
+			return err
+		}
+	}
+	if rl.parent != nil {
+		return rl.parent.Wait(ctx, bytes)
+	}
+	return nil
+}
+
+// waitN takes n tokens in pieces no larger than the burst, which a read
+// into a big buffer under a low rate would otherwise exceed
+func waitN(ctx context.Context, limiter *rate.Limiter, n int) error {
+	for n > 0 {
+		step := min(n, max(limiter.Burst(), 1))
+		if err := limiter.WaitN(ctx, step); err != nil {
+			return err
+		}
+		n -= step
+	}
+	return nil
+}
+
+// limiterFor returns the task's own limiter when it has a cap
+func (dm *DownloadManager) limiterFor(task *DownloadTask) *RateLimiter {
+	if task.limiter != nil {
+		return task.limiter
+	}
+	return dm.rateLimiter
+}
+
+// Child creates a limiter for one download that is also held to rl
+func (rl *RateLimiter) Child(bytesPerSecond int64) *RateLimiter {
+	child := NewRateLimiter(bytesPerSecond)
+	child.parent = rl
+	return child
 }
 
 func (rl *RateLimiter) SetLimit(bytesPerSecond int64) {
 	rl.mu.Lock()
 	defer rl.mu.Unlock()
 	if bytesPerSecond <= 0 {
+		// Running streams still hold their old share until rebalanced
 		rl.enabled = false
+		rl.rebalance()
 		return
 	}
 	rl.enabled = true
 	rl.maxBytes = bytesPerSecond
+	if rl.limiter == nil {
+		rl.limiter = rate.NewLimiter(rate.Limit(bytesPerSecond), int(bytesPerSecond))
+	}
 	rl.limiter.SetLimit(rate.Limit(bytesPerSecond))
 	rl.limiter.SetBurst(int(bytesPerSecond))
+	rl.rebalance()
 }
 
-// NewProxyManager creates a new proxy manager
-func NewProxyManager(proxyURL string) (*ProxyManager, error) {
-	if proxyURL == "" {
-		return &ProxyManager{enabled: false}, nil
+// Join registers a chunk stream so the budget is split evenly between
+// active chunks instead of going to whoever asks first
+func (rl *RateLimiter) Join() *fairStream {
+	rl.mu.Lock()
+	defer rl.mu.Unlock()
+	if rl.streams == nil {
+		rl.streams = make(map[*fairStream]struct{})
 	}
-	parsed, err := url.Parse(proxyURL)
-	if err != nil {
-		return nil, err
+	stream := &fairStream{
+		parent:  rl,
+		limiter: rate.NewLimiter(rate.Inf, BufferSize),
 	}
-	return &ProxyManager{
-		proxyURL: parsed,
-		enabled:  true,
-	}, nil
+	rl.streams[stream] = struct{}{}
+	rl.rebalance()
+	return stream
 }
 
-func (p *ProxyManager) GetTransport() *http.Transport {
-	transport := &http.Transport{
-		MaxIdleConns:        100,
-		MaxIdleConnsPerHost: 100,
-		IdleConnTimeout:     90 * time.Second,
-		DisableCompression:  true,
-		TLSClientConfig: &tls.Config{
-			InsecureSkipVerify: false,
-		},
+// rebalance recomputes per-stream shares, caller holds rl.mu
+func (rl *RateLimiter) rebalance() {
+	share := rate.Inf
+	burst := BufferSize
+	if rl.enabled && len(rl.streams) > 0 {
+		perStream := rl.maxBytes / int64(len(rl.streams))
+		share = rate.Limit(max(perStream, 1))
+		burst = max(int(perStream), BufferSize)
 	}
-	if p.enabled && p.proxyURL != nil {
-		transport.Proxy = http.ProxyURL(p.proxyURL)
+	for stream := range rl.streams {
+		stream.limiter.SetLimit(share)
+		stream.limiter.SetBurst(burst)
 	}
-	return transport
 }
 
-// NewMirrorManager creates a new mirror manager
-func NewMirrorManager(mirrors []string, maxRetries int) *MirrorManager {
-	return &MirrorManager{
-		mirrors:    mirrors,
-		maxRetries: maxRetries,
+func (s *fairStream) Wait(ctx context.Context, bytes int) error {
+	if err := waitN(ctx, s.limiter, bytes); err != nil {
+		return err
 	}
+	return s.parent.Wait(ctx, bytes)
 }
 
-func (m *MirrorManager) GetNextMirror() (string, bool) {
-	m.mu.Lock()
-	defer m.mu.Unlock()
-	if m.current >= len(m.mirrors) {
-		return "", false
-	}
-	mirror := m.mirrors[m.current]
-	m.current++
-	return mirror, true
+func (s *fairStream) Leave() {
+	s.parent.mu.Lock()
+	defer s.parent.mu.Unlock()
+	delete(s.parent.streams, s)
+	s.parent.rebalance()
 }
 
-// NewDownloadManager creates a new download manager
-func NewDownloadManager(config *Config) (*DownloadManager, error) {
-	proxyManager, err := NewProxyManager(config.ProxyURL)
-	if err != nil {
-		return nil, err
+// NewProxyManager creates a new proxy manager
+func NewProxyManager(proxyURL string, rules []ProxyRule, noProxy []string) (*ProxyManager, error) {
+	p := &ProxyManager{}
+	if proxyURL != "" {
+		parsed, err := parseProxyURL(proxyURL)
+		if err != nil {
+			return nil, err
+		}
+		p.proxyURL = parsed
 	}
 
-	transport := proxyManager.GetTransport()
-	if config.EnableHTTP2 {
-		http2.ConfigureTransport(transport)
+	for _, rule := range rules {
+		route := proxyRoute{pattern: strings.ToLower(rule.Host)}
+		if rule.Proxy != "" && rule.Proxy != "direct" {
+			parsed, err := parseProxyURL(rule.Proxy)
+			if err != nil {
+				return nil, fmt.Errorf("proxy rule for %s: %w", rule.Host, err)
+			}
+			route.proxy = parsed
+		}
+		p.rules = append(p.rules, route)
 	}
 
-	client := &http.Client{
-		Transport: transport,
-		Timeout:   time.Duration(config.Timeout) * time.Second,
+	for _, pattern := range noProxy {
+		pattern = strings.ToLower(strings.TrimSpace(pattern))
+		// curl and wget spell subdomain patterns ".example.com"
+		if strings.HasPrefix(pattern, ".") {
+			pattern = "*" + pattern
+		}
+		if pattern != "" {
+			p.noProxy = append(p.noProxy, pattern)
+		}
 	}
-
-	return &DownloadManager{
-		client:       client,
-		maxWorkers:   config.MaxConnections,
-		downloadDir:  config.DownloadDir,
-		verifyHashes: config.VerifyChecksum,
-		resume:       config.ResumeEnabled,
-		rateLimiter:  NewRateLimiter(config.RateLimit),
-		proxyManager: proxyManager,
-		config:       config,
-	}, nil
+	return p, nil
 }
 
-// GetFileInfo retrieves file information from URL
-func (dm *DownloadManager) GetFileInfo(ctx context.Context, urlStr string) (*DownloadTask, error) {
-	req, err := http.NewRequestWithContext(ctx, "HEAD", urlStr, nil)
+// parseProxyURL accepts http, https and socks5 proxies, with credentials as
+// user:pass@ in the URL. A bare host:port is an HTTP proxy.
+func parseProxyURL(raw string) (*url.URL, error) {
+	if !strings.Contains(raw, "://") {
+		raw = "http://" + raw
+	}
+	parsed, err := url.Parse(raw)
 	if err != nil {
 		return nil, err
 	}
+	switch parsed.Scheme {
+	case "http", "https", "socks5":
+	case "socks5h":
+		// Go's SOCKS5 dialer always lets the proxy resolve names
+		parsed.Scheme = "socks5"
+	default:
+		return nil, fmt.Errorf("unsupported proxy scheme %q", parsed.Scheme)
+	}
+	if parsed.Host == "" {
+		return nil, fmt.Errorf("proxy %q has no host", raw)
+	}
+	return parsed, nil
+}
 
-	req.Header.Set("User-Agent", dm.config.UserAgent)
-	for k, v := range dm.config.Headers {
-		req.Header.Set(k, v)
+// proxyFor picks the first matching rule, falling back to proxy_url and
+// then the HTTP_PROXY, HTTPS_PROXY and NO_PROXY environment variables
+func (p *ProxyManager) proxyFor(req *http.Request) (*url.URL, error) {
+	host := strings.ToLower(req.URL.Hostname())
+	for _, pattern := range p.noProxy {
+		if matchHost(pattern, host) {
+			return nil, nil
+		}
+	}
+	for _, route := range p.rules {
+		if matchHost(route.pattern, host) {
+			return route.proxy, nil
+		}
+	}
+	if p.proxyURL != nil {
+		return p.proxyURL, nil
 	}
+	return http.ProxyFromEnvironment(req)
+}
 
-	resp, err := dm.client.Do(req)
-	if err != nil {
-		return nil, err
+// matchHost matches exact names and "*.domain" wildcards, which also cover
+// the bare domain
+func matchHost(pattern, host string) bool {
+	if suffix, ok := strings.CutPrefix(pattern, "*."); ok {
+		return host == suffix || strings.HasSuffix(host, "."+suffix)
 	}
-	defer resp.Body.Close()
+	return host == pattern
+}
 
-	if resp.StatusCode != http.StatusOK && resp.StatusCode != http.StatusPartialContent {
-		return nil, fmt.Errorf("server returned %d", resp.StatusCode)
+// parseProxyRules reads "host=proxy,host=proxy" as used on the command line
+func parseProxyRules(value string) ([]ProxyRule, error) {
+	var rules []ProxyRule
+	for _, item := range strings.Split(value, ",") {
+		item = strings.TrimSpace(item)
+		if item == "" {
+			continue
+		}
+		host, proxy, ok := strings.Cut(item, "=")
+		if !ok {
+			return nil, fmt.Errorf("invalid proxy rule %q, want host=proxy", item)
+		}
+		rules = append(rules, ProxyRule{Host: strings.TrimSpace(host), Proxy: strings.TrimSpace(proxy)})
 	}
+	return rules, nil
+}
 
-	task := &DownloadTask{
-		URL:       urlStr,
-		StartTime: time.Now(),
-		Headers:   dm.config.Headers,
+func (p *ProxyManager) GetTransport() *http.Transport {
+	transport := &http.Transport{
+		MaxIdleConns:        100,
+		MaxIdleConnsPerHost: 100,
+		IdleConnTimeout:     90 * time.Second,
+		DisableCompression:  true,
+		TLSClientConfig: &tls.Config{
+			InsecureSkipVerify: false,
+		},
 	}
+	transport.Proxy = p.proxyFor
+	return transport
+}
 
-	if contentLength := resp.Header.Get("Content-Length"); contentLength != "" {
-		task.Size, _ = strconv.ParseInt(contentLength, 10, 64)
+// newDNSCache creates a resolver cache; a zero ttl only applies the timeout
+// and a zero maxInFlight leaves concurrent lookups unbounded
+func newDNSCache(timeout, ttl time.Duration, maxInFlight int) *dnsCache {
+	c := &dnsCache{
+		resolver: net.DefaultResolver,
+		dialer:   &net.Dialer{Timeout: 30 * time.Second, KeepAlive: 30 * time.Second},
+		timeout:  timeout,
+		ttl:      ttl,
+		entries:  make(map[string]dnsEntry),
 	}
+	if maxInFlight > 0 {
+		c.slots = make(chan struct{}, maxInFlight)
+	}
+	return c
+}
 
-	if acceptRanges := resp.Header.Get("Accept-Ranges"); acceptRanges == "bytes" {
-		task.SupportsRange = true
+func (c *dnsCache) Lookup(ctx context.Context, host string) ([]string, error) {
+	c.mu.Lock()
+	if entry, ok := c.entries[host]; ok && time.Now().Before(entry.expires) {
+		c.mu.Unlock()
+		return entry.addrs, entry.err
 	}
+	c.mu.Unlock()
 
-	if task.Filepath == "" {
-		parsedURL, _ := url.Parse(urlStr)
-		task.Filepath = path.Base(parsedURL.Path)
-		if task.Filepath == "" || task.Filepath == "/" {
-			task.Filepath = fmt.Sprintf("download_%d", time.Now().Unix())
+	// Wait for a resolver slot; time spent queued doesn't count against the timeout
+	if c.slots != nil {
+		select {
+		case c.slots <- struct{}{}:
+			defer func() { <-c.slots }()
+		case <-ctx.Done():
+			return nil, ctx.Err()
 		}
 	}
 
-	return task, nil
-}
-
-// Download performs the main download operation
-func (dm *DownloadManager) Download(ctx context.Context, task *DownloadTask) error {
-	info, err := dm.GetFileInfo(ctx, task.URL)
-	if err != nil {
-		return fmt.Errorf("failed to get file info: %w", err)
+	lookupCtx := ctx
+	if c.timeout > 0 {
+		var cancel context.CancelFunc
+		lookupCtx, cancel = context.WithTimeout(ctx, c.timeout)
+		defer cancel()
 	}
 
-	if task.Size == 0 {
-		task.Size = info.Size
+	addrs, err := c.resolver.LookupHost(lookupCtx, host)
+	if err != nil && ctx.Err() != nil {
+		// Caller gave up, not the resolver's fault
+		return nil, err
+	}
+	var dnsErr *net.DNSError
+	if err != nil && (lookupCtx.Err() == context.DeadlineExceeded || (errors.As(err, &dnsErr) && dnsErr.IsTimeout)) {
+		// Keep resolver timeouts apart from connect timeouts in retry output
+		err = fmt.Errorf("%w: %s: %v", errDNSTimeout, host, err)
 	}
-	task.SupportsRange = info.SupportsRange
 
-	outputPath := filepath.Join(dm.downloadDir, task.Filepath)
-	if err := os.MkdirAll(filepath.Dir(outputPath), 0755); err != nil {
-		return fmt.Errorf("failed to create directory: %w", err)
+	ttl := c.ttl
+	if err != nil {
+		ttl = min(c.ttl, DNSNegativeTTL)
+	}
+	if ttl > 0 {
+		c.mu.Lock()
+		c.entries[host] = dnsEntry{addrs: addrs, err: err, expires: time.Now().Add(ttl)}
+		c.mu.Unlock()
 	}
 
-	fmt.Printf("%sDownloading:%s %s\n", ColorGreen, ColorReset, task.URL)
-	fmt.Printf("%sOutput:%s %s\n", ColorCyan, ColorReset, outputPath)
-	fmt.Printf("%sSize:%s %s\n", ColorCyan, ColorReset, formatBytes(task.Size))
-	fmt.Printf("%sRange Support:%s %v\n", ColorCyan, ColorReset, task.SupportsRange)
-	fmt.Printf("%sConnections:%s %d\n\n", ColorCyan, ColorReset, task.Chunks)
+	return addrs, err
+}
 
-	progress := &ProgressInfo{Total: task.Size}
-	progressDone := make(chan bool)
-	go dm.reportProgress(ctx, task, progress, progressDone)
+// DialContext resolves through the cache and tries each address in turn
+func (c *dnsCache) DialContext(ctx context.Context, network, addr string) (net.Conn, error) {
+	dialer := c.dialer
 
-	var downloadErr error
-	
-	if task.SupportsRange && task.Chunks > 1 && task.Size > 0 {
-		downloadErr = dm.downloadParallel(ctx, task, outputPath, progress)
-	} else {
-		downloadErr = dm.downloadSingle(ctx, task, outputPath, progress)
+	host, port, err := net.SplitHostPort(addr)
+	if err != nil || net.ParseIP(host) != nil {
+		return dialer.DialContext(ctx, network, addr)
 	}
 
-	close(progressDone)
-	
-	if downloadErr != nil {
-		return downloadErr
+	addrs, err := c.Lookup(ctx, host)
+	if err != nil {
+		return nil, err
 	}
 
-	// Verify checksums
-	if dm.verifyHashes {
-		if err := dm.verifyChecksums(outputPath, task); err != nil {
-			return err
+	var lastErr error
+	for _, ip := range addrs {
+		conn, err := dialer.DialContext(ctx, network, net.JoinHostPort(ip, port))
+		if err == nil {
+			return conn, nil
 		}
+		lastErr = err
 	}
-
-	duration := time.Since(task.StartTime)
-	avgSpeed := float64(task.Size) / duration.Seconds() / 1024 / 1024
-	fmt.Printf("\n%s✓ Download completed in %s (avg %.2f MB/s)%s\n", 
-		ColorGreen, duration.Round(time.Second), avgSpeed, ColorReset)
-
-	return nil
+	if lastErr == nil {
+		lastErr = fmt.Errorf("no addresses for %s", host)
+	}
+	return nil, lastErr
 }
 
-// downloadParallel handles multi-threaded downloads
-func (dm *DownloadManager) downloadParallel(ctx context.Context, task *DownloadTask, outputPath string, progress *ProgressInfo) error {
-	tempFile, err := os.Create(outputPath + ".tmp")
-	if err != nil {
-		return err
+// connectTimeout bounds dialing and TLS handshakes; timeout_seconds stands
+// in when connect_timeout_seconds isn't set
+func (c *Config) connectTimeout() time.Duration {
+	switch {
+	case c.ConnectTimeout > 0:
+		return time.Duration(c.ConnectTimeout) * time.Second
+	case c.Timeout > 0:
+		return time.Duration(c.Timeout) * time.Second
 	}
-	defer os.Remove(outputPath + ".tmp")
+	return 30 * time.Second
+}
 
-	if err := tempFile.Truncate(task.Size); err != nil {
-		tempFile.Close()
-		return err
+// tlsConfig builds the client TLS settings shared by HTTPS and FTPS
+func (c *Config) tlsConfig() (*tls.Config, error) {
+	config := &tls.Config{InsecureSkipVerify: c.Insecure}
+	if c.Insecure {
+		logger.Warn("TLS certificate verification is disabled")
+	}
+
+	switch c.MinTLSVersion {
+	case "":
+	case "1.0":
+		config.MinVersion = tls.VersionTLS10
+	case "1.1":
+		config.MinVersion = tls.VersionTLS11
+	case "1.2":
+		config.MinVersion = tls.VersionTLS12
+	case "1.3":
+		config.MinVersion = tls.VersionTLS13
+	default:
+		return nil, fmt.Errorf("invalid min_tls_version %q, want 1.0, 1.1, 1.2 or 1.3", c.MinTLSVersion)
 	}
-	tempFile.Close()
 
-	chunkSize := task.Size / int64(task.Chunks)
-	chunks := make([]ChunkInfo, task.Chunks)
-	
-	for i := 0; i < task.Chunks; i++ {
-		chunks[i] = ChunkInfo{
-			ID:    i,
-			Start: int64(i) * chunkSize,
-			Path:  fmt.Sprintf("%s.part%d", outputPath, i),
+	// Extra CAs are trusted alongside the system ones, so a private
+	// artifact server and public mirrors work in the same run
+	if c.CACert != "" {
+		data, err := os.ReadFile(c.CACert)
+		if err != nil {
+			return nil, fmt.Errorf("failed to read CA bundle: %w", err)
 		}
-		
-		if i == task.Chunks-1 {
-			chunks[i].End = task.Size - 1
-		} else {
-			chunks[i].End = chunks[i].Start + chunkSize - 1
+		pool, err := x509.SystemCertPool()
+		if err != nil {
+			pool = x509.NewCertPool()
+		}
+		if !pool.AppendCertsFromPEM(data) {
+			return nil, fmt.Errorf("no PEM certificates in %s", c.CACert)
 		}
+		config.RootCAs = pool
 	}
 
-	var wg sync.WaitGroup
-	chunkChan := make(chan ChunkInfo, len(chunks))
-	errorChan := make(chan error, len(chunks))
-	
-	for i := 0; i < dm.maxWorkers && i < task.Chunks; i++ {
-		wg.Add(1)
-		go dm.downloadWorker(ctx, &wg, task, chunkChan, errorChan, progress)
+	if c.ClientCert != "" {
+		key := c.ClientKey
+		if key == "" {
+			key = c.ClientCert
+		}
+		cert, err := tls.LoadX509KeyPair(c.ClientCert, key)
+		if err != nil {
+			return nil, fmt.Errorf("failed to load client certificate: %w", err)
+		}
+		config.Certificates = []tls.Certificate{cert}
+	} else if c.ClientKey != "" {
+		return nil, errors.New("client_key needs client_cert")
 	}
+	return config, nil
+}
 
-	for _, chunk := range chunks {
-		chunkChan <- chunk
-	}
-	close(chunkChan)
+// idleConn fails a Read that waits longer than timeout for data, which
+// catches a connection gone silent mid-transfer. Pooled connections idle
+// for longer are closed rather than reused.
+type idleConn struct {
+	net.Conn
+	timeout time.Duration
+}
 
-	wg.Wait()
-	close(errorChan)
+func (c *idleConn) Read(p []byte) (int, error) {
+	c.Conn.SetReadDeadline(time.Now().Add(c.timeout))
+	return c.Conn.Read(p)
+}
 
-	for err := range errorChan {
+// idleTimeoutDial wraps every connection dial returns in an idleConn
+func idleTimeoutDial(dial func(context.Context, string, string) (net.Conn, error), timeout time.Duration) func(context.Context, string, string) (net.Conn, error) {
+	return func(ctx context.Context, network, addr string) (net.Conn, error) {
+		conn, err := dial(ctx, network, addr)
 		if err != nil {
-			return err
+			return nil, err
 		}
+		return &idleConn{Conn: conn, timeout: timeout}, nil
 	}
-
-	return dm.mergeChunks(outputPath, chunks)
 }
 
-// downloadWorker handles individual chunk downloads
-func (dm *DownloadManager) downloadWorker(ctx context.Context, wg *sync.WaitGroup, task *DownloadTask, chunks <-chan ChunkInfo, errors chan<- error, progress *ProgressInfo) {
-	defer wg.Done()
+// NewCircuitBreaker opens a host's circuit after threshold straight failures
+func NewCircuitBreaker(threshold int, cooldown time.Duration) *CircuitBreaker {
+	return &CircuitBreaker{
+		threshold: threshold,
+		cooldown:  cooldown,
+		hosts:     make(map[string]*hostCircuit),
+	}
+}
 
-	for chunk := range chunks {
-		atomic.AddInt32(&progress.Active, 1)
-		
-		for retry := 0; retry < dm.config.MaxRetries; retry++ {
-			if err := dm.downloadChunk(ctx, task.URL, chunk, progress, task.Headers); err == nil {
-				break
-			} else if retry == dm.config.MaxRetries-1 {
-				errors <- fmt.Errorf("chunk %d failed after %d retries: %w", chunk.ID, dm.config.MaxRetries, err)
-				atomic.AddInt32(&progress.Active, -1)
-				return
-			}
-			time.Sleep(time.Duration(dm.config.RetryDelay) * time.Second)
+// Allow returns errCircuitOpen while the host is cooling down. The caller
+// must call release once its request is over: if it was the half-open probe
+// and ended without a Record, say because it was cancelled, that lets the
+// next caller probe instead of the circuit staying open for good.
+func (cb *CircuitBreaker) Allow(host string) (release func(), err error) {
+	cb.mu.Lock()
+	defer cb.mu.Unlock()
+
+	c, ok := cb.hosts[host]
+	if !ok || c.failures < cb.threshold {
+		return func() {}, nil
+	}
+	if time.Now().Before(c.openUntil) || c.probing {
+		return nil, fmt.Errorf("%w for %s", errCircuitOpen, host)
+	}
+
+	// Half-open: this caller is the probe
+	c.probing = true
+	c.probes++
+	probe := c.probes
+	return func() {
+		cb.mu.Lock()
+		defer cb.mu.Unlock()
+		if c.probes == probe {
+			c.probing = false
 		}
-		
-		atomic.AddInt32(&progress.Active, -1)
+	}, nil
+}
+
+// Check reports errCircuitOpen while the host is cooling down, without
+// claiming the half-open probe; retries use it to stop early
+func (cb *CircuitBreaker) Check(host string) error {
+	if cb == nil {
+		return nil
+	}
+	cb.mu.Lock()
+	defer cb.mu.Unlock()
+	if c, ok := cb.hosts[host]; ok && c.failures >= cb.threshold && time.Now().Before(c.openUntil) {
+		return fmt.Errorf("%w for %s", errCircuitOpen, host)
 	}
+	return nil
 }
 
-// downloadChunk downloads a single chunk
-func (dm *DownloadManager) downloadChunk(ctx context.Context, urlStr string, chunk ChunkInfo, progress *ProgressInfo, headers map[string]string) error {
-	if dm.resume {
-		if stat, err := os.Stat(chunk.Path); err == nil {
-			if stat.Size() == chunk.End-chunk.Start+1 {
-				atomic.AddInt64(&progress.Downloaded, stat.Size())
-				return nil
-			}
-		}
+func (cb *CircuitBreaker) Record(host string, err error) {
+	if cb == nil {
+		return
 	}
+	cb.mu.Lock()
+	defer cb.mu.Unlock()
 
-	req, err := http.NewRequestWithContext(ctx, "GET", urlStr, nil)
-	if err != nil {
-		return err
+	c, ok := cb.hosts[host]
+	if !ok {
+		c = &hostCircuit{}
+		cb.hosts[host] = c
 	}
+	c.probing = false
 
-	req.Header.Set("Range", fmt.Sprintf("bytes=%d-%d", chunk.Start, chunk.End))
-	req.Header.Set("User-Agent", dm.config.UserAgent)
-	for k, v := range headers {
-		req.Header.Set(k, v)
+	if err == nil {
+		c.failures = 0
+		return
+	}
+	c.failures++
+	if c.failures >= cb.threshold {
+		c.openUntil = time.Now().Add(cb.cooldown)
 	}
+}
 
-	resp, err := dm.client.Do(req)
-	if err != nil {
-		return err
+// Spend takes one retry from host's budget and reports whether there was
+// one left
+func (b *retryBudget) Spend(host string) bool {
+	if b == nil {
+		return true
 	}
-	defer resp.Body.Close()
+	b.mu.Lock()
+	limiter, ok := b.hosts[host]
+	if !ok {
+		limiter = rate.NewLimiter(rate.Every(time.Minute/time.Duration(b.perMinute)), b.perMinute)
+		b.hosts[host] = limiter
+	}
+	b.mu.Unlock()
+	return limiter.Allow()
+}
 
-	if resp.StatusCode != http.StatusPartialContent && resp.StatusCode != http.StatusOK {
-		return fmt.Errorf("server returned %d", resp.StatusCode)
+// NewTracer exports to endpoint, appending /v1/traces when missing
+func NewTracer(endpoint string) *Tracer {
+	if !strings.HasSuffix(endpoint, "/v1/traces") {
+		endpoint = strings.TrimSuffix(endpoint, "/") + "/v1/traces"
 	}
+	return &Tracer{
+		endpoint: endpoint,
+		client:   &http.Client{Timeout: 5 * time.Second},
+	}
+}
 
-	file, err := os.Create(chunk.Path)
-	if err != nil {
-		return err
+// Start opens a span under parent, or a new trace when parent is nil
+func (t *Tracer) Start(name string, parent *Span) *Span {
+	if t == nil {
+		return nil
 	}
-	defer file.Close()
+	span := &Span{
+		tracer: t,
+		data: otlpSpan{
+			TraceID:           fmt.Sprintf("%016x%016x", rand.Uint64(), rand.Uint64()),
+			SpanID:            fmt.Sprintf("%016x", rand.Uint64()),
+			Name:              name,
+			Kind:              3, // client
+			StartTimeUnixNano: strconv.FormatInt(time.Now().UnixNano(), 10),
+		},
+	}
+	if parent != nil {
+		span.data.TraceID = parent.data.TraceID
+		span.data.ParentSpanID = parent.data.SpanID
+	}
+	return span
+}
 
-	buffer := make([]byte, BufferSize)
-	for {
-		n, err := resp.Body.Read(buffer)
-		if n > 0 {
-			if dm.rateLimiter != nil {
-				dm.rateLimiter.Wait(ctx, n)
-			}
-			if _, writeErr := file.Write(buffer[:n]); writeErr != nil {
-				return writeErr
-			}
-			atomic.AddInt64(&progress.Downloaded, int64(n))
-		}
-		if err == io.EOF {
-			break
-		}
-		if err != nil {
-			return err
-		}
+func (s *Span) SetString(key, value string) {
+	if s == nil {
+		return
 	}
+	s.data.Attributes = append(s.data.Attributes, otlpAttribute{Key: key, Value: otlpValue{StringValue: &value}})
+}
 
-	return nil
+func (s *Span) SetInt(key string, value int64) {
+	if s == nil {
+		return
+	}
+	str := strconv.FormatInt(value, 10)
+	s.data.Attributes = append(s.data.Attributes, otlpAttribute{Key: key, Value: otlpValue{IntValue: &str}})
 }
 
-// mergeChunks combines all chunks into final file
-func (dm *DownloadManager) mergeChunks(outputPath string, chunks []ChunkInfo) error {
-	output, err := os.Create(outputPath)
-	if err != nil {
-		return err
+// otlpEndpoint is otlp_endpoint, or the standard OpenTelemetry variables
+// when it isn't set
+func otlpEndpoint(config *Config) string {
+	if config.OTLPEndpoint != "" {
+		return config.OTLPEndpoint
 	}
-	defer output.Close()
+	if endpoint := os.Getenv("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"); endpoint != "" {
+		return endpoint
+	}
+	if endpoint := os.Getenv("OTEL_EXPORTER_OTLP_ENDPOINT"); endpoint != "" {
+		return strings.TrimSuffix(endpoint, "/") + "/v1/traces"
+	}
+	return ""
+}
 
-	for _, chunk := range chunks {
-		input, err := os.Open(chunk.Path)
-		if err != nil {
-			return err
-		}
+// withSpan puts span in ctx for recordResponse, and records the address of
+// the connection each request goes out on
+func withSpan(ctx context.Context, span *Span) context.Context {
+	if span == nil {
+		return ctx
+	}
+	ctx = context.WithValue(ctx, spanKey{}, span)
+	return httptrace.WithClientTrace(ctx, &httptrace.ClientTrace{
+		GotConn: func(info httptrace.GotConnInfo) {
+			span.SetString("net.peer.addr", info.Conn.RemoteAddr().String())
+			if info.Reused {
+				span.SetString("http.conn_reused", "true")
+			}
+		},
+	})
+}
 
-		if _, err := io.Copy(output, input); err != nil {
-			input.Close()
-			return err
+// recordResponse adds resp's status and CDN headers to the span in ctx
+func recordResponse(ctx context.Context, resp *http.Response) {
+	span, _ := ctx.Value(spanKey{}).(*Span)
+	if span == nil {
+		return
+	}
+	span.SetInt("http.status_code", int64(resp.StatusCode))
+	for _, name := range cdnHeaders {
+		if value := resp.Header.Get(name); value != "" {
+			span.SetString("http.response.header."+strings.ToLower(name), value)
 		}
-		
-		input.Close()
-		os.Remove(chunk.Path)
 	}
-
-	return nil
 }
 
-// downloadSingle handles single-threaded downloads
-func (dm *DownloadManager) downloadSingle(ctx context.Context, task *DownloadTask, outputPath string, progress *ProgressInfo) error {
-	req, err := http.NewRequestWithContext(ctx, "GET", task.URL, nil)
+// End records the outcome; ending a root span exports everything pending
+func (s *Span) End(err error) {
+	if s == nil {
+		return
+	}
+	s.data.EndTimeUnixNano = strconv.FormatInt(time.Now().UnixNano(), 10)
+	s.data.Status = otlpStatus{Code: 1}
 	if err != nil {
-		return err
+		s.data.Status = otlpStatus{Code: 2, Message: err.Error()}
 	}
 
-	req.Header.Set("User-Agent", dm.config.UserAgent)
-	for k, v := range task.Headers {
-		req.Header.Set(k, v)
+	t := s.tracer
+	t.mu.Lock()
+	t.pending = append(t.pending, s.data)
+	t.mu.Unlock()
+
+	if s.data.ParentSpanID == "" {
+		t.flush()
 	}
+}
 
-	resp, err := dm.client.Do(req)
-	if err != nil {
-		return err
+func (t *Tracer) flush() {
+	t.mu.Lock()
+	spans := t.pending
+	t.pending = nil
+	t.mu.Unlock()
+
+	if len(spans) == 0 {
+		return
 	}
-	defer resp.Body.Close()
 
-	if resp.StatusCode != http.StatusOK {
-		return fmt.Errorf("server returned %d", resp.StatusCode)
+	service := "fastdl"
+	payload := map[string]interface{}{
+		"resourceSpans": []interface{}{
+			map[string]interface{}{
+				"resource": map[string]interface{}{
+					"attributes": []otlpAttribute{{Key: "service.name", Value: otlpValue{StringValue: &service}}},
+				},
+				"scopeSpans": []interface{}{
+					map[string]interface{}{
+						"scope": map[string]string{"name": "fastdl", "version": Version},
+						"spans": spans,
+					},
+				},
+			},
+		},
 	}
 
-	file, err := os.Create(outputPath)
+	body, err := json.Marshal(payload)
 	if err != nil {
-		return err
+		return
 	}
-	defer file.Close()
-
-	buffer := make([]byte, BufferSize)
-	for {
-		n, err := resp.Body.Read(buffer)
-		if n > 0 {
-			if dm.rateLimiter != nil {
-				dm.rateLimiter.Wait(ctx, n)
-			}
-			if _, writeErr := file.Write(buffer[:n]); writeErr != nil {
-				return writeErr
-			}
-			atomic.AddInt64(&progress.Downloaded, int64(n))
-		}
-		if err == io.EOF {
-			break
+	resp, err := t.client.Post(t.endpoint, "application/json", bytes.NewReader(body))
+	if err != nil {
+		logger.Warn("trace export failed", "err", err)
+		return
+	}
+	resp.Body.Close()
+}
+
+// setupLogging points logger at log_file or stderr, in log_format, from
+// log_level up. -v without a log_level means debug.
+func setupLogging(config *Config) error {
+	level := slog.LevelWarn
+	if config.LogLevel != "" {
+		if err := level.UnmarshalText([]byte(config.LogLevel)); err != nil {
+			return fmt.Errorf("invalid log level %q (want debug, info, warn or error)", config.LogLevel)
 		}
+	} else if config.Verbose {
+		level = slog.LevelDebug
+	}
+
+	var out io.Writer = os.Stderr
+	color := terminal.IsTerminal(int(os.Stderr.Fd()))
+	if config.LogFile != "" {
+		file, err := openRotatingFile(config.LogFile, config.LogMaxBytes, config.LogKeep)
 		if err != nil {
 			return err
 		}
+		out, color = file, false
 	}
 
+	switch config.LogFormat {
+	case "", "pretty":
+		logger = slog.New(newPrettyHandler(out, level, color))
+	case "json":
+		logger = slog.New(slog.NewJSONHandler(out, &slog.HandlerOptions{Level: level}))
+	default:
+		return fmt.Errorf("invalid log format %q (want pretty or json)", config.LogFormat)
+	}
 	return nil
 }
 
-// reportProgress displays download progress
-func (dm *DownloadManager) reportProgress(ctx context.Context, task *DownloadTask, progress *ProgressInfo, done <-chan bool) {
-	ticker := time.NewTicker(ProgressUpdate)
-	defer ticker.Stop()
-
-	lastDownloaded := int64(0)
-	lastTime := time.Now()
-
-	for {
-		select {
-		case <-ctx.Done():
-			return
-		case <-done:
-			return
-		case <-ticker.C:
-			downloaded := atomic.LoadInt64(&progress.Downloaded)
-			now := time.Now()
-			elapsed := now.Sub(lastTime).Seconds()
-			
-			if elapsed > 0 {
-				speed := float64(downloaded-lastDownloaded) / elapsed / 1024 / 1024
-				percentage := float64(downloaded) / float64(progress.Total) * 100
-				
-				if speed > 0 {
-					remaining := progress.Total - downloaded
-					eta := time.Duration(float64(remaining) / (float64(downloaded-lastDownloaded) / elapsed)) * time.Second
-					progress.ETA = eta
-				}
+func newPrettyHandler(out io.Writer, level slog.Leveler, color bool) *prettyHandler {
+	return &prettyHandler{out: out, mu: &sync.Mutex{}, level: level, color: color}
+}
 
-				active := atomic.LoadInt32(&progress.Active)
-				
-				// Progress bar
-				barWidth := 40
-				filled := int(percentage * float64(barWidth) / 100)
-				bar := strings.Repeat("█", filled) + strings.Repeat("░", barWidth-filled)
-				
-				fmt.Printf("\r%s[%s] %.1f%% %s/%s | %.2f MB/s | %d active | ETA: %s%s",
-					ColorCyan, bar, percentage,
-					formatBytes(downloaded),
-					formatBytes(progress.Total),
-					speed,
-					active,
-					formatDuration(progress.ETA),
-					ColorReset)
-				
-				lastDownloaded = downloaded
-				lastTime = now
-			}
-		}
-	}
+func (h *prettyHandler) Enabled(_ context.Context, level slog.Level) bool {
+	return level >= h.level.Level()
 }
 
-// verifyChecksums verifies file checksums
-func (dm *DownloadManager) verifyChecksums(filepath string, task *DownloadTask) error {
-	if task.SHA256 != "" {
-		fmt.Printf("\n%sVerifying SHA256...%s", ColorYellow, ColorReset)
-		hash, err := calculateHash(filepath, "sha256")
-		if err != nil {
-			return err
-		}
-		if !strings.EqualFold(hash, task.SHA256) {
-			return fmt.Errorf("SHA256 mismatch: expected %s, got %s", task.SHA256, hash)
+func (h *prettyHandler) Handle(_ context.Context, r slog.Record) error {
+	var b strings.Builder
+	level := fmt.Sprintf("%-5s", r.Level)
+	if h.color {
+		// Start on a clean line in case a progress bar is being drawn
+		b.WriteString("\r\033[K")
+		switch {
+		case r.Level >= slog.LevelError:
+			level = ColorRed + level + ColorReset
+		case r.Level >= slog.LevelWarn:
+			level = ColorYellow + level + ColorReset
+		case r.Level >= slog.LevelInfo:
+			level = ColorGreen + level + ColorReset
+		default:
+			level = ColorCyan + level + ColorReset
 		}
-		fmt.Printf(" %s✓%s\n", ColorGreen, ColorReset)
 	}
+	fmt.Fprintf(&b, "%s %s %s", r.Time.Format("15:04:05"), level, r.Message)
+	b.WriteString(h.attrs)
+	r.Attrs(func(a slog.Attr) bool {
+		appendLogAttr(&b, h.group, a)
+		return true
+	})
+	b.WriteByte('\n')
 
-	if task.SHA1 != "" {
-		fmt.Printf("%sVerifying SHA1...%s", ColorYellow, ColorReset)
-		hash, err := calculateHash(filepath, "sha1")
-		if err != nil {
-			return err
-		}
-		if !strings.EqualFold(hash, task.SHA1) {
-			return fmt.Errorf("SHA1 mismatch: expected %s, got %s", task.SHA1, hash)
-		}
-		fmt.Printf(" %s✓%s\n", ColorGreen, ColorReset)
-	}
+	h.mu.Lock()
+	defer h.mu.Unlock()
+	_, err := io.WriteString(h.out, b.String())
+	return err
+}
 
-	if task.MD5 != "" {
-		fmt.Printf("%sVerifying MD5...%s", ColorYellow, ColorReset)
-		hash, err := calculateHash(filepath, "md5")
-		if err != nil {
-			return err
-		}
-		if !strings.EqualFold(hash, task.MD5) {
-			return fmt.Errorf("MD5 mismatch: expected %s, got %s", task.MD5, hash)
-		}
-		fmt.Printf(" %s✓%s\n", ColorGreen, ColorReset)
+func (h *prettyHandler) WithAttrs(attrs []slog.Attr) slog.Handler {
+	var b strings.Builder
+	b.WriteString(h.attrs)
+	for _, a := range attrs {
+		appendLogAttr(&b, h.group, a)
 	}
+	clone := *h
+	clone.attrs = b.String()
+	return &clone
+}
 
-	return nil
+func (h *prettyHandler) WithGroup(name string) slog.Handler {
+	clone := *h
+	clone.group += name + "."
+	return &clone
 }
 
-// calculateHash calculates file hash
-func calculateHash(filepath string, algorithm string) (string, error) {
-	file, err := os.Open(filepath)
-	if err != nil {
-		return "", err
+// appendLogAttr writes " key=value", quoting values that need it
+func appendLogAttr(b *strings.Builder, prefix string, a slog.Attr) {
+	a.Value = a.Value.Resolve()
+	if a.Equal(slog.Attr{}) {
+		return
 	}
-	defer file.Close()
-
-	var h hash.Hash
-	switch algorithm {
-	case "sha256":
-		h = sha256.New()
-	case "sha1":
-		h = sha1.New()
-	case "md5":
-		h = md5.New()
-	default:
-		return "", fmt.Errorf("unsupported hash algorithm: %s", algorithm)
+	if a.Value.Kind() == slog.KindGroup {
+		for _, member := range a.Value.Group() {
+			appendLogAttr(b, prefix+a.Key+".", member)
+		}
+		return
 	}
-
-	if _, err := io.Copy(h, file); err != nil {
-		return "", err
+	value := a.Value.String()
+	if value == "" || strings.ContainsAny(value, " \"=\n") {
+		value = strconv.Quote(value)
 	}
+	fmt.Fprintf(b, " %s%s=%s", prefix, a.Key, value)
+}
 
-	return hex.EncodeToString(h.Sum(nil)), nil
+func openRotatingFile(path string, maxSize int64, keep int) (*rotatingFile, error) {
+	r := &rotatingFile{path: path, maxSize: maxSize, keep: keep}
+	if err := os.MkdirAll(filepath.Dir(path), 0755); err != nil {
+		return nil, err
+	}
+	return r, r.open()
 }
 
-// BatchDownload handles multiple downloads
-func (dm *DownloadManager) BatchDownload(ctx context.Context, urlFile string, concurrent int) error {
-	file, err := os.Open(urlFile)
+func (r *rotatingFile) open() error {
+	file, err := os.OpenFile(r.path, os.O_CREATE|os.O_WRONLY|os.O_APPEND, 0644)
 	if err != nil {
 		return err
 	}
-	defer file.Close()
-
-	var tasks []DownloadTask
-	scanner := bufio.NewScanner(file)
-	for scanner.Scan() {
-		line := strings.TrimSpace(scanner.Text())
-		if line == "" || strings.HasPrefix(line, "#") {
-			continue
-		}
-
-		parts := strings.Fields(line)
-		task := DownloadTask{
-			URL:    parts[0],
-			Chunks: dm.maxWorkers,
-		}
+	info, err := file.Stat()
+	if err != nil {
+		file.Close()
+		return err
+	}
+	r.file, r.size = file, info.Size()
+	return nil
+}
 
-		for i := 1; i < len(parts); i++ {
-			if strings.HasPrefix(parts[i], "sha256:") {
-				task.SHA256 = strings.TrimPrefix(parts[i], "sha256:")
-			} else if strings.HasPrefix(parts[i], "sha1:") {
-				task.SHA1 = strings.TrimPrefix(parts[i], "sha1:")
-			} else if strings.HasPrefix(parts[i], "md5:") {
-				task.MD5 = strings.TrimPrefix(parts[i], "md5:")
-			}
+func (r *rotatingFile) Write(p []byte) (int, error) {
+	r.mu.Lock()
+	defer r.mu.Unlock()
+	if r.maxSize > 0 && r.size > 0 && r.size+int64(len(p)) > r.maxSize {
+		if err := r.rotate(); err != nil {
+			return 0, err
 		}
-
-		tasks = append(tasks, task)
 	}
+	n, err := r.file.Write(p)
+	r.size += int64(n)
+	return n, err
+}
 
-	fmt.Printf("%sFound %d URLs to download%s\n\n", ColorCyan, len(tasks), ColorReset)
-
-	sem := make(chan struct{}, concurrent)
-	var wg sync.WaitGroup
-	
-	for i, task := range tasks {
-		wg.Add(1)
-		go func(index int, t DownloadTask) {
-			defer wg.Done()
-			
-			sem <- struct{}{}
-			defer func() { <-sem }()
-			
-			fmt.Printf("%s[%d/%d] Downloading %s%s\n", ColorBlue, index+1, len(tasks), t.URL, ColorReset)
-			
-			if err := dm.Download(ctx, &t); err != nil {
-				fmt.Printf("%s[%d/%d] Failed: %v%s\n", ColorRed, index+1, len(tasks), err, ColorReset)
-			} else {
-				fmt.Printf("%s[%d/%d] Completed%s\n", ColorGreen, index+1, len(tasks), ColorReset)
-			}
-		}(i, task)
+func (r *rotatingFile) rotate() error {
+	r.file.Close()
+	for i := r.keep - 1; i > 0; i-- {
+		os.Rename(fmt.Sprintf("%s.%d", r.path, i), fmt.Sprintf("%s.%d", r.path, i+1))
 	}
-
-	wg.Wait()
-	return nil
+	if r.keep > 0 {
+		os.Rename(r.path, r.path+".1")
+	} else {
+		os.Remove(r.path)
+	}
+	return r.open()
 }
 
-// NewJobQueue creates a new job queue
-func NewJobQueue(maxActive int, dbPath string) (*JobQueue, error) {
-	// Create directory if it doesn't exist
-	dbDir := filepath.Dir(dbPath)
-	if err := os.MkdirAll(dbDir, 0755); err != nil {
+// OpenHistory opens, or creates, the history database at path
+func OpenHistory(path, dir string) (*History, error) {
+	if err := os.MkdirAll(filepath.Dir(path), 0755); err != nil {
 		return nil, err
 	}
-
-	db, err := sql.Open("sqlite3", dbPath)
+	// Batches and the daemon may write at the same time
+	db, err := sql.Open("sqlite3", path+"?_busy_timeout=5000")
 	if err != nil {
 		return nil, err
 	}
+	db.SetMaxOpenConns(1)
 
 	schema := `
-	CREATE TABLE IF NOT EXISTS jobs (
-		id TEXT PRIMARY KEY,
+	CREATE TABLE IF NOT EXISTS downloads (
+		id INTEGER PRIMARY KEY AUTOINCREMENT,
 		url TEXT NOT NULL,
-		protocol TEXT,
-		mirrors TEXT,
-		file_path TEXT,
-		total_size INTEGER,
-		downloaded INTEGER,
+		path TEXT,
+		size INTEGER,
+		duration_ms INTEGER,
+		checksum TEXT,
 		status TEXT,
-		priority INTEGER,
-		sha256 TEXT,
-		sha1 TEXT,
-		md5 TEXT,
-		added_time TIMESTAMP,
-		start_time TIMESTAMP,
-		end_time TIMESTAMP,
 		error TEXT,
-		metadata TEXT,
-		chunk_states TEXT
+		etag TEXT,
+		last_modified TEXT,
+		started_at TIMESTAMP,
+		finished_at TIMESTAMP
 	);
-	CREATE INDEX IF NOT EXISTS idx_status ON jobs(status);
-	CREATE INDEX IF NOT EXISTS idx_priority ON jobs(priority DESC);
+	CREATE INDEX IF NOT EXISTS idx_downloads_url ON downloads(url);
 	`
-	
 	if _, err := db.Exec(schema); err != nil {
+		db.Close()
 		return nil, err
 	}
+	return &History{db: db, dir: dir}, nil
+}
 
-	jq := &JobQueue{
-		jobs:      make(map[string]*Job),
-		queue:     make([]*Job, 0),
-		active:    make(map[string]*Job),
-		completed: make(map[string]*Job),
-		failed:    make(map[string]*Job),
-		maxActive: maxActive,
-		db:        db,
-		stopCh:    make(chan struct{}),
+func (h *History) OnComplete(task *DownloadTask, stats ProgressSnapshot) {
+	status := "completed"
+	if task.Skipped {
+		status = "skipped"
 	}
+	h.record(task, status, stats.Downloaded, nil)
+}
 
-	if err := jq.loadJobs(); err != nil {
-		return nil, err
+func (h *History) OnError(task *DownloadTask, err error) {
+	status := "failed"
+	if errors.Is(err, errInterrupted) || errors.Is(err, context.Canceled) {
+		status = "interrupted"
 	}
+	h.record(task, status, task.snapshot().Downloaded, err)
+}
 
-	return jq, nil
+func (h *History) record(task *DownloadTask, status string, received int64, err error) {
+	entry := HistoryEntry{
+		URL:          task.URL,
+		Size:         task.Size,
+		DurationMS:   time.Since(task.StartTime).Milliseconds(),
+		Checksum:     task.checksum(),
+		Status:       status,
+		ETag:         task.ETag,
+		LastModified: task.LastModified,
+		StartedAt:    task.StartTime,
+		FinishedAt:   time.Now(),
+	}
+	if entry.Size <= 0 {
+		entry.Size = received
+	}
+	if filepath.IsAbs(task.Filepath) {
+		entry.Path = task.Filepath
+	} else if task.Filepath != "" {
+		entry.Path, _ = filepath.Abs(filepath.Join(h.dir, task.Filepath))
+	}
+	if err != nil {
+		entry.Error = err.Error()
+	}
+	if err := h.Record(entry); err != nil {
+		logger.Warn("could not record download history", "url", task.URL, "err", err)
+	}
 }
 
-func (jq *JobQueue) loadJobs() error {
-	rows, err := jq.db.Query("SELECT id, url, protocol, file_path, total_size, downloaded, status, priority, sha256, sha1, md5, added_time FROM jobs WHERE status != 'completed'")
+// Record adds entry to the history
+func (h *History) Record(entry HistoryEntry) error {
+	_, err := h.db.Exec(`
+		INSERT INTO downloads (url, path, size, duration_ms, checksum, status, error, etag, last_modified, started_at, finished_at)
+		VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+	`, entry.URL, entry.Path, entry.Size, entry.DurationMS, entry.Checksum, entry.Status, entry.Error,
+		entry.ETag, entry.LastModified, entry.StartedAt, entry.FinishedAt)
+	return err
+}
+
+// Search returns the newest entries whose URL or path contains query,
+// limited to status when it is set. A limit of 0 returns everything.
+func (h *History) Search(query, status string, limit int) ([]HistoryEntry, error) {
+	if limit <= 0 {
+		limit = -1 // no limit, to SQLite
+	}
+	// % and _ in the query are matched literally, not as wildcards
+	pattern := "%" + strings.NewReplacer(`\`, `\\`, "%", `\%`, "_", `\_`).Replace(query) + "%"
+	return h.query(`
+		SELECT id, url, path, size, duration_ms, checksum, status, error, etag, last_modified, started_at, finished_at
+		FROM downloads
+		WHERE (url LIKE ? ESCAPE '\' OR path LIKE ? ESCAPE '\') AND (? = '' OR status = ?)
+		ORDER BY id DESC LIMIT ?
+	`, pattern, pattern, status, status, limit)
+}
+
+// Failed returns the latest entry of every URL whose last attempt failed
+func (h *History) Failed() ([]HistoryEntry, error) {
+	return h.query(`
+		SELECT id, url, path, size, duration_ms, checksum, status, error, etag, last_modified, started_at, finished_at
+		FROM downloads d
+		WHERE status = 'failed' AND id = (SELECT MAX(id) FROM downloads WHERE url = d.url)
+		ORDER BY id
+	`)
+}
+
+// Downloaded finds the last completed download of url whose file is still
+// there at the size it was downloaded at. A nil History knows nothing.
+func (h *History) Downloaded(url string) (*HistoryEntry, bool) {
+	if h == nil {
+		return nil, false
+	}
+	entries, err := h.query(`
+		SELECT id, url, path, size, duration_ms, checksum, status, error, etag, last_modified, started_at, finished_at
+		FROM downloads
+		WHERE url = ? AND status = 'completed'
+		ORDER BY id DESC LIMIT 1
+	`, url)
+	if err != nil || len(entries) == 0 {
+		return nil, false
+	}
+	entry := entries[0]
+	if info, err := os.Stat(entry.Path); err != nil || info.Size() != entry.Size {
+		return nil, false
+	}
+	return &entry, true
+}
+
+func (h *History) query(query string, args ...interface{}) ([]HistoryEntry, error) {
+	rows, err := h.db.Query(query, args...)
 	if err != nil {
-		return err
+		return nil, err
 	}
 	defer rows.Close()
 
+	var entries []HistoryEntry
 	for rows.Next() {
-		job := &Job{}
-		err := rows.Scan(&job.ID, &job.URL, &job.Protocol, &job.FilePath, &job.TotalSize, 
-			&job.Downloaded, &job.Status, &job.Priority, &job.SHA256, &job.SHA1, &job.MD5, &job.AddedTime)
-		if err != nil {
-			continue
-		}
-		
-		if job.Status == "downloading" {
-			job.Status = "pending"
-		}
-		
-		jq.jobs[job.ID] = job
-		if job.Status == "pending" {
-			jq.queue = append(jq.queue, job)
+		var entry HistoryEntry
+		var path, checksum, errText, etag, lastModified sql.NullString
+		if err := rows.Scan(&entry.ID, &entry.URL, &path, &entry.Size, &entry.DurationMS, &checksum, &entry.Status,
+			&errText, &etag, &lastModified, &entry.StartedAt, &entry.FinishedAt); err != nil {
+			return nil, err
 		}
+		entry.Path, entry.Checksum, entry.Error = path.String, checksum.String, errText.String
+		entry.ETag, entry.LastModified = etag.String, lastModified.String
+		entries = append(entries, entry)
 	}
-
-	return nil
+	return entries, rows.Err()
 }
 
-func (jq *JobQueue) AddJob(job *Job) error {
-	jq.mu.Lock()
-	defer jq.mu.Unlock()
-
-	if job.ID == "" {
-		job.ID = fmt.Sprintf("%d-%x", time.Now().Unix(), time.Now().UnixNano())
+// NewMetrics starts every counter at zero
+func NewMetrics() *Metrics {
+	return &Metrics{
+		running:  make(map[*DownloadTask]ProgressSnapshot),
+		results:  make(map[string]int64),
+		chunks:   make(map[string]int64),
+		retries:  make(map[string]int64),
+		failures: make(map[[2]string]int64),
+		speeds:   make([]int64, len(SpeedBuckets)),
 	}
+}
 
-	// Detect protocol from URL
-	if job.Protocol == "" {
-		parsedURL, _ := url.Parse(job.URL)
-		job.Protocol = parsedURL.Scheme
-	}
+func (m *Metrics) OnStart(task *DownloadTask) {
+	m.mu.Lock()
+	defer m.mu.Unlock()
+	m.running[task] = ProgressSnapshot{}
+}
 
-	job.Status = "pending"
-	job.AddedTime = time.Now()
+func (m *Metrics) OnChunkStart(task *DownloadTask, chunk ChunkInfo, attempt int) {
+	m.mu.Lock()
+	defer m.mu.Unlock()
+	m.chunks[hostOf(task.sourceURL())]++
+}
 
-	_, err := jq.db.Exec(`
-		INSERT INTO jobs (id, url, protocol, file_path, total_size, status, priority, sha256, sha1, md5, added_time)
-		VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
-	`, job.ID, job.URL, job.Protocol, job.FilePath, job.TotalSize, job.Status, job.Priority, 
-		job.SHA256, job.SHA1, job.MD5, job.AddedTime)
+func (m *Metrics) OnRetry(task *DownloadTask, chunk ChunkInfo, attempt int, err error) {
+	m.mu.Lock()
+	defer m.mu.Unlock()
+	m.retries[hostOf(task.sourceURL())]++
+}
+
+func (m *Metrics) OnProgress(task *DownloadTask, stats ProgressSnapshot) {
+	m.mu.Lock()
+	defer m.mu.Unlock()
+	m.progress(task, stats)
+	m.running[task] = stats
+}
+
+func (m *Metrics) OnComplete(task *DownloadTask, stats ProgressSnapshot) {
+	m.mu.Lock()
+	defer m.mu.Unlock()
+	m.progress(task, stats)
+	delete(m.running, task)
+	m.results["completed"]++
+
+	if stats.Elapsed <= 0 {
+		return
+	}
+	speed := float64(stats.Downloaded) / stats.Elapsed.Seconds()
+	m.speedSum += speed
+	m.speedObs++
+	if i := sort.SearchFloat64s(SpeedBuckets, speed); i < len(m.speeds) {
+		m.speeds[i]++
+	}
+}
+
+func (m *Metrics) OnError(task *DownloadTask, err error) {
+	m.mu.Lock()
+	defer m.mu.Unlock()
+	m.progress(task, task.snapshot())
+	delete(m.running, task)
+	m.results["failed"]++
+	m.failures[[2]string{hostOf(task.sourceURL()), string(errorKind(err))}]++
+}
+
+// progress counts the bytes a download received since its last snapshot;
+// caller holds m.mu
+func (m *Metrics) progress(task *DownloadTask, stats ProgressSnapshot) {
+	if delta := stats.Downloaded - m.running[task].Downloaded; delta > 0 {
+		m.bytes += delta
+	}
+}
+
+// Render writes the metrics in the Prometheus text format
+func (m *Metrics) Render(w io.Writer) {
+	m.mu.Lock()
+	defer m.mu.Unlock()
+
+	var connections int32
+	for _, stats := range m.running {
+		connections += stats.Active
+	}
+
+	promMetric(w, "fastdl_downloaded_bytes_total", "counter", "Bytes received by all downloads.")
+	fmt.Fprintf(w, "fastdl_downloaded_bytes_total %d\n", m.bytes)
+	promMetric(w, "fastdl_downloads_total", "counter", "Finished downloads by result.")
+	for _, result := range []string{"completed", "failed"} {
+		fmt.Fprintf(w, "fastdl_downloads_total{result=%s} %d\n", promLabel(result), m.results[result])
+	}
+	promMetric(w, "fastdl_active_downloads", "gauge", "Downloads in progress.")
+	fmt.Fprintf(w, "fastdl_active_downloads %d\n", len(m.running))
+	promMetric(w, "fastdl_active_connections", "gauge", "Chunk connections currently transferring.")
+	fmt.Fprintf(w, "fastdl_active_connections %d\n", connections)
+
+	promMetric(w, "fastdl_chunk_requests_total", "counter", "Chunk requests by host, retries included.")
+	for _, host := range sortedKeys(m.chunks) {
+		fmt.Fprintf(w, "fastdl_chunk_requests_total{host=%s} %d\n", promLabel(host), m.chunks[host])
+	}
+	promMetric(w, "fastdl_chunk_retries_total", "counter", "Chunk retries by host.")
+	for _, host := range sortedKeys(m.retries) {
+		fmt.Fprintf(w, "fastdl_chunk_retries_total{host=%s} %d\n", promLabel(host), m.retries[host])
+	}
+	promMetric(w, "fastdl_download_errors_total", "counter", "Failed downloads by host and error kind.")
+	failures := make([][2]string, 0, len(m.failures))
+	for key := range m.failures {
+		failures = append(failures, key)
+	}
+	sort.Slice(failures, func(i, j int) bool {
+		return failures[i][0] < failures[j][0] || failures[i][0] == failures[j][0] && failures[i][1] < failures[j][1]
+	})
+	for _, key := range failures {
+		fmt.Fprintf(w, "fastdl_download_errors_total{host=%s,kind=%s} %d\n", promLabel(key[0]), promLabel(key[1]), m.failures[key])
+	}
+
+	promMetric(w, "fastdl_download_speed_bytes", "histogram", "Average speed of completed downloads in bytes/sec.")
+	var cumulative int64
+	for i, bound := range SpeedBuckets {
+		cumulative += m.speeds[i]
+		fmt.Fprintf(w, "fastdl_download_speed_bytes_bucket{le=\"%g\"} %d\n", bound, cumulative)
+	}
+	fmt.Fprintf(w, "fastdl_download_speed_bytes_bucket{le=\"+Inf\"} %d\n", m.speedObs)
+	fmt.Fprintf(w, "fastdl_download_speed_bytes_sum %g\n", m.speedSum)
+	fmt.Fprintf(w, "fastdl_download_speed_bytes_count %d\n", m.speedObs)
+}
+
+func promMetric(w io.Writer, name, kind, help string) {
+	fmt.Fprintf(w, "# HELP %s %s\n# TYPE %s %s\n", name, help, name, kind)
+}
+
+var promEscaper = strings.NewReplacer(`\`, `\\`, `"`, `\"`, "\n", `\n`)
+
+func promLabel(value string) string {
+	return `"` + promEscaper.Replace(value) + `"`
+}
+
+func sortedKeys(m map[string]int64) []string {
+	keys := make([]string, 0, len(m))
+	for key := range m {
+		keys = append(keys, key)
+	}
+	sort.Strings(keys)
+	return keys
+}
+
+func hostOf(urlStr string) string {
+	parsedURL, err := url.Parse(urlStr)
+	if err != nil {
+		return ""
+	}
+	return parsedURL.Hostname()
+}
+
+// NewMirrorManager creates a new mirror manager
+func NewMirrorManager(mirrors []string, maxRetries int) *MirrorManager {
+	return &MirrorManager{
+		mirrors:    mirrors,
+		maxRetries: maxRetries,
+	}
+}
+
+func (m *MirrorManager) GetNextMirror() (string, bool) {
+	m.mu.Lock()
+	defer m.mu.Unlock()
+	if m.current >= len(m.mirrors) {
+		return "", false
+	}
+	mirror := m.mirrors[m.current]
+	m.current++
+	return mirror, true
+}
+
+// NewDownloadManager creates a new download manager
+func NewDownloadManager(config *Config) (*DownloadManager, error) {
+	proxyManager, err := NewProxyManager(config.ProxyURL, config.ProxyRules, config.NoProxy)
+	if err != nil {
+		return nil, err
+	}
+	tlsConfig, err := config.tlsConfig()
+	if err != nil {
+		return nil, err
+	}
+
+	var dns *dnsCache
+	if config.DNSTimeout > 0 || config.DNSCacheTTL > 0 || config.MaxConcurrentDNS > 0 {
+		dns = newDNSCache(time.Duration(config.DNSTimeout)*time.Second, time.Duration(config.DNSCacheTTL)*time.Second, config.MaxConcurrentDNS)
+	}
+
+	// No client-wide timeout: it would cap the whole transfer and kill a
+	// large chunk on a slow link. Connecting, the response headers and
+	// each read have their own limits instead.
+	dialer := &net.Dialer{Timeout: config.connectTimeout(), KeepAlive: 30 * time.Second}
+	dial := dialer.DialContext
+	if dns != nil {
+		dns.dialer = dialer
+		dial = dns.DialContext
+	}
+	if config.ReadTimeout > 0 {
+		dial = idleTimeoutDial(dial, time.Duration(config.ReadTimeout)*time.Second)
+	}
+	configure := func(transport *http.Transport) {
+		transport.DialContext = dial
+		transport.TLSClientConfig = tlsConfig.Clone()
+		transport.TLSHandshakeTimeout = config.connectTimeout()
+		transport.ResponseHeaderTimeout = time.Duration(config.Timeout) * time.Second
+	}
+
+	transport := proxyManager.GetTransport()
+	configure(transport)
+	if config.EnableHTTP2 {
+		http2.ConfigureTransport(transport)
+	}
+	// s3://, gs:// and az:// go through the same pools, rewritten and
+	// authorized on the way
+	s3 := newS3Client()
+	transport.RegisterProtocol("s3", &s3Transport{base: transport, s3: s3})
+	gcs, azure := newGCSTransport(transport), newAzureTransport(transport)
+	transport.RegisterProtocol("gs", gcs)
+	transport.RegisterProtocol("az", azure)
+	auth := newAuthenticator(config)
+	client := &http.Client{Transport: &authTransport{base: transport, auth: auth}, CheckRedirect: redirectPolicy(config.MaxRedirects)}
+
+	// Dedicated client without keep-alives so a retry never lands on a
+	// poisoned pooled connection
+	freshTransport := proxyManager.GetTransport()
+	freshTransport.DisableKeepAlives = true
+	configure(freshTransport)
+	freshTransport.RegisterProtocol("s3", &s3Transport{base: freshTransport, s3: s3})
+	freshTransport.RegisterProtocol("gs", &gcsTransport{base: freshTransport, token: gcs.token, endpoint: gcs.endpoint})
+	freshTransport.RegisterProtocol("az", &azureTransport{base: freshTransport, sas: azure.sas, key: azure.key, token: azure.token})
+	freshClient := &http.Client{Transport: &authTransport{base: freshTransport, auth: auth}, CheckRedirect: redirectPolicy(config.MaxRedirects)}
+
+	dm := &DownloadManager{
+		client:       client,
+		freshClient:  freshClient,
+		maxWorkers:   config.MaxConnections,
+		downloadDir:  config.DownloadDir,
+		verifyHashes: config.VerifyChecksum,
+		resume:       config.ResumeEnabled,
+		rateLimiter:  NewRateLimiter(config.RateLimit),
+		proxyManager: proxyManager,
+		tlsConfig:    tlsConfig,
+		config:       config,
+		auth:         auth,
+		buffers:      newBufferPool(config.BufferSize),
+	}
+
+	if config.LowPriority {
+		dm.applyLowPriority()
+	}
+
+	if endpoint := otlpEndpoint(config); endpoint != "" {
+		dm.tracer = NewTracer(endpoint)
+	}
+
+	if config.History && config.HistoryPath != "" {
+		history, err := OpenHistory(config.HistoryPath, config.DownloadDir)
+		if err != nil {
+			logger.Warn("download history disabled", "path", config.HistoryPath, "err", err)
+		} else {
+			dm.history = history
+			dm.AddObserver(history)
+		}
+	}
+
+	if config.CircuitThreshold > 0 {
+		dm.breaker = NewCircuitBreaker(config.CircuitThreshold, time.Duration(config.CircuitCooldown)*time.Second)
+	}
+	if config.RetryBudget > 0 {
+		dm.retries = &retryBudget{perMinute: config.RetryBudget, hosts: make(map[string]*rate.Limiter)}
+	}
+	if config.HostBackoff {
+		dm.throttle = &hostThrottle{until: make(map[string]time.Time)}
+	}
+	if config.MaxPerHost > 0 {
+		dm.hostSlots = &hostLimiter{limit: config.MaxPerHost, slots: make(map[string]chan struct{})}
+	}
+
+	if config.UseNetrc || config.NetrcFile != "" {
+		path := netrcPath(config)
+		netrc, err := parseNetrc(path)
+		// Only a file asked for by name has to exist
+		if err != nil && (config.NetrcFile != "" || !os.IsNotExist(err)) {
+			return nil, fmt.Errorf("failed to read netrc: %w", err)
+		}
+		if stat, err := os.Stat(path); err == nil && stat.Mode().Perm()&0077 != 0 {
+			logger.Warn("netrc is readable by other users, chmod 600 it", "path", path)
+		}
+		dm.auth.netrc = netrc
+	}
+
+	dm.progressOut = os.Stdout
+	if config.ProgressFD > 0 {
+		dm.progressOut = os.NewFile(uintptr(config.ProgressFD), "progress")
+	}
+	if config.ProgressFormat == "pretty" {
+		dm.bars = &multiBar{out: os.Stdout}
+	}
+
+	if config.CookieFile != "" {
+		cookies, err := loadCookieFile(config.CookieFile)
+		if err != nil {
+			return nil, fmt.Errorf("failed to read cookie file: %w", err)
+		}
+		dm.cookies = cookies
+		dm.client.Jar = cookies
+		dm.freshClient.Jar = cookies
+	}
+
+	return dm, nil
+}
+
+// redirectPolicy follows up to limit redirects. With limit 0 the redirect
+// itself is returned, and probing reports where it pointed.
+func redirectPolicy(limit int) func(*http.Request, []*http.Request) error {
+	return func(req *http.Request, via []*http.Request) error {
+		if limit <= 0 {
+			return http.ErrUseLastResponse
+		}
+		if len(via) > limit {
+			return fmt.Errorf("stopped after %d redirects", limit)
+		}
+		return nil
+	}
+}
+
+// redirectChain returns the URL a response finally came from and the URLs
+// that redirected to it, starting with the one requested
+func redirectChain(resp *http.Response) (string, []string) {
+	var chain []string
+	for req := resp.Request; req.Response != nil; req = req.Response.Request {
+		chain = append(chain, req.Response.Request.URL.String())
+	}
+	slices.Reverse(chain)
+	return resp.Request.URL.String(), chain
+}
+
+// loadCookieFile reads a Netscape cookies.txt as exported by browsers,
+// curl and wget. A missing file starts an empty jar.
+func loadCookieFile(path string) (*cookieStore, error) {
+	jar, err := cookiejar.New(nil)
+	if err != nil {
+		return nil, err
+	}
+	store := &cookieStore{jar: jar, path: path, entries: make(map[string]cookieEntry)}
+
+	data, err := os.ReadFile(path)
+	if os.IsNotExist(err) {
+		return store, nil
+	}
+	if err != nil {
+		return nil, err
+	}
+
+	for _, line := range strings.Split(string(data), "\n") {
+		line = strings.TrimRight(line, "\r")
+		httpOnly := strings.HasPrefix(line, "#HttpOnly_")
+		line = strings.TrimPrefix(line, "#HttpOnly_")
+		if line == "" || strings.HasPrefix(line, "#") {
+			continue
+		}
+
+		fields := strings.Split(line, "\t")
+		if len(fields) != 7 {
+			continue
+		}
+		host := strings.TrimPrefix(fields[0], ".")
+		cookie := &http.Cookie{
+			Name:     fields[5],
+			Value:    fields[6],
+			Path:     fields[2],
+			Secure:   fields[3] == "TRUE",
+			HttpOnly: httpOnly,
+		}
+		if fields[1] == "TRUE" {
+			cookie.Domain = host
+		}
+		if expires, _ := strconv.ParseInt(fields[4], 10, 64); expires > 0 {
+			cookie.Expires = time.Unix(expires, 0)
+			if cookie.Expires.Before(time.Now()) {
+				continue
+			}
+		}
+
+		scheme := "http"
+		if cookie.Secure {
+			scheme = "https"
+		}
+		store.jar.SetCookies(&url.URL{Scheme: scheme, Host: host, Path: cookie.Path}, []*http.Cookie{cookie})
+		store.entries[host+"|"+cookie.Path+"|"+cookie.Name] = cookieEntry{host: host, cookie: cookie}
+	}
+	return store, nil
+}
+
+func (c *cookieStore) Cookies(u *url.URL) []*http.Cookie {
+	return c.jar.Cookies(u)
+}
+
+func (c *cookieStore) SetCookies(u *url.URL, cookies []*http.Cookie) {
+	c.jar.SetCookies(u, cookies)
+
+	c.mu.Lock()
+	defer c.mu.Unlock()
+	for _, cookie := range cookies {
+		host := strings.TrimPrefix(cookie.Domain, ".")
+		if host == "" {
+			host = u.Hostname()
+		}
+		if cookie.Path == "" {
+			cookie.Path = "/"
+		}
+		key := host + "|" + cookie.Path + "|" + cookie.Name
+		if cookie.MaxAge < 0 {
+			delete(c.entries, key)
+		} else {
+			if cookie.MaxAge > 0 {
+				cookie.Expires = time.Now().Add(time.Duration(cookie.MaxAge) * time.Second)
+			}
+			c.entries[key] = cookieEntry{host: host, cookie: cookie}
+		}
+		c.dirty = true
+	}
+}
+
+// save writes the jar back to its cookies.txt if anything changed
+func (c *cookieStore) save() error {
+	if c == nil {
+		return nil
+	}
+	c.mu.Lock()
+	defer c.mu.Unlock()
+	if !c.dirty {
+		return nil
+	}
+
+	var lines []string
+	for _, entry := range c.entries {
+		cookie := entry.cookie
+		if !cookie.Expires.IsZero() && cookie.Expires.Before(time.Now()) {
+			continue
+		}
+		domain, subdomains := entry.host, "FALSE"
+		if cookie.Domain != "" {
+			domain, subdomains = "."+entry.host, "TRUE"
+		}
+		if cookie.HttpOnly {
+			domain = "#HttpOnly_" + domain
+		}
+		secure := "FALSE"
+		if cookie.Secure {
+			secure = "TRUE"
+		}
+		var expires int64
+		if !cookie.Expires.IsZero() {
+			expires = cookie.Expires.Unix()
+		}
+		lines = append(lines, strings.Join([]string{domain, subdomains, cookie.Path, secure,
+			strconv.FormatInt(expires, 10), cookie.Name, cookie.Value}, "\t"))
+	}
+	sort.Strings(lines)
+
+	data := "# Netscape HTTP Cookie File\n" + strings.Join(lines, "\n") + "\n"
+	tmp := c.path + ".tmp"
+	if err := os.WriteFile(tmp, []byte(data), 0600); err != nil {
+		return err
+	}
+	if err := os.Rename(tmp, c.path); err != nil {
+		return err
+	}
+	c.dirty = false
+	return nil
+}
+
+// prepareRequest applies the user agent, the global headers, then the
+// download's own headers (which win) to a request. Credentials are added
+// by authTransport.
+func (dm *DownloadManager) prepareRequest(req *http.Request, headers map[string]string) {
+	req.Header.Set("User-Agent", dm.config.UserAgent)
+	for k, v := range dm.config.Headers {
+		req.Header.Set(k, v)
+	}
+	for k, v := range headers {
+		req.Header.Set(k, v)
+	}
+}
+
+func newAuthenticator(config *Config) *authenticator {
+	a := &authenticator{
+		bearer:  config.BearerToken,
+		hosts:   make(map[string]bool),
+		digests: make(map[string]*digestChallenge),
+	}
+	if config.User != "" {
+		a.user, a.password, _ = strings.Cut(config.User, ":")
+	}
+	return a
+}
+
+// allow lets -user and -bearer credentials go to the host of urlStr
+func (a *authenticator) allow(urlStr string) {
+	if a.user == "" && a.bearer == "" {
+		return
+	}
+	a.mu.Lock()
+	a.hosts[hostOf(urlStr)] = true
+	a.mu.Unlock()
+}
+
+// credentials returns what to authenticate to host with
+func (a *authenticator) credentials(host string) (user, password, bearer string, ok bool) {
+	a.mu.Lock()
+	allowed := a.hosts[host]
+	a.mu.Unlock()
+	if allowed {
+		return a.user, a.password, a.bearer, true
+	}
+	if entry, found := a.netrcFor(host, ""); found {
+		return entry.login, entry.password, "", true
+	}
+	return "", "", "", false
+}
+
+// netrcFor returns the netrc entry for host, falling back to default like
+// curl. When the URL already names a user, only an entry for that login
+// counts.
+func (a *authenticator) netrcFor(host, user string) (netrcEntry, bool) {
+	if a == nil || a.netrc == nil {
+		return netrcEntry{}, false
+	}
+	entry, found := a.netrc[host]
+	if !found {
+		entry, found = a.netrc["default"]
+	}
+	if !found || entry.login == "" || (user != "" && entry.login != user) {
+		return netrcEntry{}, false
+	}
+	return entry, true
+}
+
+// digest answers host's last Digest challenge for a request, or returns ""
+// when host never sent one
+func (a *authenticator) digest(host, user, password, method, uri string) string {
+	a.mu.Lock()
+	defer a.mu.Unlock()
+	c := a.digests[host]
+	if c == nil {
+		return ""
+	}
+	c.count++
+	return c.authorization(user, password, method, uri)
+}
+
+func (t *authTransport) RoundTrip(req *http.Request) (*http.Response, error) {
+	host := req.URL.Hostname()
+	user, password, bearer, ok := t.auth.credentials(host)
+	if !ok || req.Header.Get("Authorization") != "" {
+		return t.base.RoundTrip(req)
+	}
+
+	authed := req.Clone(req.Context())
+	switch {
+	case bearer != "":
+		authed.Header.Set("Authorization", "Bearer "+bearer)
+	default:
+		if digest := t.auth.digest(host, user, password, req.Method, req.URL.RequestURI()); digest != "" {
+			authed.Header.Set("Authorization", digest)
+		} else {
+			authed.SetBasicAuth(user, password)
+		}
+	}
+	resp, err := t.base.RoundTrip(authed)
+	if err != nil || resp.StatusCode != http.StatusUnauthorized || bearer != "" {
+		return resp, err
+	}
+
+	// Answer a Digest challenge once; a second 401 means the credentials
+	// are wrong and goes back to the caller
+	challenge := parseDigestChallenge(resp.Header.Values("WWW-Authenticate"))
+	if challenge == nil || (req.Body != nil && req.GetBody == nil) {
+		return resp, nil
+	}
+	resp.Body.Close()
+	t.auth.mu.Lock()
+	t.auth.digests[host] = challenge
+	t.auth.mu.Unlock()
+
+	retry := req.Clone(req.Context())
+	if req.GetBody != nil {
+		if retry.Body, err = req.GetBody(); err != nil {
+			return nil, err
+		}
+	}
+	retry.Header.Set("Authorization", t.auth.digest(host, user, password, req.Method, req.URL.RequestURI()))
+	return t.base.RoundTrip(retry)
+}
+
+// parseDigestChallenge finds the Digest challenge among WWW-Authenticate
+// values, or returns nil. Only the MD5 and SHA-256 algorithms are known.
+func parseDigestChallenge(values []string) *digestChallenge {
+	for _, value := range values {
+		scheme, rest, _ := strings.Cut(strings.TrimSpace(value), " ")
+		if !strings.EqualFold(scheme, "Digest") {
+			continue
+		}
+		params := parseAuthParams(rest)
+		c := &digestChallenge{
+			realm:     params["realm"],
+			nonce:     params["nonce"],
+			opaque:    params["opaque"],
+			algorithm: params["algorithm"],
+		}
+		for _, qop := range strings.Split(params["qop"], ",") {
+			if strings.TrimSpace(qop) == "auth" {
+				c.qop = "auth"
+			}
+		}
+		switch strings.ToUpper(c.algorithm) {
+		case "", "MD5", "MD5-SESS", "SHA-256", "SHA-256-SESS":
+		default:
+			continue
+		}
+		if c.nonce != "" {
+			return c
+		}
+	}
+	return nil
+}
+
+// parseAuthParams splits `key=value, key="quoted, value"` pairs
+func parseAuthParams(s string) map[string]string {
+	params := make(map[string]string)
+	for s = strings.TrimSpace(s); s != ""; s = strings.TrimLeft(s, ", ") {
+		key, rest, ok := strings.Cut(s, "=")
+		if !ok {
+			break
+		}
+		key = strings.ToLower(strings.TrimSpace(key))
+		var value string
+		if strings.HasPrefix(rest, `"`) {
+			var b strings.Builder
+			i := 1
+			for ; i < len(rest) && rest[i] != '"'; i++ {
+				if rest[i] == '\\' && i+1 < len(rest) {
+					i++
+				}
+				b.WriteByte(rest[i])
+			}
+			value, s = b.String(), rest[min(i+1, len(rest)):]
+		} else {
+			value, s, _ = strings.Cut(rest, ",")
+			value = strings.TrimSpace(value)
+		}
+		params[key] = value
+	}
+	return params
+}
+
+// authorization computes the Authorization header for one request, as in
+// RFC 7616
+func (c *digestChallenge) authorization(user, password, method, uri string) string {
+	h := func(s string) string {
+		if strings.HasPrefix(strings.ToUpper(c.algorithm), "SHA-256") {
+			sum := sha256.Sum256([]byte(s))
+			return hex.EncodeToString(sum[:])
+		}
+		sum := md5.Sum([]byte(s))
+		return hex.EncodeToString(sum[:])
+	}
+
+	cnonce := fmt.Sprintf("%016x", rand.Uint64())
+	nc := fmt.Sprintf("%08x", c.count)
+	ha1 := h(user + ":" + c.realm + ":" + password)
+	if strings.HasSuffix(strings.ToUpper(c.algorithm), "-SESS") {
+		ha1 = h(ha1 + ":" + c.nonce + ":" + cnonce)
+	}
+	ha2 := h(method + ":" + uri)
+
+	var response string
+	if c.qop == "auth" {
+		response = h(strings.Join([]string{ha1, c.nonce, nc, cnonce, c.qop, ha2}, ":"))
+	} else {
+		response = h(ha1 + ":" + c.nonce + ":" + ha2)
+	}
+
+	quote := func(s string) string { return `"` + strings.NewReplacer(`\`, `\\`, `"`, `\"`).Replace(s) + `"` }
+	header := fmt.Sprintf("Digest username=%s, realm=%s, nonce=%s, uri=%s, response=%s",
+		quote(user), quote(c.realm), quote(c.nonce), quote(uri), quote(response))
+	if c.algorithm != "" {
+		header += ", algorithm=" + c.algorithm
+	}
+	if c.opaque != "" {
+		header += ", opaque=" + quote(c.opaque)
+	}
+	if c.qop == "auth" {
+		header += ", qop=auth, nc=" + nc + ", cnonce=" + quote(cnonce)
+	}
+	return header
+}
+
+// netrcPath is netrc_file, else $NETRC, else ~/.netrc
+func netrcPath(config *Config) string {
+	if config.NetrcFile != "" {
+		return config.NetrcFile
+	}
+	if path := os.Getenv("NETRC"); path != "" {
+		return path
+	}
+	homeDir, _ := os.UserHomeDir()
+	return filepath.Join(homeDir, ".netrc")
+}
+
+// parseNetrc reads machine and default entries the way curl does. The
+// first entry for a machine wins and macdef bodies are skipped.
+func parseNetrc(path string) (map[string]netrcEntry, error) {
+	data, err := os.ReadFile(path)
+	if err != nil {
+		return nil, err
+	}
+
+	entries := make(map[string]netrcEntry)
+	var machine string
+	var entry netrcEntry
+	flush := func() {
+		if _, exists := entries[machine]; machine != "" && !exists {
+			entries[machine] = entry
+		}
+		machine = ""
+		entry = netrcEntry{}
+	}
+
+	inMacro := false
+	for _, line := range strings.Split(string(data), "\n") {
+		if inMacro {
+			inMacro = strings.TrimSpace(line) != ""
+			continue
+		}
+
+		fields := strings.Fields(line)
+		for i := 0; i < len(fields); i++ {
+			next := ""
+			if i+1 < len(fields) {
+				next = fields[i+1]
+			}
+
+			switch fields[i] {
+			case "machine":
+				flush()
+				machine = next
+				i++
+			case "default":
+				flush()
+				machine = "default"
+			case "login":
+				entry.login = next
+				i++
+			case "password":
+				entry.password = next
+				i++
+			case "account":
+				i++
+			case "macdef":
+				flush()
+				inMacro = true
+				i = len(fields)
+			}
+		}
+	}
+	flush()
+
+	return entries, nil
+}
+
+// applyLowPriority throttles disk and network use for background downloads
+func (dm *DownloadManager) applyLowPriority() {
+	dm.maxWorkers = min(dm.maxWorkers, LowPriorityConnections)
+	if dm.config.RateLimit <= 0 {
+		dm.rateLimiter = NewRateLimiter(LowPriorityRate)
+	}
+
+	// Best effort: idle I/O class on Linux, nothing elsewhere
+	if runtime.GOOS == "linux" {
+		if err := exec.Command("ionice", "-c", "3", "-p", strconv.Itoa(os.Getpid())).Run(); err != nil {
+			logger.Warn("could not lower I/O priority", "err", err)
+		}
+	}
+}
+
+// AddValidator registers a check run on every finished download, after
+// checksums and signatures
+func (dm *DownloadManager) AddValidator(v ContentValidator) {
+	dm.validators = append(dm.validators, v)
+}
+
+// Pause keeps new chunk requests from starting and interrupts the ones in
+// flight, which give back their connection and host slot and save the
+// resume sidecar; they start over on Resume. Single-stream transfers are
+// held at their next read with the connection open.
+func (dm *DownloadManager) Pause() {
+	g := &dm.pause
+	g.mu.Lock()
+	defer g.mu.Unlock()
+	if !g.paused {
+		g.paused = true
+		g.resume = make(chan struct{})
+		if g.pausing != nil {
+			close(g.pausing)
+			g.pausing = nil
+		}
+	}
+}
+
+// Resume releases transfers held by Pause
+func (dm *DownloadManager) Resume() {
+	g := &dm.pause
+	g.mu.Lock()
+	defer g.mu.Unlock()
+	if g.paused {
+		g.paused = false
+		close(g.resume)
+	}
+}
+
+// Paused reports whether transfers are currently held
+func (dm *DownloadManager) Paused() bool {
+	dm.pause.mu.Lock()
+	defer dm.pause.mu.Unlock()
+	return dm.pause.paused
+}
+
+// interruptOnPause derives a context that Pause cancels with errPaused
+func (dm *DownloadManager) interruptOnPause(ctx context.Context) (context.Context, func()) {
+	ctx, cancel := context.WithCancelCause(ctx)
+	g := &dm.pause
+	g.mu.Lock()
+	if g.paused {
+		g.mu.Unlock()
+		cancel(errPaused)
+		return ctx, func() {}
+	}
+	if g.pausing == nil {
+		g.pausing = make(chan struct{})
+	}
+	pausing := g.pausing
+	g.mu.Unlock()
+
+	done := make(chan struct{})
+	go func() {
+		select {
+		case <-pausing:
+			cancel(errPaused)
+		case <-done:
+		}
+	}()
+	return ctx, func() {
+		close(done)
+		cancel(nil)
+	}
+}
+
+// waitIfPaused blocks while paused, returning early if ctx ends
+func (dm *DownloadManager) waitIfPaused(ctx context.Context) error {
+	g := &dm.pause
+	g.mu.Lock()
+	if !g.paused {
+		g.mu.Unlock()
+		return nil
+	}
+	resume := g.resume
+	g.mu.Unlock()
+
+	select {
+	case <-resume:
+		return nil
+	case <-ctx.Done():
+		return ctx.Err()
+	}
+}
+
+// handlePauseSignals pauses on SIGUSR1 and resumes on SIGUSR2
+func (dm *DownloadManager) handlePauseSignals() {
+	signals := make(chan os.Signal, 1)
+	signal.Notify(signals, syscall.SIGUSR1, syscall.SIGUSR2)
+	go func() {
+		for sig := range signals {
+			if sig == syscall.SIGUSR1 {
+				dm.Pause()
+			} else {
+				dm.Resume()
+			}
+		}
+	}()
+}
+
+// AddObserver registers o for the events of every download. Observers are
+// called synchronously from download goroutines and must not block.
+func (dm *DownloadManager) AddObserver(o DownloadObserver) {
+	dm.observers = append(dm.observers, o)
+}
+
+func (dm *DownloadManager) notify(event func(DownloadObserver)) {
+	for _, o := range dm.observers {
+		event(o)
+	}
+}
+
+// log is logger with the download's URL attached
+func (task *DownloadTask) log() *slog.Logger {
+	return logger.With("url", task.URL)
+}
+
+// sources lists the primary URL followed by its mirrors
+func (task *DownloadTask) sources() []string {
+	return append([]string{task.URL}, task.Mirrors...)
+}
+
+// sourceURL is where requests for the task currently go
+func (task *DownloadTask) sourceURL() string {
+	return task.sources()[atomic.LoadInt32(&task.source)]
+}
+
+// failover moves every worker off source after it exhausted its retries.
+// It reports false once there is no later mirror to try.
+func (task *DownloadTask) failover(source int32) bool {
+	if int(source) >= len(task.Mirrors) {
+		return false
+	}
+	atomic.CompareAndSwapInt32(&task.source, source, source+1)
+	return true
+}
+
+func newMirrorScheduler(urls []string) *mirrorScheduler {
+	m := &mirrorScheduler{}
+	for _, u := range urls {
+		m.stats = append(m.stats, MirrorStat{URL: u})
+	}
+	return m
+}
+
+// pick chooses the source for the next chunk request. Unmeasured sources
+// are assumed as fast as the best one so each gets sampled early; sources
+// that failed MirrorMaxFailures times in a row sit out while others remain.
+func (m *mirrorScheduler) pick() (int, string) {
+	m.mu.Lock()
+	defer m.mu.Unlock()
+
+	best := 0.0
+	for _, stat := range m.stats {
+		best = max(best, stat.Speed())
+	}
+	if best == 0 {
+		best = 1
+	}
+
+	chosen, chosenScore := -1, 0.0
+	for pass := 0; chosen < 0 && pass < 2; pass++ {
+		for i, stat := range m.stats {
+			if pass == 0 && stat.Failures >= MirrorMaxFailures {
+				continue
+			}
+			speed := stat.Speed()
+			if speed == 0 {
+				speed = best
+			}
+			score := float64(stat.inFlight+1) / speed
+			if chosen < 0 || score < chosenScore {
+				chosen, chosenScore = i, score
+			}
+		}
+	}
+
+	m.stats[chosen].inFlight++
+	return chosen, m.stats[chosen].URL
+}
+
+// done records the outcome of a chunk request made to source i
+func (m *mirrorScheduler) done(i int, bytes int64, elapsed time.Duration, err error) {
+	if m == nil || i < 0 {
+		return
+	}
+	m.mu.Lock()
+	defer m.mu.Unlock()
+
+	stat := &m.stats[i]
+	stat.inFlight--
+	if err != nil {
+		stat.Failures++
+		return
+	}
+	stat.Failures = 0
+	stat.Bytes += bytes
+	stat.Busy += elapsed
+	stat.Chunks++
+}
+
+// Speed is the source's average bytes/sec while serving chunks
+func (s MirrorStat) Speed() float64 {
+	if s.Busy <= 0 {
+		return 0
+	}
+	return float64(s.Bytes) / s.Busy.Seconds()
+}
+
+// MirrorStats returns per-source throughput for a striped download
+func (task *DownloadTask) MirrorStats() []MirrorStat {
+	if task.scheduler == nil {
+		return nil
+	}
+	task.scheduler.mu.Lock()
+	defer task.scheduler.mu.Unlock()
+	return slices.Clone(task.scheduler.stats)
+}
+
+// snapshot copies the task's progress; speed is left to the caller
+func (task *DownloadTask) snapshot() ProgressSnapshot {
+	stats := ProgressSnapshot{Total: task.Size, Elapsed: time.Since(task.StartTime)}
+	if task.progress != nil {
+		stats.Downloaded = atomic.LoadInt64(&task.progress.Downloaded)
+		stats.Active = atomic.LoadInt32(&task.progress.Active)
+	}
+	return stats
+}
+
+// MagicValidator rejects files that don't start with the given bytes
+func MagicValidator(magic []byte) ContentValidator {
+	return ValidatorFunc(func(path string) error {
+		file, err := os.Open(path)
+		if err != nil {
+			return err
+		}
+		defer file.Close()
+
+		head := make([]byte, len(magic))
+		if _, err := io.ReadFull(file, head); err != nil || !bytes.Equal(head, magic) {
+			return fmt.Errorf("file does not start with %x", magic)
+		}
+		return nil
+	})
+}
+
+// GetFileInfo retrieves file information from URL, sending the download's
+// headers so auth-protected files can be probed too
+func (dm *DownloadManager) GetFileInfo(ctx context.Context, urlStr string, headers map[string]string) (*DownloadTask, error) {
+	if isFTPURL(urlStr) {
+		return dm.ftpFileInfo(ctx, urlStr)
+	}
+	if isSFTPURL(urlStr) {
+		return dm.sftpFileInfo(ctx, urlStr)
+	}
+
+	release, err := dm.hostSlots.Acquire(ctx, hostOf(urlStr))
+	if err != nil {
+		return nil, err
+	}
+	defer release()
+
+	var resp *http.Response
+	var delay time.Duration
+	method := "HEAD"
+	for attempt := 0; ; attempt++ {
+		if err := dm.throttle.Wait(ctx, hostOf(urlStr)); err != nil {
+			return nil, err
+		}
+
+		req, err := http.NewRequestWithContext(ctx, method, urlStr, nil)
+		if err != nil {
+			return nil, err
+		}
+
+		dm.prepareRequest(req, headers)
+		if method == "GET" {
+			req.Header.Set("Range", "bytes=0-0")
+		}
+
+		resp, err = dm.client.Do(req)
+		if err != nil {
+			return nil, err
+		}
+		recordResponse(ctx, resp)
+		if resp.StatusCode == http.StatusOK || resp.StatusCode == http.StatusPartialContent {
+			break
+		}
+		resp.Body.Close()
+		if resp.StatusCode == http.StatusNotModified {
+			return nil, errNotModified
+		}
+		// S3 presigned URLs and some CDNs refuse HEAD, so ask for the
+		// first byte instead and read the size off Content-Range
+		if method == "HEAD" && headRefused(resp.StatusCode) {
+			logger.Debug("HEAD refused, probing with a ranged GET", "url", urlStr, "status", resp.StatusCode)
+			method = "GET"
+			attempt--
+			continue
+		}
+
+		// Only rate limiting is worth retrying here, anything else moves
+		// on to the next mirror
+		err = statusError(resp)
+		if !errors.Is(err, errThrottled) || attempt >= dm.config.MaxRetries-1 {
+			return nil, err
+		}
+		if delay, err = dm.retryDelay(urlStr, err, attempt, delay); err != nil {
+			return nil, err
+		}
+		logger.Warn("rate limited, retrying", "host", hostOf(urlStr), "delay", delay.Round(time.Second))
+		select {
+		case <-time.After(delay):
+		case <-ctx.Done():
+			return nil, ctx.Err()
+		}
+	}
+	defer resp.Body.Close()
+
+	task := &DownloadTask{
+		URL:       urlStr,
+		StartTime: time.Now(),
+		Headers:   headers,
+	}
+
+	if contentLength := resp.Header.Get("Content-Length"); contentLength != "" {
+		task.Size, _ = strconv.ParseInt(contentLength, 10, 64)
+	}
+
+	if acceptRanges := resp.Header.Get("Accept-Ranges"); acceptRanges == "bytes" {
+		task.SupportsRange = true
+	}
+
+	// A ranged GET's 206 proves range support, and its Content-Length is
+	// the one byte asked for, not the file
+	if method == "GET" && resp.StatusCode == http.StatusPartialContent {
+		task.SupportsRange = true
+		task.Size = 0
+		if _, _, total, ok := parseContentRange(resp.Header.Get("Content-Range")); ok && total > 0 {
+			task.Size = total
+		}
+	}
+	if method == "GET" && resp.StatusCode == http.StatusOK {
+		task.SupportsRange = false // the whole file, whatever Accept-Ranges says
+	}
+
+	task.ETag = resp.Header.Get("ETag")
+	task.LastModified = resp.Header.Get("Last-Modified")
+	task.contentType = resp.Header.Get("Content-Type")
+	task.multiplexed = resp.ProtoMajor == 2
+	task.FinalURL, task.Redirects = redirectChain(resp)
+	if len(task.Redirects) > 0 {
+		logger.Debug("redirected", "url", urlStr, "final", task.FinalURL, "hops", len(task.Redirects))
+	}
+
+	// The server's attachment name beats whatever the URL path ends in
+	if name := dispositionFilename(resp.Header.Get("Content-Disposition")); name != "" {
+		if task.Filepath, err = sanitizeFilename(name, dm.config.FilenameEncoding); err != nil {
+			return nil, err
+		}
+	}
+
+	// Shortlinks and mirror redirectors name nothing, where they lead does
+	if task.Filepath == "" {
+		if task.Filepath, err = extractFilename(task.FinalURL, dm.config); err != nil {
+			return nil, err
+		}
+	}
+
+	return task, nil
+}
+
+// conditionalEntry finds the history entry a conditional request can be
+// made against: the last completed download of the task's URL, still on
+// disk and with a validator to send
+func (dm *DownloadManager) conditionalEntry(task *DownloadTask) (*HistoryEntry, bool) {
+	if !dm.config.Conditional || isFTPURL(task.URL) || isSFTPURL(task.URL) {
+		return nil, false
+	}
+	entry, ok := dm.history.Downloaded(task.URL)
+	if !ok || (entry.ETag == "" && entry.LastModified == "") {
+		return nil, false
+	}
+	return entry, true
+}
+
+// conditionalHeaders adds If-None-Match and If-Modified-Since for entry to
+// a copy of headers. Servers are meant to ignore the date when they check
+// the ETag.
+func conditionalHeaders(headers map[string]string, entry *HistoryEntry) map[string]string {
+	conditional := make(map[string]string, len(headers)+2)
+	for key, value := range headers {
+		conditional[key] = value
+	}
+	if entry.ETag != "" {
+		conditional["If-None-Match"] = entry.ETag
+	}
+	if entry.LastModified != "" {
+		conditional["If-Modified-Since"] = entry.LastModified
+	}
+	return conditional
+}
+
+// headRefused reports whether a HEAD answered with status is worth asking
+// again as a GET: the method is refused or a signature only covers GET
+func headRefused(status int) bool {
+	switch status {
+	case http.StatusForbidden, http.StatusMethodNotAllowed, http.StatusNotImplemented, http.StatusBadRequest:
+		return true
+	}
+	return false
+}
+
+// outputName names a download the user didn't name: the server's or URL's
+// file name, placed by output_template when one is set
+func (dm *DownloadManager) outputName(task, info *DownloadTask) (string, error) {
+	if dm.config.OutputTemplate == "" {
+		return info.Filepath, nil
+	}
+	return expandOutputTemplate(dm.config.OutputTemplate, task, info.Filepath, info.contentType)
+}
+
+var templateVar = regexp.MustCompile(`\{([a-z]+)([0-9]*)\}`)
+
+// expandOutputTemplate fills in an output_template such as
+// "{host}/{date}/{filename}". Variables are host, path (the URL's directory),
+// path1, path2... (its segments), filename, name and ext (the file name,
+// without and with only its extension; ext falls back to the Content-Type),
+// index (position in a batch), date and hash (a short hash of the URL).
+// Every value is sanitized, so only the template itself adds directories.
+func expandOutputTemplate(template string, task *DownloadTask, filename, contentType string) (string, error) {
+	parsed, err := url.Parse(task.URL)
+	if err != nil {
+		return "", err
+	}
+	var segments []string
+	for _, segment := range strings.Split(path.Dir(parsed.Path), "/") {
+		if segment != "" && segment != "." && segment != ".." {
+			segments = append(segments, segment)
+		}
+	}
+
+	ext := strings.TrimPrefix(filepath.Ext(filename), ".")
+	name := strings.TrimSuffix(filename, filepath.Ext(filename))
+	if ext == "" && contentType != "" {
+		if media, _, err := mime.ParseMediaType(contentType); err == nil {
+			if exts, _ := mime.ExtensionsByType(media); len(exts) > 0 {
+				ext = strings.TrimPrefix(exts[0], ".")
+				filename += exts[0]
+			}
+		}
+	}
+	sum := sha256.Sum256([]byte(task.URL))
+	index := max(task.index, 1)
+
+	var expandErr error
+	clean := func(value string) string {
+		value, err := sanitizeFilename(value, "")
+		if err != nil && expandErr == nil {
+			expandErr = err
+		}
+		if value == "." || value == ".." {
+			return "_"
+		}
+		return value
+	}
+	expanded := templateVar.ReplaceAllStringFunc(template, func(match string) string {
+		parts := templateVar.FindStringSubmatch(match)
+		switch {
+		case parts[1] == "path" && parts[2] != "":
+			n, _ := strconv.Atoi(parts[2])
+			if n < 1 || n > len(segments) {
+				return "_"
+			}
+			return clean(segments[n-1])
+		case parts[2] != "":
+			// Only path takes a number
+		case parts[1] == "host":
+			return clean(parsed.Hostname())
+		case parts[1] == "path":
+			cleaned := make([]string, len(segments))
+			for i, segment := range segments {
+				cleaned[i] = clean(segment)
+			}
+			return strings.Join(cleaned, "/")
+		case parts[1] == "filename":
+			return clean(filename)
+		case parts[1] == "name":
+			return clean(name)
+		case parts[1] == "ext":
+			return clean(ext)
+		case parts[1] == "index":
+			return strconv.Itoa(index)
+		case parts[1] == "date":
+			return time.Now().Format("2006-01-02")
+		case parts[1] == "hash":
+			return hex.EncodeToString(sum[:6])
+		}
+		if expandErr == nil {
+			expandErr = configError(fmt.Errorf("unknown output template variable %s", match))
+		}
+		return ""
+	})
+	if expandErr != nil {
+		return "", expandErr
+	}
+
+	// Empty variables can leave doubled or trailing slashes behind
+	result := strings.TrimPrefix(path.Clean("/"+expanded), "/")
+	if result == "" {
+		return "", fmt.Errorf("output template %q gives an empty name for %s", template, task.URL)
+	}
+	return filepath.FromSlash(result), nil
+}
+
+// dispositionFilename extracts the filename from a Content-Disposition
+// header. mime decodes RFC 5987 filename*= values and prefers them over
+// plain filename=. Directory components are dropped.
+func dispositionFilename(header string) string {
+	if header == "" {
+		return ""
+	}
+	_, params, err := mime.ParseMediaType(header)
+	if err != nil {
+		return ""
+	}
+	name := params["filename"]
+	name = name[strings.LastIndexAny(name, `/\`)+1:]
+	if name == "." || name == ".." {
+		return ""
+	}
+	return name
+}
+
+// extractFilename derives the output name from the URL path
+func extractFilename(urlStr string, config *Config) (string, error) {
+	fallback := fmt.Sprintf("download_%d", time.Now().Unix())
+
+	parsedURL, err := url.Parse(urlStr)
+	if err != nil {
+		return fallback, nil
+	}
+
+	// Directory-style URLs (trailing slash or bare host) name the index page
+	if parsedURL.Path == "" || strings.HasSuffix(parsedURL.Path, "/") {
+		if config.DefaultIndexName != "" {
+			return config.DefaultIndexName, nil
+		}
+		return fallback, nil
+	}
+
+	name := path.Base(parsedURL.Path)
+	if name == "." || name == ".." {
+		return fallback, nil
+	}
+	return sanitizeFilename(name, config.FilenameEncoding)
+}
+
+// sanitizeFilename applies the filename_encoding policy to a name that came
+// from the URL or the server. Invalid UTF-8, control characters and the
+// characters Windows refuses are never passed through; "ascii" also
+// replaces non-ASCII, "escape" percent-encodes instead of replacing and
+// "reject" fails the download.
+func sanitizeFilename(name, policy string) (string, error) {
+	var b strings.Builder
+	for i := 0; i < len(name); {
+		r, size := utf8.DecodeRuneInString(name[i:])
+		bad := (r == utf8.RuneError && size == 1) || r < 0x20 || r == 0x7f || strings.ContainsRune(`<>:"/\|?*`, r)
+		nonASCII := r > 0x7e
+
+		switch {
+		case !bad && (!nonASCII || policy == "" || policy == "keep"):
+			b.WriteString(name[i : i+size])
+		case policy == "reject":
+			return "", fmt.Errorf("filename %q contains unsupported characters", name)
+		case policy == "escape":
+			for _, c := range []byte(name[i : i+size]) {
+				fmt.Fprintf(&b, "%%%02X", c)
+			}
+		default:
+			b.WriteByte('_')
+		}
+		i += size
+	}
+	return b.String(), nil
+}
+
+// Download performs the main download operation
+func (dm *DownloadManager) Download(ctx context.Context, task *DownloadTask) (err error) {
+	task.span = dm.tracer.Start("download", nil)
+	task.span.SetString("url", task.URL)
+	if task.StartTime.IsZero() {
+		task.StartTime = time.Now()
+	}
+	dm.auth.allow(task.URL)
+	dm.notify(func(o DownloadObserver) { o.OnStart(task) })
+	task.log().Debug("download started")
+
+	defer func() {
+		err = classifyError(err)
+		task.span.End(err)
+		if err != nil {
+			task.log().Debug("download failed", "kind", errorKind(err), "err", err)
+		} else {
+			task.log().Debug("download finished", "file", task.Filepath, "bytes", task.Size, "elapsed", time.Since(task.StartTime))
+		}
+		if dm.config.SaveCookies {
+			if saveErr := dm.cookies.save(); saveErr != nil {
+				logger.Warn("could not save cookies", "err", saveErr)
+			}
+		}
+		if err != nil {
+			dm.notify(func(o DownloadObserver) { o.OnError(task, err) })
+		} else {
+			stats := task.snapshot()
+			dm.notify(func(o DownloadObserver) { o.OnComplete(task, stats) })
+		}
+	}()
+	defer dm.recoverPanic(task.URL, &err)
+
+	if dm.config.SkipDownloaded {
+		if entry, ok := dm.history.Downloaded(task.URL); ok {
+			task.Skipped = true
+			task.Filepath = entry.Path
+			task.Size = entry.Size
+			dm.bars.Printf("%sSkipped:%s %s was downloaded to %s on %s\n", ColorYellow, ColorReset,
+				task.URL, entry.Path, entry.FinishedAt.Local().Format("2006-01-02 15:04"))
+			return nil
+		}
+	}
+
+	err = dm.downloadGuarded(ctx, task)
+	if err != nil && ctx.Err() != nil && !errors.Is(err, errInterrupted) {
+		// Parts, partial files and the sidecar are left for the next run
+		var saved int64
+		if task.progress != nil {
+			saved = atomic.LoadInt64(&task.progress.Downloaded)
+		}
+		fmt.Printf("\n%sInterrupted with %s of %s done, run again to resume%s\n",
+			ColorYellow, formatBytes(saved), formatBytes(task.Size), ColorReset)
+		err = fmt.Errorf("%w: %v", errInterrupted, err)
+	}
+	return err
+}
+
+// recoverPanic turns a panic into an error for the download it happened in,
+// so one bad file can't take the rest of a batch down with it. Must be
+// deferred directly.
+func (dm *DownloadManager) recoverPanic(what string, err *error) {
+	r := recover()
+	if r == nil {
+		return
+	}
+	logger.Error("recovered from panic", "in", what, "panic", r)
+	logger.Debug("panic stack", "in", what, "stack", string(debug.Stack()))
+	*err = fmt.Errorf("%w: %v", errPanic, r)
+}
+
+func (e *throttleError) Error() string {
+	if e.wait > 0 {
+		return fmt.Sprintf("server returned %d, retry after %s", e.status, e.wait)
+	}
+	return fmt.Sprintf("server returned %d", e.status)
+}
+
+func (e *throttleError) Unwrap() error { return errThrottled }
+
+// statusError describes an unexpected response status
+func statusError(resp *http.Response) error {
+	if resp.StatusCode == http.StatusTooManyRequests || resp.StatusCode == http.StatusServiceUnavailable {
+		return &throttleError{status: resp.StatusCode, wait: retryAfter(resp.Header.Get("Retry-After"))}
+	}
+	if location := resp.Header.Get("Location"); location != "" && resp.StatusCode/100 == 3 {
+		return &DownloadError{Kind: ErrorHTTP, Status: resp.StatusCode,
+			Err: fmt.Errorf("server redirected (%d) to %s, not following", resp.StatusCode, location)}
+	}
+	return &DownloadError{Kind: ErrorHTTP, Status: resp.StatusCode, Err: fmt.Errorf("server returned %d", resp.StatusCode)}
+}
+
+func (e *DownloadError) Error() string { return e.Err.Error() }
+
+func (e *DownloadError) Unwrap() error { return e.Err }
+
+// classifyError wraps err in a DownloadError unless it already is one.
+// Sentinels are checked before the generic network and filesystem types
+// since they usually wrap one of those.
+func classifyError(err error) error {
+	if err == nil {
+		return nil
+	}
+	var de *DownloadError
+	if errors.As(err, &de) {
+		if de == err {
+			return err
+		}
+		// Keep the outer message, which has the context added on the way up
+		return &DownloadError{Kind: de.Kind, Status: de.Status, Err: err}
+	}
+
+	classified := &DownloadError{Kind: ErrorUnknown, Err: err}
+	var throttle *throttleError
+	var netErr net.Error
+	var pathErr *os.PathError
+	isNet := errors.As(err, &netErr)
+	switch {
+	case errors.Is(err, errInterrupted), errors.Is(err, context.Canceled):
+		classified.Kind = ErrorCancelled
+	case errors.Is(err, errChecksum):
+		classified.Kind = ErrorChecksum
+	case errors.Is(err, errRangeNotSupported):
+		classified.Kind = ErrorRangeNotSupported
+	case errors.Is(err, errTruncatedBody), errors.Is(err, errShortChunk):
+		classified.Kind = ErrorTruncatedBody
+	case errors.As(err, &throttle):
+		classified.Kind, classified.Status = ErrorThrottled, throttle.status
+	case errors.Is(err, errCircuitOpen), errors.Is(err, errRetryBudget):
+		classified.Kind = ErrorCircuitOpen
+	case errors.Is(err, errDiskSpace), errors.Is(err, errQuota),
+		errors.Is(err, syscall.ENOSPC), errors.Is(err, syscall.EDQUOT):
+		classified.Kind = ErrorDisk
+	case errors.Is(err, errStalled), errors.Is(err, errDNSTimeout),
+		errors.Is(err, context.DeadlineExceeded), isNet && netErr.Timeout():
+		classified.Kind = ErrorTimeout
+	case errors.Is(err, errPanic):
+		classified.Kind = ErrorInternal
+	case errors.As(err, &pathErr):
+		classified.Kind = ErrorIO
+	case isNet, errors.Is(err, io.ErrUnexpectedEOF), errors.Is(err, errRemoteChanged):
+		classified.Kind = ErrorNetwork
+	}
+	return classified
+}
+
+// errorKind is the kind of err, or "" for nil
+func errorKind(err error) ErrorKind {
+	if err == nil {
+		return ""
+	}
+	var de *DownloadError
+	if errors.As(err, &de) {
+		return de.Kind
+	}
+	return classifyError(err).(*DownloadError).Kind
+}
+
+// configError marks err as a configuration problem rather than a failed
+// transfer
+func configError(err error) error {
+	return &DownloadError{Kind: ErrorConfig, Err: err}
+}
+
+// batchError summarises a batch with failures. When nothing succeeded it
+// is the first failure itself, so the exit code still says why.
+func batchError(failed, total int, first error) error {
+	switch {
+	case failed == 0:
+		return nil
+	case total == 1:
+		return first
+	case failed == total:
+		return fmt.Errorf("all %d downloads failed, first: %w", total, first)
+	}
+	return fmt.Errorf("%w: %d of %d downloads failed", errPartial, failed, total)
+}
+
+// exitCode maps an error to the documented process exit code
+func exitCode(err error) int {
+	if errors.Is(err, errPartial) {
+		return ExitPartial
+	}
+	switch errorKind(err) {
+	case "":
+		return ExitOK
+	case ErrorConfig:
+		return ExitConfig
+	case ErrorNetwork, ErrorHTTP, ErrorTimeout, ErrorThrottled, ErrorCircuitOpen, ErrorRangeNotSupported, ErrorTruncatedBody:
+		return ExitNetwork
+	case ErrorIO, ErrorDisk:
+		return ExitIO
+	case ErrorChecksum:
+		return ExitChecksum
+	case ErrorCancelled:
+		return ExitInterrupted
+	}
+	return ExitFailure
+}
+
+// fatal is log.Fatal with the exit code for err
+func fatal(err error) {
+	log.Print(err)
+	os.Exit(exitCode(err))
+}
+
+// retryAfter parses a Retry-After value, either delay-seconds or an HTTP date
+func retryAfter(value string) time.Duration {
+	if value == "" {
+		return 0
+	}
+	if seconds, err := strconv.Atoi(value); err == nil {
+		return max(time.Duration(seconds)*time.Second, 0)
+	}
+	if when, err := http.ParseTime(value); err == nil {
+		return max(time.Until(when), 0)
+	}
+	return 0
+}
+
+// Hold blocks requests to host for d
+func (t *hostThrottle) Hold(host string, d time.Duration) {
+	if t == nil {
+		return
+	}
+	t.mu.Lock()
+	defer t.mu.Unlock()
+	if until := time.Now().Add(d); until.After(t.until[host]) {
+		t.until[host] = until
+	}
+}
+
+// Wait returns once host may be contacted again
+func (t *hostThrottle) Wait(ctx context.Context, host string) error {
+	if t == nil {
+		return nil
+	}
+	t.mu.Lock()
+	wait := time.Until(t.until[host])
+	t.mu.Unlock()
+	if wait <= 0 {
+		return nil
+	}
+
+	timer := time.NewTimer(wait)
+	defer timer.Stop()
+	select {
+	case <-timer.C:
+		return nil
+	case <-ctx.Done():
+		return ctx.Err()
+	}
+}
+
+// Acquire waits for a connection slot to host and returns its release
+func (l *hostLimiter) Acquire(ctx context.Context, host string) (func(), error) {
+	if l == nil {
+		return func() {}, nil
+	}
+	l.mu.Lock()
+	slots, ok := l.slots[host]
+	if !ok {
+		slots = make(chan struct{}, l.limit)
+		l.slots[host] = slots
+	}
+	l.mu.Unlock()
+
+	select {
+	case slots <- struct{}{}:
+		return func() { <-slots }, nil
+	case <-ctx.Done():
+		return nil, ctx.Err()
+	}
+}
+
+// retryDelay picks the wait before retrying a failed request. Rate-limited
+// responses wait out the server's Retry-After, and with host_backoff hold
+// the whole host meanwhile; everything else uses the backoff schedule.
+func (dm *DownloadManager) retryDelay(urlStr string, err error, attempt int, prev time.Duration) (time.Duration, error) {
+	var throttled *throttleError
+	if !errors.As(err, &throttled) || throttled.wait == 0 {
+		delay := dm.backoff(attempt, prev)
+		if throttled != nil {
+			dm.throttle.Hold(hostOf(urlStr), delay)
+		}
+		return delay, nil
+	}
+	if throttled.wait > MaxRetryAfter {
+		return 0, err
+	}
+	dm.throttle.Hold(hostOf(urlStr), throttled.wait)
+	return throttled.wait, nil
+}
+
+// downloadGuarded runs the download behind the host circuit breaker
+func (dm *DownloadManager) downloadGuarded(ctx context.Context, task *DownloadTask) error {
+	if dm.breaker == nil {
+		return dm.download(ctx, task)
+	}
+
+	host := hostOf(task.URL)
+	release, err := dm.breaker.Allow(host)
+	if err != nil {
+		return err
+	}
+	defer release()
+	err = dm.download(ctx, task)
+	if ctx.Err() == nil {
+		dm.breaker.Record(host, err)
+	}
+	return err
+}
+
+func (dm *DownloadManager) download(ctx context.Context, task *DownloadTask) error {
+	// Probe the primary URL, then each mirror, and download from the first
+	// one that answers
+	var info *DownloadTask
+	var err error
+	previous, conditional := dm.conditionalEntry(task)
+	sources := task.sources()
+	for i, source := range sources {
+		headers := task.Headers
+		if conditional && source == task.URL {
+			headers = conditionalHeaders(task.Headers, previous)
+		}
+		probe := dm.tracer.Start("probe", task.span)
+		probe.SetString("source", source)
+		info, err = dm.GetFileInfo(withSpan(ctx, probe), source, headers)
+		probe.End(err)
+		if errors.Is(err, errNotModified) {
+			task.Skipped = true
+			task.Filepath = previous.Path
+			task.Size = previous.Size
+			task.ETag = previous.ETag
+			task.LastModified = previous.LastModified
+			dm.bars.Printf("%sSkipped:%s %s not modified since it was downloaded to %s\n",
+				ColorYellow, ColorReset, task.URL, previous.Path)
+			return nil
+		}
+		if err == nil {
+			atomic.StoreInt32(&task.source, int32(i))
+			task.validatorURL = source
+			break
+		}
+		if i+1 < len(sources) && ctx.Err() == nil {
+			task.log().Warn("source failed, trying mirror", "source", source, "err", err, "mirror", sources[i+1])
+		}
+	}
+	if err != nil {
+		return fmt.Errorf("failed to get file info: %w", err)
+	}
+
+	if task.Size == 0 {
+		task.Size = info.Size
+	}
+	if task.Filepath == "" {
+		if task.Filepath, err = dm.outputName(task, info); err != nil {
+			return err
+		}
+	}
+	task.SupportsRange = info.SupportsRange
+	task.ETag = info.ETag
+	task.LastModified = info.LastModified
+	task.multiplexed = info.multiplexed
+	task.FinalURL, task.Redirects = info.FinalURL, info.Redirects
+	if task.RateLimit > 0 && task.limiter == nil {
+		task.limiter = dm.rateLimiter.Child(task.RateLimit)
+	}
+
+	outputPath := filepath.Join(dm.downloadDir, task.Filepath)
+	if err := os.MkdirAll(filepath.Dir(outputPath), 0755); err != nil {
+		return fmt.Errorf("failed to create directory: %w", err)
+	}
+	// Timestamping replaces an older copy whatever on_conflict says
+	replace := false
+	if dm.config.Timestamping {
+		if stat, err := os.Stat(outputPath); err == nil {
+			if !remoteNewer(task, stat) {
+				task.Skipped = true
+				dm.bars.Printf("%sSkipped:%s %s is not newer than %s\n", ColorYellow, ColorReset, task.URL, outputPath)
+				return nil
+			}
+			replace = true
+		}
+	}
+	if !replace {
+		outputPath, err = dm.resolveConflict(task, outputPath)
+		if err != nil {
+			return err
+		}
+	}
+	if task.Skipped {
+		dm.bars.Printf("%sSkipped:%s %s already exists\n", ColorYellow, ColorReset, outputPath)
+		return nil
+	}
+	// Everything up to verification happens under a temporary name, so
+	// nothing watching the output directory sees a half-written file
+	workPath := dm.workPath(outputPath)
+	if err := os.MkdirAll(filepath.Dir(workPath), 0755); err != nil {
+		return fmt.Errorf("failed to create directory: %w", err)
+	}
+
+	// The bars already show name and size for every file
+	if dm.bars == nil {
+		fmt.Printf("%sDownloading:%s %s\n", ColorGreen, ColorReset, task.sourceURL())
+		fmt.Printf("%sOutput:%s %s\n", ColorCyan, ColorReset, outputPath)
+		fmt.Printf("%sSize:%s %s\n", ColorCyan, ColorReset, formatBytes(task.Size))
+		fmt.Printf("%sRange Support:%s %v\n", ColorCyan, ColorReset, task.SupportsRange)
+		fmt.Printf("%sConnections:%s %d\n\n", ColorCyan, ColorReset, task.Chunks)
+	}
+
+	if err := dm.checkDiskSpace(workPath, task.Size); err != nil {
+		return err
+	}
+	if err := dm.claimBytes(task.Size); err != nil {
+		return err
+	}
+
+	progress := &ProgressInfo{Total: task.Size}
+	task.progress = progress
+	progressDone := make(chan bool)
+	go dm.reportProgress(ctx, task, progress, progressDone)
+
+	if dm.config.AutoTune && task.SupportsRange && task.Size >= AutoTuneMinSize {
+		task.Connections = dm.autoTune(ctx, task)
+	}
+
+	var downloadErr error
+	task.span.SetInt("size", task.Size)
+	
+	if isFTPURL(task.sourceURL()) {
+		task.span.SetString("strategy", "ftp")
+		downloadErr = dm.downloadFTP(ctx, task, workPath, progress)
+	} else if isSFTPURL(task.sourceURL()) {
+		task.span.SetString("strategy", "sftp")
+		downloadErr = dm.downloadSFTP(ctx, task, workPath, progress)
+	} else if task.SupportsRange && task.Chunks > 1 && task.Size > 0 {
+		task.span.SetString("strategy", "multi")
+		if dm.config.StripeMirrors && len(task.Mirrors) > 0 {
+			task.scheduler = newMirrorScheduler(task.sources())
+		}
+		downloadErr = dm.downloadParallel(ctx, task, workPath, progress)
+		if errors.Is(downloadErr, errRangeNotSupported) && ctx.Err() == nil {
+			// The probe advertised ranges the GETs didn't honour
+			task.log().Warn("server ignored range requests, falling back to a single stream", "err", downloadErr)
+			task.SupportsRange = false
+			task.scheduler = nil
+			atomic.StoreInt64(&progress.Downloaded, 0)
+			task.span.SetString("strategy", "single")
+			downloadErr = dm.downloadSingle(ctx, task, workPath, progress)
+		}
+	} else {
+		task.span.SetString("strategy", "single")
+		downloadErr = dm.downloadSingle(ctx, task, workPath, progress)
+	}
+
+	close(progressDone)
+	task.span.SetInt("bytes", atomic.LoadInt64(&progress.Downloaded))
+	
+	if downloadErr != nil {
+		dm.claimBytes(-task.Size)
+		return downloadErr
+	}
+	if task.Size == 0 {
+		// Unknown lengths are only counted once they are known
+		dm.claimBytes(atomic.LoadInt64(&progress.Downloaded))
+	}
+
+	// Verify checksums
+	if dm.verifyHashes {
+		if err := dm.phase(task, "verify", func() error { return dm.verifyChecksums(workPath, task) }); err != nil {
+			return err
+		}
+	}
+
+	// Verify detached signature, never keep a file that fails it
+	if task.SignatureURL != "" {
+		if err := dm.phase(task, "signature", func() error { return dm.verifySignature(ctx, workPath, task) }); err != nil {
+			dm.removeOutput(workPath)
+			return err
+		}
+	}
+
+	for _, validator := range dm.validators {
+		if err := validator.Validate(workPath); err != nil {
+			dm.removeOutput(workPath)
+			return fmt.Errorf("content validation failed: %w", err)
+		}
+	}
+
+	if err := dm.phase(task, "commit", func() error { return dm.commitOutput(workPath, outputPath) }); err != nil {
+		return fmt.Errorf("failed to move download into place: %w", err)
+	}
+	if dm.config.Timestamping {
+		if modified, err := http.ParseTime(task.LastModified); err == nil {
+			if err := os.Chtimes(outputPath, time.Now(), modified); err != nil {
+				logger.Warn("could not set modification time", "file", outputPath, "err", err)
+			}
+		}
+	}
+
+	if dm.config.Extract && archiveKind(outputPath) != "" {
+		if err := dm.phase(task, "extract", func() error { return extractArchive(outputPath) }); err != nil {
+			return fmt.Errorf("extraction failed: %w", err)
+		}
+	}
+
+	duration := time.Since(task.StartTime)
+	avgSpeed := float64(task.Size) / duration.Seconds() / 1024 / 1024
+	if dm.bars != nil {
+		dm.bars.Printf("%s✓ %s completed in %s (avg %.2f MB/s)%s\n",
+			ColorGreen, task.Filepath, duration.Round(time.Second), avgSpeed, ColorReset)
+		return nil
+	}
+	fmt.Printf("\n%s✓ Download completed in %s (avg %.2f MB/s)%s\n", 
+		ColorGreen, duration.Round(time.Second), avgSpeed, ColorReset)
+	for _, stat := range task.MirrorStats() {
+		fmt.Printf("  %s%s%s %d chunks, %s, %.2f MB/s\n",
+			ColorCyan, stat.URL, ColorReset, stat.Chunks, formatBytes(stat.Bytes), stat.Speed()/1024/1024)
+	}
+
+	return nil
+}
+
+// phase runs one step after the transfer, such as merging or verifying,
+// under its own span
+func (dm *DownloadManager) phase(task *DownloadTask, name string, step func() error) error {
+	span := dm.tracer.Start(name, task.span)
+	if span != nil {
+		span.data.Kind = 1 // internal
+	}
+	err := step()
+	span.End(err)
+	return err
+}
+
+// downloadParallel handles multi-threaded downloads
+func (dm *DownloadManager) downloadParallel(ctx context.Context, task *DownloadTask, outputPath string, progress *ProgressInfo) error {
+	numChunks := dm.chunkCount(task)
+	chunkSize := task.Size / int64(numChunks)
+	var chunks []ChunkInfo
+	for start := int64(0); start < task.Size; {
+		end := start + chunkSize - 1
+		if len(chunks) == numChunks-1 {
+			end = task.Size - 1
+		}
+		end = task.pieces.alignEnd(end, task.Size)
+		chunks = append(chunks, ChunkInfo{
+			ID:    len(chunks),
+			Start: start,
+			End:   end,
+			Path:  fmt.Sprintf("%s.part%d", outputPath, len(chunks)),
+		})
+		start = end + 1
+	}
+	numChunks = len(chunks)
+	progress.Chunks = int32(numChunks)
+
+	// Tee copies are written during the merge, which a shared output skips
+	if dm.config.UseMmap && len(dm.config.TeeDirs) == 0 {
+		output, err := dm.mapOutput(outputPath, task.Size)
+		if err != nil {
+			task.log().Warn("mmap unavailable, using file I/O", "err", err)
+		} else {
+			return dm.downloadShared(ctx, task, output, chunks, progress)
+		}
+	}
+	if (dm.config.SharedOutput || dm.config.IOUring) && len(dm.config.TeeDirs) == 0 {
+		output, err := dm.openShared(outputPath, task.Size)
+		if err != nil {
+			return err
+		}
+		return dm.downloadShared(ctx, task, output, chunks, progress)
+	}
+
+	if dm.resume {
+		chunks = dm.prepareResume(outputPath, task, chunks)
+		numChunks = len(chunks)
+		progress.Chunks = int32(numChunks)
+	}
+
+	workers := dm.maxWorkers
+	if task.Connections > 0 {
+		workers = task.Connections
+	}
+
+	if dm.config.Preconnect {
+		dm.preconnect(ctx, task, min(workers, numChunks))
+	}
+
+	table := newChunkTable(chunks, outputPath)
+	table.pieces = task.pieces
+	var hasher *prefixHasher
+	if dm.verifyHashes {
+		hasher = newPrefixHasher(table, task, task.hashAlgorithms())
+	}
+
+	errorChan := make(chan error, len(chunks))
+	dm.runWorkers(ctx, task, table, errorChan, progress, hasher, workers)
+	close(errorChan)
+	hasher.Close()
+	chunks = table.ordered()
+
+	for err := range errorChan {
+		if errors.Is(err, errRemoteChanged) && task.IfRange != "" {
+			// Parts from the previous session are stale, and so are the
+			// size and validators the chunks were laid out from. Probe
+			// again and start over once.
+			fmt.Printf("\n%sRemote file changed, restarting download%s\n", ColorYellow, ColorReset)
+			discardParts(outputPath, chunks)
+			info, err := dm.GetFileInfo(ctx, task.sourceURL(), task.Headers)
+			if err != nil {
+				return fmt.Errorf("failed to get file info: %w", err)
+			}
+			task.IfRange = ""
+			task.Size, task.SupportsRange = info.Size, info.SupportsRange
+			task.ETag, task.LastModified = info.ETag, info.LastModified
+			atomic.StoreInt64(&progress.Total, task.Size)
+			atomic.StoreInt64(&progress.Downloaded, 0)
+			if !task.SupportsRange || task.Size <= 0 {
+				// The caller starts over on a single stream
+				return errRangeNotSupported
+			}
+			return dm.downloadParallel(ctx, task, outputPath, progress)
+		}
+		if errors.Is(err, errRangeNotSupported) {
+			// The caller starts over on a single stream
+			discardParts(outputPath, chunks)
+			return err
+		}
+		if err != nil {
+			return err
+		}
+	}
+
+	// Parts must outlive the hasher, so wait for it before merging
+	if sums, err := hasher.Wait(); err == nil {
+		task.sums = sums
+	}
+
+	if err := dm.phase(task, "merge", func() error { return dm.mergeChunks(outputPath, chunks) }); err != nil {
+		return err
+	}
+	os.Remove(resumeStatePath(outputPath))
+	return nil
+}
+
+func newPrefixHasher(table *chunkTable, task *DownloadTask, algorithms []string) *prefixHasher {
+	h := &prefixHasher{
+		table:  table,
+		hashes: make(map[string]hash.Hash),
+		output: task.output,
+		done:   make(chan int, len(table.chunks)),
+		result: make(chan error, 1),
+	}
+
+	var writers []io.Writer
+	for _, algorithm := range algorithms {
+		hh, err := newHash(algorithm)
+		if err != nil {
+			continue // reported by verifyChecksums
+		}
+		h.hashes[algorithm] = hh
+		writers = append(writers, hh)
+	}
+	h.writer = io.MultiWriter(writers...)
+
+	go h.run()
+	return h
+}
+
+// Done marks a chunk's part file as complete
+func (h *prefixHasher) Done(id int) {
+	if h != nil {
+		h.done <- id
+	}
+}
+
+// Close signals that no more chunks will complete
+func (h *prefixHasher) Close() {
+	if h != nil {
+		close(h.done)
+	}
+}
+
+// Wait returns the hex digests once every chunk has been hashed
+func (h *prefixHasher) Wait() (map[string]string, error) {
+	if h == nil {
+		return nil, fmt.Errorf("no hasher")
+	}
+	if err := <-h.result; err != nil {
+		return nil, err
+	}
+	sums := make(map[string]string)
+	for algorithm, hh := range h.hashes {
+		sums[algorithm] = hex.EncodeToString(hh.Sum(nil))
+	}
+	return sums, nil
+}
+
+// run hashes chunks in file order. Only unfinished chunks get split, and
+// their tails land after them, so positions before next never move.
+func (h *prefixHasher) run() {
+	complete := make(map[int]bool)
+	next := 0
+	for id := range h.done {
+		complete[id] = true
+		chunks := h.table.ordered()
+		for next < len(chunks) && complete[chunks[next].ID] {
+			if err := h.hashChunk(chunks[next]); err != nil {
+				h.result <- err
+				// Keep Done from blocking the workers
+				for range h.done {
+				}
+				return
+			}
+			next++
+		}
+		if next == len(chunks) {
+			h.result <- nil
+			return
+		}
+	}
+	h.result <- fmt.Errorf("chunk %d never completed", next)
+}
+
+func (h *prefixHasher) hashChunk(chunk ChunkInfo) error {
+	if h.output != nil {
+		_, err := io.Copy(h.writer, h.output.section(chunk))
+		return err
+	}
+
+	file, err := os.Open(chunk.Path)
+	if err != nil {
+		return err
+	}
+	defer file.Close()
+	_, err = io.Copy(h.writer, file)
+	return err
+}
+
+// prepareResume compares the saved state with the remote file. Matching
+// parts are kept and chunk requests carry If-Range so a change made after
+// this check still can't be spliced into the old parts.
+func (dm *DownloadManager) prepareResume(outputPath string, task *DownloadTask, chunks []ChunkInfo) []ChunkInfo {
+	previous, err := loadResumeState(outputPath)
+	// Tails split off slow chunks change the layout; reuse it so their
+	// parts still line up
+	if err == nil && previous.Size == task.Size && len(previous.Ranges) > len(chunks) {
+		if layout := chunksFromRanges(outputPath, previous.Ranges, task.Size); layout != nil {
+			chunks = layout
+		}
+	}
+
+	state := &ResumeState{
+		URL:          task.URL,
+		Size:         task.Size,
+		Chunks:       len(chunks),
+		ETag:         task.ETag,
+		LastModified: task.LastModified,
+	}
+	for _, chunk := range chunks {
+		state.Ranges = append(state.Ranges, [2]int64{chunk.Start, chunk.End})
+	}
+
+	if err == nil {
+		if previous.matches(state) {
+			task.IfRange = previous.validator()
+			state.Completed = previous.Completed
+			if len(state.Completed) > 0 {
+				fmt.Printf("%sResuming:%s %d/%d chunks already complete\n", ColorCyan, ColorReset, len(state.Completed), len(chunks))
+			}
+		} else {
+			for id := len(chunks); id < previous.Chunks; id++ {
+				os.Remove(fmt.Sprintf("%s.part%d", outputPath, id))
+			}
+			discardParts(outputPath, chunks)
+		}
+	}
+
+	state.outputPath = outputPath
+	task.resumeState = state
+	if err := saveResumeState(outputPath, state); err != nil {
+		logger.Warn("could not save resume state", "err", err)
+	}
+	return chunks
+}
+
+// chunksFromRanges rebuilds a saved chunk layout, or returns nil unless the
+// ranges cover the file exactly
+func chunksFromRanges(outputPath string, ranges [][2]int64, size int64) []ChunkInfo {
+	chunks := make([]ChunkInfo, len(ranges))
+	for id, r := range ranges {
+		chunks[id] = ChunkInfo{ID: id, Start: r[0], End: r[1], Path: fmt.Sprintf("%s.part%d", outputPath, id)}
+	}
+
+	sorted := slices.Clone(chunks)
+	sort.Slice(sorted, func(i, j int) bool { return sorted[i].Start < sorted[j].Start })
+	var next int64
+	for _, chunk := range sorted {
+		if chunk.Start != next || chunk.End < chunk.Start {
+			return nil
+		}
+		next = chunk.End + 1
+	}
+	if next != size {
+		return nil
+	}
+	return chunks
+}
+
+func resumeStatePath(outputPath string) string {
+	return outputPath + ".fastdl"
+}
+
+func loadResumeState(outputPath string) (*ResumeState, error) {
+	data, err := os.ReadFile(resumeStatePath(outputPath))
+	if err != nil {
+		return nil, err
+	}
+	var state ResumeState
+	if err := json.Unmarshal(data, &state); err != nil {
+		return nil, err
+	}
+	return &state, nil
+}
+
+func saveResumeState(outputPath string, state *ResumeState) error {
+	data, err := json.Marshal(state)
+	if err != nil {
+		return err
+	}
+	// Write then rename so a crash never leaves a torn sidecar
+	tmp := resumeStatePath(outputPath) + ".tmp"
+	if err := os.WriteFile(tmp, data, 0644); err != nil {
+		return err
+	}
+	return os.Rename(tmp, resumeStatePath(outputPath))
+}
+
+// isComplete reports whether a previous run recorded the chunk as finished
+func (s *ResumeState) isComplete(id int) bool {
+	if s == nil {
+		return false
+	}
+	s.mu.Lock()
+	defer s.mu.Unlock()
+	for _, done := range s.Completed {
+		if done == id {
+			return true
+		}
+	}
+	return false
+}
+
+// markComplete records a finished chunk and persists the sidecar so an
+// interrupted run only re-requests what is missing
+func (s *ResumeState) markComplete(id int) {
+	if s == nil {
+		return
+	}
+	s.mu.Lock()
+	defer s.mu.Unlock()
+	for _, done := range s.Completed {
+		if done == id {
+			return
+		}
+	}
+	s.Completed = append(s.Completed, id)
+	if err := saveResumeState(s.outputPath, s); err != nil {
+		logger.Warn("could not save resume state", "err", err)
+	}
+}
+
+// flush persists the sidecar as it stands, so a process stopped while
+// paused keeps every chunk finished so far
+func (s *ResumeState) flush() {
+	if s == nil {
+		return
+	}
+	s.mu.Lock()
+	defer s.mu.Unlock()
+	if err := saveResumeState(s.outputPath, s); err != nil {
+		logger.Warn("could not save resume state", "err", err)
+	}
+}
+
+// split records a chunk cut short at end and the new chunk for its tail
+func (s *ResumeState) split(id int, end int64, tail ChunkInfo) {
+	if s == nil {
+		return
+	}
+	s.mu.Lock()
+	defer s.mu.Unlock()
+	s.Ranges[id][1] = end
+	s.Ranges = append(s.Ranges, [2]int64{tail.Start, tail.End})
+	s.Chunks = len(s.Ranges)
+	if err := saveResumeState(s.outputPath, s); err != nil {
+		logger.Warn("could not save resume state", "err", err)
+	}
+}
+
+// matches reports whether parts written under s are valid for current
+func (s *ResumeState) matches(current *ResumeState) bool {
+	if s.Size != current.Size || s.Chunks != current.Chunks {
+		return false
+	}
+	// Sidecars from before ranges were recorded only have the count to go on
+	if s.Ranges != nil && !slices.Equal(s.Ranges, current.Ranges) {
+		return false
+	}
+	if s.ETag != "" && current.ETag != "" && s.ETag != current.ETag {
+		return false
+	}
+	if s.LastModified != "" && current.LastModified != "" && s.LastModified != current.LastModified {
+		return false
+	}
+	return true
+}
+
+// validator picks the If-Range value; weak ETags are not allowed there
+func (s *ResumeState) validator() string {
+	if s.ETag != "" && !strings.HasPrefix(s.ETag, "W/") {
+		return s.ETag
+	}
+	return s.LastModified
+}
+
+// discardParts removes part files and the resume state of a download
+func discardParts(outputPath string, chunks []ChunkInfo) {
+	for _, chunk := range chunks {
+		os.Remove(chunk.Path)
+	}
+	os.Remove(resumeStatePath(outputPath))
+}
+
+func newChunkTable(chunks []ChunkInfo, outputPath string) *chunkTable {
+	for i := range chunks {
+		chunks[i].live = &chunkProgress{end: chunks[i].End}
+	}
+	return &chunkTable{chunks: chunks, outputPath: outputPath}
+}
+
+// ordered returns the chunks sorted by offset with their current ends
+func (t *chunkTable) ordered() []ChunkInfo {
+	t.mu.Lock()
+	chunks := slices.Clone(t.chunks)
+	t.mu.Unlock()
+
+	for i := range chunks {
+		chunks[i].End = chunks[i].length() + chunks[i].Start - 1
+	}
+	sort.Slice(chunks, func(i, j int) bool { return chunks[i].Start < chunks[j].Start })
+	return chunks
+}
+
+// steal splits the running chunk with the longest expected time left and
+// returns its second half as a new chunk
+func (t *chunkTable) steal(state *ResumeState) (ChunkInfo, bool) {
+	t.mu.Lock()
+	defer t.mu.Unlock()
+
+	victim, worst := -1, 0.0
+	for i, chunk := range t.chunks {
+		live := chunk.live
+		live.mu.Lock()
+		remaining := live.end - chunk.Start + 1 - live.written
+		elapsed := time.Since(live.started).Seconds()
+		started := !live.started.IsZero()
+		written := live.written
+		live.mu.Unlock()
+
+		if !started || remaining < 2*StealMinSize {
+			continue
+		}
+		left := math.Inf(1)
+		if written > 0 {
+			left = float64(remaining) / (float64(written) / elapsed)
+		}
+		if victim < 0 || left > worst {
+			victim, worst = i, left
+		}
+	}
+	if victim < 0 {
+		return ChunkInfo{}, false
+	}
+
+	chunk := t.chunks[victim]
+	live := chunk.live
+	live.mu.Lock()
+	defer live.mu.Unlock()
+	// The owner kept writing since the scan
+	remaining := live.end - chunk.Start + 1 - live.written
+	if remaining < 2*StealMinSize {
+		return ChunkInfo{}, false
+	}
+	mid := chunk.Start + live.written + remaining/2
+	if t.pieces != nil {
+		mid = t.pieces.alignEnd(mid-1, math.MaxInt64) + 1
+		if mid > live.end {
+			return ChunkInfo{}, false
+		}
+	}
+	tail := ChunkInfo{
+		ID:    len(t.chunks),
+		Start: mid,
+		End:   live.end,
+		Path:  fmt.Sprintf("%s.part%d", t.outputPath, len(t.chunks)),
+		live:  &chunkProgress{end: live.end},
+	}
+	live.end = mid - 1
+	t.chunks[victim].End = mid - 1
+	t.chunks = append(t.chunks, tail)
+	state.split(chunk.ID, mid-1, tail)
+	return tail, true
+}
+
+// restart resets the chunk for a new attempt and returns its current end
+func (c ChunkInfo) restart() int64 {
+	if c.live == nil {
+		return c.End
+	}
+	c.live.mu.Lock()
+	defer c.live.mu.Unlock()
+	c.live.written = 0
+	c.live.started = time.Now()
+	return c.live.end
+}
+
+// claim records n freshly read bytes and returns how many still belong to
+// the chunk
+func (c ChunkInfo) claim(n int) int {
+	if c.live == nil {
+		return n
+	}
+	c.live.mu.Lock()
+	defer c.live.mu.Unlock()
+	n = int(min(int64(n), c.live.end-c.Start+1-c.live.written))
+	c.live.written += int64(n)
+	return n
+}
+
+// length is the chunk's current size in bytes
+func (c ChunkInfo) length() int64 {
+	if c.live == nil {
+		return c.End - c.Start + 1
+	}
+	c.live.mu.Lock()
+	defer c.live.mu.Unlock()
+	return c.live.end - c.Start + 1
+}
+
+// chunkCount decides how many pieces to split a download into. With the
+// chunk queue enabled the file is cut into ChunkSize pieces that the
+// connection workers pull from, so chunks can outnumber connections.
+func (dm *DownloadManager) chunkCount(task *DownloadTask) int {
+	count := task.Chunks
+	if dm.config.ChunkQueue && dm.config.ChunkSize > 0 {
+		bySize := (task.Size + dm.config.ChunkSize - 1) / dm.config.ChunkSize
+		count = max(count, int(min(bySize, MaxChunkCount)))
+	}
+	if int64(count) > task.Size {
+		count = int(task.Size)
+	}
+	return max(count, 1)
+}
+
+// autoTune fetches small ranges with 1, 2, 4 and 8 connections and returns
+// the count with the best aggregate throughput
+func (dm *DownloadManager) autoTune(ctx context.Context, task *DownloadTask) int {
+	fmt.Printf("%sAuto-tune:%s probing connection counts...", ColorCyan, ColorReset)
+
+	best, bestSpeed := 1, 0.0
+	offset := int64(0)
+	stride := task.Size / 16
+
+	for _, n := range []int{1, 2, 4, 8} {
+		if n > dm.maxWorkers {
+			break
+		}
+
+		var received int64
+		var wg sync.WaitGroup
+		start := time.Now()
+		for i := 0; i < n; i++ {
+			wg.Add(1)
+			go func(rangeStart int64) {
+				defer wg.Done()
+				atomic.AddInt64(&received, dm.fetchSample(ctx, task, rangeStart, rangeStart+AutoTuneSampleSize-1))
+			}(offset)
+			offset = (offset + stride) % (task.Size - AutoTuneSampleSize)
+		}
+		wg.Wait()
+
+		speed := float64(received) / time.Since(start).Seconds()
+		if speed > bestSpeed {
+			best, bestSpeed = n, speed
+		}
+	}
+
+	fmt.Printf(" %d connections (%.2f MB/s)\n", best, bestSpeed/1024/1024)
+	return best
+}
+
+// fetchSample reads a byte range and discards it, returning bytes received
+func (dm *DownloadManager) fetchSample(ctx context.Context, task *DownloadTask, start, end int64) int64 {
+	req, err := http.NewRequestWithContext(ctx, "GET", task.sourceURL(), nil)
+	if err != nil {
+		return 0
+	}
+	req.Header.Set("Range", fmt.Sprintf("bytes=%d-%d", start, end))
+	dm.prepareRequest(req, task.Headers)
+
+	resp, err := dm.client.Do(req)
+	if err != nil {
+		return 0
+	}
+	defer resp.Body.Close()
+
+	if resp.StatusCode != http.StatusPartialContent {
+		return 0
+	}
+	n, _ := io.Copy(io.Discard, resp.Body)
+	return n
+}
+
+// preconnect warms the connection pool with one handshake per worker. Over
+// HTTP/2 the requests share one connection, so the report counts the
+// distinct connections they actually got rather than the requests sent.
+func (dm *DownloadManager) preconnect(ctx context.Context, task *DownloadTask, count int) {
+	start := time.Now()
+	var mu sync.Mutex
+	conns := make(map[net.Conn]bool)
+	opened := 0
+	ctx = httptrace.WithClientTrace(ctx, &httptrace.ClientTrace{
+		GotConn: func(info httptrace.GotConnInfo) {
+			mu.Lock()
+			defer mu.Unlock()
+			conns[info.Conn] = true
+			if !info.Reused {
+				opened++
+			}
+		},
+	})
+
+	var wg sync.WaitGroup
+	for i := 0; i < count; i++ {
+		wg.Add(1)
+		go func() {
+			defer wg.Done()
+
+			req, err := http.NewRequestWithContext(ctx, "HEAD", task.sourceURL(), nil)
+			if err != nil {
+				return
+			}
+			dm.prepareRequest(req, task.Headers)
+
+			resp, err := dm.client.Do(req)
+			if err != nil {
+				return
+			}
+			io.Copy(io.Discard, resp.Body)
+			resp.Body.Close()
+		}()
+	}
+	wg.Wait()
+
+	fmt.Printf("%sPreconnect:%s %d connections ready for %d workers (%d newly opened) in %s\n",
+		ColorCyan, ColorReset, len(conns), count, opened, time.Since(start).Round(time.Millisecond))
+}
+
+// runWorkers queues the chunk table and waits for the workers to drain it.
+// With adaptive_connections the pool starts at AdaptiveStart and only grows
+// towards workers while that pays off.
+func (dm *DownloadManager) runWorkers(ctx context.Context, task *DownloadTask, table *chunkTable, errs chan<- error, progress *ProgressInfo, hasher *prefixHasher, workers int) {
+	chunks := make(chan ChunkInfo, len(table.chunks))
+	for _, chunk := range table.chunks {
+		chunks <- chunk
+	}
+	close(chunks)
+	workers = min(workers, len(table.chunks))
+	// Pipelined requests count against the connection budget too
+	if depth := dm.pipelineDepth(task); depth > 1 {
+		workers = min(workers, max(dm.maxWorkers/depth, 1))
+	}
+
+	var wg sync.WaitGroup
+	start := func() {
+		wg.Add(1)
+		go dm.downloadWorker(ctx, &wg, task, table, chunks, errs, progress, hasher)
+	}
+
+	if !dm.config.AdaptiveConns || workers <= AdaptiveStart {
+		for i := 0; i < workers; i++ {
+			start()
+		}
+		wg.Wait()
+		return
+	}
+
+	tuner := &connTuner{max: workers, idle: make(chan struct{})}
+	task.tuner = tuner
+	defer func() { task.tuner = nil }()
+	tuner.grow(AdaptiveStart, start)
+	dm.tuneConnections(ctx, tuner, progress, start)
+	wg.Wait()
+}
+
+// tuneConnections samples throughput every AdaptiveInterval until the
+// workers are done. Each step doubles the pool; the first one that doesn't
+// raise throughput by AdaptiveGain is retired again and the count holds.
+func (dm *DownloadManager) tuneConnections(ctx context.Context, t *connTuner, progress *ProgressInfo, start func()) {
+	ticker := time.NewTicker(AdaptiveInterval)
+	defer ticker.Stop()
+
+	last := atomic.LoadInt64(&progress.Downloaded)
+	var lastSpeed float64
+	added := 0
+	settled := false
+	for {
+		select {
+		case <-t.idle:
+			return
+		case <-ticker.C:
+		}
+		downloaded := atomic.LoadInt64(&progress.Downloaded)
+		speed := float64(downloaded-last) / AdaptiveInterval.Seconds()
+		last = downloaded
+		if settled || ctx.Err() != nil || dm.Paused() {
+			continue
+		}
+
+		if added > 0 && speed < lastSpeed*AdaptiveGain {
+			t.shrink(added)
+			settled = true
+			task.log().Debug("more connections didn't help, holding", "added", added)
+			continue
+		}
+		lastSpeed = speed
+		if added = t.grow(t.size(), start); added == 0 {
+			settled = true
+		}
+	}
+}
+
+// grow starts up to n more workers without passing max
+func (t *connTuner) grow(n int, start func()) int {
+	t.mu.Lock()
+	defer t.mu.Unlock()
+	if t.stopped {
+		return 0
+	}
+	n = min(n, t.max-t.active)
+	for i := 0; i < n; i++ {
+		t.active++
+		start()
+	}
+	return n
+}
+
+// shrink asks n workers to stop once their current chunk is done
+func (t *connTuner) shrink(n int) {
+	t.mu.Lock()
+	defer t.mu.Unlock()
+	t.retire += n
+}
+
+func (t *connTuner) size() int {
+	t.mu.Lock()
+	defer t.mu.Unlock()
+	return t.active - t.retire
+}
+
+// retiring reports whether the calling worker should stop
+func (t *connTuner) retiring() bool {
+	if t == nil {
+		return false
+	}
+	t.mu.Lock()
+	defer t.mu.Unlock()
+	if t.retire > 0 && t.active > 1 {
+		t.retire--
+		return true
+	}
+	return false
+}
+
+// exit records a worker leaving; the last one closes idle
+func (t *connTuner) exit() {
+	if t == nil {
+		return
+	}
+	t.mu.Lock()
+	defer t.mu.Unlock()
+	t.active--
+	if t.active == 0 {
+		t.stopped = true
+		close(t.idle)
+	}
+}
+
+// downloadWorker handles individual chunk downloads
+func (dm *DownloadManager) downloadWorker(ctx context.Context, wg *sync.WaitGroup, task *DownloadTask, table *chunkTable, chunks <-chan ChunkInfo, errs chan<- error, progress *ProgressInfo, hasher *prefixHasher) {
+	defer wg.Done()
+	defer task.tuner.exit()
+
+	// Once the queue is empty an idle worker splits the slowest chunk still
+	// running and takes its second half, instead of waiting on the tail
+	next := func() (ChunkInfo, bool) {
+		if chunk, ok := <-chunks; ok || !dm.config.WorkStealing {
+			return chunk, ok
+		}
+		chunk, ok := table.steal(task.resumeState)
+		if ok {
+			atomic.AddInt32(&progress.Chunks, 1)
+		}
+		return chunk, ok
+	}
+
+	// With pipeline_depth > 1 the worker requests its next chunks before the
+	// current one finishes, which hides round trips on high-latency links
+	depth := dm.pipelineDepth(task)
+	slots := make(chan struct{}, depth)
+	var inflight sync.WaitGroup
+	var failed int32
+
+	for {
+		slots <- struct{}{}
+		// Stop taking chunks once cancelled or failed; queued ones are dropped
+		if atomic.LoadInt32(&failed) != 0 || ctx.Err() != nil || task.tuner.retiring() {
+			<-slots
+			break
+		}
+		chunk, ok := next()
+		if !ok {
+			<-slots
+			break
+		}
+
+		inflight.Add(1)
+		go func(chunk ChunkInfo) {
+			var err error
+			defer func() {
+				if err != nil {
+					atomic.StoreInt32(&failed, 1)
+					// Stolen tails can outnumber the buffer, and one error
+					// is enough to fail the download
+					select {
+					case errs <- err:
+					default:
+					}
+				}
+				<-slots
+				inflight.Done()
+			}()
+			defer dm.recoverPanic(task.URL, &err)
+
+			err = dm.fetchChunk(ctx, task, chunk, progress, hasher)
+		}(chunk)
+	}
+	inflight.Wait()
+}
+
+// pipelineDepth is how many chunk requests each worker keeps outstanding.
+// Only HTTP/2 multiplexes them over the worker's connection; over HTTP/1.1
+// each one would open another connection past max_connections, so there
+// it's always 1. It never exceeds the connection budget either.
+func (dm *DownloadManager) pipelineDepth(task *DownloadTask) int {
+	if !task.multiplexed {
+		return 1
+	}
+	return min(max(dm.config.PipelineDepth, 1), max(dm.maxWorkers, 1))
+}
+
+// fetchChunk downloads one chunk, retrying with backoff, and only returns an
+// error once the chunk has been given up on
+func (dm *DownloadManager) fetchChunk(ctx context.Context, task *DownloadTask, chunk ChunkInfo, progress *ProgressInfo, hasher *prefixHasher) error {
+	atomic.AddInt32(&progress.Active, 1)
+	defer atomic.AddInt32(&progress.Active, -1)
+	chunkLog := task.log().With("chunk", chunk.ID, "range", fmt.Sprintf("%d-%d", chunk.Start, chunk.End))
+
+	var delay time.Duration
+	var refreshes int
+	for retry := 0; retry < dm.config.MaxRetries; retry++ {
+		if err := dm.waitIfPaused(ctx); err != nil {
+			return err
+		}
+		source := atomic.LoadInt32(&task.source)
+		client := dm.client
+		if dm.config.FreshConnRetry > 0 && retry >= dm.config.FreshConnRetry {
+			client = dm.freshClient
+		}
+		span := dm.tracer.Start("chunk", task.span)
+		span.SetInt("chunk", int64(chunk.ID))
+		span.SetInt("range_start", chunk.Start)
+		span.SetInt("range_end", chunk.End)
+		span.SetInt("attempt", int64(retry))
+		dm.notify(func(o DownloadObserver) { o.OnChunkStart(task, chunk, retry) })
+		sourceURL, mirror := task.sourceURL(), -1
+		if task.scheduler != nil {
+			mirror, sourceURL = task.scheduler.pick()
+		}
+		sourceURL = dm.currentURL(sourceURL)
+		span.SetString("source", sourceURL)
+		started := time.Now()
+		err := dm.downloadChunk(withSpan(ctx, span), client, task, sourceURL, chunk, progress)
+		if errors.Is(err, errPaused) && ctx.Err() == nil {
+			// Not a failure: the connection and host slot are given back
+			// and the chunk starts over once resumed
+			span.End(err)
+			task.resumeState.flush()
+			retry--
+			continue
+		}
+		if err == nil {
+			err = task.pieces.verify(task, chunk)
+		}
+		task.scheduler.done(mirror, chunk.End-chunk.Start+1, time.Since(started), err)
+		span.End(err)
+		chunkLog.Debug("chunk attempt", "attempt", retry, "source", sourceURL, "elapsed", time.Since(started), "err", err)
+		host := hostOf(sourceURL)
+		if ctx.Err() == nil && !errors.Is(err, errChecksum) {
+			// Every request counts, so a dead host trips the breaker
+			// without each download failing on its own first
+			dm.breaker.Record(host, err)
+		}
+		if err == nil {
+			atomic.AddInt32(&progress.ChunksDone, 1)
+			task.resumeState.markComplete(chunk.ID)
+			hasher.Done(chunk.ID)
+			dm.notify(func(o DownloadObserver) { o.OnChunkComplete(task, chunk) })
+			return nil
+		}
+		if errors.Is(err, errRemoteChanged) || errors.Is(err, errRangeNotSupported) || ctx.Err() != nil {
+			return err
+		}
+		// An expired URL is swapped for a fresh one and the chunk retried
+		// right away; finished chunks are kept
+		if refreshes < dm.config.MaxRetries && dm.expired(task, err) {
+			refreshErr := dm.refreshURL(ctx, task, sourceURL)
+			if refreshErr == nil {
+				refreshes++
+				retry, delay = retry-1, 0
+				continue
+			}
+			chunkLog.Warn("could not refresh expired URL", "err", refreshErr)
+		}
+		// A stalled source is likely to stall again, so move on right away
+		if errors.Is(err, errStalled) && task.scheduler == nil && task.failover(source) {
+			chunkLog.Warn("chunk stalled, switching to mirror", "err", err, "mirror", task.sourceURL())
+			retry, delay = -1, 0
+			continue
+		}
+		if retry == dm.config.MaxRetries-1 {
+			if task.scheduler == nil && task.failover(source) {
+				chunkLog.Warn("chunk out of retries, switching to mirror", "err", err, "mirror", task.sourceURL())
+				retry, delay = -1, 0
+				continue
+			}
+			return fmt.Errorf("chunk %d failed after %d retries: %w", chunk.ID, dm.config.MaxRetries, err)
+		}
+		if openErr := dm.breaker.Check(host); openErr != nil && task.scheduler == nil {
+			if task.failover(source) {
+				retry, delay = -1, 0
+				continue
+			}
+			return fmt.Errorf("chunk %d: %w (last error: %v)", chunk.ID, openErr, err)
+		}
+		if !dm.retries.Spend(host) {
+			return fmt.Errorf("chunk %d: %w for %s (last error: %v)", chunk.ID, errRetryBudget, host, err)
+		}
+		dm.notify(func(o DownloadObserver) { o.OnRetry(task, chunk, retry+1, err) })
+		if delay, err = dm.retryDelay(sourceURL, err, retry, delay); err != nil {
+			return fmt.Errorf("chunk %d: %w", chunk.ID, err)
+		}
+		wait := dm.tracer.Start("retry_wait", task.span)
+		wait.SetInt("chunk", int64(chunk.ID))
+		wait.SetInt("attempt", int64(retry+1))
+		wait.SetInt("delay_ms", delay.Milliseconds())
+		select {
+		case <-time.After(delay):
+			wait.End(nil)
+		case <-ctx.Done():
+			wait.End(ctx.Err())
+			return ctx.Err()
+		}
+	}
+	return nil
+}
+
+// backoff returns the wait before the next retry. Without a jitter strategy
+// the configured delay is used as-is; the strategies follow the AWS
+// "Exponential Backoff And Jitter" variants capped at MaxRetryDelay.
+func (dm *DownloadManager) backoff(attempt int, prev time.Duration) time.Duration {
+	base := time.Duration(dm.config.RetryDelay) * time.Second
+	if base <= 0 {
+		base = RetryDelay
+	}
+
+	exp := base << min(attempt, 16)
+	if exp > MaxRetryDelay || exp <= 0 {
+		exp = MaxRetryDelay
+	}
+
+	switch dm.config.JitterStrategy {
+	case "full":
+		return time.Duration(rand.Int63n(int64(exp) + 1))
+	case "equal":
+		return exp/2 + time.Duration(rand.Int63n(int64(exp/2)+1))
+	case "decorrelated":
+		upper := max(prev*3, base)
+		delay := base + time.Duration(rand.Int63n(int64(upper-base)+1))
+		return min(delay, MaxRetryDelay)
+	default:
+		return base
+	}
+}
+
+// currentURL follows source through the URLs the refresh hook replaced it
+// with
+func (dm *DownloadManager) currentURL(source string) string {
+	for {
+		fresh, ok := dm.refreshed.Load(source)
+		if !ok {
+			return source
+		}
+		source = fresh.(string)
+	}
+}
+
+// expired reports whether err is the 403 of a URL that the task has a way
+// to refresh
+func (dm *DownloadManager) expired(task *DownloadTask, err error) bool {
+	var de *DownloadError
+	return errors.As(err, &de) && de.Status == http.StatusForbidden && dm.refresher(task) != nil
+}
+
+// refresher is the task's RefreshURL, else one running refresh_command
+func (dm *DownloadManager) refresher(task *DownloadTask) URLRefresher {
+	if task.RefreshURL != nil {
+		return task.RefreshURL
+	}
+	if dm.config.RefreshCommand == "" {
+		return nil
+	}
+	return func(ctx context.Context, expired string) (string, error) {
+		return runRefreshCommand(ctx, dm.config.RefreshCommand, task, expired)
+	}
+}
+
+// refreshURL replaces an expired source with a fresh URL from the task's
+// refresher. Chunks that hit the same 403 together refresh it once; the
+// rest find it already replaced and move to the new URL.
+func (dm *DownloadManager) refreshURL(ctx context.Context, task *DownloadTask, stale string) error {
+	dm.refreshMu.Lock()
+	defer dm.refreshMu.Unlock()
+	if dm.currentURL(stale) != stale {
+		return nil
+	}
+
+	fresh, err := dm.refresher(task)(ctx, stale)
+	if err != nil {
+		return err
+	}
+	if fresh == stale {
+		return errors.New("refresh returned the same URL")
+	}
+	// Going back to a URL that already expired would loop forever
+	if _, ok := dm.refreshed.Load(fresh); ok {
+		return errors.New("refresh returned a URL that already expired")
+	}
+	parsed, err := url.Parse(fresh)
+	if err != nil || parsed.Scheme == "" || parsed.Host == "" {
+		return fmt.Errorf("refresh returned an invalid URL %q", fresh)
+	}
+	dm.refreshed.Store(stale, fresh)
+	task.log().Debug("refreshed expired URL", "fresh", parsed.Redacted())
+	dm.bars.Printf("%sURL expired, continuing from a refreshed one%s\n", ColorYellow, ColorReset)
+	return nil
+}
+
+// runRefreshCommand runs refresh_command through the shell and takes the
+// last line it prints as the fresh URL. The command finds the expired URL
+// in $FASTDL_EXPIRED_URL, the task's original one in $FASTDL_URL and the
+// output path in $FASTDL_OUTPUT.
+func runRefreshCommand(ctx context.Context, command string, task *DownloadTask, expired string) (string, error) {
+	ctx, cancel := context.WithTimeout(ctx, time.Minute)
+	defer cancel()
+	cmd := exec.CommandContext(ctx, "sh", "-c", command)
+	if runtime.GOOS == "windows" {
+		cmd = exec.CommandContext(ctx, "cmd", "/C", command)
+	}
+	cmd.Env = append(os.Environ(),
+		"FASTDL_URL="+task.URL,
+		"FASTDL_EXPIRED_URL="+expired,
+		"FASTDL_OUTPUT="+task.Filepath,
+	)
+	cmd.Stderr = os.Stderr
+	out, err := cmd.Output()
+	if err != nil {
+		return "", fmt.Errorf("refresh command: %w", err)
+	}
+	lines := strings.Split(strings.TrimSpace(string(out)), "\n")
+	return strings.TrimSpace(lines[len(lines)-1]), nil
+}
+
+// downloadChunk downloads a single chunk
+func (dm *DownloadManager) downloadChunk(ctx context.Context, client *http.Client, task *DownloadTask, source string, chunk ChunkInfo, progress *ProgressInfo) error {
+	if dm.resume && task.output == nil && task.resumeState.isComplete(chunk.ID) {
+		if stat, err := os.Stat(chunk.Path); err == nil {
+			if stat.Size() == chunk.End-chunk.Start+1 && dm.verifyResumeTail(ctx, client, source, chunk, task.Headers) {
+				atomic.AddInt64(&progress.Downloaded, stat.Size())
+				return nil
+			}
+		}
+	}
+
+	if err := dm.throttle.Wait(ctx, hostOf(source)); err != nil {
+		return err
+	}
+	release, err := dm.hostSlots.Acquire(ctx, hostOf(source))
+	if err != nil {
+		return err
+	}
+	defer release()
+
+	ctx, stopPause := dm.interruptOnPause(ctx)
+	defer stopPause()
+	ctx, received, stopWatch := dm.watchSpeed(ctx)
+	defer stopWatch()
+
+	req, err := http.NewRequestWithContext(ctx, "GET", source, nil)
+	if err != nil {
+		return err
+	}
+
+	chunk.End = chunk.restart()
+	req.Header.Set("Range", fmt.Sprintf("bytes=%d-%d", chunk.Start, chunk.End))
+	dm.prepareRequest(req, task.Headers)
+	// Validators only mean something to the source they came from
+	ifRange := task.IfRange
+	if source != dm.currentURL(task.validatorURL) {
+		ifRange = ""
+	}
+	if ifRange != "" {
+		req.Header.Set("If-Range", ifRange)
+	}
+
+	resp, err := client.Do(req)
+	if err != nil {
+		return stallCause(ctx, err)
+	}
+	defer resp.Body.Close()
+	recordResponse(ctx, resp)
+
+	// If-Range answers with the whole new file when the validator is stale
+	if resp.StatusCode == http.StatusOK && ifRange != "" {
+		return errRemoteChanged
+	}
+
+	if resp.StatusCode != http.StatusPartialContent && resp.StatusCode != http.StatusOK {
+		return statusError(resp)
+	}
+	// A 200 is the whole file from byte 0, which is only right when the
+	// chunk is the whole file. Written at the chunk's offset it would
+	// silently corrupt the output.
+	if resp.StatusCode == http.StatusOK && (chunk.Start != 0 || chunk.End != task.Size-1) {
+		return fmt.Errorf("chunk %d: %w (got 200 for bytes %d-%d)", chunk.ID, errRangeNotSupported, chunk.Start, chunk.End)
+	}
+	// Bytes from any other range than the one asked for land at the wrong
+	// offset just the same
+	if resp.StatusCode == http.StatusPartialContent {
+		contentRange := resp.Header.Get("Content-Range")
+		start, end, _, ok := parseContentRange(contentRange)
+		if !ok || start != chunk.Start || end != chunk.End {
+			return fmt.Errorf("chunk %d: %w (asked for bytes %d-%d, got Content-Range %q)",
+				chunk.ID, errRangeNotSupported, chunk.Start, chunk.End, contentRange)
+		}
+	}
+
+	var file io.Writer
+	var pending io.Closer // waits for writes still in flight
+	if task.output != nil {
+		file = task.output.writer(chunk)
+		pending, _ = file.(io.Closer)
+	} else {
+		partFile, err := os.Create(chunk.Path)
+		if err != nil {
+			return err
+		}
+		defer partFile.Close()
+		file = partFile
+		if dm.config.AsyncWrites {
+			async := newAsyncWriter(partFile, AsyncWriteDepth, dm.buffers.size)
+			file, pending = async, async
+		}
+	}
+	if pending != nil {
+		defer pending.Close()
+	}
+
+	var limiter *fairStream
+	if rl := dm.limiterFor(task); rl != nil {
+		limiter = rl.Join()
+		defer limiter.Leave()
+	}
+
+	buffer := dm.buffers.Get(0)
+	defer dm.buffers.Put(buffer)
+	for {
+		if ctx.Err() != nil {
+			return stallCause(ctx, ctx.Err())
+		}
+		n, err := resp.Body.Read(buffer)
+		if n > 0 {
+			if limiter != nil {
+				limiter.Wait(ctx, n)
+			}
+			// Bytes past a stolen tail belong to another chunk
+			n = chunk.claim(n)
+			if _, writeErr := file.Write(buffer[:n]); writeErr != nil {
+				return writeErr
+			}
+			atomic.AddInt64(received, int64(n))
+			atomic.AddInt64(&progress.Downloaded, int64(n))
+		}
+		if err == io.EOF || *received == chunk.length() {
+			break
+		}
+		if err != nil {
+			return stallCause(ctx, err)
+		}
+	}
+
+	// The chunk only counts as done once its bytes reached the file
+	if pending != nil {
+		if err := pending.Close(); err != nil {
+			return err
+		}
+	}
+
+	// A clean EOF doesn't mean the server sent the whole range; a short
+	// part would leave a gap in the merged file, so retry it instead
+	if expected := chunk.length(); *received != expected {
+		return fmt.Errorf("chunk %d: %w (%d of %d bytes)", chunk.ID, errShortChunk, *received, expected)
+	}
+
+	return nil
+}
+
+// verifyResumeTail re-fetches the last bytes of a finished part file and
+// compares them before the part is trusted on resume
+func (dm *DownloadManager) verifyResumeTail(ctx context.Context, client *http.Client, urlStr string, chunk ChunkInfo, headers map[string]string) bool {
+	n := dm.config.VerifyResumeTail
+	if n <= 0 {
+		return true
+	}
+	size := chunk.End - chunk.Start + 1
+	if n > size {
+		n = size
+	}
+
+	file, err := os.Open(chunk.Path)
+	if err != nil {
+		return false
+	}
+	defer file.Close()
+
+	local := make([]byte, n)
+	if _, err := file.ReadAt(local, size-n); err != nil {
+		return false
+	}
+
+	req, err := http.NewRequestWithContext(ctx, "GET", urlStr, nil)
+	if err != nil {
+		return false
+	}
+	req.Header.Set("Range", fmt.Sprintf("bytes=%d-%d", chunk.End-n+1, chunk.End))
+	dm.prepareRequest(req, headers)
+
+	resp, err := client.Do(req)
+	if err != nil {
+		return false
+	}
+	defer resp.Body.Close()
+
+	if resp.StatusCode != http.StatusPartialContent {
+		return false
+	}
+
+	remote, err := io.ReadAll(io.LimitReader(resp.Body, n+1))
+	if err != nil {
+		return false
+	}
+
+	return bytes.Equal(local, remote)
+}
+
+// createOutput opens the final file plus any tee copies; size is the
+// expected length used for preallocation, or zero when unknown
+func (dm *DownloadManager) createOutput(outputPath string, size int64) (io.WriteCloser, error) {
+	file, err := os.Create(outputPath)
+	if err != nil {
+		return nil, err
+	}
+	if err := preallocate(file, size, dm.config.Preallocate); err != nil {
+		file.Close()
+		return nil, fmt.Errorf("preallocate %s: %w", outputPath, err)
+	}
+	if len(dm.config.TeeDirs) == 0 {
+		return file, nil
+	}
+
+	tee := &teeWriter{primary: file, fatal: dm.config.TeeFailFatal}
+	for _, path := range dm.teePaths(outputPath) {
+		if err := os.MkdirAll(filepath.Dir(path), 0755); err != nil {
+			if err := tee.fail(path, err); err != nil {
+				tee.Close()
+				return nil, err
+			}
+			continue
+		}
+		copyFile, err := os.Create(path)
+		if err != nil {
+			if err := tee.fail(path, err); err != nil {
+				tee.Close()
+				return nil, err
+			}
+			continue
+		}
+		tee.copies = append(tee.copies, &teeCopy{path: path, file: copyFile})
+	}
+	return tee, nil
+}
+
+// mapOutput creates the output file at its full size and maps it shared and
+// writable. Mappings need the length in place, so "none" still truncates.
+func (dm *DownloadManager) mapOutput(outputPath string, size int64) (*sharedOutput, error) {
+	if size <= 0 || int64(int(size)) != size {
+		return nil, fmt.Errorf("size %d can't be mapped", size)
+	}
+
+	file, err := os.Create(outputPath)
+	if err != nil {
+		return nil, err
+	}
+	mode := dm.config.Preallocate
+	if mode == "none" {
+		mode = "sparse"
+	}
+	if err := preallocate(file, size, mode); err != nil {
+		file.Close()
+		os.Remove(outputPath)
+		return nil, err
+	}
+
+	data, err := syscall.Mmap(int(file.Fd()), 0, int(size), syscall.PROT_READ|syscall.PROT_WRITE, syscall.MAP_SHARED)
+	if err != nil {
+		file.Close()
+		os.Remove(outputPath)
+		return nil, err
+	}
+	return &sharedOutput{file: file, data: data}, nil
+}
+
+// openShared creates the output file at its full size for positional
+// writes from every chunk worker
+func (dm *DownloadManager) openShared(outputPath string, size int64) (*sharedOutput, error) {
+	file, err := os.Create(outputPath)
+	if err != nil {
+		return nil, err
+	}
+	mode := dm.config.Preallocate
+	if mode == "none" {
+		mode = "sparse"
+	}
+	if err := preallocate(file, size, mode); err != nil {
+		file.Close()
+		os.Remove(outputPath)
+		return nil, fmt.Errorf("preallocate %s: %w", outputPath, err)
+	}
+	output := &sharedOutput{file: file, buffers: dm.buffers}
+	if dm.config.IOUring {
+		if output.ring, err = newURing(URingEntries); err != nil {
+			logger.Warn("io_uring unavailable, using pwrite", "err", err)
+		}
+	}
+	return output, nil
+}
+
+// writer writes a chunk sequentially from its start offset
+func (o *sharedOutput) writer(chunk ChunkInfo) io.Writer {
+	if o.data != nil {
+		return &sliceWriter{buf: o.data[chunk.Start : chunk.End+1]}
+	}
+	if o.ring != nil {
+		return &uringWriter{ring: o.ring, buffers: o.buffers, fd: int(o.file.Fd()), off: chunk.Start}
+	}
+	return io.NewOffsetWriter(o.file, chunk.Start)
+}
+
+// section reads back a finished chunk
+func (o *sharedOutput) section(chunk ChunkInfo) io.Reader {
+	if o.data != nil {
+		return bytes.NewReader(o.data[chunk.Start : chunk.End+1])
+	}
+	return io.NewSectionReader(o.file, chunk.Start, chunk.End-chunk.Start+1)
+}
+
+// readerAt reads anywhere in the output
+func (o *sharedOutput) readerAt() io.ReaderAt {
+	if o.data != nil {
+		return bytes.NewReader(o.data)
+	}
+	return o.file
+}
+
+// newURing sets up a ring with the given submission queue size
+func newURing(entries uint32) (*uring, error) {
+	var params uringParams
+	fd, err := ioURingSetup(entries, &params)
+	if err != nil {
+		return nil, err
+	}
+	r := &uring{
+		fd:      fd,
+		pending: make(map[uint64]*uringWrite),
+		slots:   make(chan struct{}, params.sqEntries),
+		done:    make(chan struct{}),
+	}
+
+	prot, flags := syscall.PROT_READ|syscall.PROT_WRITE, syscall.MAP_SHARED
+	sqSize := params.sqOff.array + params.sqEntries*4
+	cqSize := params.cqOff.cqes + params.cqEntries*uint32(unsafe.Sizeof(uringCQE{}))
+	if r.sqRing, err = syscall.Mmap(r.fd, iouringOffSQRing, int(sqSize), prot, flags); err == nil {
+		if r.cqRing, err = syscall.Mmap(r.fd, iouringOffCQRing, int(cqSize), prot, flags); err == nil {
+			r.sqeMem, err = syscall.Mmap(r.fd, iouringOffSQEs, int(params.sqEntries)*int(unsafe.Sizeof(uringSQE{})), prot, flags)
+		}
+	}
+	if err != nil {
+		r.unmap()
+		return nil, err
+	}
+
+	word := func(ring []byte, off uint32) *uint32 { return (*uint32)(unsafe.Pointer(&ring[off])) }
+	r.sqHead, r.sqTail, r.sqMask = word(r.sqRing, params.sqOff.head), word(r.sqRing, params.sqOff.tail), word(r.sqRing, params.sqOff.ringMask)
+	r.cqHead, r.cqTail, r.cqMask = word(r.cqRing, params.cqOff.head), word(r.cqRing, params.cqOff.tail), word(r.cqRing, params.cqOff.ringMask)
+	r.sqArray = unsafe.Slice(word(r.sqRing, params.sqOff.array), params.sqEntries)
+	r.sqes = unsafe.Slice((*uringSQE)(unsafe.Pointer(&r.sqeMem[0])), params.sqEntries)
+	r.cqes = unsafe.Slice((*uringCQE)(unsafe.Pointer(&r.cqRing[params.cqOff.cqes])), params.cqEntries)
+
+	go r.reap()
+	return r, nil
+}
+
+// Write queues buf to be written at off. done runs on the reaper once all
+// of it is written or the write failed; buf must stay untouched until then.
+func (r *uring) Write(fd int, off int64, buf []byte, done func(error)) error {
+	r.slots <- struct{}{}
+	r.mu.Lock()
+	defer r.mu.Unlock()
+	if err := r.queue(&uringWrite{fd: fd, off: off, buf: buf, done: done}); err != nil {
+		<-r.slots
+		return err
+	}
+	return nil
+}
+
+// queue submits a write; r.mu must be held
+func (r *uring) queue(w *uringWrite) error {
+	r.next++
+	r.pending[r.next] = w
+	if err := r.submit(iouringOpWrite, w, r.next); err != nil {
+		delete(r.pending, r.next)
+		return err
+	}
+	return nil
+}
+
+// submit fills the next SQE and enters the kernel; r.mu must be held.
+// user data 0 is reserved for the NOP that stops the reaper.
+func (r *uring) submit(opcode uint8, w *uringWrite, id uint64) error {
+	tail := atomic.LoadUint32(r.sqTail)
+	if tail-atomic.LoadUint32(r.sqHead) >= uint32(len(r.sqes)) {
+		return fmt.Errorf("io_uring submission queue full")
+	}
+	index := tail & atomic.LoadUint32(r.sqMask)
+	sqe := uringSQE{opcode: opcode, userData: id}
+	if w != nil {
+		sqe.fd = int32(w.fd)
+		sqe.off = uint64(w.off)
+		sqe.addr = uint64(uintptr(unsafe.Pointer(&w.buf[0])))
+		sqe.len = uint32(len(w.buf))
+	}
+	r.sqes[index] = sqe
+	r.sqArray[index] = index
+	atomic.StoreUint32(r.sqTail, tail+1)
+
+	// Entries an interrupted enter left behind go in with this one
+	for {
+		unsubmitted := tail + 1 - atomic.LoadUint32(r.sqHead)
+		if unsubmitted == 0 {
+			return nil
+		}
+		err := ioURingEnter(r.fd, unsubmitted, 0, 0)
+		if err == nil {
+			return nil
+		}
+		if err != syscall.EINTR {
+			return err
+		}
+	}
+}
+
+// reap waits for completions and hands them back to their writes. A short
+// write is resubmitted for the rest of its buffer.
+func (r *uring) reap() {
+	defer close(r.done)
+	for {
+		head := atomic.LoadUint32(r.cqHead)
+		if head == atomic.LoadUint32(r.cqTail) {
+			if err := ioURingEnter(r.fd, 0, 1, iouringEnterGetEvents); err != nil && err != syscall.EINTR {
+				r.fail(err)
+				return
+			}
+			continue
+		}
+		cqe := r.cqes[head&atomic.LoadUint32(r.cqMask)]
+		atomic.StoreUint32(r.cqHead, head+1)
+		if cqe.userData == 0 {
+			return
+		}
+
+		r.mu.Lock()
+		w := r.pending[cqe.userData]
+		delete(r.pending, cqe.userData)
+		var err error
+		switch {
+		case w == nil:
+			// Already failed by fail()
+		case cqe.res < 0:
+			err = syscall.Errno(-cqe.res)
+		case cqe.res == 0:
+			err = io.ErrShortWrite
+		case int(cqe.res) < len(w.buf):
+			w.off += int64(cqe.res)
+			w.buf = w.buf[cqe.res:]
+			if err = r.queue(w); err == nil {
+				w = nil
+			}
+		}
+		r.mu.Unlock()
+		if w != nil {
+			<-r.slots
+			w.done(err)
+		}
+	}
+}
+
+// fail completes every pending write with err once the ring is unusable
+func (r *uring) fail(err error) {
+	r.mu.Lock()
+	pending := r.pending
+	r.pending = make(map[uint64]*uringWrite)
+	r.mu.Unlock()
+	for _, w := range pending {
+		<-r.slots
+		w.done(err)
+	}
+}
+
+// Close stops the reaper and releases the ring. Every write must have
+// completed.
+func (r *uring) Close() error {
+	r.mu.Lock()
+	err := r.submit(iouringOpNop, nil, 0)
+	r.mu.Unlock()
+	if err == nil {
+		<-r.done
+	}
+	r.unmap()
+	return syscall.Close(r.fd)
+}
+
+func (r *uring) unmap() {
+	for _, region := range [][]byte{r.sqRing, r.cqRing, r.sqeMem} {
+		if region != nil {
+			syscall.Munmap(region)
+		}
+	}
+}
+
+func (w *uringWriter) Write(p []byte) (int, error) {
+	if err := w.failed(); err != nil {
+		return 0, err
+	}
+	if len(p) == 0 {
+		return 0, nil
+	}
+	buf := w.buffers.Get(len(p))
+	copy(buf, p)
+	done := func(err error) {
+		w.buffers.Put(buf)
+		w.finish(err)
+	}
+	w.wg.Add(1)
+	if err := w.ring.Write(w.fd, w.off, buf, done); err != nil {
+		w.buffers.Put(buf)
+		w.wg.Done()
+		return 0, err
+	}
+	w.off += int64(len(p))
+	return len(p), nil
+}
+
+func (w *uringWriter) finish(err error) {
+	if err != nil {
+		w.mu.Lock()
+		if w.err == nil {
+			w.err = err
+		}
+		w.mu.Unlock()
+	}
+	w.wg.Done()
+}
+
+func (w *uringWriter) failed() error {
+	w.mu.Lock()
+	defer w.mu.Unlock()
+	return w.err
+}
+
+// Close waits for the chunk's writes and returns the first error
+func (w *uringWriter) Close() error {
+	w.wg.Wait()
+	return w.failed()
+}
+
+// downloadShared runs the chunk workers against an output file opened once
+// for all of them. A failed download removes the file rather than leaving
+// zero-filled gaps.
+func (dm *DownloadManager) downloadShared(ctx context.Context, task *DownloadTask, output *sharedOutput, chunks []ChunkInfo, progress *ProgressInfo) error {
+	outputPath := output.file.Name()
+	task.output = output
+	defer func() { task.output = nil }()
+
+	table := newChunkTable(chunks, outputPath)
+	table.pieces = task.pieces
+	var hasher *prefixHasher
+	if dm.verifyHashes {
+		hasher = newPrefixHasher(table, task, task.hashAlgorithms())
+	}
+
+	workers := dm.maxWorkers
+	if task.Connections > 0 {
+		workers = task.Connections
+	}
+	if dm.config.Preconnect {
+		dm.preconnect(ctx, task, min(workers, len(chunks)))
+	}
+
+	errorChan := make(chan error, len(chunks))
+	dm.runWorkers(ctx, task, table, errorChan, progress, hasher, workers)
+	close(errorChan)
+	hasher.Close()
+
+	var downloadErr error
+	for err := range errorChan {
+		if err != nil && downloadErr == nil {
+			downloadErr = err
+		}
+	}
+	if downloadErr == nil {
+		// The hasher reads the output, so it has to finish before unmapping
+		if sums, err := hasher.Wait(); err == nil {
+			task.sums = sums
+		}
+	}
+
+	// Dirty pages of a shared mapping live in the page cache, so fsync on
+	// the descriptor after munmap flushes them like msync would
+	var err error
+	if output.data != nil {
+		err = syscall.Munmap(output.data)
+	}
+	if output.ring != nil {
+		if ringErr := output.ring.Close(); err == nil {
+			err = ringErr
+		}
+	}
+	if downloadErr == nil && err == nil {
+		err = output.file.Sync()
+	}
+	if closeErr := output.file.Close(); err == nil {
+		err = closeErr
+	}
+	if downloadErr == nil {
+		downloadErr = err
+	}
+
+	if downloadErr != nil {
+		os.Remove(outputPath)
+	}
+	return downloadErr
+}
+
+// watchSpeed derives a context that is cancelled with errStalled once fewer
+// than min_speed_bytes_per_sec arrive over the sliding window, or nothing
+// at all for stall_timeout_seconds. The caller adds received bytes to the
+// returned counter. Rate-limited waits count as time, so keep the floor
+// below rate_limit_bytes.
+func (dm *DownloadManager) watchSpeed(ctx context.Context) (context.Context, *int64, func()) {
+	received := new(int64)
+	stall := time.Duration(dm.config.StallTimeout) * time.Second
+	if dm.config.MinSpeed <= 0 && stall <= 0 {
+		return ctx, received, func() {}
+	}
+
+	window := dm.config.MinSpeedWindow
+	if window <= 0 {
+		window = 30
+	}
+	floor := dm.config.MinSpeed * int64(window)
+
+	ctx, cancel := context.WithCancelCause(ctx)
+	done := make(chan struct{})
+	go func() {
+		ticker := time.NewTicker(time.Second)
+		defer ticker.Stop()
+		// Counter readings from the last window seconds, oldest first
+		samples := make([]int64, 0, window+1)
+		var last int64
+		var idle time.Duration
+		for {
+			select {
+			case <-done:
+				return
+			case <-ctx.Done():
+				return
+			case <-ticker.C:
+				current := atomic.LoadInt64(received)
+				if dm.Paused() {
+					// A held transfer isn't a stalled one
+					samples, last, idle = samples[:0], current, 0
+					continue
+				}
+
+				if current == last {
+					idle += time.Second
+				} else {
+					idle = 0
+				}
+				last = current
+				if stall > 0 && idle >= stall {
+					cancel(fmt.Errorf("%w: nothing received for %s", errStalled, stall))
+					return
+				}
+
+				samples = append(samples, current)
+				if len(samples) > window+1 {
+					samples = samples[1:]
+				}
+				if dm.config.MinSpeed > 0 && len(samples) == window+1 && current-samples[0] < floor {
+					cancel(fmt.Errorf("%w: %s in %ds", errStalled, formatBytes(current-samples[0]), window))
+					return
+				}
+			}
+		}
+	}()
+
+	return ctx, received, func() {
+		close(done)
+		cancel(nil)
+	}
+}
+
+// stallCause reports the stall or pause rather than the bare cancellation
+// it caused
+func stallCause(ctx context.Context, err error) error {
+	if cause := context.Cause(ctx); errors.Is(cause, errStalled) || errors.Is(cause, errPaused) {
+		return cause
+	}
+	return err
+}
+
+// asyncWriter hands writes to a goroutine so a slow disk doesn't hold up
+// reading from the network. Write copies into one of depth buffers and
+// only blocks when all of them are queued; the first write error is
+// returned by the next Write or by Close.
+type asyncWriter struct {
+	dst   io.Writer
+	queue chan []byte
+	free  chan []byte
+	done  chan struct{}
+	once  sync.Once
+	err   error
+	mu    sync.Mutex
+}
+
+func newAsyncWriter(dst io.Writer, depth, size int) *asyncWriter {
+	w := &asyncWriter{
+		dst:   dst,
+		queue: make(chan []byte, depth),
+		free:  make(chan []byte, depth),
+		done:  make(chan struct{}),
+	}
+	for i := 0; i < depth; i++ {
+		w.free <- make([]byte, 0, size)
+	}
+	go w.run()
+	return w
+}
+
+func (w *asyncWriter) run() {
+	defer close(w.done)
+	for buf := range w.queue {
+		if w.failed() == nil {
+			if _, err := w.dst.Write(buf); err != nil {
+				w.mu.Lock()
+				w.err = err
+				w.mu.Unlock()
+			}
+		}
+		w.free <- buf[:0]
+	}
+}
+
+func (w *asyncWriter) failed() error {
+	w.mu.Lock()
+	defer w.mu.Unlock()
+	return w.err
+}
+
+func (w *asyncWriter) Write(p []byte) (int, error) {
+	if err := w.failed(); err != nil {
+		return 0, err
+	}
+	for written := 0; written < len(p); {
+		buf := <-w.free
+		n := min(cap(buf), len(p)-written)
+		w.queue <- append(buf, p[written:written+n]...)
+		written += n
+	}
+	return len(p), nil
+}
+
+// Close waits for queued writes and returns the first error; it is safe
+// to call more than once
+func (w *asyncWriter) Close() error {
+	w.once.Do(func() { close(w.queue) })
+	<-w.done
+	return w.failed()
+}
+
+func newBufferPool(size int) *bufferPool {
+	if size <= 0 {
+		size = BufferSize
+	}
+	p := &bufferPool{size: size}
+	p.pool.New = func() interface{} {
+		raw := make([]byte, size+BufferAlign)
+		skip := int(-uintptr(unsafe.Pointer(&raw[0])) & (BufferAlign - 1))
+		buf := raw[skip : skip+size : skip+size]
+		return &buf
+	}
+	return p
+}
+
+// Get returns a pooled buffer of at least n bytes, the full pool size for
+// n == 0. Larger requests get a one-off allocation.
+func (p *bufferPool) Get(n int) []byte {
+	if n > p.size {
+		return make([]byte, n)
+	}
+	buf := *p.pool.Get().(*[]byte)
+	if n == 0 {
+		return buf
+	}
+	return buf[:n]
+}
+
+// Put returns a buffer from Get; one-off allocations are dropped
+func (p *bufferPool) Put(buf []byte) {
+	if cap(buf) != p.size {
+		return
+	}
+	buf = buf[:p.size]
+	p.pool.Put(&buf)
+}
+
+// sliceWriter writes sequentially into a fixed slice
+type sliceWriter struct {
+	buf []byte
+	off int
+}
+
+func (w *sliceWriter) Write(p []byte) (int, error) {
+	if len(p) > len(w.buf)-w.off {
+		return 0, fmt.Errorf("received more than the %d bytes requested", len(w.buf))
+	}
+	n := copy(w.buf[w.off:], p)
+	w.off += n
+	return n, nil
+}
+
+// preallocate reserves size bytes for file. "sparse" only sets the length,
+// "prealloc" allocates the blocks (fallocate on Linux, SetFileValidData on
+// Windows), "full" writes zeros so they are allocated on any filesystem and
+// "none" leaves the file to grow as it is written.
+func preallocate(file *os.File, size int64, mode string) error {
+	if size <= 0 {
+		return nil
+	}
+	switch mode {
+	case "none":
+		return nil
+	case "prealloc":
+		// Reserve the blocks up front so parallel writers don't fragment
+		// the file; platforms and filesystems that can't get zeros instead
+		if err := allocateFile(file, size); !errors.Is(err, errors.ErrUnsupported) {
+			return err
+		}
+		return preallocate(file, size, "full")
+	case "full":
+		zeros := make([]byte, BufferSize)
+		for written := int64(0); written < size; {
+			n := min(int64(len(zeros)), size-written)
+			if _, err := file.Write(zeros[:n]); err != nil {
+				return err
+			}
+			written += n
+		}
+		_, err := file.Seek(0, io.SeekStart)
+		return err
+	default:
+		return file.Truncate(size)
+	}
+}
+
+// teePaths maps an output path into each tee directory
+func (dm *DownloadManager) teePaths(outputPath string) []string {
+	rel, err := filepath.Rel(dm.downloadDir, outputPath)
+	if err != nil || strings.HasPrefix(rel, "..") {
+		rel = filepath.Base(outputPath)
+	}
+	paths := make([]string, 0, len(dm.config.TeeDirs))
+	for _, dir := range dm.config.TeeDirs {
+		paths = append(paths, filepath.Join(dir, rel))
+	}
+	return paths
+}
+
+// resolveConflict applies on_conflict when the output file already exists.
+// It returns the path to write to and sets task.Skipped when the existing
+// file should be kept as it is.
+func (dm *DownloadManager) resolveConflict(task *DownloadTask, outputPath string) (string, error) {
+	stat, err := os.Stat(outputPath)
+	if err != nil {
+		return outputPath, nil
+	}
+
+	switch dm.config.OnConflict {
+	case "skip":
+		task.Skipped = true
+	case "rename":
+		outputPath = dm.freePath(outputPath)
+		if rel, err := filepath.Rel(dm.downloadDir, outputPath); err == nil {
+			task.Filepath = rel
+		}
+	case "resume":
+		// A complete file has nothing left to fetch. A shorter one is
+		// continued in place of the .part file, over a single connection
+		// since it holds one contiguous prefix.
+		switch {
+		case task.Size > 0 && stat.Size() == task.Size:
+			task.Skipped = true
+		case task.Size > 0 && stat.Size() < task.Size && task.SupportsRange && dm.resume:
+			workPath := dm.workPath(outputPath)
+			if _, err := os.Stat(workPath); err == nil {
+				break // an interrupted .part file is further along
+			}
+			if err := os.MkdirAll(filepath.Dir(workPath), 0755); err != nil {
+				return "", err
+			}
+			if err := moveFile(outputPath, workPath); err != nil {
+				return "", fmt.Errorf("failed to resume %s: %w", outputPath, err)
+			}
+			state := &ResumeState{URL: task.URL, Size: task.Size, Chunks: 1, ETag: task.ETag, LastModified: task.LastModified}
+			if err := saveResumeState(workPath, state); err != nil {
+				return "", err
+			}
+			task.Chunks = 1
+		default:
+			logger.Warn("can't be resumed, overwriting", "file", outputPath)
+		}
+	case "", "overwrite":
+	default:
+		return "", configError(fmt.Errorf("unknown on_conflict %q (want overwrite, skip, resume or rename)", dm.config.OnConflict))
+	}
+	return outputPath, nil
+}
+
+// remoteNewer reports whether timestamping should fetch the remote file
+// over local: it was modified after the local copy or differs in size. As
+// with wget -N, a server that sends no Last-Modified is always fetched.
+func remoteNewer(task *DownloadTask, local os.FileInfo) bool {
+	modified, err := http.ParseTime(task.LastModified)
+	if err != nil {
+		return true
+	}
+	if task.Size > 0 && task.Size != local.Size() {
+		return true
+	}
+	return modified.After(local.ModTime())
+}
+
+// freePath returns "name (1).ext", "name (2).ext" and so on, the first
+// that neither exists nor has a download in progress
+func (dm *DownloadManager) freePath(outputPath string) string {
+	ext := filepath.Ext(outputPath)
+	if strings.HasSuffix(strings.ToLower(outputPath), ".tar"+strings.ToLower(ext)) {
+		ext = outputPath[len(outputPath)-len(ext)-4:]
+	}
+	base := strings.TrimSuffix(outputPath, ext)
+	for i := 1; ; i++ {
+		candidate := fmt.Sprintf("%s (%d)%s", base, i, ext)
+		if _, err := os.Stat(candidate); err != nil {
+			if _, err := os.Stat(dm.workPath(candidate)); err != nil {
+				return candidate
+			}
+		}
+	}
+}
+
+// checkDiskSpace fails fast when the filesystem holding workPath can't fit
+// what is left of a download plus disk_space_margin_bytes. Parts and
+// partial files from an earlier attempt count as already written.
+func (dm *DownloadManager) checkDiskSpace(workPath string, size int64) error {
+	if size <= 0 {
+		return nil
+	}
+	need := size + dm.config.DiskMargin
+	existing, _ := filepath.Glob(workPath + "*")
+	for _, name := range existing {
+		if stat, err := os.Stat(name); err == nil {
+			need -= stat.Size()
+		}
+	}
+
+	var fs syscall.Statfs_t
+	if err := syscall.Statfs(filepath.Dir(workPath), &fs); err != nil {
+		return nil // nothing to check against
+	}
+	free := int64(fs.Bavail) * int64(fs.Bsize)
+	if free < need {
+		return fmt.Errorf("%w on %s: need %s, %s free", errDiskSpace, filepath.Dir(workPath), formatBytes(need), formatBytes(free))
+	}
+	return nil
+}
+
+// claimBytes counts n bytes against max_total_bytes, or gives them back
+// when negative. A claim that would pass the budget is refused.
+func (dm *DownloadManager) claimBytes(n int64) error {
+	limit := dm.config.MaxTotalBytes
+	if limit <= 0 {
+		return nil
+	}
+	total := atomic.AddInt64(&dm.totalBytes, n)
+	if n >= 0 && (total > limit || (n == 0 && total >= limit)) {
+		atomic.AddInt64(&dm.totalBytes, -n)
+		return fmt.Errorf("%w: %s downloaded or in progress, max_total_bytes is %s", errQuota, formatBytes(total-n), formatBytes(limit))
+	}
+	return nil
+}
+
+// workPath is where a download is written until it is verified: next to
+// the output as <name>.part, or in temp_dir when one is set
+func (dm *DownloadManager) workPath(outputPath string) string {
+	if dm.config.TempDir == "" {
+		return outputPath + ".part"
+	}
+	rel, err := filepath.Rel(dm.downloadDir, outputPath)
+	if err != nil || strings.HasPrefix(rel, "..") {
+		rel = filepath.Base(outputPath)
+	}
+	return filepath.Join(dm.config.TempDir, rel+".part")
+}
+
+// commitOutput renames a verified download and its tee copies to their
+// final names
+func (dm *DownloadManager) commitOutput(workPath, outputPath string) error {
+	if err := moveFile(workPath, outputPath); err != nil {
+		return err
+	}
+	finals := dm.teePaths(outputPath)
+	for i, path := range dm.teePaths(workPath) {
+		if _, err := os.Stat(path); err != nil {
+			continue // dropped after a failed write
+		}
+		if err := os.MkdirAll(filepath.Dir(finals[i]), 0755); err == nil {
+			err = moveFile(path, finals[i])
+		}
+		if err != nil {
+			logger.Warn("copy left under its temporary name", "copy", finals[i], "path", path, "err", err)
+		}
+	}
+	return nil
+}
+
+// moveFile renames src to dst, copying when temp_dir is on another
+// filesystem
+func moveFile(src, dst string) error {
+	err := os.Rename(src, dst)
+	if !errors.Is(err, syscall.EXDEV) {
+		return err
+	}
+
+	in, err := os.Open(src)
+	if err != nil {
+		return err
+	}
+	defer in.Close()
+	stat, err := in.Stat()
+	if err != nil {
+		return err
+	}
+	// Copy under a temporary name too, so dst only ever appears whole
+	tmp := dst + ".part"
+	out, err := os.OpenFile(tmp, os.O_CREATE|os.O_WRONLY|os.O_TRUNC, stat.Mode().Perm())
+	if err != nil {
+		return err
+	}
+	if _, err := io.Copy(out, in); err != nil {
+		out.Close()
+		os.Remove(tmp)
+		return err
+	}
+	if err := out.Close(); err != nil {
+		os.Remove(tmp)
+		return err
+	}
+	if err := os.Rename(tmp, dst); err != nil {
+		os.Remove(tmp)
+		return err
+	}
+	return os.Remove(src)
+}
+
+// removeOutput deletes a rejected download and its tee copies
+func (dm *DownloadManager) removeOutput(outputPath string) {
+	os.Remove(outputPath)
+	for _, path := range dm.teePaths(outputPath) {
+		os.Remove(path)
+	}
+}
+
+func (t *teeWriter) Write(p []byte) (int, error) {
+	n, err := t.primary.Write(p)
+	if err != nil {
+		return n, err
+	}
+	for _, c := range t.copies {
+		if c.file == nil {
+			continue
+		}
+		if _, err := c.file.Write(p); err != nil {
+			c.file.Close()
+			c.file = nil
+			if err := t.fail(c.path, err); err != nil {
+				return n, err
+			}
+		}
+	}
+	return n, nil
+}
+
+func (t *teeWriter) fail(path string, err error) error {
+	if t.fatal {
+		return fmt.Errorf("tee to %s failed: %w", path, err)
+	}
+	os.Remove(path)
+	logger.Warn("dropping copy", "path", path, "err", err)
+	return nil
+}
+
+func (t *teeWriter) Close() error {
+	err := t.primary.Close()
+	for _, c := range t.copies {
+		if c.file == nil {
+			continue
+		}
+		if closeErr := c.file.Close(); closeErr != nil {
+			if failErr := t.fail(c.path, closeErr); err == nil {
+				err = failErr
+			}
+		}
+	}
+	return err
+}
+
+// mergeChunks combines all chunks into final file
+func (dm *DownloadManager) mergeChunks(outputPath string, chunks []ChunkInfo) error {
+	var size int64
+	for _, chunk := range chunks {
+		size += chunk.End - chunk.Start + 1
+	}
+
+	output, err := dm.createOutput(outputPath, size)
+	if err != nil {
+		return err
+	}
+	defer output.Close()
+
+	for _, chunk := range chunks {
+		input, err := os.Open(chunk.Path)
+		if err != nil {
+			return err
+		}
+
+		if _, err := io.Copy(output, input); err != nil {
+			input.Close()
+			return err
+		}
+		
+		input.Close()
+		os.Remove(chunk.Path)
+	}
+
+	return nil
+}
+
+// DownloadStream returns the response body as an in-order reader without
+// writing to disk. Dropped connections are retried from the current offset.
+func (dm *DownloadManager) DownloadStream(ctx context.Context, urlStr string, headers map[string]string) io.ReadCloser {
+	return &streamReader{
+		dm:      dm,
+		ctx:     ctx,
+		url:     urlStr,
+		headers: headers,
+	}
+}
+
+// streamReader reconnects with a Range request when the body is cut short
+type streamReader struct {
+	dm      *DownloadManager
+	ctx     context.Context
+	url     string
+	headers map[string]string
+	body    io.ReadCloser
+	offset  int64
+	retries int
+	delay   time.Duration
+	ifRange string // validator from the first response, sent on reconnects
+}
+
+func (s *streamReader) Read(p []byte) (int, error) {
+	for {
+		if s.body == nil {
+			if err := s.open(); err != nil {
+				// Retrying can't make the rest of the stream match what
+				// was already handed out
+				if errors.Is(err, errRemoteChanged) || errors.Is(err, errRangeNotSupported) || !s.retry() {
+					return 0, err
+				}
+				continue
+			}
+		}
+
+		n, err := s.body.Read(p)
+		s.offset += int64(n)
+		if n > 0 {
+			// Only drops in a row count against the retries
+			s.retries, s.delay = 0, 0
+			if s.dm.rateLimiter != nil {
+				s.dm.rateLimiter.Wait(s.ctx, n)
+			}
+		}
+		if err == nil || err == io.EOF {
+			return n, err
+		}
+
+		s.body.Close()
+		s.body = nil
+		if !s.retry() {
+			return n, err
+		}
+		if n > 0 {
+			return n, nil
+		}
+	}
+}
+
+func (s *streamReader) Close() error {
+	if s.body == nil {
+		return nil
+	}
+	err := s.body.Close()
+	s.body = nil
+	return err
+}
+
+func (s *streamReader) open() error {
+	req, err := http.NewRequestWithContext(s.ctx, "GET", s.url, nil)
+	if err != nil {
+		return err
+	}
+
+	s.dm.prepareRequest(req, s.headers)
+	if s.offset > 0 {
+		req.Header.Set("Range", fmt.Sprintf("bytes=%d-", s.offset))
+		if s.ifRange != "" {
+			req.Header.Set("If-Range", s.ifRange)
+		}
+	}
+
+	resp, err := s.dm.client.Do(req)
+	if err != nil {
+		return err
+	}
+
+	expected := http.StatusOK
+	if s.offset > 0 {
+		expected = http.StatusPartialContent
+	}
+	if s.offset > 0 && resp.StatusCode == http.StatusOK {
+		resp.Body.Close()
+		// If-Range answers with the whole new file when the one being
+		// streamed was replaced
+		if s.ifRange != "" {
+			return errRemoteChanged
+		}
+		return errRangeNotSupported
+	}
+	if resp.StatusCode != expected {
+		resp.Body.Close()
+		return statusError(resp)
+	}
+
+	// If-Range needs a strong validator, so a weak ETag falls back to
+	// Last-Modified
+	if s.offset == 0 {
+		s.ifRange = resp.Header.Get("ETag")
+		if s.ifRange == "" || strings.HasPrefix(s.ifRange, "W/") {
+			s.ifRange = resp.Header.Get("Last-Modified")
+		}
+	}
+	s.body = resp.Body
+	return nil
+}
+
+func (s *streamReader) retry() bool {
+	s.retries++
+	if s.retries >= s.dm.config.MaxRetries || s.ctx.Err() != nil {
+		return false
+	}
+	s.delay = s.dm.backoff(s.retries, s.delay)
+	time.Sleep(s.delay)
+	return true
+}
+
+// downloadSingle handles single-threaded downloads
+func (dm *DownloadManager) downloadSingle(ctx context.Context, task *DownloadTask, outputPath string, progress *ProgressInfo) error {
+	release, err := dm.hostSlots.Acquire(ctx, hostOf(task.sourceURL()))
+	if err != nil {
+		return err
+	}
+	defer release()
+
+	ctx, received, stopWatch := dm.watchSpeed(ctx)
+	defer stopWatch()
+
+	// A partial file can be continued with an open-ended Range when the
+	// server supports it and the sidecar shows the remote file is unchanged
+	resumable := dm.resume && task.SupportsRange && task.Size > 0 && len(dm.config.TeeDirs) == 0
+	var offset int64
+	var ifRange string
+	if resumable {
+		offset, ifRange = dm.prepareSingleResume(outputPath, task)
+	}
+
+	req, err := http.NewRequestWithContext(ctx, "GET", task.sourceURL(), nil)
+	if err != nil {
+		return err
+	}
+
+	dm.prepareRequest(req, task.Headers)
+	if offset > 0 {
+		req.Header.Set("Range", fmt.Sprintf("bytes=%d-", offset))
+		if ifRange != "" {
+			req.Header.Set("If-Range", ifRange)
+		}
+	}
+
+	resp, err := dm.client.Do(req)
+	if err != nil {
+		return stallCause(ctx, err)
+	}
+	defer resp.Body.Close()
+
+	switch {
+	case offset > 0 && resp.StatusCode == http.StatusPartialContent && contentRangeStart(resp) == offset:
+		fmt.Printf("%sResuming:%s from %s\n", ColorCyan, ColorReset, formatBytes(offset))
+	case resp.StatusCode == http.StatusOK:
+		if offset > 0 {
+			fmt.Printf("%sRemote file changed or range refused, restarting download%s\n", ColorYellow, ColorReset)
+		}
+		offset = 0
+	default:
+		return statusError(resp)
+	}
+
+	// Without a Content-Length the probed size still says how much is due,
+	// unless the transport decompressed a body the HEAD measured compressed
+	size := resp.ContentLength
+	if size < 0 && !resp.Uncompressed && task.Size > 0 {
+		size = task.Size - offset
+	}
+	return dm.writeStream(ctx, task, outputPath, resp.Body, offset, size, resumable, progress, received)
+}
+
+// writeStream saves a single response body, appending at offset when
+// resuming, and hashes it on the way through. size is the length of body,
+// 0 or less when unknown; a body that ends short of it is an error.
+func (dm *DownloadManager) writeStream(ctx context.Context, task *DownloadTask, outputPath string, body io.Reader, offset, size int64, resumable bool, progress *ProgressInfo, received *int64) error {
+	length := size
+	var file io.WriteCloser
+	var err error
+	if offset > 0 {
+		file, err = os.OpenFile(outputPath, os.O_WRONLY|os.O_APPEND, 0)
+	} else {
+		if resumable {
+			// The length has to track what was written for a later resume
+			size = 0
+		}
+		file, err = dm.createOutput(outputPath, size)
+	}
+	if err != nil {
+		return err
+	}
+	defer file.Close()
+
+	// Hash while streaming so verification doesn't re-read the file
+	hashes := make(map[string]hash.Hash)
+	if dm.verifyHashes {
+		for _, algorithm := range task.hashAlgorithms() {
+			if h, err := newHash(algorithm); err == nil {
+				hashes[algorithm] = h
+			}
+		}
+	}
+	if offset > 0 && len(hashes) > 0 {
+		if err := hashPrefix(outputPath, offset, hashes); err != nil {
+			return err
+		}
+	}
+	atomic.AddInt64(&progress.Downloaded, offset)
+
+	buffer := dm.buffers.Get(0)
+	defer dm.buffers.Put(buffer)
+	for {
+		if err := dm.waitIfPaused(ctx); err != nil {
+			return err
+		}
+		n, err := body.Read(buffer)
+		if n > 0 {
+			if rl := dm.limiterFor(task); rl != nil {
+				rl.Wait(ctx, n)
+			}
+			if _, writeErr := file.Write(buffer[:n]); writeErr != nil {
+				return writeErr
+			}
+			for _, h := range hashes {
+				h.Write(buffer[:n])
+			}
+			atomic.AddInt64(received, int64(n))
+			atomic.AddInt64(&progress.Downloaded, int64(n))
+		}
+		if err == io.EOF {
+			break
+		}
+		if err != nil {
+			return stallCause(ctx, err)
+		}
+	}
+	// Checked before the sidecar goes, so the next run can resume
+	if length > 0 && *received != length {
+		return fmt.Errorf("%w (%d of %d bytes)", errTruncatedBody, offset+*received, offset+length)
+	}
+
+	task.sums = make(map[string]string)
+	for algorithm, h := range hashes {
+		task.sums[algorithm] = hex.EncodeToString(h.Sum(nil))
+	}
+	if resumable {
+		os.Remove(resumeStatePath(outputPath))
+	}
+	return nil
+}
+
+// prepareSingleResume returns how much of a single-stream download is
+// already on disk and the If-Range validator to continue it with. A
+// missing or mismatched sidecar means starting over.
+func (dm *DownloadManager) prepareSingleResume(outputPath string, task *DownloadTask) (int64, string) {
+	state := &ResumeState{
+		URL:          task.URL,
+		Size:         task.Size,
+		Chunks:       1,
+		ETag:         task.ETag,
+		LastModified: task.LastModified,
+	}
+
+	var offset int64
+	var ifRange string
+	if previous, err := loadResumeState(outputPath); err == nil && previous.matches(state) {
+		if stat, err := os.Stat(outputPath); err == nil && stat.Size() < task.Size {
+			offset, ifRange = stat.Size(), previous.validator()
+		}
+	}
+
+	if err := saveResumeState(outputPath, state); err != nil {
+		logger.Warn("could not save resume state", "err", err)
+	}
+	return offset, ifRange
+}
+
+// isS3URL reports whether urlStr is an s3:// URL, which the download
+// client fetches like any other
+func isS3URL(urlStr string) bool {
+	scheme, _, _ := strings.Cut(urlStr, "://")
+	return strings.EqualFold(scheme, "s3")
+}
+
+// newS3Client reads the region and endpoint from the environment and the
+// shared config file the way the AWS CLI does
+func newS3Client() *s3Client {
+	c := &s3Client{
+		creds:   &awsCredentials{client: &http.Client{Timeout: 2 * time.Second, Transport: &http.Transport{}}},
+		region:  awsRegion(),
+		regions: make(map[string]string),
+	}
+	for _, name := range []string{"AWS_ENDPOINT_URL_S3", "AWS_ENDPOINT_URL"} {
+		if value := os.Getenv(name); value != "" {
+			if endpoint, err := url.Parse(value); err == nil && endpoint.Host != "" {
+				c.endpoint = endpoint
+				break
+			}
+		}
+	}
+	return c
+}
+
+func (t *s3Transport) RoundTrip(req *http.Request) (*http.Response, error) {
+	bucket, key := req.URL.Host, strings.TrimPrefix(req.URL.Path, "/")
+	if bucket == "" || key == "" {
+		return nil, fmt.Errorf("invalid S3 URL %s, want s3://bucket/key", req.URL.Redacted())
+	}
+	creds, err := t.s3.creds.get(req.Context())
+	if err != nil {
+		return nil, err
+	}
+
+	t.s3.mu.Lock()
+	region, known := t.s3.regions[bucket]
+	t.s3.mu.Unlock()
+	if !known {
+		region = t.s3.region
+	}
+	resp, err := t.send(req, bucket, key, region, creds)
+	if err != nil {
+		return nil, err
+	}
+
+	// A bucket in another region answers 301, or 400 to HEAD, naming it
+	moved := resp.Header.Get("X-Amz-Bucket-Region")
+	if moved != "" && moved != region && (resp.StatusCode == http.StatusMovedPermanently || resp.StatusCode == http.StatusBadRequest) {
+		resp.Body.Close()
+		t.s3.mu.Lock()
+		t.s3.regions[bucket] = moved
+		t.s3.mu.Unlock()
+		if resp, err = t.send(req, bucket, key, moved, creds); err != nil {
+			return nil, err
+		}
+	}
+	// Callers see the s3:// URL they asked for, which names the file
+	resp.Request = req
+	return resp, nil
+}
+
+func (t *s3Transport) send(req *http.Request, bucket, key, region string, creds awsKey) (*http.Response, error) {
+	out := req.Clone(req.Context())
+	out.URL = t.s3.objectURL(bucket, key, region)
+	out.Host = ""
+	out.Header.Del("Authorization") // netrc or -user never go to S3
+	if creds.ID != "" {
+		signV4(out, creds, region, "s3", time.Now())
+	}
+	return t.base.RoundTrip(out)
+}
+
+// objectURL addresses the object virtual-hosted style, except on custom
+// endpoints and for dotted bucket names, which the wildcard certificate
+// doesn't cover
+func (c *s3Client) objectURL(bucket, key, region string) *url.URL {
+	u := &url.URL{Scheme: "https", Host: bucket + ".s3." + region + ".amazonaws.com", Path: "/" + key}
+	switch {
+	case c.endpoint != nil:
+		u.Scheme, u.Host = c.endpoint.Scheme, c.endpoint.Host
+		u.Path = strings.TrimSuffix(c.endpoint.Path, "/") + "/" + bucket + "/" + key
+	case strings.Contains(bucket, "."):
+		u.Host = "s3." + region + ".amazonaws.com"
+		u.Path = "/" + bucket + "/" + key
+	}
+	u.RawPath = awsEscape(u.Path, false)
+	return u
+}
+
+// signV4 adds AWS Signature Version 4 headers to a bodyless request. Only
+// the host, Range and x-amz-* headers are signed, so proxies adding their
+// own don't break the signature.
+func signV4(req *http.Request, key awsKey, region, service string, now time.Time) {
+	amzDate := now.UTC().Format("20060102T150405Z")
+	date := amzDate[:8]
+	payload := hex.EncodeToString(sha256.New().Sum(nil))
+	req.Header.Set("X-Amz-Date", amzDate)
+	req.Header.Set("X-Amz-Content-Sha256", payload)
+	if key.Token != "" {
+		req.Header.Set("X-Amz-Security-Token", key.Token)
+	}
+
+	names := []string{"host"}
+	for name := range req.Header {
+		lower := strings.ToLower(name)
+		if strings.HasPrefix(lower, "x-amz-") || lower == "range" {
+			names = append(names, lower)
+		}
+	}
+	sort.Strings(names)
+	var headers strings.Builder
+	for _, name := range names {
+		value := req.URL.Host
+		if name != "host" {
+			value = strings.Join(req.Header.Values(name), ",")
+		}
+		headers.WriteString(name + ":" + strings.TrimSpace(value) + "\n")
+	}
+	signed := strings.Join(names, ";")
+
+	query := req.URL.Query()
+	params := make([]string, 0, len(query))
+	for name, values := range query {
+		for _, value := range values {
+			params = append(params, awsEscape(name, true)+"="+awsEscape(value, true))
+		}
+	}
+	sort.Strings(params)
+
+	canonical := strings.Join([]string{req.Method, req.URL.EscapedPath(), strings.Join(params, "&"), headers.String(), signed, payload}, "\n")
+	digest := sha256.Sum256([]byte(canonical))
+	scope := date + "/" + region + "/" + service + "/aws4_request"
+	toSign := "AWS4-HMAC-SHA256\n" + amzDate + "\n" + scope + "\n" + hex.EncodeToString(digest[:])
+
+	mac := func(key []byte, data string) []byte {
+		h := hmac.New(sha256.New, key)
+		h.Write([]byte(data))
+		return h.Sum(nil)
+	}
+	signingKey := mac(mac(mac(mac([]byte("AWS4"+key.Secret), date), region), service), "aws4_request")
+	req.Header.Set("Authorization", fmt.Sprintf("AWS4-HMAC-SHA256 Credential=%s/%s, SignedHeaders=%s, Signature=%s",
+		key.ID, scope, signed, hex.EncodeToString(mac(signingKey, toSign))))
+}
+
+// awsEscape percent-encodes everything but RFC 3986 unreserved characters,
+// and "/" unless slash is set, as SigV4 canonical requests want
+func awsEscape(s string, slash bool) string {
+	var b strings.Builder
+	for i := 0; i < len(s); i++ {
+		c := s[i]
+		if 'A' <= c && c <= 'Z' || 'a' <= c && c <= 'z' || '0' <= c && c <= '9' ||
+			c == '-' || c == '_' || c == '.' || c == '~' || (c == '/' && !slash) {
+			b.WriteByte(c)
+		} else {
+			fmt.Fprintf(&b, "%%%02X", c)
+		}
+	}
+	return b.String()
+}
+
+// get returns credentials, resolving the chain again when temporary ones
+// are about to expire
+func (c *awsCredentials) get(ctx context.Context) (awsKey, error) {
+	c.mu.Lock()
+	defer c.mu.Unlock()
+	if c.loaded && (c.key.Expires.IsZero() || time.Until(c.key.Expires) > 5*time.Minute) {
+		return c.key, nil
+	}
+	key, err := c.resolve(ctx)
+	if err != nil {
+		return awsKey{}, err
+	}
+	c.key, c.loaded = key, true
+	return key, nil
+}
+
+// resolve walks the chain the AWS CLI uses: environment, the shared
+// credentials file, the ECS task role, then the EC2 instance role. With
+// none of them requests go out unsigned, which public buckets accept.
+func (c *awsCredentials) resolve(ctx context.Context) (awsKey, error) {
+	if id := os.Getenv("AWS_ACCESS_KEY_ID"); id != "" {
+		return awsKey{ID: id, Secret: os.Getenv("AWS_SECRET_ACCESS_KEY"), Token: os.Getenv("AWS_SESSION_TOKEN")}, nil
+	}
+
+	homeDir, _ := os.UserHomeDir()
+	path := os.Getenv("AWS_SHARED_CREDENTIALS_FILE")
+	if path == "" {
+		path = filepath.Join(homeDir, ".aws", "credentials")
+	}
+	if profile, ok := readINI(path)[awsProfile()]; ok && profile["aws_access_key_id"] != "" {
+		return awsKey{ID: profile["aws_access_key_id"], Secret: profile["aws_secret_access_key"], Token: profile["aws_session_token"]}, nil
+	}
+
+	if uri := os.Getenv("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI"); uri != "" {
+		return c.fetchRole(ctx, "http://169.254.170.2"+uri, nil)
+	}
+
+	if os.Getenv("AWS_EC2_METADATA_DISABLED") != "true" {
+		key, err := c.fetchInstanceRole(ctx)
+		if err == nil {
+			return key, nil
+		}
+		logger.Debug("no instance role", "err", err)
+	}
+
+	logger.Debug("no AWS credentials found, sending S3 requests unsigned")
+	return awsKey{}, nil
+}
+
+// fetchInstanceRole asks IMDSv2 for the instance role's credentials
+func (c *awsCredentials) fetchInstanceRole(ctx context.Context) (awsKey, error) {
+	const imds = "http://169.254.169.254/latest"
+	req, err := http.NewRequestWithContext(ctx, "PUT", imds+"/api/token", nil)
+	if err != nil {
+		return awsKey{}, err
+	}
+	req.Header.Set("X-Aws-Ec2-Metadata-Token-Ttl-Seconds", "21600")
+	token, err := c.fetch(req)
+	if err != nil {
+		return awsKey{}, err
+	}
+	headers := map[string]string{"X-Aws-Ec2-Metadata-Token": string(token)}
+
+	if req, err = http.NewRequestWithContext(ctx, "GET", imds+"/meta-data/iam/security-credentials/", nil); err != nil {
+		return awsKey{}, err
+	}
+	req.Header.Set("X-Aws-Ec2-Metadata-Token", string(token))
+	roles, err := c.fetch(req)
+	if err != nil {
+		return awsKey{}, err
+	}
+	role, _, _ := strings.Cut(strings.TrimSpace(string(roles)), "\n")
+	if role == "" {
+		return awsKey{}, errors.New("instance has no role")
+	}
+	return c.fetchRole(ctx, imds+"/meta-data/iam/security-credentials/"+role, headers)
+}
+
+// fetchRole reads the JSON credentials the ECS and EC2 endpoints serve
+func (c *awsCredentials) fetchRole(ctx context.Context, endpoint string, headers map[string]string) (awsKey, error) {
+	req, err := http.NewRequestWithContext(ctx, "GET", endpoint, nil)
+	if err != nil {
+		return awsKey{}, err
+	}
+	for k, v := range headers {
+		req.Header.Set(k, v)
+	}
+	if token := os.Getenv("AWS_CONTAINER_AUTHORIZATION_TOKEN"); token != "" {
+		req.Header.Set("Authorization", token)
+	}
+	data, err := c.fetch(req)
+	if err != nil {
+		return awsKey{}, err
+	}
+	var role struct {
+		AccessKeyId     string
+		SecretAccessKey string
+		Token           string
+		Expiration      time.Time
+	}
+	if err := json.Unmarshal(data, &role); err != nil {
+		return awsKey{}, fmt.Errorf("invalid role credentials: %w", err)
+	}
+	return awsKey{ID: role.AccessKeyId, Secret: role.SecretAccessKey, Token: role.Token, Expires: role.Expiration}, nil
+}
+
+func (c *awsCredentials) fetch(req *http.Request) ([]byte, error) {
+	resp, err := c.client.Do(req)
+	if err != nil {
+		return nil, err
+	}
+	defer resp.Body.Close()
+	if resp.StatusCode != http.StatusOK {
+		return nil, fmt.Errorf("%s returned %d", req.URL.Redacted(), resp.StatusCode)
+	}
+	return io.ReadAll(io.LimitReader(resp.Body, 64*1024))
+}
+
+func awsProfile() string {
+	if profile := os.Getenv("AWS_PROFILE"); profile != "" {
+		return profile
+	}
+	return "default"
+}
+
+// awsRegion is $AWS_REGION, $AWS_DEFAULT_REGION or the profile's region in
+// ~/.aws/config, else us-east-1. Buckets elsewhere are found on first use.
+func awsRegion() string {
+	for _, name := range []string{"AWS_REGION", "AWS_DEFAULT_REGION"} {
+		if region := os.Getenv(name); region != "" {
+			return region
+		}
+	}
+	homeDir, _ := os.UserHomeDir()
+	path := os.Getenv("AWS_CONFIG_FILE")
+	if path == "" {
+		path = filepath.Join(homeDir, ".aws", "config")
+	}
+	section := "profile " + awsProfile()
+	if awsProfile() == "default" {
+		section = "default"
+	}
+	if region := readINI(path)[section]["region"]; region != "" {
+		return region
+	}
+	return "us-east-1"
+}
+
+// readINI parses the AWS config and credentials files into sections of
+// key/value pairs. A missing file has no sections.
+func readINI(path string) map[string]map[string]string {
+	sections := make(map[string]map[string]string)
+	data, err := os.ReadFile(path)
+	if err != nil {
+		return sections
+	}
+	var current map[string]string
+	for _, line := range strings.Split(string(data), "\n") {
+		line = strings.TrimSpace(line)
+		switch {
+		case line == "" || strings.HasPrefix(line, "#") || strings.HasPrefix(line, ";"):
+		case strings.HasPrefix(line, "[") && strings.HasSuffix(line, "]"):
+			name := strings.TrimSpace(line[1 : len(line)-1])
+			current = make(map[string]string)
+			sections[name] = current
+		case current != nil:
+			if key, value, ok := strings.Cut(line, "="); ok {
+				current[strings.TrimSpace(key)] = strings.TrimSpace(value)
+			}
+		}
+	}
+	return sections
+}
+
+func (b *bearerToken) get(ctx context.Context) (string, error) {
+	b.mu.Lock()
+	defer b.mu.Unlock()
+	if b.fetched && (b.expires.IsZero() || time.Until(b.expires) > 5*time.Minute) {
+		return b.token, nil
+	}
+	token, expires, err := b.fetch(ctx)
+	if err != nil {
+		return "", err
+	}
+	b.token, b.expires, b.fetched = token, expires, true
+	return token, nil
+}
+
+// postToken sends an OAuth token request and reads the standard answer
+func postToken(ctx context.Context, client *http.Client, endpoint string, form url.Values) (string, time.Time, error) {
+	req, err := http.NewRequestWithContext(ctx, "POST", endpoint, strings.NewReader(form.Encode()))
+	if err != nil {
+		return "", time.Time{}, err
+	}
+	req.Header.Set("Content-Type", "application/x-www-form-urlencoded")
+	return readToken(client, req)
+}
+
+// readToken reads {"access_token", "expires_in"}, which Google, Entra ID
+// and both metadata servers answer with. Azure's IMDS sends expires_in as
+// a string.
+func readToken(client *http.Client, req *http.Request) (string, time.Time, error) {
+	resp, err := client.Do(req)
+	if err != nil {
+		return "", time.Time{}, err
+	}
+	defer resp.Body.Close()
+	data, _ := io.ReadAll(io.LimitReader(resp.Body, 64*1024))
+	if resp.StatusCode != http.StatusOK {
+		return "", time.Time{}, fmt.Errorf("token request to %s returned %d: %s", req.URL.Host, resp.StatusCode, bytes.TrimSpace(data))
+	}
+	var answer struct {
+		AccessToken string      `json:"access_token"`
+		ExpiresIn   json.Number `json:"expires_in"`
+	}
+	if err := json.Unmarshal(data, &answer); err != nil || answer.AccessToken == "" {
+		return "", time.Time{}, fmt.Errorf("invalid token response from %s", req.URL.Host)
+	}
+	seconds, _ := answer.ExpiresIn.Int64()
+	if seconds <= 0 {
+		seconds = 3600
+	}
+	return answer.AccessToken, time.Now().Add(time.Duration(seconds) * time.Second), nil
+}
+
+func newGCSTransport(base http.RoundTripper) *gcsTransport {
+	t := &gcsTransport{base: base}
+	if host := os.Getenv("STORAGE_EMULATOR_HOST"); host != "" {
+		if !strings.Contains(host, "://") {
+			host = "http://" + host
+		}
+		t.endpoint, _ = url.Parse(host)
+	}
+	tokens := &http.Client{Transport: base, Timeout: 30 * time.Second}
+	metadata := &http.Client{Timeout: 2 * time.Second, Transport: &http.Transport{}}
+	t.token = &bearerToken{fetch: func(ctx context.Context) (string, time.Time, error) {
+		return googleToken(ctx, tokens, metadata)
+	}}
+	return t
+}
+
+func (t *gcsTransport) RoundTrip(req *http.Request) (*http.Response, error) {
+	bucket, object := req.URL.Host, strings.TrimPrefix(req.URL.Path, "/")
+	if bucket == "" || object == "" {
+		return nil, fmt.Errorf("invalid GCS URL %s, want gs://bucket/object", req.URL.Redacted())
+	}
+	out := req.Clone(req.Context())
+	out.URL = &url.URL{Scheme: "https", Host: "storage.googleapis.com", Path: "/" + bucket + "/" + object}
+	if t.endpoint != nil {
+		out.URL.Scheme, out.URL.Host = t.endpoint.Scheme, t.endpoint.Host
+	}
+	out.Host = ""
+	out.Header.Del("Authorization")
+	if t.endpoint == nil {
+		token, err := t.token.get(req.Context())
+		if err != nil {
+			return nil, err
+		}
+		if token != "" {
+			out.Header.Set("Authorization", "Bearer "+token)
+		}
+	}
+	resp, err := t.base.RoundTrip(out)
+	return cloudResponse(req, resp, err)
+}
+
+// cloudResponse finishes a response from a storage API: callers see the
+// URL they asked for, which names the file, and objects always serve
+// ranges even where HEAD doesn't say so
+func cloudResponse(req *http.Request, resp *http.Response, err error) (*http.Response, error) {
+	if err != nil {
+		return nil, err
+	}
+	resp.Request = req
+	if resp.StatusCode == http.StatusOK && resp.Header.Get("Accept-Ranges") == "" {
+		resp.Header.Set("Accept-Ranges", "bytes")
+	}
+	return resp, nil
+}
+
+// googleToken walks the Application Default Credentials chain:
+// $GOOGLE_OAUTH_ACCESS_TOKEN, the $GOOGLE_APPLICATION_CREDENTIALS or gcloud
+// credentials file, then the metadata server. Without any of them requests
+// go out anonymously, which public buckets accept.
+func googleToken(ctx context.Context, tokens, metadata *http.Client) (string, time.Time, error) {
+	if token := os.Getenv("GOOGLE_OAUTH_ACCESS_TOKEN"); token != "" {
+		return token, time.Time{}, nil
+	}
+
+	path := os.Getenv("GOOGLE_APPLICATION_CREDENTIALS")
+	if path == "" {
+		homeDir, _ := os.UserHomeDir()
+		path = filepath.Join(homeDir, ".config", "gcloud", "application_default_credentials.json")
+	}
+	if data, err := os.ReadFile(path); err == nil {
+		var creds struct {
+			Type         string `json:"type"`
+			ClientEmail  string `json:"client_email"`
+			PrivateKey   string `json:"private_key"`
+			TokenURI     string `json:"token_uri"`
+			ClientID     string `json:"client_id"`
+			ClientSecret string `json:"client_secret"`
+			RefreshToken string `json:"refresh_token"`
+		}
+		if err := json.Unmarshal(data, &creds); err != nil {
+			return "", time.Time{}, fmt.Errorf("invalid Google credentials %s: %w", path, err)
+		}
+		if creds.TokenURI == "" {
+			creds.TokenURI = "https://oauth2.googleapis.com/token"
+		}
+		switch creds.Type {
+		case "service_account":
+			assertion, err := googleJWT(creds.ClientEmail, creds.PrivateKey, creds.TokenURI)
+			if err != nil {
+				return "", time.Time{}, fmt.Errorf("invalid Google credentials %s: %w", path, err)
+			}
+			return postToken(ctx, tokens, creds.TokenURI, url.Values{
+				"grant_type": {"urn:ietf:params:oauth:grant-type:jwt-bearer"},
+				"assertion":  {assertion},
+			})
+		case "authorized_user":
+			return postToken(ctx, tokens, creds.TokenURI, url.Values{
+				"grant_type":    {"refresh_token"},
+				"client_id":     {creds.ClientID},
+				"client_secret": {creds.ClientSecret},
+				"refresh_token": {creds.RefreshToken},
+			})
+		default:
+			return "", time.Time{}, fmt.Errorf("unsupported Google credentials type %q in %s", creds.Type, path)
+		}
+	}
+
+	host := os.Getenv("GCE_METADATA_HOST")
+	if host == "" {
+		host = "metadata.google.internal"
+	}
+	req, err := http.NewRequestWithContext(ctx, "GET", "http://"+host+"/computeMetadata/v1/instance/service-accounts/default/token", nil)
+	if err != nil {
+		return "", time.Time{}, err
+	}
+	req.Header.Set("Metadata-Flavor", "Google")
+	token, expires, err := readToken(metadata, req)
+	if err == nil {
+		return token, expires, nil
+	}
+	logger.Debug("no Google credentials found, sending GCS requests anonymously", "err", err)
+	return "", time.Time{}, nil
+}
+
+// googleJWT signs the assertion a service account trades for a token
+func googleJWT(email, privateKey, audience string) (string, error) {
+	block, _ := pem.Decode([]byte(privateKey))
+	if block == nil {
+		return "", errors.New("no PEM private key")
+	}
+	parsed, err := x509.ParsePKCS8PrivateKey(block.Bytes)
+	if err != nil {
+		if parsed, err = x509.ParsePKCS1PrivateKey(block.Bytes); err != nil {
+			return "", err
+		}
+	}
+	key, ok := parsed.(*rsa.PrivateKey)
+	if !ok {
+		return "", errors.New("private key is not RSA")
+	}
+
+	now := time.Now()
+	header, _ := json.Marshal(map[string]string{"alg": "RS256", "typ": "JWT"})
+	claims, _ := json.Marshal(map[string]interface{}{
+		"iss":   email,
+		"scope": "https://www.googleapis.com/auth/devstorage.read_only",
+		"aud":   audience,
+		"iat":   now.Unix(),
+		"exp":   now.Add(time.Hour).Unix(),
+	})
+	unsigned := base64.RawURLEncoding.EncodeToString(header) + "." + base64.RawURLEncoding.EncodeToString(claims)
+	digest := sha256.Sum256([]byte(unsigned))
+	signature, err := rsa.SignPKCS1v15(nil, key, crypto.SHA256, digest[:])
+	if err != nil {
+		return "", err
+	}
+	return unsigned + "." + base64.RawURLEncoding.EncodeToString(signature), nil
+}
+
+func newAzureTransport(base http.RoundTripper) *azureTransport {
+	t := &azureTransport{base: base, sas: strings.TrimPrefix(os.Getenv("AZURE_STORAGE_SAS_TOKEN"), "?")}
+	if key := os.Getenv("AZURE_STORAGE_KEY"); key != "" {
+		if decoded, err := base64.StdEncoding.DecodeString(key); err == nil {
+			t.key = decoded
+		} else {
+			logger.Warn("ignoring AZURE_STORAGE_KEY, not base64", "err", err)
+		}
+	}
+	tokens := &http.Client{Transport: base, Timeout: 30 * time.Second}
+	metadata := &http.Client{Timeout: 2 * time.Second, Transport: &http.Transport{}}
+	t.token = &bearerToken{fetch: func(ctx context.Context) (string, time.Time, error) {
+		return azureToken(ctx, tokens, metadata)
+	}}
+	return t
+}
+
+func (t *azureTransport) RoundTrip(req *http.Request) (*http.Response, error) {
+	account := req.URL.Host
+	container, blob, _ := strings.Cut(strings.TrimPrefix(req.URL.Path, "/"), "/")
+	if account == "" || container == "" || blob == "" {
+		return nil, fmt.Errorf("invalid Azure URL %s, want az://account/container/blob", req.URL.Redacted())
+	}
+	out := req.Clone(req.Context())
+	out.URL = &url.URL{Scheme: "https", Host: account + ".blob.core.windows.net", Path: "/" + container + "/" + blob, RawQuery: t.sas}
+	out.Host = ""
+	out.Header.Del("Authorization")
+	out.Header.Set("X-Ms-Version", "2021-08-06")
+	out.Header.Set("X-Ms-Date", time.Now().UTC().Format(http.TimeFormat))
+
+	switch {
+	case t.sas != "":
+	case t.key != nil:
+		out.Header.Set("Authorization", "SharedKey "+account+":"+azureSharedKey(out, account, t.key))
+	default:
+		token, err := t.token.get(req.Context())
+		if err != nil {
+			return nil, err
+		}
+		if token != "" {
+			out.Header.Set("Authorization", "Bearer "+token)
+		}
+	}
+	resp, err := t.base.RoundTrip(out)
+	return cloudResponse(req, resp, err)
+}
+
+// azureSharedKey signs a request with the storage account key
+func azureSharedKey(req *http.Request, account string, key []byte) string {
+	var ms []string
+	for name := range req.Header {
+		if lower := strings.ToLower(name); strings.HasPrefix(lower, "x-ms-") {
+			ms = append(ms, lower+":"+strings.TrimSpace(req.Header.Get(name)))
+		}
+	}
+	sort.Strings(ms)
+
+	resource := "/" + account + req.URL.EscapedPath()
+	query := req.URL.Query()
+	names := make([]string, 0, len(query))
+	for name := range query {
+		names = append(names, name)
+	}
+	sort.Strings(names)
+	for _, name := range names {
+		values := slices.Clone(query[name])
+		sort.Strings(values)
+		resource += "\n" + strings.ToLower(name) + ":" + strings.Join(values, ",")
+	}
+
+	toSign := strings.Join([]string{
+		req.Method,
+		req.Header.Get("Content-Encoding"),
+		req.Header.Get("Content-Language"),
+		"", // Content-Length, empty for bodyless requests
+		req.Header.Get("Content-MD5"),
+		req.Header.Get("Content-Type"),
+		"", // Date, x-ms-date is used instead
+		req.Header.Get("If-Modified-Since"),
+		req.Header.Get("If-Match"),
+		req.Header.Get("If-None-Match"),
+		req.Header.Get("If-Unmodified-Since"),
+		req.Header.Get("Range"),
+		strings.Join(ms, "\n"),
+		resource,
+	}, "\n")
+	mac := hmac.New(sha256.New, key)
+	mac.Write([]byte(toSign))
+	return base64.StdEncoding.EncodeToString(mac.Sum(nil))
+}
+
+// azureToken gets an Entra ID token for storage: from a service principal
+// in $AZURE_CLIENT_ID/$AZURE_CLIENT_SECRET/$AZURE_TENANT_ID, else the
+// managed identity. Without either requests go out anonymously, which
+// public containers accept.
+func azureToken(ctx context.Context, tokens, metadata *http.Client) (string, time.Time, error) {
+	const resource = "https://storage.azure.com/"
+	if secret := os.Getenv("AZURE_CLIENT_SECRET"); secret != "" {
+		endpoint := "https://login.microsoftonline.com/" + url.PathEscape(os.Getenv("AZURE_TENANT_ID")) + "/oauth2/v2.0/token"
+		return postToken(ctx, tokens, endpoint, url.Values{
+			"grant_type":    {"client_credentials"},
+			"client_id":     {os.Getenv("AZURE_CLIENT_ID")},
+			"client_secret": {secret},
+			"scope":         {resource + ".default"},
+		})
+	}
+
+	query := url.Values{"api-version": {"2018-02-01"}, "resource": {resource}}
+	if id := os.Getenv("AZURE_CLIENT_ID"); id != "" {
+		query.Set("client_id", id) // a user-assigned identity
+	}
+	req, err := http.NewRequestWithContext(ctx, "GET", "http://169.254.169.254/metadata/identity/oauth2/token?"+query.Encode(), nil)
+	if err != nil {
+		return "", time.Time{}, err
+	}
+	req.Header.Set("Metadata", "true")
+	token, expires, err := readToken(metadata, req)
+	if err == nil {
+		return token, expires, nil
+	}
+	logger.Debug("no Azure credentials found, sending blob requests anonymously", "err", err)
+	return "", time.Time{}, nil
+}
+
+func isFTPURL(urlStr string) bool {
+	scheme, _, _ := strings.Cut(urlStr, "://")
+	switch strings.ToLower(scheme) {
+	case "ftp", "ftps", "ftpes":
+		return true
+	}
+	return false
+}
+
+// ftpFileInfo logs in to read the size and modification time; servers
+// without SIZE give an unknown length
+func (dm *DownloadManager) ftpFileInfo(ctx context.Context, urlStr string) (*DownloadTask, error) {
+	if !dm.config.EnableFTP {
+		return nil, fmt.Errorf("ftp is disabled (enable_ftp)")
+	}
+	u, err := url.Parse(urlStr)
+	if err != nil {
+		return nil, err
+	}
+
+	c, err := dm.dialFTP(ctx, u)
+	if err != nil {
+		return nil, err
+	}
+	defer c.Close()
+
+	task := &DownloadTask{
+		URL:       urlStr,
+		StartTime: time.Now(),
+	}
+	if size, err := c.size(u.Path); err == nil {
+		task.Size = size
+		task.SupportsRange = true
+	}
+	if modified, err := c.modTime(u.Path); err == nil {
+		task.LastModified = modified.Format(http.TimeFormat)
+	}
+	if task.Filepath, err = extractFilename(urlStr, dm.config); err != nil {
+		return nil, err
+	}
+	return task, nil
+}
+
+// downloadFTP retrieves the file over one data connection. A resume sends
+// REST; FTP has no If-Range, so the sidecar's size and MDTM check is all
+// that guards a partial file.
+func (dm *DownloadManager) downloadFTP(ctx context.Context, task *DownloadTask, outputPath string, progress *ProgressInfo) error {
+	source := task.sourceURL()
+	release, err := dm.hostSlots.Acquire(ctx, hostOf(source))
+	if err != nil {
+		return err
+	}
+	defer release()
+
+	ctx, received, stopWatch := dm.watchSpeed(ctx)
+	defer stopWatch()
+
+	resumable := dm.resume && task.SupportsRange && task.Size > 0 && len(dm.config.TeeDirs) == 0
+	var offset int64
+	if resumable {
+		offset, _ = dm.prepareSingleResume(outputPath, task)
+	}
+
+	u, err := url.Parse(source)
+	if err != nil {
+		return err
+	}
+	c, err := dm.dialFTP(ctx, u)
+	if err != nil {
+		return stallCause(ctx, err)
+	}
+	defer c.Close()
+
+	body, err := c.retrieve(ctx, u.Path, offset)
+	if err != nil && offset > 0 && ctx.Err() == nil {
+		fmt.Printf("%sServer refused to resume (%v), restarting download%s\n", ColorYellow, err, ColorReset)
+		offset = 0
+		body, err = c.retrieve(ctx, u.Path, 0)
+	}
+	if err != nil {
+		return stallCause(ctx, err)
+	}
+	defer body.Close()
+	if offset > 0 {
+		fmt.Printf("%sResuming:%s from %s\n", ColorCyan, ColorReset, formatBytes(offset))
+	}
+
+	if err := dm.writeStream(ctx, task, outputPath, body, offset, task.Size-offset, resumable, progress, received); err != nil {
+		return err
+	}
+	return body.Close()
+}
+
+// dialFTP connects and logs in with the URL's credentials, anonymously when
+// it has none. Cancelling ctx closes the control connection.
+func (dm *DownloadManager) dialFTP(ctx context.Context, u *url.URL) (*ftpConn, error) {
+	// The path and credentials go into commands as decoded, so an escaped
+	// line break would end the command and smuggle in another
+	fields := []string{u.Path}
+	if u.User != nil {
+		pass, _ := u.User.Password()
+		fields = append(fields, u.User.Username(), pass)
+	}
+	for _, field := range fields {
+		if strings.ContainsAny(field, "\r\n") {
+			return nil, configError(errors.New("ftp path or credentials contain a line break"))
+		}
+	}
+
+	addr := u.Host
+	if u.Port() == "" {
+		port := "21"
+		if u.Scheme == "ftps" {
+			port = "990"
+		}
+		addr = net.JoinHostPort(u.Hostname(), port)
+	}
+
+	dialer := &net.Dialer{Timeout: dm.config.connectTimeout()}
+	conn, err := dialer.DialContext(ctx, "tcp", addr)
+	if err != nil {
+		return nil, err
+	}
+
+	c := &ftpConn{conn: conn, active: dm.config.FTPActive}
+	c.stop = context.AfterFunc(ctx, func() { conn.Close() })
+	if u.Scheme == "ftps" || u.Scheme == "ftpes" {
+		// Servers commonly insist data connections resume the control session
+		c.tls = dm.tlsConfig.Clone()
+		c.tls.ServerName = u.Hostname()
+		c.tls.ClientSessionCache = tls.NewLRUClientSessionCache(4)
+	}
+	if u.Scheme == "ftps" {
+		c.conn = tls.Client(conn, c.tls)
+	}
+	c.text = textproto.NewConn(c.conn)
+
+	if _, _, err := c.text.ReadResponse(220); err != nil {
+		c.Close()
+		return nil, err
+	}
+	if u.Scheme == "ftpes" {
+		if _, _, err := c.cmd(234, "AUTH TLS"); err != nil {
+			c.Close()
+			return nil, err
+		}
+		c.conn = tls.Client(conn, c.tls)
+		c.text = textproto.NewConn(c.conn)
+	}
+
+	user, pass := "anonymous", "fastdl@"
+	if u.User != nil {
+		user = u.User.Username()
+		if p, ok := u.User.Password(); ok {
+			pass = p
+		} else if entry, ok := dm.auth.netrcFor(u.Hostname(), user); ok {
+			pass = entry.password
+		}
+	} else if entry, ok := dm.auth.netrcFor(u.Hostname(), ""); ok {
+		user, pass = entry.login, entry.password
+	}
+	code, msg, err := c.cmd(0, "USER %s", user)
+	if err == nil && code == 331 {
+		code, msg, err = c.cmd(0, "PASS %s", pass)
+	}
+	if err == nil && code != 230 {
+		err = fmt.Errorf("ftp login failed: %d %s", code, msg)
+	}
+
+	if err == nil && c.tls != nil {
+		if _, _, err = c.cmd(200, "PBSZ 0"); err == nil {
+			_, _, err = c.cmd(200, "PROT P")
+		}
+	}
+	if err == nil {
+		_, _, err = c.cmd(200, "TYPE I")
+	}
+	if err != nil {
+		c.Close()
+		return nil, err
+	}
+	return c, nil
+}
+
+// cmd sends a command and reads its reply; expect works like
+// textproto.Reader.ReadResponse, 0 accepts any code
+func (c *ftpConn) cmd(expect int, format string, args ...interface{}) (int, string, error) {
+	id, err := c.text.Cmd(format, args...)
+	if err != nil {
+		return 0, "", err
+	}
+	c.text.StartResponse(id)
+	defer c.text.EndResponse(id)
+	return c.text.ReadResponse(expect)
+}
+
+func (c *ftpConn) size(path string) (int64, error) {
+	_, msg, err := c.cmd(213, "SIZE %s", path)
+	if err != nil {
+		return 0, err
+	}
+	return strconv.ParseInt(strings.TrimSpace(msg), 10, 64)
+}
+
+func (c *ftpConn) modTime(path string) (time.Time, error) {
+	_, msg, err := c.cmd(213, "MDTM %s", path)
+	if err != nil {
+		return time.Time{}, err
+	}
+	// Some servers append fractional seconds
+	stamp, _, _ := strings.Cut(strings.TrimSpace(msg), ".")
+	return time.Parse("20060102150405", stamp)
+}
+
+// retrieve starts RETR at offset and returns the data stream
+func (c *ftpConn) retrieve(ctx context.Context, path string, offset int64) (io.ReadCloser, error) {
+	accept, err := c.openData(ctx)
+	if err != nil {
+		return nil, err
+	}
+	if offset > 0 {
+		if _, _, err := c.cmd(350, "REST %d", offset); err != nil {
+			accept(false)
+			return nil, err
+		}
+	}
+	if _, _, err := c.cmd(1, "RETR %s", path); err != nil {
+		accept(false)
+		return nil, err
+	}
+
+	data, err := accept(true)
+	if err != nil {
+		return nil, err
+	}
+	if c.tls != nil {
+		data = tls.Client(data, c.tls)
+	}
+	return &ftpData{Conn: data, c: c}, nil
+}
+
+// openData prepares a data connection. Passive mode dials the address from
+// EPSV (or PASV) on the control connection's host, ignoring the IP a NATed
+// server reports; active mode listens and has the server connect back.
+// The returned accept yields the connection, or cleans up when passed false.
+func (c *ftpConn) openData(ctx context.Context) (func(bool) (net.Conn, error), error) {
+	host, _, _ := net.SplitHostPort(c.conn.RemoteAddr().String())
+	if !c.active {
+		port, err := c.passivePort()
+		if err != nil {
+			return nil, err
+		}
+		var dialer net.Dialer
+		conn, err := dialer.DialContext(ctx, "tcp", net.JoinHostPort(host, strconv.Itoa(port)))
+		if err != nil {
+			return nil, err
+		}
+		return func(use bool) (net.Conn, error) {
+			if !use {
+				conn.Close()
+			}
+			return conn, nil
+		}, nil
+	}
+
+	localIP, _, _ := net.SplitHostPort(c.conn.LocalAddr().String())
+	ln, err := net.Listen("tcp", net.JoinHostPort(localIP, "0"))
+	if err != nil {
+		return nil, err
+	}
+	port := ln.Addr().(*net.TCPAddr).Port
+	family := 1
+	if strings.Contains(localIP, ":") {
+		family = 2
+	}
+	if _, _, err := c.cmd(200, "EPRT |%d|%s|%d|", family, localIP, port); err != nil {
+		if family == 2 {
+			ln.Close()
+			return nil, err
+		}
+		if _, _, err := c.cmd(200, "PORT %s,%d,%d", strings.ReplaceAll(localIP, ".", ","), port>>8, port&0xff); err != nil {
+			ln.Close()
+			return nil, err
+		}
+	}
+	return func(use bool) (net.Conn, error) {
+		defer ln.Close()
+		if !use {
+			return nil, nil
+		}
+		ln.(*net.TCPListener).SetDeadline(time.Now().Add(30 * time.Second))
+		return ln.Accept()
+	}, nil
+}
+
+// passivePort asks for a passive data port, EPSV first since it also
+// works over IPv6
+func (c *ftpConn) passivePort() (int, error) {
+	if _, msg, err := c.cmd(229, "EPSV"); err == nil {
+		// "Entering Extended Passive Mode (|||6446|)"
+		start := strings.Index(msg, "(|||")
+		end := strings.LastIndex(msg, "|)")
+		if start >= 0 && end > start+4 {
+			return strconv.Atoi(msg[start+4 : end])
+		}
+	}
+
+	_, msg, err := c.cmd(227, "PASV")
+	if err != nil {
+		return 0, err
+	}
+	// "Entering Passive Mode (h1,h2,h3,h4,p1,p2)"
+	start := strings.Index(msg, "(")
+	end := strings.LastIndex(msg, ")")
+	if start < 0 || end < start {
+		return 0, fmt.Errorf("malformed PASV reply %q", msg)
+	}
+	fields := strings.Split(msg[start+1:end], ",")
+	if len(fields) != 6 {
+		return 0, fmt.Errorf("malformed PASV reply %q", msg)
+	}
+	p1, err1 := strconv.Atoi(strings.TrimSpace(fields[4]))
+	p2, err2 := strconv.Atoi(strings.TrimSpace(fields[5]))
+	if err1 != nil || err2 != nil {
+		return 0, fmt.Errorf("malformed PASV reply %q", msg)
+	}
+	return p1<<8 | p2, nil
+}
+
+// Close ends the data stream and reads the transfer reply once
+func (d *ftpData) Close() error {
+	if d.c == nil {
+		return nil
+	}
+	c := d.c
+	d.c = nil
+	d.Conn.Close()
+	code, msg, err := c.text.ReadResponse(0)
+	if err != nil {
+		return err
+	}
+	if code != 226 && code != 250 {
+		return fmt.Errorf("ftp transfer failed: %d %s", code, msg)
+	}
+	return nil
+}
+
+// Close logs out and drops the control connection
+func (c *ftpConn) Close() error {
+	c.stop()
+	c.text.Cmd("QUIT")
+	return c.conn.Close()
+}
+
+// SFTP version 3 packet types and status codes
+const (
+	fxpInit    = 1
+	fxpVersion = 2
+	fxpOpen    = 3
+	fxpClose   = 4
+	fxpRead    = 5
+	fxpStat    = 17
+	fxpStatus  = 101
+	fxpHandle  = 102
+	fxpData    = 103
+	fxpAttrs   = 105
+
+	fxpStatusEOF = 1
+)
+
+// isSFTPURL also takes scp:// since servers offering scp almost always
+// run the sftp subsystem too
+func isSFTPURL(urlStr string) bool {
+	scheme, _, _ := strings.Cut(urlStr, "://")
+	switch strings.ToLower(scheme) {
+	case "sftp", "scp":
+		return true
+	}
+	return false
+}
+
+func (dm *DownloadManager) sftpFileInfo(ctx context.Context, urlStr string) (*DownloadTask, error) {
+	u, err := url.Parse(urlStr)
+	if err != nil {
+		return nil, err
+	}
+	c, err := dm.dialSFTP(ctx, u)
+	if err != nil {
+		return nil, err
+	}
+	defer c.Close()
+
+	size, modified, err := c.stat(u.Path)
+	if err != nil {
+		return nil, err
+	}
+	task := &DownloadTask{
+		URL:           urlStr,
+		StartTime:     time.Now(),
+		Size:          size,
+		SupportsRange: size > 0,
+	}
+	if !modified.IsZero() {
+		task.LastModified = modified.UTC().Format(http.TimeFormat)
+	}
+	if task.Filepath, err = extractFilename(urlStr, dm.config); err != nil {
+		return nil, err
+	}
+	return task, nil
+}
+
+// downloadSFTP streams the file through pipelined READs. Like FTP there is
+// no If-Range, the sidecar's size and mtime check guards a resume.
+func (dm *DownloadManager) downloadSFTP(ctx context.Context, task *DownloadTask, outputPath string, progress *ProgressInfo) error {
+	source := task.sourceURL()
+	release, err := dm.hostSlots.Acquire(ctx, hostOf(source))
+	if err != nil {
+		return err
+	}
+	defer release()
+
+	ctx, received, stopWatch := dm.watchSpeed(ctx)
+	defer stopWatch()
+
+	resumable := dm.resume && task.SupportsRange && len(dm.config.TeeDirs) == 0
+	var offset int64
+	if resumable {
+		offset, _ = dm.prepareSingleResume(outputPath, task)
+	}
+
+	u, err := url.Parse(source)
+	if err != nil {
+		return err
+	}
+	c, err := dm.dialSFTP(ctx, u)
+	if err != nil {
+		return stallCause(ctx, err)
+	}
+	defer c.Close()
+	// Cancelling has to unblock reads waiting on the server
+	stop := context.AfterFunc(ctx, func() { c.Close() })
+	defer stop()
+
+	handle, err := c.open(u.Path)
+	if err != nil {
+		return err
+	}
+	defer c.closeHandle(handle)
+	if offset > 0 {
+		fmt.Printf("%sResuming:%s from %s\n", ColorCyan, ColorReset, formatBytes(offset))
+	}
+
+	body := &sftpReader{c: c, handle: handle, offset: offset, size: task.Size}
+	if err := dm.writeStream(ctx, task, outputPath, body, offset, task.Size-offset, resumable, progress, received); err != nil {
+		return stallCause(ctx, err)
+	}
+	return nil
+}
+
+// dialSFTP logs in with the URL's password, the ssh agent and the usual
+// key files, checking the host against known_hosts, and starts the sftp
+// subsystem
+func (dm *DownloadManager) dialSFTP(ctx context.Context, u *url.URL) (*sftpClient, error) {
+	homeDir, _ := os.UserHomeDir()
+	c := &sftpClient{pending: make(map[uint32]chan sftpReply)}
+
+	user := os.Getenv("USER")
+	var auth []ssh.AuthMethod
+	if u.User != nil {
+		user = u.User.Username()
+		if password, ok := u.User.Password(); ok {
+			auth = append(auth, ssh.Password(password))
+		}
+	}
+	if sock := os.Getenv("SSH_AUTH_SOCK"); sock != "" {
+		if conn, err := net.Dial("unix", sock); err == nil {
+			c.closers = append(c.closers, conn)
+			auth = append(auth, ssh.PublicKeysCallback(agent.NewClient(conn).Signers))
+		}
+	}
+	keyFiles := []string{dm.config.SSHKeyFile}
+	if dm.config.SSHKeyFile == "" {
+		for _, name := range []string{"id_ed25519", "id_ecdsa", "id_rsa"} {
+			keyFiles = append(keyFiles, filepath.Join(homeDir, ".ssh", name))
+		}
+	}
+	var signers []ssh.Signer
+	for _, path := range keyFiles {
+		data, err := os.ReadFile(path)
+		if err != nil {
+			continue
+		}
+		// Passphrase-protected keys are left to the agent
+		if signer, err := ssh.ParsePrivateKey(data); err == nil {
+			signers = append(signers, signer)
+		}
+	}
+	if len(signers) > 0 {
+		auth = append(auth, ssh.PublicKeys(signers...))
+	}
+
+	knownHostsFile := dm.config.SSHKnownHosts
+	if knownHostsFile == "" {
+		knownHostsFile = filepath.Join(homeDir, ".ssh", "known_hosts")
+	}
+	hostKeys, err := knownhosts.New(knownHostsFile)
+	if err != nil {
+		c.Close()
+		return nil, fmt.Errorf("known_hosts: %w", err)
+	}
+
+	addr := u.Host
+	if u.Port() == "" {
+		addr = net.JoinHostPort(u.Hostname(), "22")
+	}
+	timeout := dm.config.connectTimeout()
+	dialer := &net.Dialer{Timeout: timeout}
+	conn, err := dialer.DialContext(ctx, "tcp", addr)
+	if err != nil {
+		c.Close()
+		return nil, err
+	}
+	sshConn, chans, reqs, err := ssh.NewClientConn(conn, addr, &ssh.ClientConfig{
+		User:            user,
+		Auth:            auth,
+		HostKeyCallback: hostKeys,
+		Timeout:         timeout,
+	})
+	if err != nil {
+		conn.Close()
+		c.Close()
+		return nil, err
+	}
+	c.conn = ssh.NewClient(sshConn, chans, reqs)
+
+	if c.session, err = c.conn.NewSession(); err == nil {
+		if c.w, err = c.session.StdinPipe(); err == nil {
+			var stdout io.Reader
+			if stdout, err = c.session.StdoutPipe(); err == nil {
+				c.r = bufio.NewReader(stdout)
+				err = c.session.RequestSubsystem("sftp")
+			}
+		}
+	}
+	if err == nil {
+		err = c.handshake()
+	}
+	if err != nil {
+		c.Close()
+		return nil, fmt.Errorf("sftp: %w", err)
+	}
+	go c.readReplies()
+	return c, nil
+}
+
+// handshake exchanges INIT and VERSION, which carry no request id
+func (c *sftpClient) handshake() error {
+	if err := c.writePacket(fxpInit, binary.BigEndian.AppendUint32(nil, 3)); err != nil {
+		return err
+	}
+	typ, _, err := c.readPacket()
+	if err != nil {
+		return err
+	}
+	if typ != fxpVersion {
+		return fmt.Errorf("unexpected packet %d instead of version", typ)
+	}
+	return nil
+}
+
+func (c *sftpClient) writePacket(typ byte, payload []byte) error {
+	packet := binary.BigEndian.AppendUint32(nil, uint32(len(payload)+1))
+	packet = append(packet, typ)
+	packet = append(packet, payload...)
+	_, err := c.w.Write(packet)
+	return err
+}
+
+func (c *sftpClient) readPacket() (byte, []byte, error) {
+	var header [5]byte
+	if _, err := io.ReadFull(c.r, header[:]); err != nil {
+		return 0, nil, err
+	}
+	length := binary.BigEndian.Uint32(header[:4])
+	if length == 0 || length > 1<<20 {
+		return 0, nil, fmt.Errorf("bad packet length %d", length)
+	}
+	payload := make([]byte, length-1)
+	if _, err := io.ReadFull(c.r, payload); err != nil {
+		return 0, nil, err
+	}
+	return header[4], payload, nil
+}
+
+// readReplies hands each reply to the request waiting for its id. When the
+// connection drops every waiter sees its channel closed.
+func (c *sftpClient) readReplies() {
+	for {
+		typ, payload, err := c.readPacket()
+		if err == nil && len(payload) < 4 {
+			err = fmt.Errorf("short packet")
+		}
+		if err != nil {
+			c.mu.Lock()
+			c.err = err
+			for id, reply := range c.pending {
+				close(reply)
+				delete(c.pending, id)
+			}
+			c.mu.Unlock()
+			return
+		}
+
+		id := binary.BigEndian.Uint32(payload)
+		c.mu.Lock()
+		reply, ok := c.pending[id]
+		delete(c.pending, id)
+		c.mu.Unlock()
+		if ok {
+			reply <- sftpReply{typ: typ, data: payload[4:]}
+		}
+	}
+}
+
+// request sends a packet under a fresh id; the reply arrives on the channel
+func (c *sftpClient) request(typ byte, body []byte) (<-chan sftpReply, error) {
+	c.mu.Lock()
+	if c.err != nil {
+		c.mu.Unlock()
+		return nil, c.err
+	}
+	c.nextID++
+	id := c.nextID
+	reply := make(chan sftpReply, 1)
+	c.pending[id] = reply
+	c.mu.Unlock()
+
+	if err := c.writePacket(typ, append(binary.BigEndian.AppendUint32(nil, id), body...)); err != nil {
+		c.mu.Lock()
+		delete(c.pending, id)
+		c.mu.Unlock()
+		return nil, err
+	}
+	return reply, nil
+}
+
+// call sends a request and waits for its reply, turning error statuses
+// into errors
+func (c *sftpClient) call(typ byte, body []byte) (sftpReply, error) {
+	ch, err := c.request(typ, body)
+	if err != nil {
+		return sftpReply{}, err
+	}
+	reply, ok := <-ch
+	if !ok {
+		return reply, fmt.Errorf("sftp connection lost")
+	}
+	if reply.typ == fxpStatus {
+		if code, err := sftpStatusErr(reply.data); code != 0 {
+			return reply, err
+		}
+	}
+	return reply, nil
+}
+
+// sftpStatusErr decodes a STATUS reply; code 0 is success
+func sftpStatusErr(data []byte) (uint32, error) {
+	if len(data) < 4 {
+		return 1<<32 - 1, fmt.Errorf("sftp: malformed status")
+	}
+	code := binary.BigEndian.Uint32(data)
+	msg, _, _ := sftpString(data[4:])
+	return code, fmt.Errorf("sftp: %s (status %d)", msg, code)
+}
+
+func sftpString(data []byte) ([]byte, []byte, bool) {
+	if len(data) < 4 {
+		return nil, nil, false
+	}
+	n := binary.BigEndian.Uint32(data)
+	if uint64(len(data)-4) < uint64(n) {
+		return nil, nil, false
+	}
+	return data[4 : 4+n], data[4+n:], true
+}
+
+func appendSFTPString(buf []byte, value []byte) []byte {
+	buf = binary.BigEndian.AppendUint32(buf, uint32(len(value)))
+	return append(buf, value...)
+}
+
+// stat returns the size and mtime of path; either is zero when the server
+// leaves it out
+func (c *sftpClient) stat(path string) (int64, time.Time, error) {
+	reply, err := c.call(fxpStat, appendSFTPString(nil, []byte(path)))
+	if err != nil {
+		return 0, time.Time{}, err
+	}
+	if reply.typ != fxpAttrs || len(reply.data) < 4 {
+		return 0, time.Time{}, fmt.Errorf("sftp: unexpected reply %d to stat", reply.typ)
+	}
+
+	flags := binary.BigEndian.Uint32(reply.data)
+	attrs := reply.data[4:]
+	var size int64
+	var modified time.Time
+	if flags&0x1 != 0 && len(attrs) >= 8 {
+		size = int64(binary.BigEndian.Uint64(attrs))
+		attrs = attrs[8:]
+	}
+	if flags&0x2 != 0 && len(attrs) >= 8 {
+		attrs = attrs[8:] // uid, gid
+	}
+	if flags&0x4 != 0 && len(attrs) >= 4 {
+		attrs = attrs[4:] // permissions
+	}
+	if flags&0x8 != 0 && len(attrs) >= 8 {
+		modified = time.Unix(int64(binary.BigEndian.Uint32(attrs[4:])), 0)
+	}
+	return size, modified, nil
+}
+
+func (c *sftpClient) open(path string) ([]byte, error) {
+	body := appendSFTPString(nil, []byte(path))
+	body = binary.BigEndian.AppendUint32(body, 0x1) // SSH_FXF_READ
+	body = binary.BigEndian.AppendUint32(body, 0)   // no attributes
+	reply, err := c.call(fxpOpen, body)
+	if err != nil {
+		return nil, err
+	}
+	handle, _, ok := sftpString(reply.data)
+	if reply.typ != fxpHandle || !ok {
+		return nil, fmt.Errorf("sftp: unexpected reply %d to open", reply.typ)
+	}
+	return handle, nil
+}
+
+func (c *sftpClient) closeHandle(handle []byte) {
+	c.call(fxpClose, appendSFTPString(nil, handle))
+}
+
+// Close ends the session and the SSH connection; safe to call twice
+func (c *sftpClient) Close() error {
+	if c.session != nil {
+		c.session.Close()
+	}
+	if c.conn != nil {
+		c.conn.Close()
+	}
+	for _, closer := range c.closers {
+		closer.Close()
+	}
+	return nil
+}
+
+func (r *sftpReader) Read(p []byte) (int, error) {
+	for len(r.buf) == 0 {
+		if r.err != nil {
+			return 0, r.err
+		}
+		r.fill()
+		if len(r.queue) == 0 {
+			if r.err == nil {
+				r.err = io.EOF
+			}
+			continue
+		}
+
+		head := r.queue[0]
+		r.queue = r.queue[1:]
+		reply, ok := <-head.reply
+		switch {
+		case !ok:
+			r.err = fmt.Errorf("sftp connection lost")
+		case reply.typ == fxpStatus:
+			code, err := sftpStatusErr(reply.data)
+			if code == fxpStatusEOF {
+				// Requests past the end come back EOF as well
+				r.err = io.EOF
+			} else {
+				r.err = err
+			}
+		case reply.typ == fxpData:
+			data, _, ok := sftpString(reply.data)
+			if !ok {
+				r.err = fmt.Errorf("sftp: malformed data reply")
+				break
+			}
+			r.buf = data
+			// A short read leaves a gap, ask for the rest before anything queued
+			if len(data) > 0 && len(data) < int(head.length) {
+				next, err := r.read(head.offset+int64(len(data)), head.length-uint32(len(data)))
+				if err != nil {
+					r.err = err
+				} else {
+					r.queue = append([]sftpRead{next}, r.queue...)
+				}
+			}
+		default:
+			r.err = fmt.Errorf("sftp: unexpected reply %d to read", reply.typ)
+		}
+	}
+
+	n := copy(p, r.buf)
+	r.buf = r.buf[n:]
+	return n, nil
+}
+
+// fill tops the queue up to SFTPMaxRequests; without a known size reads
+// go one at a time until EOF
+func (r *sftpReader) fill() {
+	for r.err == nil && len(r.queue) < SFTPMaxRequests {
+		if r.size > 0 && r.offset >= r.size {
+			return
+		}
+		if r.size <= 0 && len(r.queue) > 0 {
+			return
+		}
+		read, err := r.read(r.offset, BufferSize)
+		if err != nil {
+			r.err = err
+			return
+		}
+		r.queue = append(r.queue, read)
+		r.offset += BufferSize
+	}
+}
+
+func (r *sftpReader) read(offset int64, length uint32) (sftpRead, error) {
+	body := appendSFTPString(nil, r.handle)
+	body = binary.BigEndian.AppendUint64(body, uint64(offset))
+	body = binary.BigEndian.AppendUint32(body, length)
+	reply, err := r.c.request(fxpRead, body)
+	return sftpRead{offset: offset, length: length, reply: reply}, err
+}
+
+// contentRangeStart parses the first byte position of a 206 response, or
+// -1 when the header is missing or malformed
+func contentRangeStart(resp *http.Response) int64 {
+	start, _, _, ok := parseContentRange(resp.Header.Get("Content-Range"))
+	if !ok {
+		return -1
+	}
+	return start
+}
+
+// parseContentRange splits "bytes first-last/total" into its positions.
+// total is -1 when the server sends "*" for an unknown length.
+func parseContentRange(value string) (start, end, total int64, ok bool) {
+	value, ok = strings.CutPrefix(value, "bytes ")
+	if !ok {
+		return 0, 0, 0, false
+	}
+	span, size, ok := strings.Cut(value, "/")
+	if !ok {
+		return 0, 0, 0, false
+	}
+	first, last, ok := strings.Cut(span, "-")
+	if !ok {
+		return 0, 0, 0, false
+	}
+	var err1, err2 error
+	start, err1 = strconv.ParseInt(first, 10, 64)
+	end, err2 = strconv.ParseInt(last, 10, 64)
+	if err1 != nil || err2 != nil || start < 0 || end < start {
+		return 0, 0, 0, false
+	}
+	total = -1
+	if size != "*" {
+		if total, err1 = strconv.ParseInt(size, 10, 64); err1 != nil || total <= end {
+			return 0, 0, 0, false
+		}
+	}
+	return start, end, total, true
+}
+
+// hashPrefix feeds the first n bytes of an existing file into hashes
+func hashPrefix(path string, n int64, hashes map[string]hash.Hash) error {
+	file, err := os.Open(path)
+	if err != nil {
+		return err
+	}
+	defer file.Close()
+
+	writers := make([]io.Writer, 0, len(hashes))
+	for _, h := range hashes {
+		writers = append(writers, h)
+	}
+	_, err = io.CopyN(io.MultiWriter(writers...), file, n)
+	return err
+}
+
+// reportProgress displays download progress
+func (dm *DownloadManager) reportProgress(ctx context.Context, task *DownloadTask, progress *ProgressInfo, done <-chan bool) {
+	ticker := time.NewTicker(ProgressUpdate)
+	defer ticker.Stop()
+
+	if dm.bars != nil {
+		row := dm.bars.Add(task, progress)
+		defer dm.bars.Remove(row)
+		select {
+		case <-ctx.Done():
+		case <-done:
+		}
+		return
+	}
+
+	lastDownloaded := int64(0)
+	lastTime := time.Now()
+	ndjson := dm.config.ProgressFormat == "ndjson"
+
+	for {
+		select {
+		case <-ctx.Done():
+			return
+		case <-done:
+			if ndjson {
+				dm.writeProgress(task, progress, 0, "done")
+			}
+			return
+		case <-ticker.C:
+			downloaded := atomic.LoadInt64(&progress.Downloaded)
+			now := time.Now()
+			elapsed := now.Sub(lastTime).Seconds()
+
+			if dm.Paused() {
+				if ndjson {
+					dm.writeProgress(task, progress, 0, "paused")
+				} else {
+					fmt.Printf("\r%s[PAUSED] %s/%s | send SIGUSR2 to resume%s\033[K",
+						ColorYellow, formatBytes(downloaded), formatBytes(progress.Total), ColorReset)
+				}
+				lastDownloaded, lastTime = downloaded, now
+				continue
+			}
+			
+			if elapsed > 0 {
+				speed := float64(downloaded-lastDownloaded) / elapsed / 1024 / 1024
+				percentage := 0.0
+				if progress.Total > 0 {
+					percentage = min(float64(downloaded)/float64(progress.Total)*100, 100)
+				}
+				
+				if speed > 0 {
+					remaining := progress.Total - downloaded
+					eta := time.Duration(float64(remaining) / (float64(downloaded-lastDownloaded) / elapsed)) * time.Second
+					progress.ETA = eta
+				}
+
+				active := atomic.LoadInt32(&progress.Active)
+
+				if len(dm.observers) > 0 {
+					stats := task.snapshot()
+					stats.Speed = float64(downloaded-lastDownloaded) / elapsed
+					dm.notify(func(o DownloadObserver) { o.OnProgress(task, stats) })
+				}
+
+				lastDownloaded = downloaded
+				lastTime = now
+
+				if ndjson {
+					dm.writeProgress(task, progress, speed*1024*1024, "downloading")
+					continue
+				}
+				
+				// Progress bar
+				barWidth := 40
+				filled := int(percentage * float64(barWidth) / 100)
+				bar := strings.Repeat("█", filled) + strings.Repeat("░", barWidth-filled)
+				
+				fmt.Printf("\r%s[%s] %.1f%% %s/%s | %.2f MB/s | %d active | ETA: %s%s",
+					ColorCyan, bar, percentage,
+					formatBytes(downloaded),
+					formatBytes(progress.Total),
+					speed,
+					active,
+					formatDuration(progress.ETA),
+					ColorReset)
+			}
+		}
+	}
+}
+
+// writeProgress emits one ndjson progress line; lines from concurrent
+// downloads never interleave
+func (dm *DownloadManager) writeProgress(task *DownloadTask, progress *ProgressInfo, speed float64, status string) {
+	line := DownloadProgress{
+		URL:        task.URL,
+		File:       task.Filepath,
+		Downloaded: atomic.LoadInt64(&progress.Downloaded),
+		Total:      progress.Total,
+		Speed:      speed,
+		ETA:        progress.ETA.Seconds(),
+		Active:     atomic.LoadInt32(&progress.Active),
+		Status:     status,
+	}
+	if line.Total > 0 {
+		line.Percent = min(float64(line.Downloaded)/float64(line.Total)*100, 100)
+	}
+
+	data, err := json.Marshal(line)
+	if err != nil {
+		return
+	}
+	dm.progressMu.Lock()
+	defer dm.progressMu.Unlock()
+	dm.progressOut.Write(append(data, '\n'))
+}
+
+// Add starts drawing a bar for a download
+func (b *multiBar) Add(task *DownloadTask, progress *ProgressInfo) *barRow {
+	row := &barRow{task: task, progress: progress, lastTime: time.Now()}
+
+	b.mu.Lock()
+	defer b.mu.Unlock()
+	b.rows = append(b.rows, row)
+	b.started++
+	if !b.running {
+		b.running = true
+		go b.run()
+	}
+	return row
+}
+
+// Remove drops a finished download's bar, it still counts in the aggregate
+func (b *multiBar) Remove(row *barRow) {
+	b.mu.Lock()
+	defer b.mu.Unlock()
+	b.rows = slices.DeleteFunc(b.rows, func(r *barRow) bool { return r == row })
+	b.finished++
+	b.drawLocked()
+}
+
+// Printf writes a message above the bars; a nil multiBar prints plainly
+func (b *multiBar) Printf(format string, args ...interface{}) {
+	if b == nil {
+		fmt.Printf(format, args...)
+		return
+	}
+	b.mu.Lock()
+	defer b.mu.Unlock()
+	b.clearLocked()
+	fmt.Fprintf(b.out, format, args...)
+	b.drawLocked()
+}
+
+// run redraws until no downloads are left
+func (b *multiBar) run() {
+	ticker := time.NewTicker(ProgressUpdate)
+	defer ticker.Stop()
+
+	for range ticker.C {
+		b.mu.Lock()
+		b.drawLocked()
+		if len(b.rows) == 0 {
+			b.running = false
+			b.mu.Unlock()
+			return
+		}
+		b.mu.Unlock()
+	}
+}
+
+func (b *multiBar) clearLocked() {
+	if b.lines > 0 {
+		fmt.Fprintf(b.out, "\033[%dA\r\033[J", b.lines)
+		b.lines = 0
+	}
+}
+
+func (b *multiBar) drawLocked() {
+	var out strings.Builder
+	if b.lines > 0 {
+		fmt.Fprintf(&out, "\033[%dA", b.lines)
+	}
+
+	now := time.Now()
+	var downloaded, total int64
+	var speed float64
+	for _, row := range b.rows {
+		done := atomic.LoadInt64(&row.progress.Downloaded)
+		if elapsed := now.Sub(row.lastTime).Seconds(); elapsed >= ProgressUpdate.Seconds()/2 {
+			row.speed = float64(done-row.lastDownloaded) / elapsed
+			row.lastDownloaded, row.lastTime = done, now
+		}
+		downloaded += done
+		total += row.progress.Total
+		speed += row.speed
+
+		line := fmt.Sprintf("%-24s %s %s/s", shortName(row.task.Filepath, 24),
+			renderBar(done, row.progress.Total, 25), formatBytes(int64(row.speed)))
+		if row.speed > 0 && row.progress.Total > done {
+			line += " ETA " + formatDuration(time.Duration(float64(row.progress.Total-done)/row.speed)*time.Second)
+		}
+		if chunks := atomic.LoadInt32(&row.progress.Chunks); chunks > 0 {
+			line += fmt.Sprintf(" | chunks %d/%d", atomic.LoadInt32(&row.progress.ChunksDone), chunks)
+		}
+		fmt.Fprintf(&out, "\r\033[K%s%s%s\n", ColorCyan, line, ColorReset)
+	}
+
+	fmt.Fprintf(&out, "\r\033[K%s%-24s %s %s/s | %d/%d files%s\n\033[J", ColorGreen,
+		"Total", renderBar(downloaded, total, 25), formatBytes(int64(speed)),
+		b.finished, b.started, ColorReset)
+
+	b.lines = len(b.rows) + 1
+	io.WriteString(b.out, out.String())
+}
+
+// renderBar formats a fixed-width bar with percentage and byte counts
+func renderBar(done, total int64, width int) string {
+	percentage := 0.0
+	if total > 0 {
+		percentage = min(float64(done)/float64(total)*100, 100)
+	}
+	filled := int(percentage * float64(width) / 100)
+	return fmt.Sprintf("[%s%s] %5.1f%% %s/%s", strings.Repeat("█", filled), strings.Repeat("░", width-filled),
+		percentage, formatBytes(done), formatBytes(total))
+}
+
+// shortName trims a file name to width runes, keeping the extension visible
+func shortName(name string, width int) string {
+	runes := []rune(name)
+	if len(runes) <= width {
+		return name
+	}
+	keep := width - 1
+	return string(runes[:keep/2]) + "…" + string(runes[len(runes)-(keep-keep/2):])
+}
+
+// verifyChecksums verifies file checksums
+func (dm *DownloadManager) verifyChecksums(filepath string, task *DownloadTask) error {
+	expected := task.expectedSums()
+	task.Digests = make(map[string]string)
+
+	for _, algorithm := range task.hashAlgorithms() {
+		want, verify := expected[algorithm]
+		if verify {
+			fmt.Printf("\n%sVerifying %s...%s", ColorYellow, strings.ToUpper(algorithm), ColorReset)
+		}
+		sum, err := fileHash(filepath, task, algorithm)
+		if err != nil {
+			return err
+		}
+		task.Digests[algorithm] = sum
+		if !verify {
+			fmt.Printf("\n%s%s:%s %s", ColorCyan, strings.ToUpper(algorithm), ColorReset, sum)
+			continue
+		}
+		if !strings.EqualFold(sum, want) {
+			return fmt.Errorf("%w: %s expected %s, got %s", errChecksum, strings.ToUpper(algorithm), want, sum)
+		}
+		fmt.Printf(" %s✓%s", ColorGreen, ColorReset)
+	}
+	fmt.Println()
+
+	return nil
+}
+
+// expectedSums collects the digests a task must match, keyed by algorithm
+func (task *DownloadTask) expectedSums() map[string]string {
+	sums := make(map[string]string)
+	for algorithm, expected := range map[string]string{"sha256": task.SHA256, "sha1": task.SHA1, "md5": task.MD5} {
+		if expected != "" {
+			sums[algorithm] = expected
+		}
+	}
+	if task.Checksum != "" {
+		algorithm := strings.ToLower(task.ChecksumAlgo)
+		if algorithm == "" {
+			algorithm = "sha256"
+		}
+		sums[algorithm] = task.Checksum
+	}
+	return sums
+}
+
+// checksum names the file's digest as algo:hex for the history, preferring
+// sha256 when more than one was computed
+func (task *DownloadTask) checksum() string {
+	sums := task.Digests
+	if len(sums) == 0 {
+		sums = task.sums
+	}
+	if sum, ok := sums["sha256"]; ok {
+		return "sha256:" + sum
+	}
+	algorithms := make([]string, 0, len(sums))
+	for algorithm := range sums {
+		algorithms = append(algorithms, algorithm)
+	}
+	if len(algorithms) == 0 {
+		return ""
+	}
+	sort.Strings(algorithms)
+	return algorithms[0] + ":" + sums[algorithms[0]]
+}
+
+// hashAlgorithms lists what to compute for a task: the expected digests in
+// a stable order, or just SHA-256 so there's always a digest to report
+func (task *DownloadTask) hashAlgorithms() []string {
+	expected := task.expectedSums()
+	if len(expected) == 0 {
+		return []string{"sha256"}
+	}
+	algorithms := make([]string, 0, len(expected))
+	for algorithm := range expected {
+		algorithms = append(algorithms, algorithm)
+	}
+	sort.Strings(algorithms)
+	return algorithms
+}
+
+// verifySignature checks a detached OpenPGP signature against the trusted key
+func (dm *DownloadManager) verifySignature(ctx context.Context, filePath string, task *DownloadTask) error {
+	if task.PublicKey == "" {
+		return configError(errors.New("signature verification requires a public key"))
+	}
+
+	fmt.Printf("%sVerifying signature...%s", ColorYellow, ColorReset)
+
+	keyData, err := os.ReadFile(task.PublicKey)
+	if err != nil {
+		return fmt.Errorf("failed to read public key: %w", err)
+	}
+
+	var keyring openpgp.EntityList
+	if isArmored(keyData) {
+		keyring, err = openpgp.ReadArmoredKeyRing(bytes.NewReader(keyData))
+	} else {
+		keyring, err = openpgp.ReadKeyRing(bytes.NewReader(keyData))
+	}
+	if err != nil {
+		return fmt.Errorf("failed to parse public key: %w", err)
+	}
+
+	sigData, err := dm.fetchSignature(ctx, task)
+	if err != nil {
+		return fmt.Errorf("failed to fetch signature: %w", err)
+	}
+
+	file, err := os.Open(filePath)
+	if err != nil {
+		return err
+	}
+	defer file.Close()
+
+	if isArmored(sigData) {
+		_, err = openpgp.CheckArmoredDetachedSignature(keyring, file, bytes.NewReader(sigData))
+	} else {
+		_, err = openpgp.CheckDetachedSignature(keyring, file, bytes.NewReader(sigData))
+	}
+	if err != nil {
+		fmt.Printf(" %s✗%s\n", ColorRed, ColorReset)
+		return &DownloadError{Kind: ErrorChecksum, Err: fmt.Errorf("signature verification failed: %w", err)}
+	}
+
+	fmt.Printf(" %s✓%s\n", ColorGreen, ColorReset)
+	return nil
+}
+
+// fetchSignature loads the signature from disk or through the download client
+func (dm *DownloadManager) fetchSignature(ctx context.Context, task *DownloadTask) ([]byte, error) {
+	parsedURL, err := url.Parse(task.SignatureURL)
+	if err != nil || (parsedURL.Scheme != "http" && parsedURL.Scheme != "https" && !isS3URL(task.SignatureURL)) {
+		return os.ReadFile(task.SignatureURL)
+	}
+
+	req, err := http.NewRequestWithContext(ctx, "GET", task.SignatureURL, nil)
+	if err != nil {
+		return nil, err
+	}
+
+	dm.prepareRequest(req, task.Headers)
+
+	resp, err := dm.client.Do(req)
+	if err != nil {
+		return nil, err
+	}
+	defer resp.Body.Close()
+
+	if resp.StatusCode != http.StatusOK {
+		return nil, statusError(resp)
+	}
+
+	return io.ReadAll(resp.Body)
+}
+
+func isArmored(data []byte) bool {
+	return bytes.HasPrefix(bytes.TrimSpace(data), []byte("-----BEGIN"))
+}
+
+// archiveKind recognizes the archive formats extractArchive handles
+func archiveKind(path string) string {
+	lower := strings.ToLower(path)
+	switch {
+	case strings.HasSuffix(lower, ".zip"):
+		return ".zip"
+	case strings.HasSuffix(lower, ".tar.gz"):
+		return ".tar.gz"
+	case strings.HasSuffix(lower, ".tgz"):
+		return ".tgz"
+	case strings.HasSuffix(lower, ".tar"):
+		return ".tar"
+	}
+	return ""
+}
+
+// extractArchive unpacks next to the archive. Files land in a hidden temp
+// directory first and the finished tree is renamed into place, so a
+// directory watcher never sees a half-extracted result.
+func extractArchive(archivePath string) error {
+	kind := archiveKind(archivePath)
+	dest := archivePath[:len(archivePath)-len(kind)]
+	if _, err := os.Stat(dest); err == nil {
+		return fmt.Errorf("%s already exists", dest)
+	}
+
+	staging, err := os.MkdirTemp(filepath.Dir(archivePath), "."+filepath.Base(dest)+".extract-")
+	if err != nil {
+		return err
+	}
+
+	fmt.Printf("%sExtracting...%s", ColorYellow, ColorReset)
+	if kind == ".zip" {
+		err = extractZip(archivePath, staging)
+	} else {
+		err = extractTar(archivePath, staging, kind != ".tar")
+	}
+	if err != nil {
+		os.RemoveAll(staging)
+		return err
+	}
+
+	if err := os.Rename(staging, dest); err != nil {
+		os.RemoveAll(staging)
+		return err
+	}
+	fmt.Printf(" %s✓%s %s\n", ColorGreen, ColorReset, dest)
+	return nil
+}
+
+// safeJoin rejects entries that would escape root ("zip slip")
+func safeJoin(root, name string) (string, error) {
+	target := filepath.Join(root, name)
+	if target != root && !strings.HasPrefix(target, root+string(os.PathSeparator)) {
+		return "", fmt.Errorf("illegal path in archive: %s", name)
+	}
+	return target, nil
+}
+
+func extractZip(archivePath, root string) error {
+	reader, err := zip.OpenReader(archivePath)
+	if err != nil {
+		return err
+	}
+	defer reader.Close()
+
+	for _, entry := range reader.File {
+		target, err := safeJoin(root, entry.Name)
+		if err != nil {
+			return err
+		}
+		if entry.FileInfo().IsDir() {
+			if err := os.MkdirAll(target, 0755); err != nil {
+				return err
+			}
+			continue
+		}
+
+		input, err := entry.Open()
+		if err != nil {
+			return err
+		}
+		err = writeExtracted(target, input, entry.Mode())
+		input.Close()
+		if err != nil {
+			return err
+		}
+	}
+	return nil
+}
+
+func extractTar(archivePath, root string, gzipped bool) error {
+	file, err := os.Open(archivePath)
+	if err != nil {
+		return err
+	}
+	defer file.Close()
+
+	var input io.Reader = file
+	if gzipped {
+		gz, err := gzip.NewReader(file)
+		if err != nil {
+			return err
+		}
+		defer gz.Close()
+		input = gz
+	}
+
+	reader := tar.NewReader(input)
+	for {
+		header, err := reader.Next()
+		if err == io.EOF {
+			return nil
+		}
+		if err != nil {
+			return err
+		}
+
+		target, err := safeJoin(root, header.Name)
+		if err != nil {
+			return err
+		}
+		switch header.Typeflag {
+		case tar.TypeDir:
+			if err := os.MkdirAll(target, 0755); err != nil {
+				return err
+			}
+		case tar.TypeReg:
+			if err := writeExtracted(target, reader, header.FileInfo().Mode()); err != nil {
+				return err
+			}
+		}
+	}
+}
+
+func writeExtracted(target string, input io.Reader, mode os.FileMode) error {
+	if err := os.MkdirAll(filepath.Dir(target), 0755); err != nil {
+		return err
+	}
+	output, err := os.OpenFile(target, os.O_CREATE|os.O_WRONLY|os.O_TRUNC, mode.Perm()|0600)
+	if err != nil {
+		return err
+	}
+	if _, err := io.Copy(output, input); err != nil {
+		output.Close()
+		return err
+	}
+	return output.Close()
+}
+
+// fileHash prefers a digest computed during the download
+func fileHash(filepath string, task *DownloadTask, algorithm string) (string, error) {
+	if sum, ok := task.sums[algorithm]; ok {
+		return sum, nil
+	}
+	return calculateHash(filepath, algorithm)
+}
+
+func newHash(algorithm string) (hash.Hash, error) {
+	switch algorithm {
+	case "sha256":
+		return sha256.New(), nil
+	case "sha1":
+		return sha1.New(), nil
+	case "md5":
+		return md5.New(), nil
+	case "sha512":
+		return sha512.New(), nil
+	case "blake2b":
+		return blake2b.New256(nil)
+	}
+	return nil, fmt.Errorf("unsupported hash algorithm: %s", algorithm)
+}
+
+// calculateHash calculates file hash
+func calculateHash(filepath string, algorithm string) (string, error) {
+	h, err := newHash(algorithm)
+	if err != nil {
+		return "", err
+	}
+
+	file, err := os.Open(filepath)
+	if err != nil {
+		return "", err
+	}
+	defer file.Close()
+
+	if _, err := io.Copy(h, file); err != nil {
+		return "", err
+	}
+
+	return hex.EncodeToString(h.Sum(nil)), nil
+}
+
+func isMetalink(name string) bool {
+	name, _, _ = strings.Cut(strings.ToLower(name), "?")
+	return strings.HasSuffix(name, ".meta4") || strings.HasSuffix(name, ".metalink")
+}
+
+func isTorrent(name string) bool {
+	name, _, _ = strings.Cut(strings.ToLower(name), "?")
+	return strings.HasSuffix(name, ".torrent")
+}
+
+// isDescriptor reports whether a source lists downloads rather than being
+// one: a metalink or a torrent
+func isDescriptor(name string) bool {
+	return isMetalink(name) || isTorrent(name)
+}
+
+// loadDescriptor expands a metalink or torrent into its tasks
+func (dm *DownloadManager) loadDescriptor(ctx context.Context, source string) ([]DownloadTask, error) {
+	if isTorrent(source) {
+		return dm.loadTorrent(ctx, source)
+	}
+	return dm.loadMetalink(ctx, source)
+}
+
+// readSource reads a small document from a local path or URL
+func (dm *DownloadManager) readSource(ctx context.Context, source string) ([]byte, error) {
+	if !strings.Contains(source, "://") {
+		return os.ReadFile(source)
+	}
+	req, err := http.NewRequestWithContext(ctx, "GET", source, nil)
+	if err != nil {
+		return nil, err
+	}
+	dm.prepareRequest(req, nil)
+	resp, err := dm.client.Do(req)
+	if err != nil {
+		return nil, err
+	}
+	defer resp.Body.Close()
+	if resp.StatusCode != http.StatusOK {
+		return nil, statusError(resp)
+	}
+	return io.ReadAll(io.LimitReader(resp.Body, 16<<20))
+}
+
+// loadMetalink reads a metalink from a local path or URL and turns each
+// file into a task: the best URL first with the rest as mirrors, plus its
+// size, whole-file hashes and piece hashes
+func (dm *DownloadManager) loadMetalink(ctx context.Context, source string) ([]DownloadTask, error) {
+	data, err := dm.readSource(ctx, source)
+	if err != nil {
+		return nil, err
+	}
+
+	var doc metalink
+	if err := xml.Unmarshal(data, &doc); err != nil {
+		return nil, fmt.Errorf("invalid metalink %s: %w", source, err)
+	}
+
+	var tasks []DownloadTask
+	for _, file := range append(doc.Files, doc.V3Files...) {
+		task, err := file.task(dm.maxWorkers)
+		if err != nil {
+			return nil, fmt.Errorf("metalink %s: %w", source, err)
+		}
+		tasks = append(tasks, task)
+	}
+	if len(tasks) == 0 {
+		return nil, fmt.Errorf("metalink %s lists no files", source)
+	}
+	return tasks, nil
+}
+
+func (f metalinkFile) task(chunks int) (DownloadTask, error) {
+	// Names may carry directories, but never outside the download dir
+	name := strings.TrimPrefix(path.Clean("/"+f.Name), "/")
+	if name == "" {
+		return DownloadTask{}, fmt.Errorf("file without a name")
+	}
+
+	urls := append(f.URLs, f.V3URLs...)
+	rank := func(u metalinkURL) int {
+		switch {
+		case u.Priority > 0:
+			return u.Priority
+		case u.Preference > 0:
+			return 1000 - u.Preference
+		}
+		return 1 << 30
+	}
+	sort.SliceStable(urls, func(i, j int) bool { return rank(urls[i]) < rank(urls[j]) })
+
+	var sources []string
+	for _, u := range urls {
+		link := strings.TrimSpace(u.URL)
+		if scheme, _, _ := strings.Cut(link, "://"); scheme == "http" || scheme == "https" || isFTPURL(link) {
+			sources = append(sources, link)
+		}
+	}
+	if len(sources) == 0 {
+		return DownloadTask{}, fmt.Errorf("%s has no http or ftp URLs", name)
+	}
+
+	task := DownloadTask{
+		URL:      sources[0],
+		Mirrors:  sources[1:],
+		Filepath: name,
+		Size:     f.Size,
+		Chunks:   chunks,
+	}
+	for _, h := range append(f.Hashes, f.V3Hashes...) {
+		value := strings.TrimSpace(h.Value)
+		switch strings.ReplaceAll(strings.ToLower(h.Type), "-", "") {
+		case "sha256":
+			task.SHA256 = value
+		case "sha1":
+			task.SHA1 = value
+		case "md5":
+			task.MD5 = value
+		case "sha512":
+			task.Checksum, task.ChecksumAlgo = value, "sha512"
+		}
+	}
+
+	// Prefer the strongest piece hashes we can compute
+	for _, want := range []string{"sha256", "sha1"} {
+		for _, p := range append(f.Pieces, f.V3Pieces...) {
+			if strings.ReplaceAll(strings.ToLower(p.Type), "-", "") == want && p.Length > 0 && len(p.Hashes) > 0 {
+				task.pieces = &pieceHashes{algorithm: want, length: p.Length, hashes: p.Hashes}
+				break
+			}
+		}
+		if task.pieces != nil {
+			break
+		}
+	}
+	return task, nil
+}
+
+// alignEnd moves a chunk end forward to the last byte of its piece, capped
+// at the file's last byte
+func (p *pieceHashes) alignEnd(end, size int64) int64 {
+	if p == nil || p.length <= 1 {
+		return min(end, size-1)
+	}
+	global := p.offset + end + 1
+	if rem := global % p.length; rem != 0 {
+		end += p.length - rem
+	}
+	return min(end, size-1)
+}
+
+// verify checks every piece that lies wholly inside a finished chunk; the
+// stream's last piece may be short. Torrent pieces that straddle two files
+// are never wholly inside one chunk and go unchecked.
+func (p *pieceHashes) verify(task *DownloadTask, chunk ChunkInfo) error {
+	if p == nil {
+		return nil
+	}
+	total := p.total
+	if total == 0 {
+		total = p.offset + task.Size
+	}
+	// Piece positions are in the hashed stream, file positions are offset
+	first := p.offset + chunk.Start
+	end := first + chunk.length() - 1
+
+	var src io.ReaderAt
+	base := first
+	if task.output != nil {
+		src, base = task.output.readerAt(), p.offset
+	} else {
+		file, err := os.Open(chunk.Path)
+		if err != nil {
+			return err
+		}
+		defer file.Close()
+		src = file
+	}
+
+	buf := make([]byte, p.length)
+	for piece := (first + p.length - 1) / p.length; piece*p.length <= end; piece++ {
+		start := piece * p.length
+		n := min(p.length, total-start)
+		if start+n-1 > end {
+			break
+		}
+		if int(piece) >= len(p.hashes) {
+			return fmt.Errorf("%w: no hash for piece %d", errChecksum, piece)
+		}
+		if _, err := src.ReadAt(buf[:n], start-base); err != nil {
+			return err
+		}
+		h, err := newHash(p.algorithm)
+		if err != nil {
+			return err
+		}
+		h.Write(buf[:n])
+		if !strings.EqualFold(hex.EncodeToString(h.Sum(nil)), strings.TrimSpace(p.hashes[piece])) {
+			return fmt.Errorf("%w: piece %d", errChecksum, piece)
+		}
+	}
+	return nil
+}
+
+// loadTorrent reads a .torrent from a local path or URL and downloads its
+// files from the web seeds in url-list (BEP 19) rather than from peers.
+// Every seed becomes a mirror and the SHA-1 piece hashes check each chunk.
+func (dm *DownloadManager) loadTorrent(ctx context.Context, source string) ([]DownloadTask, error) {
+	data, err := dm.readSource(ctx, source)
+	if err != nil {
+		return nil, err
+	}
+	value, _, err := bdecode(data, 0)
+	if err != nil {
+		return nil, fmt.Errorf("invalid torrent %s: %w", source, err)
+	}
+	meta, _ := value.(map[string]interface{})
+	info, _ := meta["info"].(map[string]interface{})
+	if info == nil {
+		return nil, fmt.Errorf("invalid torrent %s: no info dictionary", source)
+	}
+
+	name, _ := info["name"].(string)
+	name = strings.TrimPrefix(path.Clean("/"+name), "/")
+	pieceLength, _ := info["piece length"].(int64)
+	pieces, _ := info["pieces"].(string)
+	if name == "" || pieceLength <= 0 || len(pieces)%sha1.Size != 0 {
+		return nil, fmt.Errorf("invalid torrent %s: bad name, piece length or pieces", source)
+	}
+	hashes := make([]string, len(pieces)/sha1.Size)
+	for i := range hashes {
+		hashes[i] = hex.EncodeToString([]byte(pieces[i*sha1.Size : (i+1)*sha1.Size]))
+	}
+
+	var seeds []string
+	switch list := meta["url-list"].(type) {
+	case string:
+		seeds = append(seeds, list)
+	case []interface{}:
+		for _, item := range list {
+			if seed, ok := item.(string); ok {
+				seeds = append(seeds, seed)
+			}
+		}
+	}
+	seeds = slices.DeleteFunc(seeds, func(seed string) bool {
+		scheme, _, _ := strings.Cut(seed, "://")
+		return scheme != "http" && scheme != "https" && !isFTPURL(seed)
+	})
+	if len(seeds) == 0 {
+		return nil, fmt.Errorf("torrent %s has no web seeds; peer downloads are not supported", source)
+	}
+
+	// A single-file torrent has a length, a multi-file one a list of files
+	// that live under a directory named after the torrent
+	type torrentFile struct {
+		path    []string
+		length  int64
+		padding bool
+	}
+	var files []torrentFile
+	single := true
+	if length, ok := info["length"].(int64); ok {
+		files = append(files, torrentFile{length: length})
+	} else {
+		single = false
+		list, _ := info["files"].([]interface{})
+		for _, item := range list {
+			entry, _ := item.(map[string]interface{})
+			length, _ := entry["length"].(int64)
+			parts, _ := entry["path"].([]interface{})
+			var elems []string
+			for _, part := range parts {
+				if elem, ok := part.(string); ok {
+					elems = append(elems, elem)
+				}
+			}
+			if len(elems) == 0 {
+				return nil, fmt.Errorf("invalid torrent %s: file without a path", source)
+			}
+			attr, _ := entry["attr"].(string)
+			files = append(files, torrentFile{path: elems, length: length, padding: strings.Contains(attr, "p")})
+		}
+	}
+
+	var total int64
+	for _, file := range files {
+		total += file.length
+	}
+	if (total+pieceLength-1)/pieceLength != int64(len(hashes)) {
+		return nil, fmt.Errorf("invalid torrent %s: %d pieces for %d bytes", source, len(hashes), total)
+	}
+
+	var tasks []DownloadTask
+	var offset int64
+	for _, file := range files {
+		// Web seeds ending in a slash name a directory holding the torrent
+		urls := make([]string, len(seeds))
+		for i, seed := range seeds {
+			urls[i] = seed
+			if single && !strings.HasSuffix(seed, "/") {
+				continue
+			}
+			if !strings.HasSuffix(seed, "/") {
+				urls[i] += "/"
+			}
+			urls[i] += url.PathEscape(name)
+			for _, elem := range file.path {
+				urls[i] += "/" + url.PathEscape(elem)
+			}
+		}
+
+		target := name
+		if !single {
+			target = strings.TrimPrefix(path.Clean("/"+name+"/"+strings.Join(file.path, "/")), "/")
+		}
+		task := DownloadTask{
+			URL:      urls[0],
+			Mirrors:  urls[1:],
+			Filepath: target,
+			Size:     file.length,
+			Chunks:   dm.maxWorkers,
+			pieces: &pieceHashes{
+				algorithm: "sha1",
+				length:    pieceLength,
+				hashes:    hashes,
+				offset:    offset,
+				total:     total,
+			},
+		}
+		offset += file.length
+		// Padding files (BEP 47) are zeros that seeds don't serve
+		if file.length > 0 && !file.padding {
+			tasks = append(tasks, task)
+		}
+	}
+	return tasks, nil
+}
+
+// bdecode parses one bencoded value from the front of data into an int64,
+// a string, a []interface{} or a map[string]interface{}. depth is how many
+// lists and dicts enclose it.
+func bdecode(data []byte, depth int) (interface{}, []byte, error) {
+	if len(data) == 0 {
+		return nil, nil, io.ErrUnexpectedEOF
+	}
+	if depth > MaxBencodeDepth {
+		return nil, nil, fmt.Errorf("nested more than %d levels deep", MaxBencodeDepth)
+	}
+	switch c := data[0]; {
+	case c == 'i':
+		end := bytes.IndexByte(data, 'e')
+		if end < 0 {
+			return nil, nil, io.ErrUnexpectedEOF
+		}
+		n, err := strconv.ParseInt(string(data[1:end]), 10, 64)
+		if err != nil {
+			return nil, nil, err
+		}
+		return n, data[end+1:], nil
+	case c == 'l':
+		var list []interface{}
+		data = data[1:]
+		for len(data) > 0 && data[0] != 'e' {
+			item, rest, err := bdecode(data, depth+1)
+			if err != nil {
+				return nil, nil, err
+			}
+			list, data = append(list, item), rest
+		}
+		if len(data) == 0 {
+			return nil, nil, io.ErrUnexpectedEOF
+		}
+		return list, data[1:], nil
+	case c == 'd':
+		dict := make(map[string]interface{})
+		data = data[1:]
+		for len(data) > 0 && data[0] != 'e' {
+			key, rest, err := bdecode(data, depth+1)
+			if err != nil {
+				return nil, nil, err
+			}
+			name, ok := key.(string)
+			if !ok {
+				return nil, nil, fmt.Errorf("dictionary key is not a string")
+			}
+			value, rest, err := bdecode(rest, depth+1)
+			if err != nil {
+				return nil, nil, err
+			}
+			dict[name], data = value, rest
+		}
+		if len(data) == 0 {
+			return nil, nil, io.ErrUnexpectedEOF
+		}
+		return dict, data[1:], nil
+	case c >= '0' && c <= '9':
+		colon := bytes.IndexByte(data, ':')
+		if colon < 0 {
+			return nil, nil, io.ErrUnexpectedEOF
+		}
+		n, err := strconv.Atoi(string(data[:colon]))
+		if err != nil || n < 0 || n > len(data)-colon-1 {
+			return nil, nil, fmt.Errorf("bad string length at %q", data[:colon])
+		}
+		return string(data[colon+1 : colon+1+n]), data[colon+1+n:], nil
+	}
+	return nil, nil, fmt.Errorf("unexpected byte %q", data[0])
+}
+
+// parseURLFile reads "URL [sha256:..] [sha1:..] [md5:..] [sha512:..] [blake2b:..] [rate:bytes/sec]
+// [header:Name=Value]... [mirror:URL]..." lines. Header values are percent-decoded so they
+// can hold spaces, e.g. header:Authorization=Bearer%20abc. Indented key=value lines after
+// a URL set options for it alone, see setOption.
+func (dm *DownloadManager) parseURLFile(urlFile string) ([]DownloadTask, error) {
+	if isDescriptor(urlFile) {
+		return dm.loadDescriptor(context.Background(), urlFile)
+	}
+
+	// "-" reads the list from stdin, for generated lists piped in
+	var input io.Reader = os.Stdin
+	if urlFile != "-" {
+		file, err := os.Open(urlFile)
+		if err != nil {
+			return nil, err
+		}
+		defer file.Close()
+		input = file
+	}
+
+	var tasks []DownloadTask
+	group := 0 // first task of the latest URL line, which a glob can make several
+	lineNum := 0
+	scanner := bufio.NewScanner(input)
+	for scanner.Scan() {
+		lineNum++
+		raw := scanner.Text()
+		line := strings.TrimSpace(raw)
+		if line == "" || strings.HasPrefix(line, "#") {
+			continue
+		}
+
+		// Indented lines hold aria2-style options for the URL above
+		if raw[0] == ' ' || raw[0] == '\t' {
+			if len(tasks) == 0 {
+				return nil, fmt.Errorf("line %d: option %q before any URL", lineNum, line)
+			}
+			for i := group; i < len(tasks); i++ {
+				if err := tasks[i].setOption(line); err != nil {
+					return nil, fmt.Errorf("line %d: %w", lineNum, err)
+				}
+			}
+			continue
+		}
+
+		parts := strings.Fields(line)
+		task := DownloadTask{
+			URL:    parts[0],
+			Chunks: dm.maxWorkers,
+			line:   lineNum,
+		}
+
+		for i := 1; i < len(parts); i++ {
+			if strings.HasPrefix(parts[i], "sha256:") {
+				task.SHA256 = strings.TrimPrefix(parts[i], "sha256:")
+			} else if strings.HasPrefix(parts[i], "sha1:") {
+				task.SHA1 = strings.TrimPrefix(parts[i], "sha1:")
+			} else if strings.HasPrefix(parts[i], "md5:") {
+				task.MD5 = strings.TrimPrefix(parts[i], "md5:")
+			} else if algorithm, sum, ok := strings.Cut(parts[i], ":"); ok && (algorithm == "sha512" || algorithm == "blake2b") {
+				task.Checksum, task.ChecksumAlgo = sum, algorithm
+			} else if strings.HasPrefix(parts[i], "rate:") {
+				task.RateLimit, _ = strconv.ParseInt(strings.TrimPrefix(parts[i], "rate:"), 10, 64)
+			} else if strings.HasPrefix(parts[i], "header:") {
+				name, value, ok := strings.Cut(strings.TrimPrefix(parts[i], "header:"), "=")
+				if !ok {
+					return nil, fmt.Errorf("line %d: invalid header %q, want header:Name=Value", lineNum, parts[i])
+				}
+				if decoded, err := url.PathUnescape(value); err == nil {
+					value = decoded
+				}
+				if task.Headers == nil {
+					task.Headers = make(map[string]string)
+				}
+				task.Headers[name] = value
+			} else if strings.HasPrefix(parts[i], "mirror:") {
+				task.Mirrors = append(task.Mirrors, strings.TrimPrefix(parts[i], "mirror:"))
+			}
+		}
+
+		urls := []string{task.URL}
+		if !dm.config.GlobOff {
+			var err error
+			if urls, err = expandGlob(task.URL); err != nil {
+				return nil, fmt.Errorf("line %d: %w", lineNum, err)
+			}
+		}
+		group = len(tasks)
+		for _, u := range urls {
+			task.URL = u
+			tasks = append(tasks, task)
+		}
+	}
+
+	return tasks, scanner.Err()
+}
+
+// setOption applies one option line from under a URL in a batch file. The
+// names follow aria2's input files where they overlap:
+//
+//	https://example.com/file.iso
+//	  out=images/debian.iso
+//	  checksum=sha-256=0123abcd...
+//	  header=Authorization: Bearer abc
+//	  split=8
+//	  priority=10
+func (t *DownloadTask) setOption(option string) error {
+	key, value, ok := strings.Cut(option, "=")
+	if !ok {
+		return fmt.Errorf("invalid option %q, want key=value", option)
+	}
+	key, value = strings.TrimSpace(key), strings.TrimSpace(value)
+
+	switch key {
+	case "out":
+		t.Filepath = value
+	case "checksum":
+		algorithm, sum, ok := strings.Cut(value, "=")
+		if !ok {
+			algorithm, sum, ok = strings.Cut(value, ":")
+		}
+		if !ok {
+			return fmt.Errorf("invalid checksum %q, want algorithm=hex", value)
+		}
+		switch algorithm = strings.ReplaceAll(strings.ToLower(algorithm), "-", ""); algorithm {
+		case "sha256":
+			t.SHA256 = sum
+		case "sha1":
+			t.SHA1 = sum
+		case "md5":
+			t.MD5 = sum
+		case "sha512", "blake2b":
+			t.Checksum, t.ChecksumAlgo = sum, algorithm
+		default:
+			return fmt.Errorf("unsupported checksum algorithm %q", algorithm)
+		}
+	case "header":
+		name, v, ok := strings.Cut(value, ":")
+		if !ok {
+			return fmt.Errorf("invalid header %q, want Name: Value", value)
+		}
+		if t.Headers == nil {
+			t.Headers = make(map[string]string)
+		}
+		t.Headers[strings.TrimSpace(name)] = strings.TrimSpace(v)
+	case "split", "connections":
+		n, err := strconv.Atoi(value)
+		if err != nil || n < 1 {
+			return fmt.Errorf("invalid %s %q", key, value)
+		}
+		t.Chunks, t.Connections = n, n
+	case "max-download-limit", "rate":
+		limit, err := parseByteSize(value)
+		if err != nil {
+			return fmt.Errorf("invalid %s: %w", key, err)
+		}
+		t.RateLimit = limit
+	case "priority":
+		n, err := strconv.Atoi(value)
+		if err != nil {
+			return fmt.Errorf("invalid priority %q", value)
+		}
+		t.Priority = n
+	case "mirror":
+		t.Mirrors = append(t.Mirrors, value)
+	case "sig":
+		t.SignatureURL = value
+	case "pubkey":
+		t.PublicKey = value
+	default:
+		return fmt.Errorf("unknown option %q", key)
+	}
+	return nil
+}
+
+// globRange matches a curl-style [001-120], [a-z] or [1-99:2] range
+var globRange = regexp.MustCompile(`^\[(?:(\d+)-(\d+)|([a-zA-Z])-([a-zA-Z]))(?::(\d+))?\]`)
+
+// expandGlob expands curl-style URL globs: numeric ranges keep the zero
+// padding of their start, letter ranges and {one,two} lists. A backslash
+// escapes [ and {, and brackets that aren't a range, like an IPv6 host,
+// stay as they are.
+func expandGlob(pattern string) ([]string, error) {
+	results := []string{""}
+	for i := 0; i < len(pattern); {
+		var options []string
+		c := pattern[i]
+		listEnd := -1
+		if end := strings.IndexByte(pattern[i:], '}'); c == '{' && end > 0 && strings.Contains(pattern[i:i+end], ",") {
+			listEnd = i + end
+		}
+		switch {
+		case c == '\\' && i+1 < len(pattern) && strings.IndexByte("[]{}", pattern[i+1]) >= 0:
+			options = []string{pattern[i+1 : i+2]}
+			i += 2
+		case c == '[' && globRange.MatchString(pattern[i:]):
+			match := globRange.FindStringSubmatch(pattern[i:])
+			var err error
+			if options, err = globRangeValues(match); err != nil {
+				return nil, err
+			}
+			i += len(match[0])
+		case listEnd > 0:
+			options = strings.Split(pattern[i+1:listEnd], ",")
+			i = listEnd + 1
+		default:
+			end := i + 1
+			for end < len(pattern) && strings.IndexByte(`\[{`, pattern[end]) < 0 {
+				end++
+			}
+			options = []string{pattern[i:end]}
+			i = end
+		}
+
+		if len(results)*len(options) > MaxGlobURLs {
+			return nil, fmt.Errorf("%s expands to more than %d URLs", pattern, MaxGlobURLs)
+		}
+		next := make([]string, 0, len(results)*len(options))
+		for _, prefix := range results {
+			for _, option := range options {
+				next = append(next, prefix+option)
+			}
+		}
+		results = next
+	}
+	return results, nil
+}
+
+// globRangeValues lists the values of one globRange match
+func globRangeValues(match []string) ([]string, error) {
+	step := 1
+	if match[5] != "" {
+		step, _ = strconv.Atoi(match[5])
+	}
+	if step < 1 {
+		return nil, fmt.Errorf("bad step in %s", match[0])
+	}
+
+	var values []string
+	if match[1] != "" {
+		start, err1 := strconv.Atoi(match[1])
+		end, err2 := strconv.Atoi(match[2])
+		if err1 != nil || err2 != nil || end < start || (end-start)/step >= MaxGlobURLs {
+			return nil, fmt.Errorf("bad range %s", match[0])
+		}
+		width := 0
+		if len(match[1]) > 1 && match[1][0] == '0' {
+			width = len(match[1])
+		}
+		for n := start; n <= end; n += step {
+			values = append(values, fmt.Sprintf("%0*d", width, n))
+		}
+		return values, nil
+	}
+
+	start, end := match[3][0], match[4][0]
+	if end < start || (start <= 'Z') != (end <= 'Z') {
+		return nil, fmt.Errorf("bad range %s", match[0])
+	}
+	for c := int(start); c <= int(end); c += step {
+		values = append(values, string(rune(c)))
+	}
+	return values, nil
+}
+
+// validateManifest catches entries that would write the same file but
+// disagree on its checksums, before any network activity
+func (dm *DownloadManager) validateManifest(tasks []DownloadTask) error {
+	byName := make(map[string]*DownloadTask)
+	for i := range tasks {
+		task := &tasks[i]
+		name := task.Filepath
+		if name == "" {
+			var err error
+			if name, err = extractFilename(task.URL, dm.config); err != nil {
+				return fmt.Errorf("url file line %d: %w", task.line, err)
+			}
+		}
+
+		prev, ok := byName[name]
+		if !ok {
+			byName[name] = task
+			continue
+		}
+
+		prevSums := prev.expectedSums()
+		for algo, b := range task.expectedSums() {
+			if a, ok := prevSums[algo]; ok && !strings.EqualFold(a, b) {
+				return fmt.Errorf("url file lines %d and %d both write %s with different %s (%s vs %s)",
+					prev.line, task.line, name, algo, a, b)
+			}
+		}
+	}
+	return nil
+}
+
+// Plan runs the pre-flight checks for each task and reports the outcome
+func (dm *DownloadManager) Plan(ctx context.Context, tasks []DownloadTask) []PlanEntry {
+	entries := make([]PlanEntry, 0, len(tasks))
+	seen := make(map[string]int)
+
+	for i, task := range tasks {
+		task.index = i + 1
+		entry := PlanEntry{URL: task.URL, Chunks: task.Chunks}
+
+		info, err := dm.GetFileInfo(ctx, task.URL, task.Headers)
+		if err != nil {
+			entry.Error = err.Error()
+			entries = append(entries, entry)
+			continue
+		}
+
+		entry.Filename = task.Filepath
+		if entry.Filename == "" {
+			if entry.Filename, err = dm.outputName(&task, info); err != nil {
+				entry.Error = err.Error()
+				entries = append(entries, entry)
+				continue
+			}
+		}
+		entry.OutputPath = filepath.Join(dm.downloadDir, entry.Filename)
+		entry.Size = info.Size
+
+		// Count chunks the way downloadParallel will, chunk_queue included,
+		// so the part files checked are the ones it would resume from
+		if info.SupportsRange && task.Chunks > 1 && info.Size > 0 {
+			entry.Strategy = "multi"
+			if task.Size == 0 {
+				task.Size = info.Size
+			}
+			entry.Chunks = dm.chunkCount(&task)
+		} else {
+			entry.Strategy = "single"
+			entry.Chunks = 1
+		}
+		entry.Action = dm.planAction(entry.OutputPath, entry.Chunks)
+
+		if prev, ok := seen[entry.OutputPath]; ok {
+			entry.Collision = true
+			entries[prev].Collision = true
+		}
+		seen[entry.OutputPath] = len(entries)
+		entries = append(entries, entry)
+	}
+
+	return entries
+}
+
+func (dm *DownloadManager) planAction(outputPath string, chunks int) string {
+	if dm.resume {
+		work := dm.workPath(outputPath)
+		if _, err := os.Stat(work); err == nil {
+			return "resume"
+		}
+		for i := 0; i < chunks; i++ {
+			if _, err := os.Stat(fmt.Sprintf("%s.part%d", work, i)); err == nil {
+				return "resume"
+			}
+		}
+	}
+	if _, err := os.Stat(outputPath); err == nil {
+		switch dm.config.OnConflict {
+		case "skip", "resume", "rename":
+			return dm.config.OnConflict
+		}
+		return "overwrite"
+	}
+	return "download"
+}
+
+func printPlan(entries []PlanEntry) error {
+	encoder := json.NewEncoder(os.Stdout)
+	encoder.SetIndent("", "  ")
+	return encoder.Encode(entries)
+}
+
+// BatchDownload handles multiple downloads
+func (dm *DownloadManager) BatchDownload(ctx context.Context, urlFile string, concurrent int) (err error) {
+	tasks, err := dm.parseURLFile(urlFile)
+	if err != nil {
+		return err
+	}
+	if err := dm.validateManifest(tasks); err != nil {
+		return err
+	}
+
+	if dm.config.PlanOnly {
+		return printPlan(dm.Plan(ctx, tasks))
+	}
+
+	queue, err := newBatchQueue(dm.config.QueueOrder)
+	if err != nil {
+		return err
+	}
+	if queue.order == "smallest" || queue.order == "largest" {
+		dm.probeSizes(ctx, tasks, concurrent)
+	}
+	for i := range tasks {
+		tasks[i].index = i + 1
+		queue.Push(&tasks[i])
+	}
+
+	fmt.Printf("%sFound %d URLs to download%s\n\n", ColorCyan, len(tasks), ColorReset)
+
+	var archive *zipArchive
+	if dm.config.ArchiveOutput != "" {
+		archive, err = newZipArchive(dm.config.ArchiveOutput)
+		if err != nil {
+			return err
+		}
+		// The central directory is written on Close; without it the zip
+		// is unreadable
+		defer func() {
+			if closeErr := archive.Close(); closeErr != nil && err == nil {
+				err = fmt.Errorf("failed to write %s: %w", dm.config.ArchiveOutput, closeErr)
+			}
+		}()
+	}
+
+	if dm.config.LowPriority {
+		concurrent = min(concurrent, LowPriorityParallel)
+	}
+
+	// Fail-fast cancels in-flight downloads and anything still queued
+	ctx, cancel := context.WithCancel(ctx)
+	defer cancel()
+	var failed int64
+	var firstErr error
+	var failOnce sync.Once
+	fail := func(url string, err error) {
+		atomic.AddInt64(&failed, 1)
+		failOnce.Do(func() {
+			firstErr = fmt.Errorf("%s: %w", url, err)
+			if dm.config.FailFast {
+				cancel()
+			}
+		})
+	}
+
+	run := func(t *DownloadTask) {
+		if dm.bars == nil {
+			fmt.Printf("%s[%d/%d] Downloading %s%s\n", ColorBlue, t.index, len(tasks), t.URL, ColorReset)
+		}
+
+		if err := dm.Download(ctx, t); err != nil {
+			dm.bars.Printf("%s[%d/%d] Failed: %v%s\n", ColorRed, t.index, len(tasks), err, ColorReset)
+			fail(t.URL, err)
+			return
+		}
+
+		if archive != nil {
+			if err := archive.Add(filepath.Join(dm.downloadDir, t.Filepath), t.Filepath); err != nil {
+				dm.bars.Printf("%s[%d/%d] Archive failed: %v%s\n", ColorRed, t.index, len(tasks), err, ColorReset)
+				fail(t.URL, err)
+				return
+			}
+		}
+		if dm.bars == nil {
+			status := "Completed"
+			if t.Skipped {
+				status = "Skipped"
+			}
+			fmt.Printf("%s[%d/%d] %s%s\n", ColorGreen, t.index, len(tasks), status, ColorReset)
+		}
+	}
+
+	// Each worker takes the next queued task as soon as it is free
+	var wg sync.WaitGroup
+	for w := 0; w < max(concurrent, 1); w++ {
+		wg.Add(1)
+		go func() {
+			defer wg.Done()
+			for {
+				t, ok := queue.Pop()
+				if !ok {
+					return
+				}
+				if ctx.Err() != nil {
+					dm.bars.Printf("%s[%d/%d] Not started: %s%s\n", ColorYellow, t.index, len(tasks), t.URL, ColorReset)
+					continue
+				}
+				run(t)
+			}
+		}()
+	}
+
+	wg.Wait()
+	if firstErr != nil && dm.config.FailFast {
+		return fmt.Errorf("batch aborted after first failure: %w", firstErr)
+	}
+	return batchError(int(failed), len(tasks), firstErr)
+}
+
+// newBatchQueue makes an empty queue for the given queue_order
+func newBatchQueue(order string) (*batchQueue, error) {
+	switch order {
+	case "", "fifo", "lifo", "smallest", "largest":
+	default:
+		return nil, configError(fmt.Errorf("unknown queue_order %q (want fifo, lifo, smallest or largest)", order))
+	}
+	return &batchQueue{order: order, seq: make(map[*DownloadTask]int)}, nil
+}
+
+// before reports whether a starts ahead of b
+func (q *batchQueue) before(a, b *DownloadTask) bool {
+	if a.Priority != b.Priority {
+		return a.Priority > b.Priority
+	}
+	switch q.order {
+	case "lifo":
+		return q.seq[a] > q.seq[b]
+	case "smallest", "largest":
+		// Unknown sizes go last either way
+		if a.Size > 0 && b.Size > 0 && a.Size != b.Size {
+			return (a.Size < b.Size) == (q.order == "smallest")
+		}
+		if (a.Size > 0) != (b.Size > 0) {
+			return a.Size > 0
+		}
+	}
+	return q.seq[a] < q.seq[b]
+}
+
+// Push queues task in order
+func (q *batchQueue) Push(task *DownloadTask) {
+	q.mu.Lock()
+	defer q.mu.Unlock()
+	q.seq[task] = q.next
+	q.next++
+	i := sort.Search(len(q.pending), func(i int) bool { return q.before(task, q.pending[i]) })
+	q.pending = slices.Insert(q.pending, i, task)
+}
+
+// Pop takes the next task to start, false once the queue is empty
+func (q *batchQueue) Pop() (*DownloadTask, bool) {
+	q.mu.Lock()
+	defer q.mu.Unlock()
+	if len(q.pending) == 0 {
+		return nil, false
+	}
+	task := q.pending[0]
+	q.pending = q.pending[1:]
+	delete(q.seq, task)
+	return task, true
+}
+
+// probeSizes looks up the size of every task ahead of a size-ordered
+// batch, concurrent at a time. Failures leave the size unknown; the
+// download itself reports them.
+func (dm *DownloadManager) probeSizes(ctx context.Context, tasks []DownloadTask, concurrent int) {
+	sem := make(chan struct{}, max(concurrent, 1))
+	var wg sync.WaitGroup
+	for i := range tasks {
+		wg.Add(1)
+		sem <- struct{}{}
+		go func(task *DownloadTask) {
+			defer wg.Done()
+			defer func() { <-sem }()
+			if info, err := dm.GetFileInfo(ctx, task.URL, task.Headers); err == nil {
+				task.Size = info.Size
+			}
+		}(&tasks[i])
+	}
+	wg.Wait()
+}
+
+// newZipArchive creates the batch output archive
+func newZipArchive(path string) (*zipArchive, error) {
+	if err := os.MkdirAll(filepath.Dir(path), 0755); err != nil {
+		return nil, err
+	}
+	file, err := os.Create(path)
+	if err != nil {
+		return nil, err
+	}
+	return &zipArchive{
+		file:   file,
+		writer: zip.NewWriter(file),
+	}, nil
+}
+
+// Add streams a finished download into the archive and removes the original
+func (z *zipArchive) Add(src, name string) error {
+	z.mu.Lock()
+	defer z.mu.Unlock()
+
+	input, err := os.Open(src)
+	if err != nil {
+		return err
+	}
+	defer input.Close()
+
+	info, err := input.Stat()
+	if err != nil {
+		return err
+	}
+
+	header, err := zip.FileInfoHeader(info)
+	if err != nil {
+		return err
+	}
+	header.Name = filepath.ToSlash(name)
+	header.Method = zip.Deflate
+
+	w, err := z.writer.CreateHeader(header)
+	if err != nil {
+		return err
+	}
+	if _, err := io.Copy(w, input); err != nil {
+		return err
+	}
+
+	input.Close()
+	return os.Remove(src)
+}
+
+func (z *zipArchive) Close() error {
+	err := z.writer.Close()
+	if closeErr := z.file.Close(); err == nil {
+		err = closeErr
+	}
+	return err
+}
+
+// NewJobQueue creates a new job queue
+func NewJobQueue(maxActive int, dbPath string) (*JobQueue, error) {
+	// Create directory if it doesn't exist
+	dbDir := filepath.Dir(dbPath)
+	if err := os.MkdirAll(dbDir, 0755); err != nil {
+		return nil, err
+	}
+
+	db, err := sql.Open("sqlite3", dbPath)
+	if err != nil {
+		return nil, err
+	}
+
+	schema := `
+	CREATE TABLE IF NOT EXISTS jobs (
+		id TEXT PRIMARY KEY,
+		url TEXT NOT NULL,
+		protocol TEXT,
+		mirrors TEXT,
+		file_path TEXT,
+		total_size INTEGER,
+		downloaded INTEGER,
+		status TEXT,
+		priority INTEGER,
+		sha256 TEXT,
+		sha1 TEXT,
+		md5 TEXT,
+		added_time TIMESTAMP,
+		start_time TIMESTAMP,
+		end_time TIMESTAMP,
+		error TEXT,
+		metadata TEXT,
+		chunk_states TEXT
+	);
+	CREATE INDEX IF NOT EXISTS idx_status ON jobs(status);
+	CREATE INDEX IF NOT EXISTS idx_priority ON jobs(priority DESC);
+	`
+	
+	if _, err := db.Exec(schema); err != nil {
+		return nil, err
+	}
+
+	jq := &JobQueue{
+		jobs:      make(map[string]*Job),
+		queue:     make([]*Job, 0),
+		active:    make(map[string]*Job),
+		completed: make(map[string]*Job),
+		failed:    make(map[string]*Job),
+		maxActive: maxActive,
+		db:        db,
+		stopCh:    make(chan struct{}),
+		cancels:   make(map[string]context.CancelFunc),
+		tasks:     make(map[*DownloadTask]*Job),
+		watchers:  make(map[chan JobEvent]struct{}),
+	}
+
+	if err := jq.loadJobs(); err != nil {
+		return nil, err
+	}
+
+	return jq, nil
+}
+
+func (jq *JobQueue) loadJobs() error {
+	rows, err := jq.db.Query("SELECT id, url, protocol, file_path, total_size, downloaded, status, priority, sha256, sha1, md5, added_time FROM jobs WHERE status != 'completed'")
+	if err != nil {
+		return err
+	}
+	defer rows.Close()
+
+	for rows.Next() {
+		job := &Job{}
+		err := rows.Scan(&job.ID, &job.URL, &job.Protocol, &job.FilePath, &job.TotalSize, 
+			&job.Downloaded, &job.Status, &job.Priority, &job.SHA256, &job.SHA1, &job.MD5, &job.AddedTime)
+		if err != nil {
+			continue
+		}
+		
+		if job.Status == "downloading" {
+			job.Status = "pending"
+		}
+		
+		jq.jobs[job.ID] = job
+		if job.Status == "pending" {
+			jq.queue = append(jq.queue, job)
+		}
+	}
+
+	return nil
+}
+
+func (jq *JobQueue) AddJob(job *Job) error {
+	jq.mu.Lock()
+	defer jq.mu.Unlock()
+
+	if job.ID == "" {
+		job.ID = fmt.Sprintf("%d-%x", time.Now().Unix(), time.Now().UnixNano())
+	}
+
+	// Detect protocol from URL
+	if job.Protocol == "" {
+		parsedURL, _ := url.Parse(job.URL)
+		job.Protocol = parsedURL.Scheme
+	}
+
+	job.Status = "pending"
+	job.AddedTime = time.Now()
+
+	_, err := jq.db.Exec(`
+		INSERT INTO jobs (id, url, protocol, file_path, total_size, status, priority, sha256, sha1, md5, added_time)
+		VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+	`, job.ID, job.URL, job.Protocol, job.FilePath, job.TotalSize, job.Status, job.Priority, 
+		job.SHA256, job.SHA1, job.MD5, job.AddedTime)
+	
+	if err != nil {
+		return err
+	}
+
+	jq.jobs[job.ID] = job
+	jq.queue = append(jq.queue, job)
+	jq.sortQueue()
+	jq.publish("status", job)
+
+	return nil
+}
+
+func (jq *JobQueue) sortQueue() {
+	sort.Slice(jq.queue, func(i, j int) bool {
+		return jq.queue[i].Priority > jq.queue[j].Priority
+	})
+}
+
+func (jq *JobQueue) ProcessQueue(ctx context.Context) {
+	ticker := time.NewTicker(time.Second)
+	defer ticker.Stop()
+
+	for {
+		select {
+		case <-ctx.Done():
+			return
+		case <-jq.stopCh:
+			return
+		case <-ticker.C:
+			jq.processNext()
+		}
+	}
+}
+
+func (jq *JobQueue) processNext() {
+	jq.mu.Lock()
+	defer jq.mu.Unlock()
+
+	if len(jq.active) >= jq.maxActive || len(jq.queue) == 0 {
+		return
+	}
+
+	job := jq.queue[0]
+	jq.queue = jq.queue[1:]
+	jq.active[job.ID] = job
+	ctx, cancel := context.WithCancel(context.Background())
+	jq.cancels[job.ID] = cancel
+
+	go jq.processJob(ctx, job)
+}
+
+func (jq *JobQueue) processJob(ctx context.Context, job *Job) {
+	task := &DownloadTask{
+		URL:      job.URL,
+		Filepath: job.FilePath,
+		SHA256:   job.SHA256,
+		SHA1:     job.SHA1,
+		MD5:      job.MD5,
+		Chunks:   job.Chunks,
+		Mirrors:  job.Mirrors,
+	}
+
+	defer func() {
+		jq.mu.Lock()
+		if cancel := jq.cancels[job.ID]; cancel != nil {
+			cancel()
+		}
+		delete(jq.cancels, job.ID)
+		delete(jq.tasks, task)
+		delete(jq.active, job.ID)
+		jq.mu.Unlock()
+	}()
+
+	jq.mu.Lock()
+	job.Status = "downloading"
+	now := time.Now()
+	job.StartTime = &now
+	jq.tasks[task] = job
+	jq.publish("status", job)
+	jq.mu.Unlock()
+
+	if jq.manager == nil {
+		return
+	}
+	err := jq.manager.Download(ctx, task)
+
+	jq.mu.Lock()
+	defer jq.mu.Unlock()
+	switch {
+	case jq.jobs[job.ID] != job:
+		return // removed while running
+	case job.Status == "paused":
+		return // Pause cancelled it; the partial download resumes later
+	case err != nil:
+		job.Status = "failed"
+		job.Error = err.Error()
+		job.ErrorKind = errorKind(err)
+		jq.failed[job.ID] = job
+	default:
+		job.Status = "completed"
+		end := time.Now()
+		job.EndTime = &end
+		job.FilePath = task.Filepath
+		job.TotalSize, job.Downloaded = task.Size, task.Size
+		jq.completed[job.ID] = job
+	}
+	jq.updateJobInDB(job)
+	jq.publish("status", job)
+}
+
+// Pause takes a pending job off the queue or stops a running one, keeping
+// its partial download
+func (jq *JobQueue) Pause(id string) error {
+	jq.mu.Lock()
+	defer jq.mu.Unlock()
+	job, exists := jq.jobs[id]
+	if !exists {
+		return errJobNotFound
+	}
+	if job.Status != "pending" && job.Status != "downloading" {
+		return fmt.Errorf("job %s is %s", id, job.Status)
+	}
+	job.Status = "paused"
+	jq.dequeue(job)
+	if cancel := jq.cancels[id]; cancel != nil {
+		cancel()
+	}
+	jq.updateJobInDB(job)
+	jq.publish("status", job)
+	return nil
+}
+
+// Resume queues a paused job again
+func (jq *JobQueue) Resume(id string) error {
+	jq.mu.Lock()
+	defer jq.mu.Unlock()
+	job, exists := jq.jobs[id]
+	if !exists {
+		return errJobNotFound
+	}
+	if job.Status != "paused" {
+		return fmt.Errorf("job %s is %s", id, job.Status)
+	}
+	if _, running := jq.active[id]; running {
+		return fmt.Errorf("job %s is still stopping", id)
+	}
+	job.Status = "pending"
+	jq.queue = append(jq.queue, job)
+	jq.sortQueue()
+	jq.updateJobInDB(job)
+	jq.publish("status", job)
+	return nil
+}
+
+// Retry queues a failed job again
+func (jq *JobQueue) Retry(id string) error {
+	jq.mu.Lock()
+	defer jq.mu.Unlock()
+	job, exists := jq.failed[id]
+	if !exists {
+		return fmt.Errorf("%w in failed queue", errJobNotFound)
+	}
+	job.Status = "pending"
+	job.Error = ""
+	job.ErrorKind = ""
+	delete(jq.failed, id)
+	jq.queue = append(jq.queue, job)
+	jq.sortQueue()
+	jq.updateJobInDB(job)
+	jq.publish("status", job)
+	return nil
+}
+
+// Remove forgets a job, stopping it first if it is running. Files already
+// written are left alone.
+func (jq *JobQueue) Remove(id string) error {
+	jq.mu.Lock()
+	defer jq.mu.Unlock()
+	job, exists := jq.jobs[id]
+	if !exists {
+		return errJobNotFound
+	}
+	jq.dequeue(job)
+	if cancel := jq.cancels[id]; cancel != nil {
+		cancel()
+	}
+	delete(jq.jobs, id)
+	delete(jq.completed, id)
+	delete(jq.failed, id)
+	jq.db.Exec("DELETE FROM jobs WHERE id = ?", id)
+	job.Status = "removed"
+	jq.publish("status", job)
+	return nil
+}
+
+// Get returns a copy of the job with this id
+func (jq *JobQueue) Get(id string) (*Job, bool) {
+	jq.mu.RLock()
+	defer jq.mu.RUnlock()
+	job, exists := jq.jobs[id]
+	if !exists {
+		return nil, false
+	}
+	copied := *job
+	return &copied, true
+}
+
+// Jobs returns copies of every job, oldest first
+func (jq *JobQueue) Jobs() []*Job {
+	jq.mu.RLock()
+	jobs := make([]*Job, 0, len(jq.jobs))
+	for _, job := range jq.jobs {
+		copied := *job
+		jobs = append(jobs, &copied)
+	}
+	jq.mu.RUnlock()
+	sort.Slice(jobs, func(i, j int) bool { return jobs[i].AddedTime.Before(jobs[j].AddedTime) })
+	return jobs
+}
+
+// Subscribe returns a channel of job events and a func that unsubscribes
+// it. A subscriber that falls behind misses events rather than stall the
+// queue.
+func (jq *JobQueue) Subscribe() (<-chan JobEvent, func()) {
+	ch := make(chan JobEvent, 64)
+	jq.watchMu.Lock()
+	jq.watchers[ch] = struct{}{}
+	jq.watchMu.Unlock()
+	return ch, func() {
+		jq.watchMu.Lock()
+		delete(jq.watchers, ch)
+		jq.watchMu.Unlock()
+	}
+}
+
+// publish sends a copy of job to every subscriber
+func (jq *JobQueue) publish(kind string, job *Job) {
+	event := JobEvent{Type: kind, Job: *job}
+	jq.watchMu.Lock()
+	defer jq.watchMu.Unlock()
+	for ch := range jq.watchers {
+		select {
+		case ch <- event:
+		default:
+		}
+	}
+}
+
+func (o jobObserver) OnProgress(task *DownloadTask, stats ProgressSnapshot) {
+	o.jq.mu.Lock()
+	defer o.jq.mu.Unlock()
+	job, running := o.jq.tasks[task]
+	if !running {
+		return
+	}
+	job.TotalSize = stats.Total
+	job.Downloaded = stats.Downloaded
+	job.Speed = stats.Speed
+	if stats.Speed > 0 && stats.Total > stats.Downloaded {
+		job.ETA = int(float64(stats.Total-stats.Downloaded) / stats.Speed)
+	}
+	o.jq.publish("progress", job)
+}
+
+// totals adds up the progress of every job
+func (jq *JobQueue) totals() *queueTotals {
+	jq.mu.RLock()
+	defer jq.mu.RUnlock()
+	totals := &queueTotals{Active: len(jq.active), Pending: len(jq.queue)}
+	for _, job := range jq.jobs {
+		totals.Downloaded += job.Downloaded
+		totals.Total += job.TotalSize
+		totals.Speed += job.Speed
+	}
+	return totals
+}
+
+// progress reports a job the way the ndjson progress stream reports a
+// download
+func (job *Job) progress() DownloadProgress {
+	progress := DownloadProgress{
+		ID:         job.ID,
+		URL:        job.URL,
+		File:       job.FilePath,
+		Downloaded: job.Downloaded,
+		Total:      job.TotalSize,
+		Speed:      job.Speed,
+		ETA:        float64(job.ETA),
+		Status:     job.Status,
+	}
+	if progress.Total > 0 {
+		progress.Percent = min(float64(progress.Downloaded)/float64(progress.Total)*100, 100)
+	}
+	return progress
+}
+
+// jobFinished reports whether a job in status will change no further
+func jobFinished(status string) bool {
+	return status == "completed" || status == "failed" || status == "removed"
+}
+
+// dequeue drops job from the pending queue, caller holds jq.mu
+func (jq *JobQueue) dequeue(job *Job) {
+	if i := slices.Index(jq.queue, job); i >= 0 {
+		jq.queue = slices.Delete(jq.queue, i, i+1)
+	}
+}
+
+// Summary is the job counts and the jobs themselves, as /api/jobs and the
+// control socket report them
+func (jq *JobQueue) Summary() map[string]interface{} {
+	jq.mu.RLock()
+	defer jq.mu.RUnlock()
+	jobs := make(map[string]*Job, len(jq.jobs))
+	for id, job := range jq.jobs {
+		copied := *job
+		jobs[id] = &copied
+	}
+	return map[string]interface{}{
+		"pending":   len(jq.queue),
+		"active":    len(jq.active),
+		"completed": len(jq.completed),
+		"failed":    len(jq.failed),
+		"jobs":      jobs,
+	}
+}
+
+func (jq *JobQueue) updateJobInDB(job *Job) {
+	_, err := jq.db.Exec(`
+		UPDATE jobs SET status = ?, downloaded = ?, error = ?, start_time = ?, end_time = ?
+		WHERE id = ?
+	`, job.Status, job.Downloaded, job.Error, job.StartTime, job.EndTime, job.ID)
+	if err != nil {
+		fmt.Printf("Failed to update job in DB: %v\n", err)
+	}
+}
+
+// DaemonServer implementation
+func NewDaemonServer(config *Config, queue *JobQueue) *DaemonServer {
+	return &DaemonServer{
+		queue:       queue,
+		config:      config,
+		rateLimiter: NewRateLimiter(config.RateLimit),
+		metrics:     NewMetrics(),
+	}
+}
+
+func (d *DaemonServer) Start() error {
+	mux := http.NewServeMux()
 	
-	if err != nil {
+	// API endpoints
+	mux.HandleFunc("/api/jobs", d.authorized(d.handleJobs))
+	mux.HandleFunc("/api/jobs/add", d.authorized(d.handleAddJob))
+	mux.HandleFunc("/api/jobs/pause", d.authorized(d.handlePauseJob))
+	mux.HandleFunc("/api/jobs/resume", d.authorized(d.handleResumeJob))
+	mux.HandleFunc("/api/jobs/delete", d.authorized(d.handleDeleteJob))
+	mux.HandleFunc("/api/jobs/retry", d.authorized(d.handleRetryJob))
+	mux.HandleFunc("/api/status", d.authorized(d.handleStatus))
+	mux.HandleFunc("/api/config", d.authorized(d.handleConfig))
+	mux.HandleFunc("/api/stats", d.authorized(d.handleStats))
+	// aria2 clients authenticate with rpc_secret; without one the endpoint
+	// is as open as the rest of the API, so it takes api_token instead
+	if d.config.RPCSecret != "" {
+		mux.HandleFunc("/jsonrpc", d.handleJSONRPC)
+	} else {
+		mux.HandleFunc("/jsonrpc", d.authorized(d.handleJSONRPC))
+	}
+	mux.HandleFunc("/metrics", d.authorized(d.handleMetrics))
+
+	// REST
+	mux.HandleFunc("/downloads", d.authorized(d.handleDownloads))
+	mux.HandleFunc("/downloads/", d.authorized(d.handleDownload))
+	mux.HandleFunc("/ws/downloads", d.authorized(d.handleProgressSocket))
+	mux.HandleFunc("/ws/downloads/", d.authorized(d.handleProgressSocket))
+
+	// Serve simple web UI
+	mux.HandleFunc("/", d.handleWebUI)
+
+	// Without api_token anyone who can connect may queue downloads, so
+	// only local clients can
+	addr := fmt.Sprintf("127.0.0.1:%d", d.config.DaemonPort)
+	if d.config.APIToken != "" {
+		addr = fmt.Sprintf(":%d", d.config.DaemonPort)
+	} else {
+		fmt.Printf("%s[Daemon] No api_token set, only accepting local connections%s\n", ColorYellow, ColorReset)
+	}
+	d.server = &http.Server{
+		Addr:    addr,
+		Handler: mux,
+	}
+
+	fmt.Printf("%s[Daemon] Server listening on http://localhost:%d%s\n", ColorGreen, d.config.DaemonPort, ColorReset)
+	return d.server.ListenAndServe()
+}
+
+// ListenControl serves the control socket that "fastdl ctl" talks to: one
+// JSON request per line, one JSON response per line. A socket left behind
+// by a daemon that died is replaced; one that still answers is an error.
+func (d *DaemonServer) ListenControl(path string) error {
+	if conn, err := net.Dial("unix", path); err == nil {
+		conn.Close()
+		return fmt.Errorf("a daemon is already listening on %s", path)
+	}
+	os.Remove(path)
+	if err := os.MkdirAll(filepath.Dir(path), 0700); err != nil {
 		return err
 	}
 
-	jq.jobs[job.ID] = job
-	jq.queue = append(jq.queue, job)
-	jq.sortQueue()
+	// Anyone who can connect can queue downloads as this user, so the
+	// socket is created private rather than chmodded once it's reachable
+	mask := syscall.Umask(0077)
+	listener, err := net.Listen("unix", path)
+	syscall.Umask(mask)
+	if err != nil {
+		return err
+	}
+	if err := os.Chmod(path, 0600); err != nil {
+		listener.Close()
+		return err
+	}
+	d.control = listener
 
+	go func() {
+		for {
+			conn, err := listener.Accept()
+			if err != nil {
+				return
+			}
+			go d.serveControl(conn)
+		}
+	}()
 	return nil
 }
 
-func (jq *JobQueue) sortQueue() {
-	sort.Slice(jq.queue, func(i, j int) bool {
-		return jq.queue[i].Priority > jq.queue[j].Priority
-	})
+// CloseControl stops the control socket and removes it
+func (d *DaemonServer) CloseControl() {
+	if d.control == nil {
+		return
+	}
+	d.control.Close()
+	os.Remove(d.control.Addr().String())
 }
 
-func (jq *JobQueue) ProcessQueue(ctx context.Context) {
+func (d *DaemonServer) serveControl(conn net.Conn) {
+	defer conn.Close()
+	scanner := bufio.NewScanner(conn)
+	encoder := json.NewEncoder(conn)
+	for scanner.Scan() {
+		var req controlRequest
+		response := controlResponse{OK: true}
+		err := json.Unmarshal(scanner.Bytes(), &req)
+		if err == nil {
+			response.Result, err = d.runControl(req)
+		}
+		if err != nil {
+			response = controlResponse{Error: err.Error()}
+		}
+		if encoder.Encode(response) != nil {
+			return
+		}
+	}
+}
+
+func (d *DaemonServer) runControl(req controlRequest) (interface{}, error) {
+	switch req.Command {
+	case "add":
+		if req.URL == "" {
+			return nil, errors.New("add needs a url")
+		}
+		job := &Job{URL: req.URL, FilePath: req.Output, Priority: req.Priority, SHA256: req.SHA256}
+		if err := d.queue.AddJob(job); err != nil {
+			return nil, err
+		}
+		return map[string]string{"id": job.ID}, nil
+	case "pause":
+		return nil, d.queue.Pause(req.ID)
+	case "resume":
+		return nil, d.queue.Resume(req.ID)
+	case "retry":
+		return nil, d.queue.Retry(req.ID)
+	case "remove":
+		return nil, d.queue.Remove(req.ID)
+	case "status":
+		return d.queue.Summary(), nil
+	case "rate":
+		d.setRate(req.Rate)
+		return map[string]int64{"rate": req.Rate}, nil
+	}
+	return nil, fmt.Errorf("unknown command %q", req.Command)
+}
+
+// setRate changes the bandwidth cap shared by all of the daemon's downloads
+func (d *DaemonServer) setRate(bytesPerSecond int64) {
+	d.config.RateLimit = bytesPerSecond
+	if d.queue.manager != nil {
+		d.queue.manager.rateLimiter.SetLimit(bytesPerSecond)
+	}
+}
+
+// downloadRequest is the body of POST /downloads
+type downloadRequest struct {
+	URL         string   `json:"url"`
+	Output      string   `json:"output,omitempty"`
+	Mirrors     []string `json:"mirrors,omitempty"`
+	Priority    int      `json:"priority,omitempty"`
+	Connections int      `json:"connections,omitempty"`
+	SHA256      string   `json:"sha256,omitempty"`
+	SHA1        string   `json:"sha1,omitempty"`
+	MD5         string   `json:"md5,omitempty"`
+}
+
+// authorized guards an API handler with api_token, sent as a bearer token
+// or, for EventSource clients that can't set headers, a token parameter.
+// Without a token only local clients reach the daemon, but any page open
+// in their browser could still post to it, so other origins are refused.
+func (d *DaemonServer) authorized(next http.HandlerFunc) http.HandlerFunc {
+	return func(w http.ResponseWriter, r *http.Request) {
+		if d.config.APIToken == "" && !sameOrigin(r) {
+			restError(w, http.StatusForbidden, errors.New("cross-origin requests need api_token"))
+			return
+		}
+		if d.config.APIToken != "" {
+			token := strings.TrimPrefix(r.Header.Get("Authorization"), "Bearer ")
+			if token == "" {
+				token = r.URL.Query().Get("token")
+			}
+			if subtle.ConstantTimeCompare([]byte(token), []byte(d.config.APIToken)) != 1 {
+				w.Header().Set("WWW-Authenticate", `Bearer realm="fastdl"`)
+				restError(w, http.StatusUnauthorized, errors.New("missing or wrong API token"))
+				return
+			}
+		}
+		next(w, r)
+	}
+}
+
+// handleDownloads is the REST collection: GET lists every job, POST
+// queues one
+func (d *DaemonServer) handleDownloads(w http.ResponseWriter, r *http.Request) {
+	switch r.Method {
+	case http.MethodGet:
+		restJSON(w, http.StatusOK, d.queue.Jobs())
+	case http.MethodPost:
+		var req downloadRequest
+		if err := json.NewDecoder(r.Body).Decode(&req); err != nil {
+			restError(w, http.StatusBadRequest, err)
+			return
+		}
+		if req.URL == "" {
+			restError(w, http.StatusBadRequest, errors.New("url is required"))
+			return
+		}
+		if req.Output != "" && !filepath.IsLocal(req.Output) {
+			restError(w, http.StatusBadRequest, fmt.Errorf("output %q is outside the download directory", req.Output))
+			return
+		}
+		job := &Job{
+			URL:      req.URL,
+			Mirrors:  req.Mirrors,
+			FilePath: req.Output,
+			Priority: req.Priority,
+			Chunks:   req.Connections,
+			SHA256:   req.SHA256,
+			SHA1:     req.SHA1,
+			MD5:      req.MD5,
+		}
+		if err := d.queue.AddJob(job); err != nil {
+			restError(w, http.StatusInternalServerError, err)
+			return
+		}
+		w.Header().Set("Location", "/downloads/"+job.ID)
+		added, _ := d.queue.Get(job.ID)
+		restJSON(w, http.StatusCreated, added)
+	default:
+		restError(w, http.StatusMethodNotAllowed, errors.New("method not allowed"))
+	}
+}
+
+// handleDownload serves one job: GET and DELETE /downloads/{id}, POST
+// /downloads/{id}/pause, resume or retry, and GET /downloads/{id}/progress
+func (d *DaemonServer) handleDownload(w http.ResponseWriter, r *http.Request) {
+	id, action, _ := strings.Cut(strings.TrimPrefix(r.URL.Path, "/downloads/"), "/")
+	var err error
+	switch {
+	case action == "" && r.Method == http.MethodGet:
+		job, exists := d.queue.Get(id)
+		if !exists {
+			restError(w, http.StatusNotFound, errJobNotFound)
+			return
+		}
+		restJSON(w, http.StatusOK, job)
+		return
+	case action == "" && r.Method == http.MethodDelete:
+		err = d.queue.Remove(id)
+	case action == "progress" && r.Method == http.MethodGet:
+		d.streamProgress(w, r, id)
+		return
+	case action == "pause" && r.Method == http.MethodPost:
+		err = d.queue.Pause(id)
+	case action == "resume" && r.Method == http.MethodPost:
+		err = d.queue.Resume(id)
+	case action == "retry" && r.Method == http.MethodPost:
+		err = d.queue.Retry(id)
+	case action == "" || action == "progress" || action == "pause" || action == "resume" || action == "retry":
+		restError(w, http.StatusMethodNotAllowed, errors.New("method not allowed"))
+		return
+	default:
+		restError(w, http.StatusNotFound, fmt.Errorf("no such endpoint %q", action))
+		return
+	}
+
+	switch {
+	case errors.Is(err, errJobNotFound):
+		restError(w, http.StatusNotFound, err)
+	case err != nil:
+		restError(w, http.StatusConflict, err)
+	default:
+		w.WriteHeader(http.StatusNoContent)
+	}
+}
+
+// streamProgress sends a job as server-sent events: "progress" every
+// second, "status" when it changes, until it finishes or is removed
+func (d *DaemonServer) streamProgress(w http.ResponseWriter, r *http.Request, id string) {
+	job, exists := d.queue.Get(id)
+	if !exists {
+		restError(w, http.StatusNotFound, errJobNotFound)
+		return
+	}
+	flusher, ok := w.(http.Flusher)
+	if !ok {
+		restError(w, http.StatusInternalServerError, errors.New("streaming not supported"))
+		return
+	}
+
+	events, unsubscribe := d.queue.Subscribe()
+	defer unsubscribe()
 	ticker := time.NewTicker(time.Second)
 	defer ticker.Stop()
 
+	w.Header().Set("Content-Type", "text/event-stream")
+	w.Header().Set("Cache-Control", "no-cache")
+	w.Header().Set("X-Accel-Buffering", "no") // nginx would hold events back
+	send := func(event string, job *Job) {
+		data, _ := json.Marshal(job)
+		fmt.Fprintf(w, "event: %s\ndata: %s\n\n", event, data)
+		flusher.Flush()
+	}
+
+	send("progress", job)
+	for !jobFinished(job.Status) {
+		select {
+		case <-r.Context().Done():
+			return
+		case event := <-events:
+			if event.Job.ID != id || event.Type != "status" {
+				continue
+			}
+			job = &event.Job
+			send("status", job)
+		case <-ticker.C:
+			if job, exists = d.queue.Get(id); !exists {
+				return
+			}
+			send("progress", job)
+		}
+	}
+}
+
+// progressMessage is one message on a progress WebSocket
+type progressMessage struct {
+	Type     string            `json:"type"` // progress, status or, on the aggregate channel, total
+	Progress *DownloadProgress `json:"progress,omitempty"`
+	Total    *queueTotals      `json:"total,omitempty"`
+}
+
+// queueTotals sums up every download the daemon knows about
+type queueTotals struct {
+	Downloaded int64   `json:"downloaded"`
+	Total      int64   `json:"total"`
+	Speed      float64 `json:"speed"`
+	Active     int     `json:"active"`
+	Pending    int     `json:"pending"`
+}
+
+// socketHandshake lets non-browser clients, which send no Origin, connect.
+// With an API token that keeps other sites out; without one a page from
+// another origin could read every URL and path being downloaded.
+func (d *DaemonServer) socketHandshake(_ *websocket.Config, r *http.Request) error {
+	if d.config.APIToken == "" && !sameOrigin(r) {
+		return errors.New("cross-origin connections need api_token")
+	}
+	return nil
+}
+
+// handleProgressSocket streams progress over a WebSocket instead of making
+// frontends poll: /ws/downloads carries every download plus a "total"
+// message each second, /ws/downloads/{id} one download until it finishes
+func (d *DaemonServer) handleProgressSocket(w http.ResponseWriter, r *http.Request) {
+	id := strings.TrimPrefix(strings.TrimPrefix(r.URL.Path, "/ws/downloads"), "/")
+	if _, exists := d.queue.Get(id); id != "" && !exists {
+		restError(w, http.StatusNotFound, errJobNotFound)
+		return
+	}
+	server := websocket.Server{
+		Handshake: d.socketHandshake,
+		Handler:   func(ws *websocket.Conn) { d.streamSocket(ws, id) },
+	}
+	server.ServeHTTP(w, r)
+}
+
+func (d *DaemonServer) streamSocket(ws *websocket.Conn, id string) {
+	defer ws.Close()
+	events, unsubscribe := d.queue.Subscribe()
+	defer unsubscribe()
+
+	// Nothing is read from clients; the read only notices them leaving
+	closed := make(chan struct{})
+	go func() {
+		io.Copy(io.Discard, ws)
+		close(closed)
+	}()
+
+	var tick <-chan time.Time
+	if id == "" {
+		ticker := time.NewTicker(time.Second)
+		defer ticker.Stop()
+		tick = ticker.C
+	}
+
+	// Start with where every download stands
+	for _, job := range d.queue.Jobs() {
+		if id != "" && job.ID != id {
+			continue
+		}
+		progress := job.progress()
+		if websocket.JSON.Send(ws, progressMessage{Type: "status", Progress: &progress}) != nil {
+			return
+		}
+		if id != "" && jobFinished(job.Status) {
+			return
+		}
+	}
+
 	for {
+		var message progressMessage
 		select {
-		case <-ctx.Done():
+		case <-closed:
 			return
-		case <-jq.stopCh:
+		case event := <-events:
+			if id != "" && event.Job.ID != id {
+				continue
+			}
+			progress := event.Job.progress()
+			message = progressMessage{Type: event.Type, Progress: &progress}
+		case <-tick:
+			message = progressMessage{Type: "total", Total: d.queue.totals()}
+		}
+		if websocket.JSON.Send(ws, message) != nil {
+			return
+		}
+		if id != "" && message.Type == "status" && jobFinished(message.Progress.Status) {
 			return
-		case <-ticker.C:
-			jq.processNext()
 		}
 	}
 }
 
-func (jq *JobQueue) processNext() {
-	jq.mu.Lock()
-	defer jq.mu.Unlock()
+func restJSON(w http.ResponseWriter, status int, v interface{}) {
+	w.Header().Set("Content-Type", "application/json")
+	w.WriteHeader(status)
+	json.NewEncoder(w).Encode(v)
+}
 
-	if len(jq.active) >= jq.maxActive || len(jq.queue) == 0 {
+func restError(w http.ResponseWriter, status int, err error) {
+	restJSON(w, status, map[string]string{"error": err.Error()})
+}
+
+// aria2 JSON-RPC, so AriaNg, aria2 browser extensions and other frontends
+// built for aria2 can drive the daemon. Served on /jsonrpc over plain HTTP
+// POST and over WebSocket, which also carries aria2's download
+// notifications. Numbers go out as strings, as aria2 sends them.
+
+// rpcRequest is one JSON-RPC 2.0 call
+type rpcRequest struct {
+	JSONRPC string            `json:"jsonrpc"`
+	ID      json.RawMessage   `json:"id"`
+	Method  string            `json:"method"`
+	Params  []json.RawMessage `json:"params"`
+}
+
+type rpcResponse struct {
+	JSONRPC string          `json:"jsonrpc"`
+	ID      json.RawMessage `json:"id"`
+	Result  interface{}     `json:"result,omitempty"`
+	Error   *rpcError       `json:"error,omitempty"`
+}
+
+type rpcError struct {
+	Code    int    `json:"code"`
+	Message string `json:"message"`
+}
+
+func (e *rpcError) Error() string {
+	return e.Message
+}
+
+// rpcNotification is a server-initiated message on an aria2 WebSocket
+type rpcNotification struct {
+	JSONRPC string              `json:"jsonrpc"`
+	Method  string              `json:"method"`
+	Params  []map[string]string `json:"params"`
+}
+
+var aria2Methods = []string{
+	"aria2.addUri", "aria2.remove", "aria2.forceRemove", "aria2.pause", "aria2.forcePause",
+	"aria2.pauseAll", "aria2.forcePauseAll", "aria2.unpause", "aria2.unpauseAll",
+	"aria2.tellStatus", "aria2.getUris", "aria2.getFiles", "aria2.getPeers", "aria2.getServers",
+	"aria2.tellActive", "aria2.tellWaiting", "aria2.tellStopped", "aria2.getOption",
+	"aria2.changeOption", "aria2.getGlobalOption", "aria2.changeGlobalOption", "aria2.getGlobalStat",
+	"aria2.purgeDownloadResult", "aria2.removeDownloadResult", "aria2.getVersion",
+	"aria2.getSessionInfo", "aria2.saveSession",
+	"system.multicall", "system.listMethods", "system.listNotifications",
+}
+
+var aria2Notifications = []string{
+	"aria2.onDownloadStart", "aria2.onDownloadPause", "aria2.onDownloadStop",
+	"aria2.onDownloadComplete", "aria2.onDownloadError",
+}
+
+// handleJSONRPC serves aria2 JSON-RPC; a WebSocket upgrade switches to
+// the socket transport
+func (d *DaemonServer) handleJSONRPC(w http.ResponseWriter, r *http.Request) {
+	if strings.EqualFold(r.Header.Get("Upgrade"), "websocket") {
+		server := websocket.Server{
+			Handshake: d.rpcHandshake,
+			Handler:   d.aria2Socket,
+		}
+		server.ServeHTTP(w, r)
 		return
 	}
 
-	job := jq.queue[0]
-	jq.queue = jq.queue[1:]
-	jq.active[job.ID] = job
+	// Web frontends like AriaNg are served from another origin, which is
+	// only safe when rpc_secret keeps every other site out
+	if d.config.RPCSecret == "" {
+		if !sameOrigin(r) {
+			http.Error(w, "cross-origin requests need rpc_secret", http.StatusForbidden)
+			return
+		}
+	} else {
+		w.Header().Set("Access-Control-Allow-Origin", "*")
+		w.Header().Set("Access-Control-Allow-Headers", "Content-Type")
+		w.Header().Set("Access-Control-Allow-Methods", "POST, OPTIONS")
+	}
+	switch r.Method {
+	case http.MethodOptions:
+		return
+	case http.MethodPost:
+	default:
+		http.Error(w, "Method not allowed", http.StatusMethodNotAllowed)
+		return
+	}
+
+	body, err := io.ReadAll(io.LimitReader(r.Body, 1<<20))
+	if err != nil {
+		http.Error(w, err.Error(), http.StatusBadRequest)
+		return
+	}
+	w.Header().Set("Content-Type", "application/json-rpc")
+	json.NewEncoder(w).Encode(d.aria2Dispatch(body))
+}
+
+// rpcHandshake accepts JSON-RPC sockets from other origins only when
+// rpc_secret is set, as without it any page the user visits could queue
+// downloads
+func (d *DaemonServer) rpcHandshake(_ *websocket.Config, r *http.Request) error {
+	if d.config.RPCSecret == "" && !sameOrigin(r) {
+		return errors.New("cross-origin connections need rpc_secret")
+	}
+	return nil
+}
+
+// sameOrigin reports whether a browser request comes from a page this
+// daemon served. Other clients send no Origin and pass.
+func sameOrigin(r *http.Request) bool {
+	origin := r.Header.Get("Origin")
+	if origin == "" {
+		return true
+	}
+	u, err := url.Parse(origin)
+	return err == nil && strings.EqualFold(u.Host, r.Host)
+}
+
+// aria2Socket serves one WebSocket: calls in, responses and notifications out
+func (d *DaemonServer) aria2Socket(ws *websocket.Conn) {
+	defer ws.Close()
+	var sendMu sync.Mutex
+	send := func(v interface{}) error {
+		sendMu.Lock()
+		defer sendMu.Unlock()
+		return websocket.JSON.Send(ws, v)
+	}
+
+	events, unsubscribe := d.queue.Subscribe()
+	defer unsubscribe()
+	done := make(chan struct{})
+	defer close(done)
+	go func() {
+		for {
+			select {
+			case <-done:
+				return
+			case event := <-events:
+				method := aria2Notification(event.Job.Status)
+				if event.Type != "status" || method == "" {
+					continue
+				}
+				send(rpcNotification{JSONRPC: "2.0", Method: method, Params: []map[string]string{{"gid": event.Job.ID}}})
+			}
+		}
+	}()
+
+	for {
+		var message []byte
+		if err := websocket.Message.Receive(ws, &message); err != nil {
+			return
+		}
+		if err := send(d.aria2Dispatch(message)); err != nil {
+			return
+		}
+	}
+}
+
+// aria2Dispatch answers one JSON-RPC message, which may be a batch
+func (d *DaemonServer) aria2Dispatch(body []byte) interface{} {
+	if trimmed := bytes.TrimSpace(body); len(trimmed) > 0 && trimmed[0] == '[' {
+		var batch []rpcRequest
+		if err := json.Unmarshal(trimmed, &batch); err != nil {
+			return rpcResponse{JSONRPC: "2.0", Error: &rpcError{Code: -32700, Message: "Parse error."}}
+		}
+		responses := make([]rpcResponse, len(batch))
+		for i, req := range batch {
+			responses[i] = d.aria2Respond(req)
+		}
+		return responses
+	}
+
+	var req rpcRequest
+	if err := json.Unmarshal(body, &req); err != nil {
+		return rpcResponse{JSONRPC: "2.0", Error: &rpcError{Code: -32700, Message: "Parse error."}}
+	}
+	return d.aria2Respond(req)
+}
+
+func (d *DaemonServer) aria2Respond(req rpcRequest) rpcResponse {
+	response := rpcResponse{JSONRPC: "2.0", ID: req.ID}
+	result, err := d.aria2Call(req.Method, req.Params)
+	var rpcErr *rpcError
+	switch {
+	case errors.As(err, &rpcErr):
+		response.Error = rpcErr
+	case err != nil:
+		response.Error = &rpcError{Code: 1, Message: err.Error()}
+	default:
+		response.Result = result
+	}
+	return response
+}
+
+// aria2Call runs one aria2 method. params still carries the
+// "token:<secret>" that aria2 clients put first.
+func (d *DaemonServer) aria2Call(method string, params []json.RawMessage) (interface{}, error) {
+	switch method {
+	case "system.listMethods":
+		return aria2Methods, nil
+	case "system.listNotifications":
+		return aria2Notifications, nil
+	case "system.multicall":
+		var calls []struct {
+			MethodName string            `json:"methodName"`
+			Params     []json.RawMessage `json:"params"`
+		}
+		if err := aria2Param(params, 0, &calls); err != nil {
+			return nil, err
+		}
+		results := make([]interface{}, len(calls))
+		for i, call := range calls {
+			result, err := d.aria2Call(call.MethodName, call.Params)
+			if err != nil {
+				results[i] = rpcError{Code: 1, Message: err.Error()}
+				continue
+			}
+			results[i] = []interface{}{result}
+		}
+		return results, nil
+	}
+
+	var token string
+	if len(params) > 0 && json.Unmarshal(params[0], &token) == nil && strings.HasPrefix(token, "token:") {
+		params = params[1:]
+	}
+	if d.config.RPCSecret != "" && subtle.ConstantTimeCompare([]byte(token), []byte("token:"+d.config.RPCSecret)) != 1 {
+		return nil, errors.New("Unauthorized")
+	}
+
+	switch method {
+	case "aria2.addUri":
+		var uris []string
+		options := map[string]string{}
+		if err := aria2Param(params, 0, &uris); err != nil {
+			return nil, err
+		}
+		if err := aria2Param(params, 1, &options); err != nil {
+			return nil, err
+		}
+		return d.aria2Add(uris, options)
+	case "aria2.remove", "aria2.forceRemove", "aria2.removeDownloadResult":
+		return d.aria2Each(params, d.queue.Remove)
+	case "aria2.pause", "aria2.forcePause":
+		return d.aria2Each(params, d.queue.Pause)
+	case "aria2.unpause":
+		return d.aria2Each(params, d.queue.Resume)
+	case "aria2.pauseAll", "aria2.forcePauseAll":
+		for _, job := range d.aria2Jobs("active", "waiting") {
+			d.queue.Pause(job.ID)
+		}
+		return "OK", nil
+	case "aria2.unpauseAll":
+		for _, job := range d.aria2Jobs("paused") {
+			d.queue.Resume(job.ID)
+		}
+		return "OK", nil
+	case "aria2.purgeDownloadResult":
+		for _, job := range d.aria2Jobs("complete", "error") {
+			d.queue.Remove(job.ID)
+		}
+		return "OK", nil
+	case "aria2.tellStatus", "aria2.getUris", "aria2.getFiles", "aria2.getOption":
+		var gid string
+		if err := aria2Param(params, 0, &gid); err != nil {
+			return nil, err
+		}
+		job, exists := d.queue.Get(gid)
+		if !exists {
+			return nil, fmt.Errorf("GID %s is not found", gid)
+		}
+		status := d.aria2Status(job)
+		switch method {
+		case "aria2.getUris":
+			return status["files"].([]map[string]interface{})[0]["uris"], nil
+		case "aria2.getFiles":
+			return status["files"], nil
+		case "aria2.getOption":
+			return map[string]string{"dir": status["dir"].(string), "out": filepath.Base(job.FilePath)}, nil
+		}
+		var keys []string
+		aria2Param(params, 1, &keys)
+		return aria2Keys(status, keys), nil
+	case "aria2.tellActive":
+		var keys []string
+		aria2Param(params, 0, &keys)
+		return d.aria2List(d.aria2Jobs("active"), 0, -1, keys), nil
+	case "aria2.tellWaiting", "aria2.tellStopped":
+		var offset, num int
+		var keys []string
+		if err := aria2Param(params, 0, &offset); err != nil {
+			return nil, err
+		}
+		if err := aria2Param(params, 1, &num); err != nil {
+			return nil, err
+		}
+		aria2Param(params, 2, &keys)
+		jobs := d.aria2Jobs("waiting", "paused")
+		if method == "aria2.tellStopped" {
+			jobs = d.aria2Jobs("complete", "error")
+		}
+		return d.aria2List(jobs, offset, num, keys), nil
+	case "aria2.getPeers", "aria2.getServers":
+		return []interface{}{}, nil
+	case "aria2.changeOption", "aria2.saveSession":
+		return "OK", nil // the queue is already saved to the job database
+	case "aria2.getGlobalOption":
+		d.queue.mu.RLock()
+		defer d.queue.mu.RUnlock()
+		return map[string]string{
+			"dir":                        d.config.DownloadDir,
+			"max-concurrent-downloads":   strconv.Itoa(d.queue.maxActive),
+			"max-overall-download-limit": strconv.FormatInt(d.config.RateLimit, 10),
+			"split":                      strconv.Itoa(d.config.MaxConnections),
+		}, nil
+	case "aria2.changeGlobalOption":
+		options := map[string]string{}
+		if err := aria2Param(params, 0, &options); err != nil {
+			return nil, err
+		}
+		if limit, ok := options["max-overall-download-limit"]; ok {
+			rate, err := parseByteSize(limit)
+			if err != nil {
+				return nil, err
+			}
+			d.setRate(rate)
+		}
+		if value, ok := options["max-concurrent-downloads"]; ok {
+			n, err := strconv.Atoi(value)
+			if err != nil || n < 1 {
+				return nil, fmt.Errorf("invalid max-concurrent-downloads %q", value)
+			}
+			d.queue.mu.Lock()
+			d.queue.maxActive = n
+			d.queue.mu.Unlock()
+		}
+		return "OK", nil
+	case "aria2.getGlobalStat":
+		var speed float64
+		counts := map[string]int{}
+		for _, job := range d.aria2Jobs("active", "waiting", "paused", "complete", "error") {
+			counts[aria2State(job.Status)]++
+			speed += job.Speed
+		}
+		return map[string]string{
+			"downloadSpeed":   strconv.FormatInt(int64(speed), 10),
+			"uploadSpeed":     "0",
+			"numActive":       strconv.Itoa(counts["active"]),
+			"numWaiting":      strconv.Itoa(counts["waiting"] + counts["paused"]),
+			"numStopped":      strconv.Itoa(counts["complete"] + counts["error"]),
+			"numStoppedTotal": strconv.Itoa(counts["complete"] + counts["error"]),
+		}, nil
+	case "aria2.getVersion":
+		return map[string]interface{}{
+			"version":         Version,
+			"enabledFeatures": []string{"HTTPS", "FTP", "SFTP", "Message Digest", "Metalink", "BitTorrent"},
+		}, nil
+	case "aria2.getSessionInfo":
+		return map[string]string{"sessionId": fmt.Sprintf("%016x", os.Getpid())}, nil
+	}
+	return nil, &rpcError{Code: -32601, Message: "Method not found."}
+}
+
+// aria2Param decodes params[i] into v, leaving v alone when it's missing
+func aria2Param(params []json.RawMessage, i int, v interface{}) error {
+	if i >= len(params) {
+		return nil
+	}
+	if err := json.Unmarshal(params[i], v); err != nil {
+		return fmt.Errorf("invalid parameter %d: %w", i+1, err)
+	}
+	return nil
+}
 
-	go jq.processJob(job)
+// aria2Each runs action on the GID in params and answers with the GID
+func (d *DaemonServer) aria2Each(params []json.RawMessage, action func(string) error) (interface{}, error) {
+	var gid string
+	if err := aria2Param(params, 0, &gid); err != nil {
+		return nil, err
+	}
+	if err := action(gid); err != nil {
+		return nil, err
+	}
+	return gid, nil
 }
 
-func (jq *JobQueue) processJob(job *Job) {
-	defer func() {
-		jq.mu.Lock()
-		delete(jq.active, job.ID)
-		jq.mu.Unlock()
-	}()
+// aria2Add queues uris (the first is the download, the rest its mirrors)
+// with aria2's dir, out, split and checksum options
+func (d *DaemonServer) aria2Add(uris []string, options map[string]string) (interface{}, error) {
+	if len(uris) == 0 {
+		return nil, errors.New("No URI to download.")
+	}
+	job := &Job{
+		ID:      fmt.Sprintf("%016x", rand.Uint64()),
+		URL:     uris[0],
+		Mirrors: uris[1:],
+	}
+
+	// Output paths are relative to the download directory, so a dir
+	// outside it is ignored
+	dir := options["dir"]
+	if filepath.IsAbs(dir) {
+		base, _ := filepath.Abs(d.config.DownloadDir)
+		if rel, err := filepath.Rel(base, dir); err == nil && !strings.HasPrefix(rel, "..") {
+			dir = rel
+		} else {
+			dir = ""
+		}
+	}
+	name := options["out"]
+	if name == "" && dir != "" {
+		name, _ = extractFilename(job.URL, d.config)
+	}
+	if name != "" {
+		job.FilePath = filepath.Join(dir, name)
+		// A relative dir or out can still climb out with ".."
+		if !filepath.IsLocal(job.FilePath) {
+			return nil, fmt.Errorf("path %q is outside the download directory", job.FilePath)
+		}
+	}
 
-	job.Status = "downloading"
-	now := time.Now()
-	job.StartTime = &now
+	if split := options["split"]; split != "" {
+		n, err := strconv.Atoi(split)
+		if err != nil {
+			return nil, fmt.Errorf("invalid split %q", split)
+		}
+		job.Chunks = n
+	}
+	if checksum := options["checksum"]; checksum != "" {
+		algo, digest, _ := strings.Cut(checksum, "=")
+		switch strings.ReplaceAll(strings.ToLower(algo), "-", "") {
+		case "sha256":
+			job.SHA256 = digest
+		case "sha1":
+			job.SHA1 = digest
+		case "md5":
+			job.MD5 = digest
+		default:
+			return nil, fmt.Errorf("unsupported checksum %q", algo)
+		}
+	}
 
-	ctx := context.Background()
-	task := &DownloadTask{
-		URL:      job.URL,
-		Filepath: job.FilePath,
-		SHA256:   job.SHA256,
-		SHA1:     job.SHA1,
-		MD5:      job.MD5,
-		Chunks:   job.Chunks,
+	if err := d.queue.AddJob(job); err != nil {
+		return nil, err
 	}
+	return job.ID, nil
+}
 
-	if jq.manager != nil {
-		if err := jq.manager.Download(ctx, task); err != nil {
-			job.Status = "failed"
-			job.Error = err.Error()
-			jq.mu.Lock()
-			jq.failed[job.ID] = job
-			jq.mu.Unlock()
-		} else {
-			job.Status = "completed"
-			end := time.Now()
-			job.EndTime = &end
-			jq.mu.Lock()
-			jq.completed[job.ID] = job
-			jq.mu.Unlock()
+// aria2Jobs copies the jobs in the given aria2 states, oldest first
+func (d *DaemonServer) aria2Jobs(states ...string) []*Job {
+	var jobs []*Job
+	for _, job := range d.queue.Jobs() {
+		if slices.Contains(states, aria2State(job.Status)) {
+			jobs = append(jobs, job)
 		}
 	}
-
-	jq.updateJobInDB(job)
+	return jobs
 }
 
-func (jq *JobQueue) updateJobInDB(job *Job) {
-	_, err := jq.db.Exec(`
-		UPDATE jobs SET status = ?, downloaded = ?, error = ?, start_time = ?, end_time = ?
-		WHERE id = ?
-	`, job.Status, job.Downloaded, job.Error, job.StartTime, job.EndTime, job.ID)
-	if err != nil {
-		fmt.Printf("Failed to update job in DB: %v\n", err)
+// aria2List pages jobs the way tellWaiting and tellStopped do: a negative
+// offset counts back from the end and walks backwards
+func (d *DaemonServer) aria2List(jobs []*Job, offset, num int, keys []string) []map[string]interface{} {
+	if offset < 0 {
+		slices.Reverse(jobs)
+		offset = -offset - 1
+	}
+	list := []map[string]interface{}{}
+	for i := offset; i < len(jobs) && (num < 0 || len(list) < num); i++ {
+		list = append(list, aria2Keys(d.aria2Status(jobs[i]), keys))
 	}
+	return list
 }
 
-// DaemonServer implementation
-func NewDaemonServer(config *Config, queue *JobQueue) *DaemonServer {
-	return &DaemonServer{
-		queue:       queue,
-		config:      config,
-		rateLimiter: NewRateLimiter(config.RateLimit),
+// aria2Status describes job as aria2.tellStatus does
+func (d *DaemonServer) aria2Status(job *Job) map[string]interface{} {
+	dir := d.config.DownloadDir
+	path := filepath.Join(dir, job.FilePath)
+	uris := []map[string]string{}
+	for _, uri := range append([]string{job.URL}, job.Mirrors...) {
+		uris = append(uris, map[string]string{"uri": uri, "status": "used"})
 	}
+	total, completed := strconv.FormatInt(job.TotalSize, 10), strconv.FormatInt(job.Downloaded, 10)
+	status := map[string]interface{}{
+		"gid":             job.ID,
+		"status":          aria2State(job.Status),
+		"totalLength":     total,
+		"completedLength": completed,
+		"uploadLength":    "0",
+		"downloadSpeed":   strconv.FormatInt(int64(job.Speed), 10),
+		"uploadSpeed":     "0",
+		"connections":     strconv.Itoa(job.Chunks),
+		"numPieces":       "1",
+		"pieceLength":     total,
+		"dir":             dir,
+		"files": []map[string]interface{}{{
+			"index":           "1",
+			"path":            path,
+			"length":          total,
+			"completedLength": completed,
+			"selected":        "true",
+			"uris":            uris,
+		}},
+	}
+	if job.Status == "failed" {
+		status["errorCode"] = aria2ErrorCode(job)
+		status["errorMessage"] = job.Error
+	}
+	return status
 }
 
-func (d *DaemonServer) Start() error {
-	mux := http.NewServeMux()
-	
-	// API endpoints
-	mux.HandleFunc("/api/jobs", d.handleJobs)
-	mux.HandleFunc("/api/jobs/add", d.handleAddJob)
-	mux.HandleFunc("/api/jobs/pause", d.handlePauseJob)
-	mux.HandleFunc("/api/jobs/resume", d.handleResumeJob)
-	mux.HandleFunc("/api/jobs/delete", d.handleDeleteJob)
-	mux.HandleFunc("/api/jobs/retry", d.handleRetryJob)
-	mux.HandleFunc("/api/status", d.handleStatus)
-	mux.HandleFunc("/api/config", d.handleConfig)
-	mux.HandleFunc("/api/stats", d.handleStats)
+// aria2Keys keeps only the requested keys, all of them when none are given
+func aria2Keys(status map[string]interface{}, keys []string) map[string]interface{} {
+	if len(keys) == 0 {
+		return status
+	}
+	filtered := make(map[string]interface{}, len(keys))
+	for _, key := range keys {
+		if value, ok := status[key]; ok {
+			filtered[key] = value
+		}
+	}
+	return filtered
+}
 
-	// Serve simple web UI
-	mux.HandleFunc("/", d.handleWebUI)
+// aria2State maps a job status onto aria2's
+func aria2State(status string) string {
+	switch status {
+	case "downloading":
+		return "active"
+	case "pending":
+		return "waiting"
+	case "completed":
+		return "complete"
+	case "failed":
+		return "error"
+	}
+	return status // paused, removed
+}
 
-	d.server = &http.Server{
-		Addr:    fmt.Sprintf(":%d", d.config.DaemonPort),
-		Handler: mux,
-	}
+// aria2Notification names the notification for a job entering status
+func aria2Notification(status string) string {
+	switch status {
+	case "downloading":
+		return "aria2.onDownloadStart"
+	case "paused":
+		return "aria2.onDownloadPause"
+	case "removed":
+		return "aria2.onDownloadStop"
+	case "completed":
+		return "aria2.onDownloadComplete"
+	case "failed":
+		return "aria2.onDownloadError"
+	}
+	return ""
+}
 
-	fmt.Printf("%s[Daemon] Server listening on http://localhost:%d%s\n", ColorGreen, d.config.DaemonPort, ColorReset)
-	return d.server.ListenAndServe()
+// aria2ErrorCode maps a failed job onto aria2's exit status codes
+func aria2ErrorCode(job *Job) string {
+	switch job.ErrorKind {
+	case ErrorTimeout:
+		return "2"
+	case ErrorHTTP:
+		return "22"
+	case ErrorNetwork, ErrorTruncatedBody:
+		return "6"
+	case ErrorDisk:
+		return "9"
+	case ErrorIO:
+		return "15"
+	case ErrorChecksum:
+		return "32"
+	case ErrorCancelled:
+		return "7"
+	}
+	return "1"
 }
 
 func (d *DaemonServer) handleJobs(w http.ResponseWriter, r *http.Request) {
@@ -1114,19 +11391,8 @@ func (d *DaemonServer) handleJobs(w http.ResponseWriter, r *http.Request) {
 		return
 	}
 
-	d.queue.mu.RLock()
-	defer d.queue.mu.RUnlock()
-
-	response := map[string]interface{}{
-		"pending":   len(d.queue.queue),
-		"active":    len(d.queue.active),
-		"completed": len(d.queue.completed),
-		"failed":    len(d.queue.failed),
-		"jobs":      d.queue.jobs,
-	}
-
 	w.Header().Set("Content-Type", "application/json")
-	json.NewEncoder(w).Encode(response)
+	json.NewEncoder(w).Encode(d.queue.Summary())
 }
 
 func (d *DaemonServer) handleAddJob(w http.ResponseWriter, r *http.Request) {
@@ -1140,6 +11406,10 @@ func (d *DaemonServer) handleAddJob(w http.ResponseWriter, r *http.Request) {
 		http.Error(w, err.Error(), http.StatusBadRequest)
 		return
 	}
+	if job.FilePath != "" && !filepath.IsLocal(job.FilePath) {
+		http.Error(w, "file_path is outside the download directory", http.StatusBadRequest)
+		return
+	}
 
 	if err := d.queue.AddJob(&job); err != nil {
 		http.Error(w, err.Error(), http.StatusInternalServerError)
@@ -1157,16 +11427,7 @@ func (d *DaemonServer) handlePauseJob(w http.ResponseWriter, r *http.Request) {
 		return
 	}
 
-	d.queue.mu.Lock()
-	defer d.queue.mu.Unlock()
-
-	if job, exists := d.queue.jobs[jobID]; exists {
-		job.Status = "paused"
-		d.queue.updateJobInDB(job)
-		w.Write([]byte(`{"status":"paused"}`))
-	} else {
-		http.Error(w, "Job not found", http.StatusNotFound)
-	}
+	d.jobResult(w, d.queue.Pause(jobID), "paused")
 }
 
 func (d *DaemonServer) handleResumeJob(w http.ResponseWriter, r *http.Request) {
@@ -1176,18 +11437,7 @@ func (d *DaemonServer) handleResumeJob(w http.ResponseWriter, r *http.Request) {
 		return
 	}
 
-	d.queue.mu.Lock()
-	defer d.queue.mu.Unlock()
-
-	if job, exists := d.queue.jobs[jobID]; exists {
-		job.Status = "pending"
-		d.queue.queue = append(d.queue.queue, job)
-		d.queue.sortQueue()
-		d.queue.updateJobInDB(job)
-		w.Write([]byte(`{"status":"resumed"}`))
-	} else {
-		http.Error(w, "Job not found", http.StatusNotFound)
-	}
+	d.jobResult(w, d.queue.Resume(jobID), "resumed")
 }
 
 func (d *DaemonServer) handleDeleteJob(w http.ResponseWriter, r *http.Request) {
@@ -1197,16 +11447,7 @@ func (d *DaemonServer) handleDeleteJob(w http.ResponseWriter, r *http.Request) {
 		return
 	}
 
-	d.queue.mu.Lock()
-	defer d.queue.mu.Unlock()
-
-	if _, exists := d.queue.jobs[jobID]; exists {
-		delete(d.queue.jobs, jobID)
-		d.queue.db.Exec("DELETE FROM jobs WHERE id = ?", jobID)
-		w.Write([]byte(`{"status":"deleted"}`))
-	} else {
-		http.Error(w, "Job not found", http.StatusNotFound)
-	}
+	d.jobResult(w, d.queue.Remove(jobID), "deleted")
 }
 
 func (d *DaemonServer) handleRetryJob(w http.ResponseWriter, r *http.Request) {
@@ -1216,19 +11457,18 @@ func (d *DaemonServer) handleRetryJob(w http.ResponseWriter, r *http.Request) {
 		return
 	}
 
-	d.queue.mu.Lock()
-	defer d.queue.mu.Unlock()
+	d.jobResult(w, d.queue.Retry(jobID), "retrying")
+}
 
-	if job, exists := d.queue.failed[jobID]; exists {
-		job.Status = "pending"
-		job.Error = ""
-		delete(d.queue.failed, jobID)
-		d.queue.queue = append(d.queue.queue, job)
-		d.queue.sortQueue()
-		d.queue.updateJobInDB(job)
-		w.Write([]byte(`{"status":"retrying"}`))
-	} else {
-		http.Error(w, "Job not found in failed queue", http.StatusNotFound)
+// jobResult answers a job command with its new status or the error
+func (d *DaemonServer) jobResult(w http.ResponseWriter, err error, status string) {
+	switch {
+	case errors.Is(err, errJobNotFound):
+		http.Error(w, err.Error(), http.StatusNotFound)
+	case err != nil:
+		http.Error(w, err.Error(), http.StatusConflict)
+	default:
+		w.Write([]byte(fmt.Sprintf(`{"status":%q}`, status)))
 	}
 }
 
@@ -1305,200 +11545,708 @@ func (d *DaemonServer) handleStats(w http.ResponseWriter, r *http.Request) {
 	json.NewEncoder(w).Encode(stats)
 }
 
-func (d *DaemonServer) handleWebUI(w http.ResponseWriter, r *http.Request) {
-	html := `<!DOCTYPE html>
+// handleMetrics serves Prometheus metrics: the download counters plus the
+// queue's gauges
+func (d *DaemonServer) handleMetrics(w http.ResponseWriter, r *http.Request) {
+	w.Header().Set("Content-Type", "text/plain; version=0.0.4")
+	d.metrics.Render(w)
+
+	totals := d.queue.totals()
+	promMetric(w, "fastdl_queue_depth", "gauge", "Jobs waiting to start.")
+	fmt.Fprintf(w, "fastdl_queue_depth %d\n", totals.Pending)
+	promMetric(w, "fastdl_queue_speed_bytes", "gauge", "Combined speed of the running jobs in bytes/sec.")
+	fmt.Fprintf(w, "fastdl_queue_speed_bytes %g\n", totals.Speed)
+	promMetric(w, "fastdl_rate_limit_bytes", "gauge", "Bandwidth cap in bytes/sec, 0 when unlimited.")
+	fmt.Fprintf(w, "fastdl_rate_limit_bytes %d\n", d.config.RateLimit)
+}
+
+// webUI is the daemon's dashboard. It is plain HTML and JavaScript with
+// no build step, talking to the REST API and the /ws/downloads socket.
+const webUI = `<!DOCTYPE html>
 <html>
 <head>
-    <title>FastDL Dashboard</title>
+    <meta charset="utf-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+    <title>FastDL</title>
     <style>
-        body { font-family: Arial, sans-serif; background: #1a1a1a; color: #fff; margin: 0; padding: 20px; }
+        body { font-family: system-ui, Arial, sans-serif; background: #1a1a1a; color: #eee; margin: 0; padding: 20px; }
         .container { max-width: 1200px; margin: 0 auto; }
-        h1 { color: #4CAF50; }
-        .stats { display: grid; grid-template-columns: repeat(auto-fit, minmax(200px, 1fr)); gap: 20px; margin: 20px 0; }
-        .stat-card { background: #2a2a2a; padding: 20px; border-radius: 8px; }
-        .stat-value { font-size: 24px; font-weight: bold; color: #4CAF50; }
-        .stat-label { color: #888; margin-top: 5px; }
-        .jobs-table { width: 100%; background: #2a2a2a; border-radius: 8px; overflow: hidden; }
-        .jobs-table th { background: #333; padding: 15px; text-align: left; }
-        .jobs-table td { padding: 15px; border-top: 1px solid #333; }
-        .status { padding: 5px 10px; border-radius: 4px; font-size: 12px; }
-        .status.active { background: #4CAF50; }
+        h1 { color: #4CAF50; margin-top: 0; }
+        .stats { display: grid; grid-template-columns: repeat(auto-fit, minmax(180px, 1fr)); gap: 16px; margin: 20px 0; }
+        .stat-card { background: #2a2a2a; padding: 16px; border-radius: 8px; }
+        .stat-value { font-size: 22px; font-weight: bold; color: #4CAF50; }
+        .stat-label { color: #888; margin-top: 4px; }
+        form { display: flex; gap: 8px; flex-wrap: wrap; margin: 20px 0; }
+        input { background: #333; border: 1px solid #555; color: #fff; padding: 10px; border-radius: 4px; }
+        #url { flex: 1; min-width: 260px; }
+        #output { width: 180px; }
+        #priority { width: 80px; }
+        button { background: #444; color: #fff; border: none; padding: 6px 12px; border-radius: 4px; cursor: pointer; }
+        button:hover { background: #555; }
+        button.primary { background: #4CAF50; padding: 10px 20px; }
+        table { width: 100%; background: #2a2a2a; border-radius: 8px; border-collapse: collapse; overflow: hidden; }
+        th { background: #333; padding: 12px; text-align: left; }
+        td { padding: 12px; border-top: 1px solid #333; vertical-align: middle; }
+        td.name { max-width: 420px; overflow: hidden; text-overflow: ellipsis; white-space: nowrap; }
+        .bar { background: #444; border-radius: 4px; height: 8px; width: 160px; overflow: hidden; }
+        .bar div { background: #4CAF50; height: 100%; }
+        .status { padding: 4px 8px; border-radius: 4px; font-size: 12px; background: #555; }
+        .status.downloading { background: #4CAF50; }
         .status.pending { background: #FF9800; }
+        .status.paused { background: #9E9E9E; }
         .status.completed { background: #2196F3; }
         .status.failed { background: #F44336; }
-        .add-job { background: #4CAF50; color: white; border: none; padding: 10px 20px; border-radius: 4px; cursor: pointer; }
-        .add-job:hover { background: #45a049; }
-        input { background: #333; border: 1px solid #555; color: white; padding: 10px; border-radius: 4px; width: 100%; margin: 5px 0; }
+        .error { color: #F44336; font-size: 12px; }
+        #banner { color: #FF9800; }
     </style>
 </head>
 <body>
     <div class="container">
-        <h1>FastDL Dashboard</h1>
-        <div class="stats" id="stats"></div>
-        <div style="margin: 20px 0;">
-            <h2>Add New Download</h2>
-            <input type="text" id="urlInput" placeholder="Enter URL">
-            <button class="add-job" onclick="addJob()">Add Download</button>
+        <h1>FastDL</h1>
+        <div id="banner"></div>
+        <div class="stats">
+            <div class="stat-card"><div class="stat-value" id="active">0</div><div class="stat-label">Active</div></div>
+            <div class="stat-card"><div class="stat-value" id="pending">0</div><div class="stat-label">Queued</div></div>
+            <div class="stat-card"><div class="stat-value" id="speed">0 B/s</div><div class="stat-label">Speed</div></div>
+            <div class="stat-card"><div class="stat-value" id="downloaded">0 B</div><div class="stat-label">Downloaded</div></div>
         </div>
-        <h2>Jobs</h2>
-        <table class="jobs-table">
-            <thead>
-                <tr>
-                    <th>ID</th>
-                    <th>URL</th>
-                    <th>Status</th>
-                    <th>Progress</th>
-                    <th>Actions</th>
-                </tr>
-            </thead>
-            <tbody id="jobsList"></tbody>
+        <form id="add">
+            <input id="url" type="url" placeholder="https://example.com/file.iso" required>
+            <input id="output" placeholder="Save as (optional)">
+            <input id="priority" type="number" value="0" title="Priority">
+            <button class="primary" type="submit">Add</button>
+        </form>
+        <table>
+            <thead><tr><th>File</th><th>Status</th><th>Progress</th><th>Speed</th><th>ETA</th><th></th></tr></thead>
+            <tbody id="jobs"></tbody>
         </table>
     </div>
     <script>
-        async function fetchData() {
-            try {
-                const [jobsRes, statsRes, statusRes] = await Promise.all([
-                    fetch('/api/jobs'),
-                    fetch('/api/stats'),
-                    fetch('/api/status')
-                ]);
-                
-                const jobs = await jobsRes.json();
-                const stats = await statsRes.json();
-                const status = await statusRes.json();
-                
-                updateStats(stats, status, jobs);
-                updateJobsList(jobs);
-            } catch (error) {
-                console.error('Error fetching data:', error);
-            }
+        // An api_token can be given once as #token=... and is remembered
+        var match = location.hash.match(/token=([^&]+)/);
+        if (match) {
+            localStorage.setItem('fastdl-token', decodeURIComponent(match[1]));
+            history.replaceState(null, '', location.pathname);
         }
-        
-        function updateStats(stats, status, jobs) {
-            const statsDiv = document.getElementById('stats');
-            statsDiv.innerHTML = ` +
-                '<div class="stat-card">
-                    <div class="stat-value">${jobs.active || 0}</div>
-                    <div class="stat-label">Active Downloads</div>
-                </div>
-                <div class="stat-card">
-                    <div class="stat-value">${jobs.pending || 0}</div>
-                    <div class="stat-label">Pending</div>
-                </div>
-                <div class="stat-card">
-                    <div class="stat-value">${jobs.completed || 0}</div>
-                    <div class="stat-label">Completed</div>
-                </div>
-                <div class="stat-card">
-                    <div class="stat-value">${stats.total_downloaded || '0 B'}</div>
-                    <div class="stat-label">Total Downloaded</div>
-                </div>';
+        var token = localStorage.getItem('fastdl-token') || '';
+        var jobs = {};
+
+        function api(method, path, body) {
+            var headers = {};
+            if (token) headers['Authorization'] = 'Bearer ' + token;
+            if (body) headers['Content-Type'] = 'application/json';
+            return fetch(path, {method: method, headers: headers, body: body && JSON.stringify(body)}).then(function (res) {
+                if (res.status === 401) {
+                    token = prompt('API token') || '';
+                    localStorage.setItem('fastdl-token', token);
+                    throw new Error('unauthorized');
+                }
+                if (!res.ok) return res.json().then(function (e) { throw new Error(e.error); });
+                return res.status === 204 ? null : res.json();
+            });
         }
-        
-        function updateJobsList(data) {
-            const tbody = document.getElementById('jobsList');
-            tbody.innerHTML = '';
-            
-            if (data.jobs) {
-                Object.entries(data.jobs).forEach(([id, job]) => {
-                    const progress = job.total_size > 0 
-                        ? Math.round((job.downloaded / job.total_size) * 100) 
-                        : 0;
-                    
-                    tbody.innerHTML += ` +
-                        '<tr>
-                            <td>${id.substring(0, 8)}...</td>
-                            <td>${job.url}</td>
-                            <td><span class="status ${job.status}">${job.status}</span></td>
-                            <td>${progress}%</td>
-                            <td>
-                                <button onclick="pauseJob(\'${id}\')">Pause</button>
-                                <button onclick="resumeJob(\'${id}\')">Resume</button>
-                                <button onclick="deleteJob(\'${id}\')">Delete</button>
-                            </td>
-                        </tr>';
-                });
-            }
+
+        function bytes(n) {
+            var units = ['B', 'KB', 'MB', 'GB', 'TB'], i = 0;
+            while (n >= 1024 && i < units.length - 1) { n /= 1024; i++; }
+            return n.toFixed(i ? 1 : 0) + ' ' + units[i];
+        }
+
+        function duration(s) {
+            if (!s) return '';
+            if (s < 60) return s + 's';
+            if (s < 3600) return Math.floor(s / 60) + 'm' + (s % 60) + 's';
+            return Math.floor(s / 3600) + 'h' + Math.floor(s % 3600 / 60) + 'm';
+        }
+
+        function cell(row, text, className) {
+            var td = row.insertCell();
+            td.textContent = text;
+            if (className) td.className = className;
+            return td;
+        }
+
+        function button(td, label, action) {
+            var b = document.createElement('button');
+            b.textContent = label;
+            b.onclick = function () { action().catch(function (e) { banner(e.message); }); };
+            td.appendChild(b);
+            td.appendChild(document.createTextNode(' '));
+        }
+
+        function banner(text) {
+            document.getElementById('banner').textContent = text || '';
         }
-        
-        async function addJob() {
-            const url = document.getElementById('urlInput').value;
-            if (!url) return;
-            
-            try {
-                await fetch('/api/jobs/add', {
-                    method: 'POST',
-                    headers: {'Content-Type': 'application/json'},
-                    body: JSON.stringify({url: url})
+
+        function render() {
+            var tbody = document.getElementById('jobs');
+            tbody.textContent = '';
+            Object.keys(jobs).map(function (id) { return jobs[id]; })
+                .sort(function (a, b) { return a.added - b.added; })
+                .forEach(function (job) {
+                    var row = tbody.insertRow();
+                    var name = cell(row, job.file || job.url, 'name');
+                    name.title = job.url;
+                    if (job.error) {
+                        var error = document.createElement('div');
+                        error.className = 'error';
+                        error.textContent = job.error;
+                        name.appendChild(error);
+                    }
+                    var status = document.createElement('span');
+                    status.className = 'status ' + job.status;
+                    status.textContent = job.status;
+                    cell(row, '').appendChild(status);
+
+                    var percent = job.total > 0 ? Math.min(100, job.downloaded / job.total * 100) : 0;
+                    var progress = cell(row, '');
+                    var bar = document.createElement('div');
+                    bar.className = 'bar';
+                    bar.appendChild(document.createElement('div')).style.width = percent + '%';
+                    progress.appendChild(bar);
+                    progress.appendChild(document.createTextNode(bytes(job.downloaded) + (job.total > 0 ? ' / ' + bytes(job.total) : '')));
+
+                    cell(row, job.status === 'downloading' ? bytes(job.speed) + '/s' : '');
+                    cell(row, job.status === 'downloading' ? duration(Math.round(job.eta)) : '');
+
+                    var actions = cell(row, '');
+                    var path = '/downloads/' + encodeURIComponent(job.id);
+                    if (job.status === 'downloading' || job.status === 'pending') button(actions, 'Pause', function () { return api('POST', path + '/pause'); });
+                    if (job.status === 'paused') button(actions, 'Resume', function () { return api('POST', path + '/resume'); });
+                    if (job.status === 'failed') button(actions, 'Retry', function () { return api('POST', path + '/retry'); });
+                    button(actions, job.status === 'completed' || job.status === 'failed' ? 'Remove' : 'Cancel', function () { return api('DELETE', path); });
                 });
-                document.getElementById('urlInput').value = '';
-                fetchData();
-            } catch (error) {
-                console.error('Error adding job:', error);
-            }
         }
-        
-        async function pauseJob(id) {
-            await fetch('/api/jobs/pause?id=' + id, {method: 'POST'});
-            fetchData();
+
+        // Jobs from the REST API and progress messages end up in one shape
+        function fromJob(job) {
+            return {id: job.id, url: job.url, file: job.file_path, status: job.status, downloaded: job.downloaded,
+                total: job.total_size, speed: job.speed, eta: job.eta, error: job.error, added: Date.parse(job.added_time)};
+        }
+
+        function load() {
+            return api('GET', '/downloads').then(function (list) {
+                jobs = {};
+                list.forEach(function (job) { jobs[job.id] = fromJob(job); });
+                render();
+                banner('');
+            });
+        }
+
+        var reloading = null;
+        function reload() {
+            if (!reloading) reloading = setTimeout(function () { reloading = null; load(); }, 250);
         }
-        
-        async function resumeJob(id) {
-            await fetch('/api/jobs/resume?id=' + id, {method: 'POST'});
-            fetchData();
+
+        function totals(total) {
+            document.getElementById('active').textContent = total.active;
+            document.getElementById('pending').textContent = total.pending;
+            document.getElementById('speed').textContent = bytes(total.speed) + '/s';
+            document.getElementById('downloaded').textContent = bytes(total.downloaded);
         }
-        
-        async function deleteJob(id) {
-            await fetch('/api/jobs/delete?id=' + id, {method: 'DELETE'});
-            fetchData();
+
+        function connect() {
+            var scheme = location.protocol === 'https:' ? 'wss://' : 'ws://';
+            var ws = new WebSocket(scheme + location.host + '/ws/downloads' + (token ? '?token=' + encodeURIComponent(token) : ''));
+            ws.onmessage = function (event) {
+                var message = JSON.parse(event.data);
+                if (message.type === 'total') return totals(message.total);
+                var p = message.progress, job = jobs[p.id];
+                if (p.status === 'removed') {
+                    delete jobs[p.id];
+                } else if (job) {
+                    job.downloaded = p.downloaded;
+                    job.total = p.total;
+                    job.speed = p.speed;
+                    job.eta = p.eta_seconds;
+                    job.status = p.status;
+                }
+                // New jobs, and errors and final sizes of finished ones, come from the REST API
+                if (!job || p.status === 'failed' || p.status === 'completed') reload();
+                render();
+            };
+            ws.onclose = function () {
+                banner('Disconnected from the daemon, reconnecting...');
+                setTimeout(function () { load().then(connect, connect); }, 2000);
+            };
         }
-        
-        // Auto-refresh every 2 seconds
-        setInterval(fetchData, 2000);
-        fetchData();
+
+        document.getElementById('add').onsubmit = function (event) {
+            event.preventDefault();
+            var url = document.getElementById('url'), output = document.getElementById('output');
+            api('POST', '/downloads', {url: url.value, output: output.value, priority: parseInt(document.getElementById('priority').value, 10) || 0})
+                .then(function () { url.value = ''; output.value = ''; return load(); })
+                .catch(function (e) { banner(e.message); });
+        };
+
+        load().catch(function (e) { banner(e.message); }).then(connect);
     </script>
 </body>
-</html>`
-	
-	w.Header().Set("Content-Type", "text/html")
-	w.Write([]byte(html))
+</html>
+`
+
+func (d *DaemonServer) handleWebUI(w http.ResponseWriter, r *http.Request) {
+	if r.URL.Path != "/" {
+		http.NotFound(w, r)
+		return
+	}
+	w.Header().Set("Content-Type", "text/html; charset=utf-8")
+	w.Write([]byte(webUI))
 }
 
 // Configuration functions
+// loadConfig reads the config file over the defaults
 func loadConfig(path string) (*Config, error) {
 	config := DefaultConfig()
-	if path == "" {
-		path = config.ConfigPath
+	if err := config.readFile(path); err != nil {
+		return nil, err
+	}
+	return config, nil
+}
+
+// layeredConfig is what download commands start from before their flags:
+// base, then the config file, then FASTDL_* environment variables, so
+// nothing secret has to go on the command line
+func layeredConfig(base *Config) (*Config, error) {
+	if err := base.readFile(""); err != nil {
+		return nil, err
+	}
+	if err := base.applyEnv(os.Environ()); err != nil {
+		return nil, err
+	}
+	return base, nil
+}
+
+// configFile picks the file to read: path when given, then $FASTDL_CONFIG,
+// then config.toml next to the JSON config when there is one
+func (c *Config) configFile(path string) string {
+	if path != "" {
+		return path
+	}
+	if path = os.Getenv("FASTDL_CONFIG"); path != "" {
+		return path
+	}
+	toml := filepath.Join(filepath.Dir(c.ConfigPath), "config.toml")
+	if _, err := os.Stat(toml); err == nil {
+		return toml
+	}
+	return c.ConfigPath
+}
+
+// readFile overlays a JSON or, by extension, TOML config file on c. A
+// missing file leaves c alone. TOML keys are the JSON field names and
+// unknown ones are an error, since that file is written by hand.
+func (c *Config) readFile(path string) error {
+	path = c.configFile(path)
+	data, err := os.ReadFile(path)
+	if err != nil {
+		return nil // Use defaults if config doesn't exist
+	}
+
+	if strings.HasSuffix(path, ".toml") {
+		values, err := parseTOML(data)
+		if err != nil {
+			return fmt.Errorf("%s: %w", path, err)
+		}
+		if data, err = json.Marshal(values); err != nil {
+			return err
+		}
+		decoder := json.NewDecoder(bytes.NewReader(data))
+		decoder.DisallowUnknownFields()
+		if err := decoder.Decode(c); err != nil {
+			return fmt.Errorf("%s: %w", path, err)
+		}
+		c.ConfigPath = path
+		return nil
+	}
+
+	return json.Unmarshal(data, c)
+}
+
+// envAliases are the short FASTDL_* names from the README
+var envAliases = map[string]string{
+	"downloads":   "download_dir",
+	"connections": "max_connections",
+	"debug":       "verbose",
+	"auto_verify": "verify_checksum",
+}
+
+// applyEnv sets fields from FASTDL_<KEY> variables, where KEY is the
+// config file key in upper case: FASTDL_MAX_CONNECTIONS=8,
+// FASTDL_PROXY_URL=socks5://... Lists are comma separated; maps and
+// structured values take JSON.
+func (c *Config) applyEnv(environ []string) error {
+	fields := make(map[string]reflect.Value)
+	v := reflect.ValueOf(c).Elem()
+	for i := 0; i < v.NumField(); i++ {
+		key, _, _ := strings.Cut(v.Type().Field(i).Tag.Get("json"), ",")
+		fields[key] = v.Field(i)
+	}
+
+	for _, entry := range environ {
+		name, value, _ := strings.Cut(entry, "=")
+		key, ok := strings.CutPrefix(name, "FASTDL_")
+		if !ok {
+			continue
+		}
+		key = strings.ToLower(key)
+		if alias, ok := envAliases[key]; ok {
+			key = alias
+		}
+		field, ok := fields[key]
+		if !ok || key == "config_path" {
+			continue // FASTDL_CONFIG picks the file instead
+		}
+		if err := setConfigField(field, value); err != nil {
+			return fmt.Errorf("invalid %s: %w", name, err)
+		}
+	}
+	return nil
+}
+
+// setConfigField parses value into one Config field
+func setConfigField(field reflect.Value, value string) error {
+	switch field.Kind() {
+	case reflect.String:
+		field.SetString(value)
+		return nil
+	case reflect.Bool:
+		b, err := strconv.ParseBool(value)
+		if err != nil {
+			return err
+		}
+		field.SetBool(b)
+		return nil
+	case reflect.Int, reflect.Int64:
+		n, err := strconv.ParseInt(value, 10, 64)
+		if err != nil {
+			return err
+		}
+		field.SetInt(n)
+		return nil
+	case reflect.Slice:
+		if field.Type().Elem().Kind() == reflect.String && !strings.HasPrefix(value, "[") {
+			field.Set(reflect.ValueOf(strings.Split(value, ",")))
+			return nil
+		}
+	}
+	return json.Unmarshal([]byte(value), field.Addr().Interface())
+}
+
+func saveConfig(config *Config) error {
+	if strings.HasSuffix(config.ConfigPath, ".toml") {
+		return fmt.Errorf("settings come from %s, edit that file instead", config.ConfigPath)
+	}
+
+	configDir := filepath.Dir(config.ConfigPath)
+	if err := os.MkdirAll(configDir, 0755); err != nil {
+		return err
+	}
+
+	file, err := os.Create(config.ConfigPath)
+	if err != nil {
+		return err
+	}
+	defer file.Close()
+
+	encoder := json.NewEncoder(file)
+	encoder.SetIndent("", "  ")
+	return encoder.Encode(config)
+}
+
+// tomlParser reads the part of TOML a config file needs: key = value
+// pairs, [tables], [[arrays of tables]], dotted keys, strings, integers,
+// floats, booleans, arrays and inline tables. Dates and multi-line strings
+// are rejected.
+type tomlParser struct {
+	data string
+	pos  int
+	line int
+}
+
+// parseTOML decodes a document into maps, slices and scalars that
+// encoding/json can marshal
+func parseTOML(data []byte) (map[string]interface{}, error) {
+	p := &tomlParser{data: string(data), line: 1}
+	root := make(map[string]interface{})
+	current := root
+
+	for {
+		p.skipBlank()
+		if p.pos >= len(p.data) {
+			return root, nil
+		}
+
+		switch {
+		case strings.HasPrefix(p.data[p.pos:], "[["):
+			p.pos += 2
+			keys, err := p.key()
+			if err != nil {
+				return nil, err
+			}
+			if !p.consume("]]") {
+				return nil, p.errorf("expected ]]")
+			}
+			parent, err := tomlTable(root, keys[:len(keys)-1])
+			if err != nil {
+				return nil, p.errorf("%v", err)
+			}
+			last := keys[len(keys)-1]
+			array, ok := parent[last].([]interface{})
+			if parent[last] != nil && !ok {
+				return nil, p.errorf("%s is not an array of tables", strings.Join(keys, "."))
+			}
+			current = make(map[string]interface{})
+			parent[last] = append(array, current)
+		case p.consume("["):
+			keys, err := p.key()
+			if err != nil {
+				return nil, err
+			}
+			if !p.consume("]") {
+				return nil, p.errorf("expected ]")
+			}
+			if current, err = tomlTable(root, keys); err != nil {
+				return nil, p.errorf("%v", err)
+			}
+		default:
+			keys, err := p.key()
+			if err != nil {
+				return nil, err
+			}
+			if !p.consume("=") {
+				return nil, p.errorf("expected = after %s", strings.Join(keys, "."))
+			}
+			p.skipSpace()
+			value, err := p.value()
+			if err != nil {
+				return nil, err
+			}
+			table, err := tomlTable(current, keys[:len(keys)-1])
+			if err != nil {
+				return nil, p.errorf("%v", err)
+			}
+			if _, exists := table[keys[len(keys)-1]]; exists {
+				return nil, p.errorf("duplicate key %s", strings.Join(keys, "."))
+			}
+			table[keys[len(keys)-1]] = value
+		}
+
+		p.skipSpace()
+		p.skipComment()
+		if p.pos < len(p.data) && p.data[p.pos] != '\n' && p.data[p.pos] != '\r' {
+			return nil, p.errorf("unexpected %q", p.data[p.pos])
+		}
+	}
+}
+
+// tomlTable walks keys down from table, creating tables on the way; an
+// array of tables resolves to its last element
+func tomlTable(table map[string]interface{}, keys []string) (map[string]interface{}, error) {
+	for _, key := range keys {
+		switch next := table[key].(type) {
+		case nil:
+			child := make(map[string]interface{})
+			table[key] = child
+			table = child
+		case map[string]interface{}:
+			table = next
+		case []interface{}:
+			last, ok := next[len(next)-1].(map[string]interface{})
+			if !ok {
+				return nil, fmt.Errorf("%s is not a table", key)
+			}
+			table = last
+		default:
+			return nil, fmt.Errorf("%s is not a table", key)
+		}
+	}
+	return table, nil
+}
+
+func (p *tomlParser) errorf(format string, args ...interface{}) error {
+	return fmt.Errorf("line %d: %s", p.line, fmt.Sprintf(format, args...))
+}
+
+func (p *tomlParser) consume(token string) bool {
+	p.skipSpace()
+	if strings.HasPrefix(p.data[p.pos:], token) {
+		p.pos += len(token)
+		return true
+	}
+	return false
+}
+
+func (p *tomlParser) skipSpace() {
+	for p.pos < len(p.data) && (p.data[p.pos] == ' ' || p.data[p.pos] == '\t') {
+		p.pos++
+	}
+}
+
+func (p *tomlParser) skipComment() {
+	if p.pos < len(p.data) && p.data[p.pos] == '#' {
+		for p.pos < len(p.data) && p.data[p.pos] != '\n' {
+			p.pos++
+		}
+	}
+}
+
+// skipBlank moves past whitespace, newlines and comments
+func (p *tomlParser) skipBlank() {
+	for {
+		p.skipSpace()
+		p.skipComment()
+		if p.pos >= len(p.data) || (p.data[p.pos] != '\n' && p.data[p.pos] != '\r') {
+			return
+		}
+		if p.data[p.pos] == '\n' {
+			p.line++
+		}
+		p.pos++
+	}
+}
+
+// key reads a bare, quoted or dotted key
+func (p *tomlParser) key() ([]string, error) {
+	var keys []string
+	for {
+		p.skipSpace()
+		if p.pos < len(p.data) && (p.data[p.pos] == '"' || p.data[p.pos] == '\'') {
+			key, err := p.str()
+			if err != nil {
+				return nil, err
+			}
+			keys = append(keys, key)
+		} else {
+			start := p.pos
+			for p.pos < len(p.data) && isBareKeyChar(p.data[p.pos]) {
+				p.pos++
+			}
+			if p.pos == start {
+				return nil, p.errorf("expected a key")
+			}
+			keys = append(keys, p.data[start:p.pos])
+		}
+		if !p.consume(".") {
+			return keys, nil
+		}
+	}
+}
+
+func isBareKeyChar(c byte) bool {
+	return c == '_' || c == '-' || (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9')
+}
+
+func (p *tomlParser) value() (interface{}, error) {
+	if p.pos >= len(p.data) {
+		return nil, p.errorf("expected a value")
+	}
+
+	switch p.data[p.pos] {
+	case '"', '\'':
+		return p.str()
+	case '[':
+		p.pos++
+		var array []interface{}
+		for {
+			p.skipBlank()
+			if p.consume("]") {
+				return array, nil
+			}
+			item, err := p.value()
+			if err != nil {
+				return nil, err
+			}
+			array = append(array, item)
+			p.skipBlank()
+			if !p.consume(",") {
+				p.skipBlank()
+				if !p.consume("]") {
+					return nil, p.errorf("expected , or ] in array")
+				}
+				return array, nil
+			}
+		}
+	case '{':
+		p.pos++
+		table := make(map[string]interface{})
+		if p.consume("}") {
+			return table, nil
+		}
+		for {
+			keys, err := p.key()
+			if err != nil {
+				return nil, err
+			}
+			if !p.consume("=") {
+				return nil, p.errorf("expected = in inline table")
+			}
+			p.skipSpace()
+			item, err := p.value()
+			if err != nil {
+				return nil, err
+			}
+			inner, err := tomlTable(table, keys[:len(keys)-1])
+			if err != nil {
+				return nil, p.errorf("%v", err)
+			}
+			inner[keys[len(keys)-1]] = item
+			if p.consume("}") {
+				return table, nil
+			}
+			if !p.consume(",") {
+				return nil, p.errorf("expected , or } in inline table")
+			}
+		}
 	}
 
-	file, err := os.Open(path)
-	if err != nil {
-		return config, nil // Use defaults if config doesn't exist
+	start := p.pos
+	for p.pos < len(p.data) && !strings.ContainsRune(" \t\r\n,]}#", rune(p.data[p.pos])) {
+		p.pos++
 	}
-	defer file.Close()
-
-	if err := json.NewDecoder(file).Decode(config); err != nil {
-		return nil, err
+	token := p.data[start:p.pos]
+	switch token {
+	case "true":
+		return true, nil
+	case "false":
+		return false, nil
 	}
-
-	return config, nil
+	if n, err := strconv.ParseInt(token, 0, 64); err == nil {
+		return n, nil
+	}
+	if f, err := strconv.ParseFloat(strings.ReplaceAll(token, "_", ""), 64); err == nil {
+		return f, nil
+	}
+	return nil, p.errorf("unsupported value %q", token)
 }
 
-func saveConfig(config *Config) error {
-	configDir := filepath.Dir(config.ConfigPath)
-	if err := os.MkdirAll(configDir, 0755); err != nil {
-		return err
+// str reads a basic "..." string, whose escapes match Go's, or a literal
+// '...' one
+func (p *tomlParser) str() (string, error) {
+	quote := p.data[p.pos]
+	if strings.HasPrefix(p.data[p.pos:], strings.Repeat(string(quote), 3)) {
+		return "", p.errorf("multi-line strings are not supported")
+	}
+	end := p.pos + 1
+	for end < len(p.data) && p.data[end] != quote && p.data[end] != '\n' {
+		if quote == '"' && p.data[end] == '\\' {
+			end++
+		}
+		end++
 	}
-
-	file, err := os.Create(config.ConfigPath)
+	if end >= len(p.data) || p.data[end] != quote {
+		return "", p.errorf("unterminated string")
+	}
+	raw := p.data[p.pos : end+1]
+	p.pos = end + 1
+	if quote == '\'' {
+		return raw[1 : len(raw)-1], nil
+	}
+	s, err := strconv.Unquote(raw)
 	if err != nil {
-		return err
+		return "", p.errorf("bad string %s", raw)
 	}
-	defer file.Close()
-
-	encoder := json.NewEncoder(file)
-	encoder.SetIndent("", "  ")
-	return encoder.Encode(config)
+	return s, nil
 }
 
 // Utility functions
@@ -1536,16 +12284,87 @@ func formatDuration(d time.Duration) string {
 
 // CLI Commands
 func cmdDownload(args []string) {
+	// Flags default to the config file and FASTDL_* variables, so only the
+	// ones given override those. Files land in the working directory
+	// unless download_dir is configured.
+	base := DefaultConfig()
+	base.DownloadDir = "."
+	config, err := layeredConfig(base)
+	if err != nil {
+		fatal(configError(err))
+	}
+
 	fs := flag.NewFlagSet("download", flag.ExitOnError)
-	connections := fs.Int("c", DefaultChunks, "number of connections")
-	output := fs.String("o", "", "output file path")
+	connections := fs.Int("c", config.MaxConnections, "number of connections")
+	output := fs.String("o", "", "output file path (- for stdout)")
 	sha256Hash := fs.String("sha256", "", "SHA256 hash")
 	sha1Hash := fs.String("sha1", "", "SHA1 hash")
 	md5Hash := fs.String("md5", "", "MD5 hash")
-	downloadDir := fs.String("d", ".", "download directory")
-	rateLimit := fs.Int64("rate", 0, "rate limit in bytes/sec")
-	proxy := fs.String("proxy", "", "proxy URL")
-	header := fs.String("H", "", "custom header (format: Key:Value)")
+	checksum := fs.String("checksum", "", "expected digest as algorithm:hex (sha256, sha1, md5, sha512, blake2b)")
+	cookieFile := fs.String("cookies", config.CookieFile, "load cookies from this Netscape cookies.txt")
+	mirrors := fs.String("mirrors", "", "fallback URLs for the same file (comma separated)")
+	stripe := fs.Bool("stripe", config.StripeMirrors, "fetch chunks from all -mirrors at once, favoring the fastest")
+	steal := fs.Bool("steal", config.WorkStealing, "split the slowest chunk for idle connections near the end")
+	adaptive := fs.Bool("adaptive", config.AdaptiveConns, "start with few connections and add more while speed improves (up to -c)")
+	ftpActive := fs.Bool("ftp-active", config.FTPActive, "use active mode FTP (the server connects back)")
+	sshKey := fs.String("ssh-key", config.SSHKeyFile, "private key for sftp:// (default: ssh agent and ~/.ssh/id_*)")
+	progressFormat := fs.String("progress-format", config.ProgressFormat, "progress output: text, ndjson or pretty")
+	progressFD := fs.Int("progress-fd", config.ProgressFD, "write ndjson progress to this file descriptor instead of stdout")
+	saveCookies := fs.Bool("save-cookies", config.SaveCookies, "write received cookies back to the -cookies file")
+	downloadDir := fs.String("d", config.DownloadDir, "download directory")
+	tempDir := fs.String("temp-dir", config.TempDir, "write in-progress files here instead of next to the output")
+	onConflict := fs.String("on-conflict", config.OnConflict, "when the file exists: overwrite, skip, resume or rename")
+	skipDownloaded := fs.Bool("skip-downloaded", config.SkipDownloaded, "skip URLs the history shows were already downloaded and are still on disk")
+	conditional := fs.Bool("conditional", config.Conditional, "ask the server whether a previously downloaded file changed, skip it on 304 Not Modified")
+	timestamping := fs.Bool("N", config.Timestamping, "only download files newer than the local copy, and give them the server's modification time")
+	fs.BoolVar(timestamping, "timestamping", config.Timestamping, "same as -N")
+	maxRedirects := fs.Int("max-redirects", config.MaxRedirects, "follow at most this many redirects")
+	noFollow := fs.Bool("no-follow-redirects", false, "fail on redirects instead of following them")
+	user := fs.String("user", "", "user:password for the URLs' hosts, basic or digest as the server asks")
+	bearer := fs.String("bearer", "", "bearer token for the URLs' hosts")
+	outputTemplate := fs.String("output-template", config.OutputTemplate, "place files by template, e.g. {host}/{date}/{filename}")
+	fileAllocation := fs.String("file-allocation", config.Preallocate, "preallocate output files: none, sparse, prealloc (fallocate) or full (zero-filled)")
+	rateLimit := fs.Int64("rate", config.RateLimit, "rate limit in bytes/sec")
+	proxy := fs.String("proxy", config.ProxyURL, "proxy URL: http://, https:// or socks5://, optionally with user:pass@")
+	noProxy := fs.String("no-proxy", "", "comma-separated hosts to reach directly (*.domain or .domain for subdomains)")
+	proxyRules := fs.String("proxy-rules", "", "per-host proxies, e.g. *.corp.example=socks5://127.0.0.1:1080,cdn.example=direct")
+	var headers headerFlag
+	fs.Var(&headers, "H", "custom header (format: Key:Value), repeatable")
+	preconnect := fs.Bool("preconnect", config.Preconnect, "open all connections before fetching chunks")
+	lowPriority := fs.Bool("low", config.LowPriority, "low priority mode (idle I/O, fewer connections, throttled)")
+	planOnly := fs.Bool("plan", false, "print the resolved download plan as JSON and exit")
+	magic := fs.String("magic", "", "expected leading bytes of the file (hex)")
+	chunkSize := fs.Int64("chunk-size", 0, "split into chunks of this many bytes, queued across connections")
+	tee := fs.String("tee", "", "also write the file into these directories (comma separated)")
+	autoTune := fs.Bool("auto-tune", config.AutoTune, "probe for the fastest connection count before large downloads")
+	useNetrc := fs.Bool("netrc", config.UseNetrc, "use credentials from ~/.netrc")
+	netrcFile := fs.String("netrc-file", config.NetrcFile, "use credentials from this netrc file")
+	refreshCmd := fs.String("refresh-cmd", config.RefreshCommand, "command printing a fresh URL when the URL expires mid-download")
+	caCert := fs.String("cacert", config.CACert, "also trust the CAs in this PEM file")
+	clientCert := fs.String("cert", config.ClientCert, "client certificate (PEM) for mutual TLS")
+	clientKey := fs.String("key", config.ClientKey, "private key (PEM) for -cert, if not in the same file")
+	insecure := fs.Bool("insecure", config.Insecure, "don't verify server certificates")
+	minTLS := fs.String("min-tls-version", config.MinTLSVersion, "lowest TLS version to accept: 1.0, 1.1, 1.2 or 1.3")
+	otlp := fs.String("otlp", config.OTLPEndpoint, "export trace spans to this OTLP/HTTP endpoint")
+	extract := fs.Bool("x", config.Extract, "extract .zip/.tar/.tar.gz downloads atomically next to the file")
+	signature := fs.String("sig", "", "detached signature URL or path (.asc/.sig)")
+	publicKey := fs.String("pubkey", "", "trusted public key file for -sig")
+	verbose := fs.Bool("v", config.Verbose, "verbose output")
+	logLevel := fs.String("log-level", config.LogLevel, "log from this level up: debug, info, warn, error")
+	logFormat := fs.String("log-format", config.LogFormat, "log format: pretty or json")
+	logFile := fs.String("log-file", config.LogFile, "write logs to this file, rotated by size, instead of stderr")
+	pipeline := fs.Int("pipeline", config.PipelineDepth, "chunk requests each connection keeps outstanding over HTTP/2 (helps on high-latency links)")
+	useMmap := fs.Bool("mmap", config.UseMmap, "write chunks through a memory-mapped output file")
+	sharedOutput := fs.Bool("shared-file", config.SharedOutput, "write chunks into one output file handle instead of part files (no resume)")
+	ioURing := fs.Bool("io-uring", config.IOUring, "with -shared-file, submit chunk writes through io_uring (Linux)")
+	bufferSize := fs.Int("buffer-size", config.BufferSize, "bytes read from the network per write, from a shared buffer pool")
+	asyncWrites := fs.Bool("async-writes", config.AsyncWrites, "write chunks from a separate goroutine so disk stalls don't pause the network")
+	minSpeed := fs.Int64("min-speed", config.MinSpeed, "abort and retry a transfer slower than this many bytes/sec over -min-speed-window")
+	minSpeedWindow := fs.Int("min-speed-window", config.MinSpeedWindow, "seconds a transfer may stay below -min-speed")
+	stallTimeout := fs.Int("stall-timeout", config.StallTimeout, "abort and retry a transfer that receives nothing for this many seconds")
+	connectTimeout := fs.Int("connect-timeout", config.ConnectTimeout, "seconds to connect and finish the TLS handshake (default: -timeout)")
+	readTimeout := fs.Int("read-timeout", config.ReadTimeout, "seconds a connection may go without receiving data (0 = no limit)")
+	timeout := fs.Int("timeout", config.Timeout, "seconds to wait for response headers")
 	
 	if err := fs.Parse(args); err != nil {
 		log.Fatal(err)
@@ -1554,27 +12373,113 @@ func cmdDownload(args []string) {
 	if fs.NArg() < 1 {
 		fmt.Println("Usage: fastdl download [options] <URL>")
 		fs.PrintDefaults()
-		os.Exit(1)
+		os.Exit(ExitConfig)
 	}
 
-	config := DefaultConfig()
 	config.MaxConnections = *connections
 	config.DownloadDir = *downloadDir
+	config.TempDir = *tempDir
+	config.OnConflict = *onConflict
+	config.SkipDownloaded = *skipDownloaded
+	config.Conditional = *conditional
+	config.Timestamping = *timestamping
+	config.MaxRedirects = *maxRedirects
+	if *noFollow {
+		config.MaxRedirects = 0
+	}
+	// Defaults stay out of -h, which would print the secrets
+	if *user != "" {
+		config.User = *user
+	}
+	if *bearer != "" {
+		config.BearerToken = *bearer
+	}
+	config.OutputTemplate = *outputTemplate
+	config.Preallocate = *fileAllocation
 	config.RateLimit = *rateLimit
 	config.ProxyURL = *proxy
-	
-	if *header != "" {
-		parts := strings.SplitN(*header, ":", 2)
-		if len(parts) == 2 {
-			config.Headers[strings.TrimSpace(parts[0])] = strings.TrimSpace(parts[1])
+	if *noProxy != "" {
+		config.NoProxy = strings.Split(*noProxy, ",")
+	}
+	if *proxyRules != "" {
+		rules, err := parseProxyRules(*proxyRules)
+		if err != nil {
+			fatal(configError(err))
 		}
+		config.ProxyRules = rules
+	}
+	config.Preconnect = *preconnect
+	config.LowPriority = *lowPriority
+	if *chunkSize > 0 {
+		config.ChunkSize = *chunkSize
+		config.ChunkQueue = true
+	}
+	if *tee != "" {
+		config.TeeDirs = strings.Split(*tee, ",")
+	}
+	config.AutoTune = *autoTune
+	config.UseNetrc = *useNetrc
+	config.NetrcFile = *netrcFile
+	config.RefreshCommand = *refreshCmd
+	config.CACert = *caCert
+	config.ClientCert = *clientCert
+	config.ClientKey = *clientKey
+	config.Insecure = *insecure
+	config.MinTLSVersion = *minTLS
+	config.OTLPEndpoint = *otlp
+	config.Extract = *extract
+	config.Verbose = *verbose
+	config.LogLevel = *logLevel
+	config.LogFormat = *logFormat
+	config.LogFile = *logFile
+	config.PipelineDepth = *pipeline
+	config.UseMmap = *useMmap
+	config.SharedOutput = *sharedOutput
+	config.IOUring = *ioURing
+	config.BufferSize = *bufferSize
+	config.AsyncWrites = *asyncWrites
+	config.MinSpeed = *minSpeed
+	config.MinSpeedWindow = *minSpeedWindow
+	config.StallTimeout = *stallTimeout
+	config.Timeout = *timeout
+	config.ConnectTimeout = *connectTimeout
+	config.ReadTimeout = *readTimeout
+	config.CookieFile = *cookieFile
+	config.SaveCookies = *saveCookies
+	config.StripeMirrors = *stripe
+	config.WorkStealing = *steal
+	config.AdaptiveConns = *adaptive
+	config.FTPActive = *ftpActive
+	config.SSHKeyFile = *sshKey
+	config.ProgressFormat = *progressFormat
+	config.ProgressFD = *progressFD
+	
+	if config.Headers == nil {
+		config.Headers = make(map[string]string)
+	}
+	for k, v := range headers {
+		config.Headers[k] = v
+	}
+
+	if err := setupLogging(config); err != nil {
+		fatal(configError(err))
 	}
 
 	dm, err := NewDownloadManager(config)
 	if err != nil {
-		log.Fatal(err)
+		fatal(configError(err))
+	}
+
+	if *magic != "" {
+		magicBytes, err := hex.DecodeString(*magic)
+		if err != nil {
+			fatal(configError(fmt.Errorf("invalid -magic: %v", err)))
+		}
+		dm.AddValidator(MagicValidator(magicBytes))
 	}
 
+	dm.handlePauseSignals()
+
 	ctx, cancel := context.WithCancel(context.Background())
 	defer cancel()
 	
@@ -1582,55 +12487,196 @@ func cmdDownload(args []string) {
 	signal.Notify(sigChan, os.Interrupt, syscall.SIGTERM)
 	go func() {
 		<-sigChan
-		fmt.Println("\n\nDownload interrupted")
+		fmt.Println("\n\nDownload interrupted, saving resume state (press Ctrl-C again to quit now)")
 		cancel()
+		<-sigChan
+		os.Exit(ExitInterrupted)
 	}()
 
+	// Metalinks and torrents expand into their files, each with mirrors
+	// and hashes
+	if isDescriptor(fs.Arg(0)) {
+		tasks, err := dm.loadDescriptor(ctx, fs.Arg(0))
+		if err != nil {
+			fatal(err)
+		}
+		for i := range tasks {
+			tasks[i].Chunks = dm.maxWorkers
+			tasks[i].Headers = config.Headers
+			if err := dm.Download(ctx, &tasks[i]); err != nil {
+				fatal(err)
+			}
+		}
+		return
+	}
+
 	task := &DownloadTask{
-		URL:      fs.Arg(0),
-		Filepath: *output,
-		SHA256:   *sha256Hash,
-		SHA1:     *sha1Hash,
-		MD5:      *md5Hash,
-		Chunks:   *connections,
-		Headers:  config.Headers,
+		URL:          fs.Arg(0),
+		Filepath:     *output,
+		SHA256:       *sha256Hash,
+		SHA1:         *sha1Hash,
+		MD5:          *md5Hash,
+		Chunks:       dm.maxWorkers, // -c, clamped in low priority mode
+		Headers:      config.Headers,
+		SignatureURL: *signature,
+		PublicKey:    *publicKey,
+	}
+	if *mirrors != "" {
+		task.Mirrors = strings.Split(*mirrors, ",")
+	}
+
+	if *checksum != "" {
+		algorithm, sum, ok := strings.Cut(*checksum, ":")
+		if !ok {
+			fatal(configError(errors.New("invalid -checksum, want algorithm:hex")))
+		}
+		if _, err := newHash(algorithm); err != nil {
+			fatal(configError(err))
+		}
+		task.Checksum, task.ChecksumAlgo = sum, algorithm
 	}
 
-	if task.Filepath == "" {
-		parsedURL, _ := url.Parse(task.URL)
-		task.Filepath = path.Base(parsedURL.Path)
+	if *planOnly {
+		if err := printPlan(dm.Plan(ctx, []DownloadTask{*task})); err != nil {
+			log.Fatal(err)
+		}
+		return
+	}
+
+	// "-o -" streams the body to stdout without touching disk
+	if task.Filepath == "-" {
+		stream := dm.DownloadStream(ctx, task.URL, task.Headers)
+		defer stream.Close()
+		if _, err := io.Copy(os.Stdout, stream); err != nil {
+			fatal(err)
+		}
+		return
 	}
 
 	if err := dm.Download(ctx, task); err != nil {
-		log.Fatal(err)
+		fatal(err)
 	}
 }
 
 func cmdBatch(args []string) {
+	base := DefaultConfig()
+	base.DownloadDir = "."
+	config, err := layeredConfig(base)
+	if err != nil {
+		fatal(configError(err))
+	}
+
 	fs := flag.NewFlagSet("batch", flag.ExitOnError)
-	concurrent := fs.Int("c", 4, "concurrent downloads")
-	downloadDir := fs.String("d", ".", "download directory")
-	connections := fs.Int("w", DefaultChunks, "connections per download")
+	concurrent := fs.Int("c", config.MaxParallel, "concurrent downloads")
+	fs.IntVar(concurrent, "max-concurrent", config.MaxParallel, "same as -c")
+	queueOrder := fs.String("queue-order", config.QueueOrder, "start entries of equal priority in this order: fifo, lifo, smallest or largest")
+	downloadDir := fs.String("d", config.DownloadDir, "download directory")
+	tempDir := fs.String("temp-dir", config.TempDir, "write in-progress files here instead of next to the output")
+	onConflict := fs.String("on-conflict", config.OnConflict, "when the file exists: overwrite, skip, resume or rename")
+	skipDownloaded := fs.Bool("skip-downloaded", config.SkipDownloaded, "skip URLs the history shows were already downloaded and are still on disk")
+	conditional := fs.Bool("conditional", config.Conditional, "ask the server whether a previously downloaded file changed, skip it on 304 Not Modified")
+	timestamping := fs.Bool("N", config.Timestamping, "only download files newer than the local copy, and give them the server's modification time")
+	fs.BoolVar(timestamping, "timestamping", config.Timestamping, "same as -N")
+	maxRedirects := fs.Int("max-redirects", config.MaxRedirects, "follow at most this many redirects")
+	noFollow := fs.Bool("no-follow-redirects", false, "fail on redirects instead of following them")
+	user := fs.String("user", "", "user:password for the URLs' hosts, basic or digest as the server asks")
+	bearer := fs.String("bearer", "", "bearer token for the URLs' hosts")
+	useNetrc := fs.Bool("netrc", config.UseNetrc, "use credentials from ~/.netrc")
+	netrcFile := fs.String("netrc-file", config.NetrcFile, "use credentials from this netrc file")
+	refreshCmd := fs.String("refresh-cmd", config.RefreshCommand, "command printing a fresh URL when the URL expires mid-download")
+	caCert := fs.String("cacert", config.CACert, "also trust the CAs in this PEM file")
+	clientCert := fs.String("cert", config.ClientCert, "client certificate (PEM) for mutual TLS")
+	clientKey := fs.String("key", config.ClientKey, "private key (PEM) for -cert, if not in the same file")
+	insecure := fs.Bool("insecure", config.Insecure, "don't verify server certificates")
+	minTLS := fs.String("min-tls-version", config.MinTLSVersion, "lowest TLS version to accept: 1.0, 1.1, 1.2 or 1.3")
+	outputTemplate := fs.String("output-template", config.OutputTemplate, "place files by template, e.g. {host}/{date}/{filename}")
+	fileAllocation := fs.String("file-allocation", config.Preallocate, "preallocate output files: none, sparse, prealloc (fallocate) or full (zero-filled)")
+	connections := fs.Int("w", config.MaxConnections, "connections per download")
+	archive := fs.String("zip", config.ArchiveOutput, "collect completed downloads into this zip file")
+	lowPriority := fs.Bool("low", config.LowPriority, "low priority mode (idle I/O, fewer connections, throttled)")
+	failFast := fs.Bool("fail-fast", config.FailFast, "abort the whole batch on the first failure")
+	noFailOnPartial := fs.Bool("no-fail-on-partial", false, "exit 0 when only some of the downloads failed")
+	planOnly := fs.Bool("plan", false, "print the resolved download plan as JSON and exit")
+	globOff := fs.Bool("globoff", config.GlobOff, "take [] and {} in URLs literally instead of expanding them")
+	breaker := fs.Int("breaker", config.CircuitThreshold, "skip a host after this many consecutive failed requests (0 = off)")
+	retryBudget := fs.Int("retry-budget", config.RetryBudget, "retries any one host may cause per minute across the batch (0 = unlimited)")
+	hostBackoff := fs.Bool("host-backoff", config.HostBackoff, "pause all downloads from a host that answers 429/503")
+	perHost := fs.Int("per-host", config.MaxPerHost, "max connections to any one host across the batch (0 = unlimited)")
+	maxTotal := fs.Int64("max-total", config.MaxTotalBytes, "stop starting downloads once this many bytes are claimed (0 = unlimited)")
+	verbose := fs.Bool("v", config.Verbose, "verbose output")
+	logLevel := fs.String("log-level", config.LogLevel, "log from this level up: debug, info, warn, error")
+	logFormat := fs.String("log-format", config.LogFormat, "log format: pretty or json")
+	logFile := fs.String("log-file", config.LogFile, "write logs to this file, rotated by size, instead of stderr")
+	progressFormat := fs.String("progress-format", config.ProgressFormat, "progress output: text, ndjson or pretty")
+	progressFD := fs.Int("progress-fd", config.ProgressFD, "write ndjson progress to this file descriptor instead of stdout")
 	
 	if err := fs.Parse(args); err != nil {
 		log.Fatal(err)
 	}
 
 	if fs.NArg() < 1 {
-		fmt.Println("Usage: fastdl batch [options] <url-file>")
+		fmt.Println("Usage: fastdl batch [options] <url-file | ->")
 		fs.PrintDefaults()
-		os.Exit(1)
+		os.Exit(ExitConfig)
 	}
 
-	config := DefaultConfig()
 	config.MaxConnections = *connections
 	config.DownloadDir = *downloadDir
+	config.TempDir = *tempDir
+	config.OnConflict = *onConflict
+	config.SkipDownloaded = *skipDownloaded
+	config.Conditional = *conditional
+	config.Timestamping = *timestamping
+	config.MaxRedirects = *maxRedirects
+	if *noFollow {
+		config.MaxRedirects = 0
+	}
+	// Defaults stay out of -h, which would print the secrets
+	if *user != "" {
+		config.User = *user
+	}
+	if *bearer != "" {
+		config.BearerToken = *bearer
+	}
+	config.UseNetrc = *useNetrc
+	config.NetrcFile = *netrcFile
+	config.RefreshCommand = *refreshCmd
+	config.CACert = *caCert
+	config.ClientCert = *clientCert
+	config.ClientKey = *clientKey
+	config.Insecure = *insecure
+	config.MinTLSVersion = *minTLS
+	config.OutputTemplate = *outputTemplate
+	config.Preallocate = *fileAllocation
+	config.ArchiveOutput = *archive
+	config.LowPriority = *lowPriority
+	config.FailFast = *failFast
+	config.PlanOnly = *planOnly
+	config.GlobOff = *globOff
+	config.QueueOrder = *queueOrder
+	config.CircuitThreshold = *breaker
+	config.RetryBudget = *retryBudget
+	config.HostBackoff = *hostBackoff
+	config.MaxPerHost = *perHost
+	config.MaxTotalBytes = *maxTotal
+	config.Verbose = *verbose
+	config.LogLevel = *logLevel
+	config.LogFormat = *logFormat
+	config.LogFile = *logFile
+	config.ProgressFormat = *progressFormat
+	config.ProgressFD = *progressFD
+
+	if err := setupLogging(config); err != nil {
+		fatal(configError(err))
+	}
 
 	dm, err := NewDownloadManager(config)
 	if err != nil {
-		log.Fatal(err)
+		fatal(configError(err))
 	}
 
+	dm.handlePauseSignals()
+
 	ctx, cancel := context.WithCancel(context.Background())
 	defer cancel()
 	
@@ -1638,13 +12684,328 @@ func cmdBatch(args []string) {
 	signal.Notify(sigChan, os.Interrupt, syscall.SIGTERM)
 	go func() {
 		<-sigChan
-		fmt.Println("\n\nBatch download interrupted")
+		fmt.Println("\n\nBatch download interrupted, saving resume state (press Ctrl-C again to quit now)")
 		cancel()
+		<-sigChan
+		os.Exit(ExitInterrupted)
 	}()
 
-	if err := dm.BatchDownload(ctx, fs.Arg(0), *concurrent); err != nil {
-		log.Fatal(err)
+	err = dm.BatchDownload(ctx, fs.Arg(0), *concurrent)
+	switch {
+	case err == nil:
+	case ctx.Err() != nil:
+		os.Exit(ExitInterrupted)
+	case errors.Is(err, errPartial) && *noFailOnPartial:
+		fmt.Printf("%sWarning: %v%s\n", ColorYellow, err, ColorReset)
+	default:
+		fatal(err)
+	}
+}
+
+// headerFlag collects repeated -H "Key: Value" options
+type headerFlag map[string]string
+
+func (h *headerFlag) String() string {
+	return fmt.Sprint(map[string]string(*h))
+}
+
+func (h *headerFlag) Set(value string) error {
+	name, v, ok := strings.Cut(value, ":")
+	if !ok {
+		return fmt.Errorf("want Key:Value, got %q", value)
+	}
+	if *h == nil {
+		*h = make(headerFlag)
+	}
+	(*h)[strings.TrimSpace(name)] = strings.TrimSpace(v)
+	return nil
+}
+
+// fetchResult is one line of "fastdl <url>... -json" output
+type fetchResult struct {
+	URL       string            `json:"url"`
+	Status    string            `json:"status"` // completed, failed, interrupted
+	Path      string            `json:"path,omitempty"`
+	Size      int64             `json:"size"`
+	Digests   map[string]string `json:"digests,omitempty"`
+	FinalURL  string            `json:"final_url,omitempty"` // after redirects
+	Redirects []string          `json:"redirects,omitempty"`
+	Error     string            `json:"error,omitempty"`
+	ErrorKind ErrorKind         `json:"error_kind,omitempty"`
+}
+
+// cmdFetch downloads the URLs given directly on the command line, one after
+// another. Options may come before or after the URLs. -config-json takes a
+// whole Config as JSON for scripts on top of the config file and FASTDL_*
+// variables, and explicit flags override it.
+func cmdFetch(args []string) {
+	fs := flag.NewFlagSet("fastdl", flag.ExitOnError)
+	outputDir := fs.String("o", ".", "output directory")
+	connections := fs.Int("c", DefaultChunks, "connections per download")
+	chunkSize := fs.String("chunk-size", "", "chunk size, e.g. 4M (queues chunks across connections)")
+	retries := fs.Int("retries", MaxRetries, "retries per chunk")
+	inputFile := fs.String("i", "", "read more URLs from this file (batch format, - for stdin)")
+	jsonOutput := fs.Bool("json", false, "print one JSON result per URL")
+	noFailOnPartial := fs.Bool("no-fail-on-partial", false, "exit 0 when only some of the downloads failed")
+	globOff := fs.Bool("globoff", false, "take [] and {} in URLs literally instead of expanding them")
+	maxRedirects := fs.Int("max-redirects", 10, "follow at most this many redirects")
+	noFollow := fs.Bool("no-follow-redirects", false, "fail on redirects instead of following them")
+	user := fs.String("user", "", "user:password for the URLs' hosts, basic or digest as the server asks")
+	bearer := fs.String("bearer", "", "bearer token for the URLs' hosts")
+	useNetrc := fs.Bool("netrc", false, "use credentials from ~/.netrc")
+	netrcFile := fs.String("netrc-file", "", "use credentials from this netrc file")
+	caCert := fs.String("cacert", "", "also trust the CAs in this PEM file")
+	clientCert := fs.String("cert", "", "client certificate (PEM) for mutual TLS")
+	clientKey := fs.String("key", "", "private key (PEM) for -cert, if not in the same file")
+	insecure := fs.Bool("insecure", false, "don't verify server certificates")
+	minTLS := fs.String("min-tls-version", "", "lowest TLS version to accept: 1.0, 1.1, 1.2 or 1.3")
+	configJSON := fs.String("config-json", "", "full configuration as a JSON object (- reads it from stdin)")
+	progressFormat := fs.String("progress-format", "text", "progress output: text, ndjson or pretty")
+	progressFD := fs.Int("progress-fd", 0, "write ndjson progress to this file descriptor instead of stdout")
+	var headers headerFlag
+	fs.Var(&headers, "H", "custom header (format: Key:Value), repeatable")
+
+	urls, err := parseInterspersed(fs, args)
+	if err != nil {
+		fatal(configError(err))
+	}
+
+	config, err := layeredConfig(DefaultConfig())
+	if err != nil {
+		fatal(configError(err))
+	}
+	if *configJSON == "-" && *inputFile == "-" {
+		fatal(configError(errors.New("-config-json and -i cannot both read stdin")))
+	}
+	if *configJSON == "-" {
+		// Kept off argv so secrets don't show in ps and size isn't capped
+		data, err := io.ReadAll(os.Stdin)
+		if err != nil {
+			fatal(err)
+		}
+		*configJSON = string(data)
+	}
+	if *configJSON != "" {
+		if err := json.Unmarshal([]byte(*configJSON), config); err != nil {
+			fatal(configError(fmt.Errorf("invalid -config-json: %v", err)))
+		}
+	}
+
+	var flagErr error
+	fs.Visit(func(f *flag.Flag) {
+		switch f.Name {
+		case "o":
+			config.DownloadDir = *outputDir
+		case "c":
+			config.MaxConnections = *connections
+		case "retries":
+			config.MaxRetries = *retries
+		case "progress-format":
+			config.ProgressFormat = *progressFormat
+		case "progress-fd":
+			config.ProgressFD = *progressFD
+		case "globoff":
+			config.GlobOff = *globOff
+		case "max-redirects":
+			config.MaxRedirects = *maxRedirects
+		case "no-follow-redirects":
+			if *noFollow {
+				config.MaxRedirects = 0
+			}
+		case "user":
+			config.User = *user
+		case "bearer":
+			config.BearerToken = *bearer
+		case "netrc":
+			config.UseNetrc = *useNetrc
+		case "netrc-file":
+			config.NetrcFile = *netrcFile
+		case "cacert":
+			config.CACert = *caCert
+		case "cert":
+			config.ClientCert = *clientCert
+		case "key":
+			config.ClientKey = *clientKey
+		case "insecure":
+			config.Insecure = *insecure
+		case "min-tls-version":
+			config.MinTLSVersion = *minTLS
+		case "H":
+			if config.Headers == nil {
+				config.Headers = make(map[string]string)
+			}
+			for k, v := range headers {
+				config.Headers[k] = v
+			}
+		case "chunk-size":
+			size, err := parseByteSize(*chunkSize)
+			if err != nil {
+				flagErr = fmt.Errorf("invalid -chunk-size: %w", err)
+				return
+			}
+			config.ChunkSize = size
+			config.ChunkQueue = true
+		}
+	})
+	if flagErr != nil {
+		fatal(configError(flagErr))
+	}
+
+	// Banners, progress and summaries go to stderr so stdout carries
+	// nothing but the JSON results
+	results := os.Stdout
+	if *jsonOutput {
+		os.Stdout = os.Stderr
+	}
+
+	if err := setupLogging(config); err != nil {
+		fatal(configError(err))
+	}
+
+	dm, err := NewDownloadManager(config)
+	if err != nil {
+		fatal(configError(err))
+	}
+
+	var tasks []DownloadTask
+	for _, u := range urls {
+		if isDescriptor(u) {
+			linked, err := dm.loadDescriptor(context.Background(), u)
+			if err != nil {
+				fatal(err)
+			}
+			for _, task := range linked {
+				task.Chunks, task.Headers = dm.maxWorkers, config.Headers
+				tasks = append(tasks, task)
+			}
+			continue
+		}
+		expanded := []string{u}
+		if !config.GlobOff {
+			if expanded, err = expandGlob(u); err != nil {
+				fatal(configError(err))
+			}
+		}
+		for _, target := range expanded {
+			tasks = append(tasks, DownloadTask{URL: target, Chunks: dm.maxWorkers, Headers: config.Headers})
+		}
+	}
+	if *inputFile != "" {
+		fileTasks, err := dm.parseURLFile(*inputFile)
+		if err != nil {
+			fatal(err)
+		}
+		tasks = append(tasks, fileTasks...)
+	}
+	if len(tasks) == 0 {
+		fmt.Println("Usage: fastdl <url>... [options]")
+		fs.PrintDefaults()
+		os.Exit(ExitConfig)
+	}
+
+	dm.handlePauseSignals()
+
+	ctx, cancel := context.WithCancel(context.Background())
+	defer cancel()
+
+	sigChan := make(chan os.Signal, 1)
+	signal.Notify(sigChan, os.Interrupt, syscall.SIGTERM)
+	go func() {
+		<-sigChan
+		fmt.Fprintln(os.Stderr, "\n\nDownload interrupted, saving resume state (press Ctrl-C again to quit now)")
+		cancel()
+		<-sigChan
+		os.Exit(ExitInterrupted)
+	}()
+
+	failed := 0
+	var firstErr error
+	encoder := json.NewEncoder(results)
+	for i := range tasks {
+		task := &tasks[i]
+		err := dm.Download(ctx, task)
+		if err != nil {
+			failed++
+			if firstErr == nil {
+				firstErr = fmt.Errorf("%s: %w", task.URL, err)
+			}
+			if !*jsonOutput {
+				fmt.Printf("%sFailed: %s: %v%s\n", ColorRed, task.URL, err, ColorReset)
+			}
+		}
+		if *jsonOutput {
+			result := fetchResult{URL: task.URL, Status: "completed", Size: task.Size, Digests: task.Digests,
+				FinalURL: task.FinalURL, Redirects: task.Redirects}
+			if task.Filepath != "" {
+				result.Path = filepath.Join(config.DownloadDir, task.Filepath)
+			}
+			if err != nil {
+				result.Status = "failed"
+				if errors.Is(err, errInterrupted) {
+					result.Status = "interrupted"
+				}
+				result.Error = err.Error()
+				result.ErrorKind = errorKind(err)
+			}
+			encoder.Encode(result)
+		}
+		if ctx.Err() != nil {
+			break
+		}
 	}
+
+	err = batchError(failed, len(tasks), firstErr)
+	switch {
+	case err == nil:
+	case ctx.Err() != nil:
+		os.Exit(ExitInterrupted)
+	case errors.Is(err, errPartial) && *noFailOnPartial:
+	default:
+		// Each failure was already reported above
+		os.Exit(exitCode(err))
+	}
+}
+
+// parseInterspersed parses flags that may appear between positional
+// arguments, which the flag package alone stops at
+func parseInterspersed(fs *flag.FlagSet, args []string) ([]string, error) {
+	var positional []string
+	for {
+		if err := fs.Parse(args); err != nil {
+			return nil, err
+		}
+		args = fs.Args()
+		if len(args) == 0 {
+			return positional, nil
+		}
+		positional = append(positional, args[0])
+		args = args[1:]
+	}
+}
+
+// parseByteSize reads sizes like 4194304, 512K, 4M or 1G (powers of 1024)
+func parseByteSize(value string) (int64, error) {
+	value = strings.TrimSpace(strings.ToUpper(value))
+	value = strings.TrimSuffix(strings.TrimSuffix(value, "B"), "I")
+	multiplier := int64(1)
+	if n := len(value); n > 0 {
+		switch value[n-1] {
+		case 'K':
+			multiplier = 1 << 10
+		case 'M':
+			multiplier = 1 << 20
+		case 'G':
+			multiplier = 1 << 30
+		}
+		if multiplier > 1 {
+			value = value[:n-1]
+		}
+	}
+	size, err := strconv.ParseInt(value, 10, 64)
+	if err != nil || size <= 0 {
+		return 0, fmt.Errorf("bad size %q", value)
+	}
+	return size * multiplier, nil
 }
 
 func cmdDaemon(args []string) {
@@ -1652,6 +13013,13 @@ func cmdDaemon(args []string) {
 	port := fs.Int("port", 8080, "daemon port")
 	configPath := fs.String("config", "", "config file path")
 	workers := fs.Int("workers", 4, "max parallel downloads")
+	logLevel := fs.String("log-level", "", "log from this level up: debug, info, warn, error (default: log_level from the config)")
+	logFormat := fs.String("log-format", "", "log format: pretty or json (default: log_format from the config)")
+	logFile := fs.String("log-file", "", "write logs to this file, rotated by size (default: log_file from the config)")
+	socket := fs.String("socket", "", "control socket for 'fastdl ctl' (default: control_socket from the config)")
+	rateLimit := fs.String("rate", "", "bandwidth cap for all downloads, e.g. 2M (bytes/sec)")
+	apiToken := fs.String("api-token", "", "bearer token the REST and /api endpoints require (default: api_token from the config)")
+	rpcSecret := fs.String("rpc-secret", "", "token aria2 JSON-RPC clients must send (default: rpc_secret from the config)")
 	
 	if err := fs.Parse(args); err != nil {
 		log.Fatal(err)
@@ -1665,9 +13033,35 @@ func cmdDaemon(args []string) {
 	config.DaemonPort = *port
 	config.EnableDaemon = true
 	config.MaxParallel = *workers
+	if *socket != "" {
+		config.ControlSocket = *socket
+	}
+	if *apiToken != "" {
+		config.APIToken = *apiToken
+	}
+	if *logLevel != "" {
+		config.LogLevel = *logLevel
+	}
+	if *logFormat != "" {
+		config.LogFormat = *logFormat
+	}
+	if *logFile != "" {
+		config.LogFile = *logFile
+	}
+	if *rpcSecret != "" {
+		config.RPCSecret = *rpcSecret
+	}
+	if *rateLimit != "" {
+		if config.RateLimit, err = parseByteSize(*rateLimit); err != nil {
+			fatal(configError(fmt.Errorf("invalid -rate: %w", err)))
+		}
+	}
 
 	// Save config
 	saveConfig(config)
+	if err := setupLogging(config); err != nil {
+		fatal(configError(err))
+	}
 
 	// Create download manager
 	dm, err := NewDownloadManager(config)
@@ -1681,9 +13075,16 @@ func cmdDaemon(args []string) {
 		log.Fatal(err)
 	}
 	queue.manager = dm
+	dm.AddObserver(jobObserver{jq: queue})
 
 	// Create daemon server
 	daemon := NewDaemonServer(config, queue)
+	dm.AddObserver(daemon.metrics)
+	if config.ControlSocket != "" {
+		if err := daemon.ListenControl(config.ControlSocket); err != nil {
+			log.Fatal(err)
+		}
+	}
 	
 	// Start processing queue in background
 	ctx := context.Background()
@@ -1695,6 +13096,7 @@ func cmdDaemon(args []string) {
 	go func() {
 		<-sigChan
 		fmt.Println("\nShutting down daemon...")
+		daemon.CloseControl()
 		if daemon.server != nil {
 			daemon.server.Shutdown(context.Background())
 		}
@@ -1706,6 +13108,10 @@ func cmdDaemon(args []string) {
 	fmt.Printf("%s╠════════════════════════════════════════╣%s\n", ColorGreen, ColorReset)
 	fmt.Printf("%s║  Web UI: http://localhost:%d         ║%s\n", ColorCyan, config.DaemonPort, ColorReset)
 	fmt.Printf("%s║  API:    http://localhost:%d/api     ║%s\n", ColorCyan, config.DaemonPort, ColorReset)
+	fmt.Printf("%s║  aria2:  http://localhost:%d/jsonrpc ║%s\n", ColorCyan, config.DaemonPort, ColorReset)
+	if daemon.control != nil {
+		fmt.Printf("%s║  Control: %s%s\n", ColorCyan, config.ControlSocket, ColorReset)
+	}
 	fmt.Printf("%s╚════════════════════════════════════════╝%s\n\n", ColorGreen, ColorReset)
 	
 	if err := daemon.Start(); err != nil {
@@ -1713,6 +13119,196 @@ func cmdDaemon(args []string) {
 	}
 }
 
+// cmdHistory lists and searches past downloads, and can retry the ones
+// whose last attempt failed
+func cmdHistory(args []string) {
+	base := DefaultConfig()
+	base.DownloadDir = "."
+	config, err := layeredConfig(base)
+	if err != nil {
+		fatal(configError(err))
+	}
+
+	fs := flag.NewFlagSet("history", flag.ExitOnError)
+	status := fs.String("status", "", "only entries with this status: completed, skipped, failed, interrupted")
+	limit := fs.Int("limit", 50, "show at most this many entries, newest first (0 = all)")
+	jsonOutput := fs.Bool("json", false, "print entries as JSON lines")
+	redownload := fs.Bool("redownload-failed", false, "download again every URL whose last attempt failed")
+	positional, err := parseInterspersed(fs, args)
+	if err != nil {
+		fatal(configError(err))
+	}
+
+	history, err := OpenHistory(config.HistoryPath, config.DownloadDir)
+	if err != nil {
+		fatal(err)
+	}
+
+	if *redownload {
+		failed, err := history.Failed()
+		if err != nil {
+			fatal(err)
+		}
+		if len(failed) == 0 {
+			fmt.Printf("%sNo failed downloads to retry%s\n", ColorGreen, ColorReset)
+			return
+		}
+		// Failed entries keep the absolute path they were meant for
+		config.DownloadDir = ""
+		if err := setupLogging(config); err != nil {
+			fatal(configError(err))
+		}
+		dm, err := NewDownloadManager(config)
+		if err != nil {
+			fatal(configError(err))
+		}
+		var firstErr error
+		errorCount := 0
+		for i, entry := range failed {
+			fmt.Printf("%s[%d/%d] Downloading %s%s\n", ColorBlue, i+1, len(failed), entry.URL, ColorReset)
+			if err := dm.Download(context.Background(), &DownloadTask{URL: entry.URL, Filepath: entry.Path}); err != nil {
+				fmt.Printf("%s[%d/%d] Failed: %v%s\n", ColorRed, i+1, len(failed), err, ColorReset)
+				errorCount++
+				if firstErr == nil {
+					firstErr = err
+				}
+			}
+		}
+		if err := batchError(errorCount, len(failed), firstErr); err != nil {
+			fatal(err)
+		}
+		return
+	}
+
+	entries, err := history.Search(strings.Join(positional, " "), *status, *limit)
+	if err != nil {
+		fatal(err)
+	}
+	if *jsonOutput {
+		encoder := json.NewEncoder(os.Stdout)
+		for _, entry := range entries {
+			encoder.Encode(entry)
+		}
+		return
+	}
+	for _, entry := range entries {
+		color := ColorGreen
+		switch entry.Status {
+		case "failed", "interrupted":
+			color = ColorRed
+		case "skipped":
+			color = ColorYellow
+		}
+		fmt.Printf("%s  %s%-11s%s %10s %8s  %s\n", entry.FinishedAt.Local().Format("2006-01-02 15:04:05"),
+			color, entry.Status, ColorReset, formatBytes(entry.Size),
+			(time.Duration(entry.DurationMS) * time.Millisecond).Round(time.Second), entry.URL)
+		if entry.Path != "" {
+			fmt.Printf("  %s-> %s%s\n", ColorCyan, entry.Path, ColorReset)
+		}
+		if entry.Error != "" {
+			fmt.Printf("  %s%s%s\n", ColorRed, entry.Error, ColorReset)
+		}
+	}
+}
+
+// cmdCtl sends one command to a running daemon over its control socket
+func cmdCtl(args []string) {
+	config, err := layeredConfig(DefaultConfig())
+	if err != nil {
+		fatal(configError(err))
+	}
+
+	fs := flag.NewFlagSet("ctl", flag.ExitOnError)
+	socket := fs.String("socket", config.ControlSocket, "daemon control socket")
+	jsonOutput := fs.Bool("json", false, "print the daemon's raw JSON response")
+	output := fs.String("o", "", "add: output file name")
+	priority := fs.Int("priority", 0, "add: higher starts first")
+	sha256Hash := fs.String("sha256", "", "add: expected SHA256")
+
+	positional, err := parseInterspersed(fs, args)
+	if err != nil {
+		fatal(configError(err))
+	}
+	if len(positional) == 0 {
+		fmt.Println("Usage: fastdl ctl [options] <command> [args]")
+		fmt.Println("Commands: add URL, status, pause ID, resume ID, retry ID, rm ID, rate BYTES/SEC (0 = unlimited)")
+		fs.PrintDefaults()
+		os.Exit(ExitConfig)
+	}
+
+	req := controlRequest{Command: positional[0]}
+	arg := ""
+	if len(positional) > 1 {
+		arg = positional[1]
+	}
+	switch req.Command {
+	case "add":
+		req.URL, req.Output, req.Priority, req.SHA256 = arg, *output, *priority, *sha256Hash
+	case "rm", "remove":
+		req.Command, req.ID = "remove", arg
+	case "pause", "resume", "retry":
+		req.ID = arg
+	case "ls", "status":
+		req.Command = "status"
+	case "rate":
+		if arg != "0" {
+			if req.Rate, err = parseByteSize(arg); err != nil {
+				fatal(configError(err))
+			}
+		}
+	}
+
+	conn, err := net.DialTimeout("unix", *socket, 5*time.Second)
+	if err != nil {
+		fatal(fmt.Errorf("no daemon on %s, start one with 'fastdl daemon': %w", *socket, err))
+	}
+	defer conn.Close()
+
+	if err := json.NewEncoder(conn).Encode(req); err != nil {
+		fatal(err)
+	}
+	var response struct {
+		OK     bool            `json:"ok"`
+		Error  string          `json:"error"`
+		Result json.RawMessage `json:"result"`
+	}
+	if err := json.NewDecoder(conn).Decode(&response); err != nil {
+		fatal(err)
+	}
+
+	switch {
+	case *jsonOutput:
+		fmt.Println(string(response.Result))
+	case !response.OK:
+		fmt.Printf("%s%s%s\n", ColorRed, response.Error, ColorReset)
+	case req.Command == "status":
+		var summary struct {
+			Pending, Active, Completed, Failed int
+			Jobs                               map[string]*Job
+		}
+		json.Unmarshal(response.Result, &summary)
+		jobs := make([]*Job, 0, len(summary.Jobs))
+		for _, job := range summary.Jobs {
+			jobs = append(jobs, job)
+		}
+		sort.Slice(jobs, func(i, j int) bool { return jobs[i].AddedTime.Before(jobs[j].AddedTime) })
+		fmt.Printf("%s%d pending, %d active, %d completed, %d failed%s\n",
+			ColorCyan, summary.Pending, summary.Active, summary.Completed, summary.Failed, ColorReset)
+		for _, job := range jobs {
+			fmt.Printf("%-36s %-11s %3d  %10s  %s\n", job.ID, job.Status, job.Priority, formatBytes(job.Downloaded), job.URL)
+		}
+	case req.Command == "add":
+		var added struct{ ID string }
+		json.Unmarshal(response.Result, &added)
+		fmt.Printf("%sQueued:%s %s\n", ColorGreen, ColorReset, added.ID)
+	default:
+		fmt.Printf("%sOK%s\n", ColorGreen, ColorReset)
+	}
+	if !response.OK {
+		os.Exit(ExitFailure)
+	}
+}
+
 func cmdVerify(args []string) {
 	fs := flag.NewFlagSet("verify", flag.ExitOnError)
 	algorithm := fs.String("a", "sha256", "hash algorithm (sha256/sha1/md5)")
@@ -1724,7 +13320,7 @@ func cmdVerify(args []string) {
 	if fs.NArg() < 2 {
 		fmt.Println("Usage: fastdl verify [options] <file> <hash>")
 		fs.PrintDefaults()
-		os.Exit(1)
+		os.Exit(ExitConfig)
 	}
 
 	filepath := fs.Arg(0)
@@ -1744,7 +13340,7 @@ func cmdVerify(args []string) {
 		fmt.Printf("%s✗%s\n", ColorRed, ColorReset)
 		fmt.Printf("%sExpected: %s%s\n", ColorRed, expectedHash, ColorReset)
 		fmt.Printf("%sGot:      %s%s\n", ColorRed, calculatedHash, ColorReset)
-		os.Exit(1)
+		os.Exit(ExitChecksum)
 	}
 }
 
@@ -1783,7 +13379,7 @@ func cmdConfig(args []string) {
 		parts := strings.SplitN(*set, "=", 2)
 		if len(parts) != 2 {
 			fmt.Printf("%sInvalid format. Use: key=value%s\n", ColorRed, ColorReset)
-			os.Exit(1)
+			os.Exit(ExitConfig)
 		}
 		
 		key := strings.TrimSpace(parts[0])
@@ -1794,10 +13390,108 @@ func cmdConfig(args []string) {
 			config.MaxConnections, _ = strconv.Atoi(value)
 		case "download_dir":
 			config.DownloadDir = value
+		case "temp_dir":
+			config.TempDir = value
+		case "on_conflict":
+			config.OnConflict = value
+		case "output_template":
+			config.OutputTemplate = value
+		case "disk_space_margin_bytes":
+			config.DiskMargin, _ = strconv.ParseInt(value, 10, 64)
+		case "max_total_bytes":
+			config.MaxTotalBytes, _ = strconv.ParseInt(value, 10, 64)
+		case "async_writes":
+			config.AsyncWrites = value == "true"
+		case "globoff":
+			config.GlobOff = value == "true"
+		case "queue_order":
+			config.QueueOrder = value
+		case "control_socket":
+			config.ControlSocket = value
+		case "rpc_secret":
+			config.RPCSecret = value
+		case "api_token":
+			config.APIToken = value
+		case "log_level":
+			config.LogLevel = value
+		case "log_format":
+			config.LogFormat = value
+		case "log_file":
+			config.LogFile = value
+		case "log_max_bytes":
+			if size, err := parseByteSize(value); err == nil {
+				config.LogMaxBytes = size
+			}
+		case "log_keep":
+			if n, err := strconv.Atoi(value); err == nil {
+				config.LogKeep = n
+			}
+		case "history":
+			config.History = value == "true"
+		case "history_path":
+			config.HistoryPath = value
+		case "skip_downloaded":
+			config.SkipDownloaded = value == "true"
+		case "conditional":
+			config.Conditional = value == "true"
+		case "timestamping":
+			config.Timestamping = value == "true"
+		case "max_redirects":
+			if n, err := strconv.Atoi(value); err == nil {
+				config.MaxRedirects = n
+			}
+		case "user":
+			config.User = value
+		case "bearer_token":
+			config.BearerToken = value
+		case "refresh_command":
+			config.RefreshCommand = value
+		case "ca_cert":
+			config.CACert = value
+		case "client_cert":
+			config.ClientCert = value
+		case "client_key":
+			config.ClientKey = value
+		case "insecure":
+			config.Insecure = value == "true"
+		case "min_tls_version":
+			config.MinTLSVersion = value
 		case "rate_limit":
 			config.RateLimit, _ = strconv.ParseInt(value, 10, 64)
 		case "proxy_url":
 			config.ProxyURL = value
+		case "no_proxy":
+			config.NoProxy = strings.Split(value, ",")
+		case "cookie_file":
+			config.CookieFile = value
+		case "save_cookies":
+			config.SaveCookies = value == "true"
+		case "stripe_mirrors":
+			config.StripeMirrors = value == "true"
+		case "progress_format":
+			config.ProgressFormat = value
+		case "host_backoff":
+			config.HostBackoff = value == "true"
+		case "max_connections_per_host":
+			config.MaxPerHost, _ = strconv.Atoi(value)
+		case "work_stealing":
+			config.WorkStealing = value == "true"
+		case "adaptive_connections":
+			config.AdaptiveConns = value == "true"
+		case "enable_ftp":
+			config.EnableFTP = value == "true"
+		case "ftp_active":
+			config.FTPActive = value == "true"
+		case "ssh_key_file":
+			config.SSHKeyFile = value
+		case "ssh_known_hosts":
+			config.SSHKnownHosts = value
+		case "proxy_rules":
+			rules, err := parseProxyRules(value)
+			if err != nil {
+				log.Fatal(err)
+			}
+			config.ProxyRules = rules
 		case "daemon_port":
 			config.DaemonPort, _ = strconv.Atoi(value)
 		case "enable_http2":
@@ -1806,9 +13500,81 @@ func cmdConfig(args []string) {
 			config.EnableDaemon = value == "true"
 		case "max_parallel":
 			config.MaxParallel, _ = strconv.Atoi(value)
+		case "preconnect":
+			config.Preconnect = value == "true"
+		case "default_index_name":
+			config.DefaultIndexName = value
+		case "fresh_connection_after_retries":
+			config.FreshConnRetry, _ = strconv.Atoi(value)
+		case "verify_resume_tail_bytes":
+			config.VerifyResumeTail, _ = strconv.ParseInt(value, 10, 64)
+		case "dns_timeout_seconds":
+			config.DNSTimeout, _ = strconv.Atoi(value)
+		case "dns_cache_ttl_seconds":
+			config.DNSCacheTTL, _ = strconv.Atoi(value)
+		case "low_priority":
+			config.LowPriority = value == "true"
+		case "fail_fast":
+			config.FailFast = value == "true"
+		case "jitter_strategy":
+			config.JitterStrategy = value
+		case "chunk_size":
+			config.ChunkSize, _ = strconv.ParseInt(value, 10, 64)
+		case "chunk_queue":
+			config.ChunkQueue = value == "true"
+		case "tee_dirs":
+			config.TeeDirs = strings.Split(value, ",")
+		case "tee_fail_fatal":
+			config.TeeFailFatal = value == "true"
+		case "auto_tune":
+			config.AutoTune = value == "true"
+		case "use_netrc":
+			config.UseNetrc = value == "true"
+		case "netrc_file":
+			config.NetrcFile = value
+		case "circuit_breaker_threshold":
+			config.CircuitThreshold, _ = strconv.Atoi(value)
+		case "circuit_breaker_cooldown_seconds":
+			config.CircuitCooldown, _ = strconv.Atoi(value)
+		case "retry_budget_per_minute":
+			config.RetryBudget, _ = strconv.Atoi(value)
+		case "otlp_endpoint":
+			config.OTLPEndpoint = value
+		case "extract_archives":
+			config.Extract = value == "true"
+		case "filename_encoding":
+			config.FilenameEncoding = value
+		case "max_concurrent_dns":
+			config.MaxConcurrentDNS, _ = strconv.Atoi(value)
+		case "preallocate":
+			config.Preallocate = value
+		case "verbose":
+			config.Verbose = value == "true"
+		case "pipeline_depth":
+			config.PipelineDepth, _ = strconv.Atoi(value)
+		case "use_mmap":
+			config.UseMmap = value == "true"
+		case "shared_output":
+			config.SharedOutput = value == "true"
+		case "io_uring":
+			config.IOUring = value == "true"
+		case "buffer_size":
+			config.BufferSize, _ = strconv.Atoi(value)
+		case "min_speed_bytes_per_sec":
+			config.MinSpeed, _ = strconv.ParseInt(value, 10, 64)
+		case "min_speed_window_seconds":
+			config.MinSpeedWindow, _ = strconv.Atoi(value)
+		case "timeout_seconds":
+			config.Timeout, _ = strconv.Atoi(value)
+		case "connect_timeout_seconds":
+			config.ConnectTimeout, _ = strconv.Atoi(value)
+		case "read_timeout_seconds":
+			config.ReadTimeout, _ = strconv.Atoi(value)
+		case "stall_timeout_seconds":
+			config.StallTimeout, _ = strconv.Atoi(value)
 		default:
 			fmt.Printf("%sUnknown configuration key: %s%s\n", ColorRed, key, ColorReset)
-			os.Exit(1)
+			os.Exit(ExitConfig)
 		}
 		
 		if err := saveConfig(config); err != nil {
@@ -1865,7 +13631,10 @@ func cmdTUI(args []string) {
 	// Simple TUI mode using terminal controls
 	fmt.Printf("\033[2J\033[H") // Clear screen
 	
-	config, _ := loadConfig("")
+	config, err := layeredConfig(DefaultConfig())
+	if err != nil {
+		fatal(configError(err))
+	}
 	dm, err := NewDownloadManager(config)
 	if err != nil {
 		log.Fatal(err)
@@ -2014,8 +13783,9 @@ func cmdInfo() {
 	fmt.Printf("\n%sProtocols:%s\n", ColorCyan, ColorReset)
 	fmt.Printf("  • HTTP/HTTPS\n")
 	fmt.Printf("  • HTTP/2\n")
-	fmt.Printf("  • FTP (planned)\n")
-	fmt.Printf("  • BitTorrent (planned)\n")
+	fmt.Printf("  • FTP/FTPS\n")
+	fmt.Printf("  • SFTP\n")
+	fmt.Printf("  • BitTorrent web seeds (peers planned)\n")
 }
 
 func printUsage() {
@@ -2023,12 +13793,15 @@ func printUsage() {
 	fmt.Printf("%s║       FastDL v%s - High-Performance Downloader  ║%s\n", ColorGreen, Version, ColorReset)
 	fmt.Printf("%s╚══════════════════════════════════════════════════════╝%s\n\n", ColorGreen, ColorReset)
 	
-	fmt.Printf("%sUsage:%s fastdl <command> [options]\n\n", ColorCyan, ColorReset)
+	fmt.Printf("%sUsage:%s fastdl <command> [options]\n", ColorCyan, ColorReset)
+	fmt.Printf("       fastdl <url>... [-o DIR] [-c N] [-chunk-size 4M] [-retries N] [-i urls.txt] [-json]\n\n")
 	
 	fmt.Printf("%sCommands:%s\n", ColorYellow, ColorReset)
 	fmt.Printf("  %sdownload%s    Download a single file\n", ColorWhite, ColorReset)
 	fmt.Printf("  %sbatch%s       Download multiple files from URL list\n", ColorWhite, ColorReset)
 	fmt.Printf("  %sdaemon%s      Start daemon with Web UI\n", ColorWhite, ColorReset)
+	fmt.Printf("  %sctl%s         Control a running daemon\n", ColorWhite, ColorReset)
+	fmt.Printf("  %shistory%s     Search past downloads\n", ColorWhite, ColorReset)
 	fmt.Printf("  %stui%s         Interactive TUI mode\n", ColorWhite, ColorReset)
 	fmt.Printf("  %sconfig%s      Manage configuration\n", ColorWhite, ColorReset)
 	fmt.Printf("  %sverify%s      Verify file checksum\n", ColorWhite, ColorReset)
@@ -2075,6 +13848,10 @@ func main() {
 		cmdBatch(args)
 	case "daemon", "server":
 		cmdDaemon(args)
+	case "ctl":
+		cmdCtl(args)
+	case "history":
+		cmdHistory(args)
 	case "tui", "ui":
 		cmdTUI(args)
 	case "config", "cfg":
@@ -2088,8 +13865,13 @@ func main() {
 	case "version", "-v", "--version":
 		fmt.Printf("FastDL v%s\n", Version)
 	default:
+		// "fastdl <url>... [options]" without a subcommand
+		if strings.Contains(command, "://") || strings.HasPrefix(command, "-") || isDescriptor(command) {
+			cmdFetch(os.Args[1:])
+			return
+		}
 		fmt.Printf("%sUnknown command: %s%s\n\n", ColorRed, command, ColorReset)
 		printUsage()
-		os.Exit(1)
+		os.Exit(ExitConfig)
 	}
 }
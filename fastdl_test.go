@@ -0,0 +1,272 @@
+package main
+
+import (
+	"bytes"
+	"context"
+	"errors"
+	"fmt"
+	"math/rand"
+	"net/http"
+	"net/http/httptest"
+	"os"
+	"path"
+	"path/filepath"
+	"strconv"
+	"strings"
+	"sync"
+	"testing"
+	"time"
+)
+
+// newTestManager builds a manager that writes into a temporary directory
+// and keeps no history
+func newTestManager(t *testing.T) *DownloadManager {
+	t.Helper()
+	config := DefaultConfig()
+	config.DownloadDir = t.TempDir()
+	config.History = false
+	config.RetryDelay = 1
+	dm, err := NewDownloadManager(config)
+	if err != nil {
+		t.Fatalf("NewDownloadManager: %v", err)
+	}
+	return dm
+}
+
+// testData is size bytes that differ from position to position, so data
+// landing at the wrong offset shows up
+func testData(size int) []byte {
+	data := make([]byte, size)
+	rand.New(rand.NewSource(1)).Read(data)
+	return data
+}
+
+// serveData answers every path with data, honouring HEAD and Range
+func serveData(t *testing.T, data []byte) *httptest.Server {
+	t.Helper()
+	server := httptest.NewServer(http.HandlerFunc(func(w http.ResponseWriter, r *http.Request) {
+		http.ServeContent(w, r, path.Base(r.URL.Path), time.Time{}, bytes.NewReader(data))
+	}))
+	t.Cleanup(server.Close)
+	return server
+}
+
+// checkFile fails the test unless the task's output holds want
+func checkFile(t *testing.T, dm *DownloadManager, task *DownloadTask, want []byte) {
+	t.Helper()
+	got, err := os.ReadFile(filepath.Join(dm.downloadDir, task.Filepath))
+	if err != nil {
+		t.Fatalf("%s: %v", task.URL, err)
+	}
+	if !bytes.Equal(got, want) {
+		t.Errorf("%s: output differs from the served file (%d of %d bytes)", task.URL, len(got), len(want))
+	}
+}
+
+func TestExtractFilename(t *testing.T) {
+	tests := []struct {
+		name  string
+		url   string
+		index string
+		want  string
+	}{
+		{"file", "https://example.com/files/archive.tar.gz", "index.html", "archive.tar.gz"},
+		{"query string", "https://example.com/file.iso?token=abc", "index.html", "file.iso"},
+		{"escaped name", "https://example.com/my%20file.txt", "index.html", "my file.txt"},
+		{"trailing slash", "https://example.com/docs/", "index.html", "index.html"},
+		{"nested trailing slash", "https://example.com/a/b/c/", "default.htm", "default.htm"},
+		{"bare host", "https://example.com", "index.html", "index.html"},
+		{"bare host with slash", "https://example.com/", "index.html", "index.html"},
+		{"bare host with port", "http://localhost:8080", "index.html", "index.html"},
+		{"trailing slash without index name", "https://example.com/docs/", "", "download_"},
+		{"bare host without index name", "https://example.com", "", "download_"},
+	}
+	for _, tt := range tests {
+		t.Run(tt.name, func(t *testing.T) {
+			got, err := extractFilename(tt.url, &Config{DefaultIndexName: tt.index})
+			if err != nil {
+				t.Fatalf("extractFilename(%q) error: %v", tt.url, err)
+			}
+			// The fallback ends in a timestamp
+			if strings.HasSuffix(tt.want, "_") {
+				if !strings.HasPrefix(got, tt.want) {
+					t.Errorf("extractFilename(%q) = %q, want prefix %q", tt.url, got, tt.want)
+				}
+				return
+			}
+			if got != tt.want {
+				t.Errorf("extractFilename(%q) = %q, want %q", tt.url, got, tt.want)
+			}
+		})
+	}
+}
+
+func TestSanitizeFilename(t *testing.T) {
+	tests := []struct {
+		name   string
+		input  string
+		policy string
+		want   string // "" means the policy rejects it
+	}{
+		{"utf-8 latin-1 kept", "café.txt", "keep", "café.txt"},
+		{"utf-8 latin-1 default", "café.txt", "", "café.txt"},
+		{"utf-8 latin-1 ascii", "café.txt", "ascii", "caf_.txt"},
+		{"utf-8 latin-1 escaped", "café.txt", "escape", "caf%C3%A9.txt"},
+		{"utf-8 latin-1 rejected", "café.txt", "reject", ""},
+
+		{"raw latin-1 kept", "caf\xe9.txt", "keep", "caf_.txt"},
+		{"raw latin-1 ascii", "caf\xe9.txt", "ascii", "caf_.txt"},
+		{"raw latin-1 escaped", "caf\xe9.txt", "escape", "caf%E9.txt"},
+		{"raw latin-1 rejected", "caf\xe9.txt", "reject", ""},
+
+		{"control kept", "a\x01b\tc.txt", "keep", "a_b_c.txt"},
+		{"control ascii", "a\x01b\tc.txt", "ascii", "a_b_c.txt"},
+		{"control escaped", "a\x01b\tc.txt", "escape", "a%01b%09c.txt"},
+		{"control rejected", "a\x01b\tc.txt", "reject", ""},
+		{"delete escaped", "a\x7fb", "escape", "a%7Fb"},
+
+		{"windows reserved kept", `a:b?c.txt`, "keep", "a_b_c.txt"},
+		{"windows reserved escaped", `a:b?c.txt`, "escape", "a%3Ab%3Fc.txt"},
+		{"plain ascii rejected policy", "report-2024.pdf", "reject", "report-2024.pdf"},
+	}
+	for _, tt := range tests {
+		t.Run(tt.name, func(t *testing.T) {
+			got, err := sanitizeFilename(tt.input, tt.policy)
+			if tt.want == "" {
+				if err == nil {
+					t.Errorf("sanitizeFilename(%q, %q) = %q, want an error", tt.input, tt.policy, got)
+				}
+				return
+			}
+			if err != nil {
+				t.Fatalf("sanitizeFilename(%q, %q) error: %v", tt.input, tt.policy, err)
+			}
+			if got != tt.want {
+				t.Errorf("sanitizeFilename(%q, %q) = %q, want %q", tt.input, tt.policy, got, tt.want)
+			}
+		})
+	}
+}
+
+// panicObserver panics when a chunk of a URL containing "bad" starts
+type panicObserver struct {
+	NopObserver
+}
+
+func (panicObserver) OnChunkStart(task *DownloadTask, _ ChunkInfo, _ int) {
+	if strings.Contains(task.URL, "bad") {
+		panic("injected panic")
+	}
+}
+
+func TestPanicFailsOnlyItsDownload(t *testing.T) {
+	data := testData(1 << 20)
+	server := serveData(t, data)
+	dm := newTestManager(t)
+	dm.AddObserver(panicObserver{})
+
+	tasks := []*DownloadTask{
+		{URL: server.URL + "/one.bin", Chunks: 4},
+		{URL: server.URL + "/bad.bin", Chunks: 4},
+		{URL: server.URL + "/two.bin", Chunks: 4},
+	}
+	errs := make([]error, len(tasks))
+	var wg sync.WaitGroup
+	for i, task := range tasks {
+		wg.Add(1)
+		go func(i int, task *DownloadTask) {
+			defer wg.Done()
+			errs[i] = dm.Download(context.Background(), task)
+		}(i, task)
+	}
+	wg.Wait()
+
+	if !errors.Is(errs[1], errPanic) {
+		t.Errorf("bad.bin: got %v, want a panic error", errs[1])
+	}
+	for _, i := range []int{0, 2} {
+		if errs[i] != nil {
+			t.Errorf("%s failed: %v", tasks[i].URL, errs[i])
+			continue
+		}
+		checkFile(t, dm, tasks[i], data)
+	}
+}
+
+// retryRecorder keeps the errors chunks were retried for
+type retryRecorder struct {
+	NopObserver
+	mu     sync.Mutex
+	errors []error
+}
+
+func (r *retryRecorder) OnRetry(_ *DownloadTask, _ ChunkInfo, _ int, err error) {
+	r.mu.Lock()
+	defer r.mu.Unlock()
+	r.errors = append(r.errors, err)
+}
+
+func TestTruncatedChunkIsRetried(t *testing.T) {
+	data := testData(1 << 20)
+	var truncated sync.Once
+	server := httptest.NewServer(http.HandlerFunc(func(w http.ResponseWriter, r *http.Request) {
+		start, end, ok := parseRange(r.Header.Get("Range"))
+		if !ok || r.Method != http.MethodGet {
+			http.ServeContent(w, r, "file.bin", time.Time{}, bytes.NewReader(data))
+			return
+		}
+		cut := false
+		truncated.Do(func() { cut = true })
+		if !cut {
+			http.ServeContent(w, r, "file.bin", time.Time{}, bytes.NewReader(data))
+			return
+		}
+		// A well-formed 206 whose body ends cleanly halfway through
+		half := (end - start + 1) / 2
+		w.Header().Set("Content-Range", fmt.Sprintf("bytes %d-%d/%d", start, end, len(data)))
+		w.Header().Set("Content-Length", strconv.FormatInt(half, 10))
+		w.WriteHeader(http.StatusPartialContent)
+		w.Write(data[start : start+half])
+	}))
+	t.Cleanup(server.Close)
+
+	dm := newTestManager(t)
+	retries := &retryRecorder{}
+	dm.AddObserver(retries)
+	task := &DownloadTask{URL: server.URL + "/file.bin", Chunks: 4}
+	if err := dm.Download(context.Background(), task); err != nil {
+		t.Fatalf("download failed: %v", err)
+	}
+	checkFile(t, dm, task, data)
+
+	if len(retries.errors) != 1 || !errors.Is(retries.errors[0], errShortChunk) {
+		t.Errorf("got retries %v, want one for a short chunk", retries.errors)
+	}
+}
+
+// parseRange reads a "bytes=start-end" request header
+func parseRange(header string) (start, end int64, ok bool) {
+	spec, found := strings.CutPrefix(header, "bytes=")
+	if !found {
+		return 0, 0, false
+	}
+	first, last, found := strings.Cut(spec, "-")
+	if !found {
+		return 0, 0, false
+	}
+	start, err1 := strconv.ParseInt(first, 10, 64)
+	end, err2 := strconv.ParseInt(last, 10, 64)
+	return start, end, err1 == nil && err2 == nil
+}
+
+func TestBdecodeDepthLimit(t *testing.T) {
+	nested := func(depth int) []byte {
+		return []byte(strings.Repeat("l", depth) + "i1e" + strings.Repeat("e", depth))
+	}
+	if _, _, err := bdecode(nested(MaxBencodeDepth), 0); err != nil {
+		t.Errorf("%d levels: %v", MaxBencodeDepth, err)
+	}
+	if _, _, err := bdecode(nested(100000), 0); err == nil {
+		t.Error("100000 levels decoded, want an error")
+	}
+}
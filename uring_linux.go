@@ -0,0 +1,32 @@
+package main
+
+import (
+	"syscall"
+	"unsafe"
+)
+
+// io_uring_setup(2) and io_uring_enter(2), which syscall has no wrappers
+// for; the numbers are the same on every architecture
+const (
+	sysIOUringSetup = 425
+	sysIOUringEnter = 426
+)
+
+// ioURingSetup creates a ring and returns its file descriptor
+func ioURingSetup(entries uint32, params *uringParams) (int, error) {
+	fd, _, errno := syscall.Syscall(sysIOUringSetup, uintptr(entries), uintptr(unsafe.Pointer(params)), 0)
+	if errno != 0 {
+		return 0, errno
+	}
+	return int(fd), nil
+}
+
+// ioURingEnter submits toSubmit entries and, with iouringEnterGetEvents in
+// flags, waits for minComplete completions
+func ioURingEnter(fd int, toSubmit, minComplete, flags uint32) error {
+	_, _, errno := syscall.Syscall6(sysIOUringEnter, uintptr(fd), uintptr(toSubmit), uintptr(minComplete), uintptr(flags), 0, 0)
+	if errno != 0 {
+		return errno
+	}
+	return nil
+}
@@ -0,0 +1,16 @@
+//go:build !linux
+
+package main
+
+import "errors"
+
+// io_uring is Linux only; elsewhere newURing fails and shared_output falls
+// back to pwrite
+
+func ioURingSetup(entries uint32, params *uringParams) (int, error) {
+	return 0, errors.ErrUnsupported
+}
+
+func ioURingEnter(fd int, toSubmit, minComplete, flags uint32) error {
+	return errors.ErrUnsupported
+}